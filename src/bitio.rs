@@ -0,0 +1,474 @@
+use std::io::{self, Read, Write};
+
+// Mask with the lowest `count` bits set
+fn low_bits(count: u32) -> u64 {
+    if count >= 64 { u64::MAX } else { (1u64 << count) - 1 }
+}
+
+// Writes bits most significant bit first, collecting them in a 64-bit word and
+// handing whole words to the underlying writer as soon as they fill up
+pub struct BitWriter<W: Write> {
+    inner: W,
+    buffer: u64,
+    // Number of bits currently held in `buffer`
+    bit_count: u32,
+    bits_written: u64,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BitWriter {
+            inner,
+            buffer: 0,
+            bit_count: 0,
+            bits_written: 0,
+        }
+    }
+
+    // Write the lowest `count` bits of `value`, most significant first
+    pub fn write_bits(&mut self, value: u64, count: u32) -> io::Result<()> {
+        debug_assert!(count <= 64);
+        if count == 0 {
+            return Ok(());
+        }
+        let value = value & low_bits(count);
+        self.bits_written += count as u64;
+
+        let free = 64 - self.bit_count;
+        if count < free {
+            self.buffer = (self.buffer << count) | value;
+            self.bit_count += count;
+            return Ok(());
+        }
+
+        // Top off the word with the leading bits of `value` and flush it
+        let rest = count - free;
+        let head = value >> rest;
+        self.buffer = if free == 64 { head } else { (self.buffer << free) | head };
+        self.inner.write_all(&self.buffer.to_be_bytes())?;
+
+        self.buffer = value & low_bits(rest);
+        self.bit_count = rest;
+        Ok(())
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+        self.write_bits(bit as u64, 1)
+    }
+
+    // Total number of bits written so far, not counting padding
+    pub fn bits_written(&self) -> u64 {
+        self.bits_written
+    }
+
+    // Pad the last partial byte with zero bits and write out everything buffered
+    pub fn flush_to_byte(&mut self) -> io::Result<()> {
+        let padding = (8 - self.bit_count % 8) % 8;
+        let total = self.bit_count + padding;
+        let word = if total == 0 { 0 } else { self.buffer << padding << (64 - total) };
+        self.inner.write_all(&word.to_be_bytes()[..(total / 8) as usize])?;
+        self.buffer = 0;
+        self.bit_count = 0;
+        Ok(())
+    }
+
+    // Pad to a byte boundary and hand back the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_to_byte()?;
+        Ok(self.inner)
+    }
+}
+
+// Reads bits most significant bit first. Bytes are pulled from the underlying
+// reader only when the bits are actually needed, so once the reader has been
+// aligned to a byte boundary it is positioned right after the last byte used.
+pub struct BitReader<R: Read> {
+    inner: R,
+    buffer: u64,
+    // Number of unread bits in the low end of `buffer`
+    bit_count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R) -> Self {
+        BitReader {
+            inner,
+            buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    // Make sure at least `count` bits are buffered
+    fn refill(&mut self, count: u32) -> io::Result<()> {
+        while self.bit_count < count {
+            let mut byte = [0u8; 1];
+            self.inner.read_exact(&mut byte)?;
+            self.buffer = (self.buffer << 8) | byte[0] as u64;
+            self.bit_count += 8;
+        }
+        Ok(())
+    }
+
+    // Read `count` bits (at most 56) as the low bits of the result
+    pub fn read_bits(&mut self, count: u32) -> io::Result<u64> {
+        debug_assert!(count <= 56);
+        if count == 0 {
+            return Ok(0);
+        }
+        self.refill(count)?;
+        self.bit_count -= count;
+        Ok((self.buffer >> self.bit_count) & low_bits(count))
+    }
+
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    // Look at the next `count` bits (at most 56) without consuming them
+    pub fn peek_bits(&mut self, count: u32) -> io::Result<u64> {
+        debug_assert!(count <= 56);
+        if count == 0 {
+            return Ok(0);
+        }
+        self.refill(count)?;
+        Ok((self.buffer >> (self.bit_count - count)) & low_bits(count))
+    }
+
+    // Consume bits that have already been peeked at
+    pub fn consume(&mut self, count: u32) {
+        debug_assert!(count <= self.bit_count);
+        self.bit_count -= count;
+    }
+
+    // Drop the bits left over in the current byte (the padding)
+    pub fn align_to_byte(&mut self) {
+        self.bit_count -= self.bit_count % 8;
+    }
+
+    // Hand back the underlying reader. Any whole bytes still buffered are lost,
+    // which can't happen after reading up to and aligning to a byte boundary.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+// Source of bits for Huffman decoding. Codes are read one bit at a time in
+// stream order, so whatever the bit packing, the next bits are handed out
+// with the first one most significant.
+pub trait CodeBits {
+    // Look at the next `count` bits (at most 32) without consuming them
+    fn peek_code_bits(&mut self, count: u32) -> io::Result<u64>;
+
+    // Consume bits that have already been peeked at
+    fn consume_code_bits(&mut self, count: u32) -> io::Result<()>;
+}
+
+impl<R: Read> CodeBits for BitReader<R> {
+    fn peek_code_bits(&mut self, count: u32) -> io::Result<u64> {
+        self.peek_bits(count)
+    }
+
+    fn consume_code_bits(&mut self, count: u32) -> io::Result<()> {
+        self.consume(count);
+        Ok(())
+    }
+}
+
+// Reverse the order of the lowest `count` bits of `value`
+pub fn reverse_bits(value: u64, count: u32) -> u64 {
+    if count == 0 { 0 } else { value.reverse_bits() >> (64 - count) }
+}
+
+// Writes bits least significant bit first, the way DEFLATE packs them
+pub struct LsbBitWriter<W: Write> {
+    inner: W,
+    buffer: u64,
+    // Number of bits currently held in the low end of `buffer`
+    bit_count: u32,
+    bits_written: u64,
+}
+
+impl<W: Write> LsbBitWriter<W> {
+    pub fn new(inner: W) -> Self {
+        LsbBitWriter {
+            inner,
+            buffer: 0,
+            bit_count: 0,
+            bits_written: 0,
+        }
+    }
+
+    // Write the lowest `count` bits (at most 32) of `value`, least significant first
+    pub fn write_bits(&mut self, value: u64, count: u32) -> io::Result<()> {
+        debug_assert!(count <= 32);
+        self.buffer |= (value & low_bits(count)) << self.bit_count;
+        self.bit_count += count;
+        self.bits_written += count as u64;
+        if self.bit_count >= 32 {
+            self.inner.write_all(&(self.buffer as u32).to_le_bytes())?;
+            self.buffer >>= 32;
+            self.bit_count -= 32;
+        }
+        Ok(())
+    }
+
+    // Write a Huffman code of `length` bits. Codes go out most significant
+    // bit first even though everything else is packed the other way round.
+    pub fn write_code(&mut self, code: u64, length: u32) -> io::Result<()> {
+        self.write_bits(reverse_bits(code, length), length)
+    }
+
+    // Total number of bits written so far, not counting padding
+    pub fn bits_written(&self) -> u64 {
+        self.bits_written
+    }
+
+    // Write zero bits up to the next byte boundary. Unlike the padding added
+    // by flush_to_byte, these bits are part of the stream.
+    pub fn align_to_byte(&mut self) -> io::Result<()> {
+        self.write_bits(0, (8 - self.bit_count % 8) % 8)
+    }
+
+    // Pad the last partial byte with zero bits and write out everything buffered
+    pub fn flush_to_byte(&mut self) -> io::Result<()> {
+        let bytes = self.bit_count.div_ceil(8) as usize;
+        self.inner.write_all(&self.buffer.to_le_bytes()[..bytes])?;
+        self.buffer = 0;
+        self.bit_count = 0;
+        Ok(())
+    }
+
+    // Pad to a byte boundary and hand back the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_to_byte()?;
+        Ok(self.inner)
+    }
+}
+
+// Reads bits least significant bit first. Like BitReader it only pulls bytes
+// from the underlying reader as they are needed, but Huffman decoding may
+// peek a little past the end of the bit stream. Once aligned to a byte, the
+// reader itself implements Read and hands out any bytes it already pulled in
+// before reading further, so whatever follows the bit stream isn't lost.
+pub struct LsbBitReader<R: Read> {
+    inner: R,
+    buffer: u64,
+    // Number of unread bits in the low end of `buffer`
+    bit_count: u32,
+}
+
+impl<R: Read> LsbBitReader<R> {
+    pub fn new(inner: R) -> Self {
+        LsbBitReader {
+            inner,
+            buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    // Try to buffer at least `count` bits, stopping early at the end of the input
+    fn refill(&mut self, count: u32) -> io::Result<()> {
+        while self.bit_count < count {
+            let mut byte = [0u8; 1];
+            if self.inner.read(&mut byte)? == 0 {
+                return Ok(());
+            }
+            self.buffer |= (byte[0] as u64) << self.bit_count;
+            self.bit_count += 8;
+        }
+        Ok(())
+    }
+
+    // Read `count` bits (at most 32), the first one least significant
+    pub fn read_bits(&mut self, count: u32) -> io::Result<u64> {
+        debug_assert!(count <= 32);
+        self.refill(count)?;
+        if self.bit_count < count {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let value = self.buffer & low_bits(count);
+        self.buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    // Drop the bits left over in the current byte (the padding)
+    pub fn align_to_byte(&mut self) {
+        let padding = self.bit_count % 8;
+        self.buffer >>= padding;
+        self.bit_count -= padding;
+    }
+}
+
+impl<R: Read> CodeBits for LsbBitReader<R> {
+    // Bits past the end of the input read as zeros; consuming them fails
+    fn peek_code_bits(&mut self, count: u32) -> io::Result<u64> {
+        debug_assert!(count <= 32);
+        self.refill(count)?;
+        Ok(reverse_bits(self.buffer & low_bits(count), count))
+    }
+
+    fn consume_code_bits(&mut self, count: u32) -> io::Result<()> {
+        if count > self.bit_count {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buffer >>= count;
+        self.bit_count -= count;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for LsbBitReader<R> {
+    // Only valid on a byte boundary
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        debug_assert!(self.bit_count.is_multiple_of(8));
+        if self.bit_count == 0 {
+            return self.inner.read(buf);
+        }
+        let mut count = 0;
+        while count < buf.len() && self.bit_count > 0 {
+            buf[count] = self.buffer as u8;
+            self.buffer >>= 8;
+            self.bit_count -= 8;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_bits_are_written_msb_first() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(0b101, 3).unwrap();
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0b0000_1111, 8).unwrap();
+        assert_eq!(writer.bits_written(), 12);
+        assert_eq!(writer.finish().unwrap(), vec![0b1011_0000, 0b1111_0000]);
+    }
+
+    #[test]
+    fn test_round_trip_across_word_boundaries() {
+        // Mix of widths so values straddle the 64-bit buffer over and over
+        let values: Vec<(u64, u32)> = (0..1000u64)
+            .map(|i| (i.wrapping_mul(0x9E37_79B9_7F4A_7C15), (i % 56) as u32 + 1))
+            .collect();
+
+        let mut writer = BitWriter::new(Vec::new());
+        for &(value, count) in &values {
+            writer.write_bits(value, count).unwrap();
+        }
+        let total_bits = writer.bits_written();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes.len() as u64, total_bits.div_ceil(8));
+
+        let mut reader = BitReader::new(Cursor::new(bytes));
+        for &(value, count) in &values {
+            assert_eq!(reader.read_bits(count).unwrap(), value & low_bits(count));
+        }
+    }
+
+    #[test]
+    fn test_full_word_writes() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(0x0123_4567_89AB_CDEF, 64).unwrap();
+        writer.write_bits(0x1, 4).unwrap();
+        writer.write_bits(0xFEDC_BA98_7654_3210, 64).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(
+            bytes,
+            vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0x1F, 0xED, 0xCB, 0xA9, 0x87, 0x65, 0x43, 0x21, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_reader_does_not_read_ahead() {
+        let mut reader = BitReader::new(Cursor::new(vec![0b1100_0000, 0xAA, 0xBB]));
+        assert!(reader.read_bit().unwrap());
+        assert!(reader.read_bit().unwrap());
+        reader.align_to_byte();
+
+        let mut rest = Vec::new();
+        reader.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn test_peek_and_consume() {
+        let mut reader = BitReader::new(Cursor::new(vec![0b1011_0110, 0b0100_0000]));
+        assert_eq!(reader.peek_bits(4).unwrap(), 0b1011);
+        assert_eq!(reader.peek_bits(10).unwrap(), 0b10_1101_1001);
+        reader.consume(3);
+        assert_eq!(reader.read_bits(7).unwrap(), 0b101_1001);
+    }
+
+    #[test]
+    fn test_lsb_bits_round_trip() {
+        let values: Vec<(u64, u32)> = (0..1000u64)
+            .map(|i| (i.wrapping_mul(0x9E37_79B9_7F4A_7C15), (i % 32) as u32 + 1))
+            .collect();
+
+        let mut writer = LsbBitWriter::new(Vec::new());
+        for &(value, count) in &values {
+            writer.write_bits(value, count).unwrap();
+        }
+        let total_bits = writer.bits_written();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes.len() as u64, total_bits.div_ceil(8));
+
+        let mut reader = LsbBitReader::new(Cursor::new(bytes));
+        for &(value, count) in &values {
+            assert_eq!(reader.read_bits(count).unwrap(), value & low_bits(count));
+        }
+    }
+
+    // DEFLATE packs values from the least significant bit and codes from the
+    // most significant one
+    #[test]
+    fn test_lsb_packing_and_codes() {
+        let mut writer = LsbBitWriter::new(Vec::new());
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0b01, 2).unwrap();
+        writer.write_code(0b00110, 5).unwrap();
+        assert_eq!(writer.finish().unwrap(), vec![0b0110_0011]);
+
+        let mut reader = LsbBitReader::new(Cursor::new(vec![0b0110_0011]));
+        assert_eq!(reader.read_bits(3).unwrap(), 0b011);
+        assert_eq!(reader.peek_code_bits(5).unwrap(), 0b00110);
+        reader.consume_code_bits(5).unwrap();
+    }
+
+    // Peeking past the end gives zeros, but those bits can't be consumed
+    #[test]
+    fn test_lsb_peek_past_the_end() {
+        let mut reader = LsbBitReader::new(Cursor::new(vec![0b0000_0001]));
+        assert_eq!(reader.peek_code_bits(12).unwrap(), 0b1000_0000_0000);
+        reader.consume_code_bits(8).unwrap();
+        assert!(reader.consume_code_bits(1).is_err());
+    }
+
+    // Bytes pulled in by a peek are handed out again once aligned
+    #[test]
+    fn test_lsb_reader_returns_buffered_bytes() {
+        let mut reader = LsbBitReader::new(Cursor::new(vec![0xFF, 0xAA, 0xBB, 0xCC]));
+        assert_eq!(reader.read_bits(3).unwrap(), 0b111);
+        reader.peek_code_bits(20).unwrap();
+        reader.align_to_byte();
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![0xAA, 0xBB, 0xCC]);
+    }
+
+    #[test]
+    fn test_reading_past_the_end_fails() {
+        let mut reader = BitReader::new(Cursor::new(vec![0xFF]));
+        assert_eq!(reader.read_bits(4).unwrap(), 0xF);
+        let err = reader.read_bits(5).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,342 @@
+// The Burrows-Wheeler transform and the stages bzip2 runs after it. The BWT
+// sorts every suffix of a block and outputs the byte before each one, which
+// groups bytes by what follows them: text turns into long runs of a few
+// bytes. Move-to-front then turns those runs into mostly zeros, and zero runs
+// are coded as numbers so the entropy coder sees short, skewed symbol streams.
+
+use crate::error::{Error, Result};
+
+// Largest block the transform takes, as in bzip2 -9
+pub const MAX_BWT_BLOCK_SIZE: usize = 900_000;
+
+// Symbols after move-to-front and zero-run coding. RUN_A and RUN_B are the
+// digits of a run of zeros in bijective base 2; any other index `v` is coded as
+// `v + 1`.
+pub const RUN_A: u16 = 0;
+pub const RUN_B: u16 = 1;
+pub const MTF_ALPHABET_SIZE: usize = 257;
+
+// The start of every suffix of `data`, in sorted order. A suffix that is a
+// prefix of another sorts first. Prefix doubling: after the round with step
+// `k`, suffixes are ranked by their first 2k bytes, so O(n log n) overall.
+pub fn suffix_array(data: &[u8]) -> Vec<u32> {
+    let n = data.len();
+    if n == 0 {
+        return Vec::new();
+    }
+
+    // Sort by the first byte, then rank by it. Ranks start at one so zero can
+    // stand for "past the end".
+    let bytes: Vec<u32> = data.iter().map(|&byte| byte as u32).collect();
+    let mut suffixes: Vec<u32> = (0..n as u32).collect();
+    counting_sort(&mut suffixes, &bytes, 256);
+    let mut rank = vec![0u32; n];
+    rerank(&suffixes, &mut rank, |i| bytes[i as usize]);
+
+    let mut next_rank = vec![0u32; n];
+    let mut by_second_key = Vec::with_capacity(n);
+    let mut k = 1;
+    while rank[suffixes[n - 1] as usize] as usize != n {
+        // Sort by the first 2k bytes: order by the second half, which is the
+        // rank of the suffix k further on, then stably by the first half
+        by_second_key.clear();
+        by_second_key.extend((n - k.min(n)) as u32..n as u32);
+        by_second_key.extend(suffixes.iter().filter(|&&i| i as usize >= k).map(|&i| i - k as u32));
+        std::mem::swap(&mut suffixes, &mut by_second_key);
+        counting_sort(&mut suffixes, &rank, n + 1);
+
+        rerank(&suffixes, &mut next_rank, |i| (rank[i as usize], rank.get(i as usize + k).copied().unwrap_or(0)));
+        std::mem::swap(&mut rank, &mut next_rank);
+        k *= 2;
+    }
+    suffixes
+}
+
+// Rank sorted suffixes from one up, giving equal keys equal ranks
+fn rerank<K: PartialEq>(suffixes: &[u32], rank: &mut [u32], key: impl Fn(u32) -> K) {
+    rank[suffixes[0] as usize] = 1;
+    for pair in suffixes.windows(2) {
+        let step = (key(pair[0]) != key(pair[1])) as u32;
+        rank[pair[1] as usize] = rank[pair[0] as usize] + step;
+    }
+}
+
+// Stable sort of `items` by `keys[item]`, all keys below `key_count`
+fn counting_sort(items: &mut Vec<u32>, keys: &[u32], key_count: usize) {
+    let mut starts = vec![0usize; key_count + 1];
+    for &item in items.iter() {
+        starts[keys[item as usize] as usize + 1] += 1;
+    }
+    for key in 1..starts.len() {
+        starts[key] += starts[key - 1];
+    }
+    let mut sorted = vec![0u32; items.len()];
+    for &item in items.iter() {
+        let start = &mut starts[keys[item as usize] as usize];
+        sorted[*start] = item;
+        *start += 1;
+    }
+    *items = sorted;
+}
+
+// Transform a block. Returns the last column of the sorted rotations of the
+// block with an end marker appended, without the marker itself, and the row the
+// marker was in.
+pub fn forward(data: &[u8]) -> (Vec<u8>, u32) {
+    let mut output = Vec::with_capacity(data.len());
+    // The rotation starting at the marker sorts first and ends in the last byte
+    let mut primary = 0;
+    if let Some(&last) = data.last() {
+        output.push(last);
+    }
+    for (row, &start) in suffix_array(data).iter().enumerate() {
+        match start {
+            0 => primary = row as u32 + 1,
+            _ => output.push(data[start as usize - 1]),
+        }
+    }
+    (output, primary)
+}
+
+// Undo `forward`
+pub fn inverse(transformed: &[u8], primary: u32) -> Result<Vec<u8>> {
+    let n = transformed.len();
+    let primary = primary as usize;
+    if n == 0 || primary == 0 || primary > n {
+        return Err(Error::CorruptData(format!("invalid BWT primary index {}", primary)));
+    }
+
+    // The byte in each row of the last column, with the marker in row `primary`
+    let last_column = |row: usize| if row < primary { transformed[row] } else { transformed[row - 1] };
+
+    // Row of the first column each row's byte moves to; the marker takes row 0
+    let mut starts = [0usize; 257];
+    for &byte in transformed {
+        starts[byte as usize + 1] += 1;
+    }
+    starts[0] = 1;
+    for byte in 1..257 {
+        starts[byte] += starts[byte - 1];
+    }
+    let mut next = vec![0u32; n + 1];
+    for row in (0..=n).filter(|&row| row != primary) {
+        let byte = last_column(row) as usize;
+        next[row] = starts[byte] as u32;
+        starts[byte] += 1;
+    }
+
+    // Walk backwards from the rotation that starts with the marker
+    let mut output = vec![0u8; n];
+    let mut row = 0;
+    for i in (0..n).rev() {
+        if row == primary {
+            return Err(Error::CorruptData("BWT data doesn't form a single cycle".to_string()));
+        }
+        output[i] = last_column(row);
+        row = next[row] as usize;
+    }
+    if row != primary {
+        return Err(Error::CorruptData("BWT data doesn't form a single cycle".to_string()));
+    }
+    Ok(output)
+}
+
+// The transform as bzip2 does it, without a marker: the last column of the
+// sorted rotations of the block, and the row holding the block itself. Equal
+// rotations may come in any order; they end in the same byte.
+pub fn forward_cyclic(data: &[u8]) -> (Vec<u8>, u32) {
+    let n = data.len();
+    // The first n bytes of each suffix of the doubled block are a rotation
+    let doubled = [data, data].concat();
+    let mut output = Vec::with_capacity(n);
+    let mut primary = 0;
+    for start in suffix_array(&doubled).into_iter().filter(|&start| (start as usize) < n) {
+        if start == 0 {
+            primary = output.len() as u32;
+        }
+        output.push(data[(start as usize + n - 1) % n]);
+    }
+    (output, primary)
+}
+
+// Undo `forward_cyclic`
+pub fn inverse_cyclic(transformed: &[u8], primary: u32) -> Result<Vec<u8>> {
+    let n = transformed.len();
+    let primary = primary as usize;
+    if primary >= n {
+        return Err(Error::CorruptData(format!("invalid BWT primary index {}", primary)));
+    }
+
+    // The row each row's rotation moves to when its last byte moves to the
+    // front, so following it from the primary row reads the block forwards
+    let mut starts = [0usize; 256];
+    for &byte in transformed {
+        starts[byte as usize] += 1;
+    }
+    let mut total = 0;
+    for start in starts.iter_mut() {
+        (*start, total) = (total, total + *start);
+    }
+    let mut next = vec![0u32; n];
+    for (row, &byte) in transformed.iter().enumerate() {
+        next[starts[byte as usize]] = row as u32;
+        starts[byte as usize] += 1;
+    }
+
+    // A block that repeats itself makes several shorter cycles, each of which
+    // spells out the repeated part, so the walk never needs to leave one
+    let mut output = Vec::with_capacity(n);
+    let mut row = next[primary] as usize;
+    for _ in 0..n {
+        output.push(transformed[row]);
+        row = next[row] as usize;
+    }
+    Ok(output)
+}
+
+// Move-to-front, then code zero runs with RUN_A/RUN_B digits
+pub fn move_to_front(data: &[u8]) -> Vec<u16> {
+    let mut order: Vec<u8> = (0..=255).collect();
+    let mut symbols = Vec::with_capacity(data.len() / 2);
+    let mut zeros = 0usize;
+    for &byte in data {
+        // `order` is a permutation of all 256 byte values, so every byte is in it
+        let index = order.iter().position(|&b| b == byte).expect("every byte value is in the order");
+        if index == 0 {
+            zeros += 1;
+            continue;
+        }
+        push_run(&mut symbols, zeros);
+        zeros = 0;
+        order.copy_within(0..index, 1);
+        order[0] = byte;
+        symbols.push(index as u16 + 1);
+    }
+    push_run(&mut symbols, zeros);
+    symbols
+}
+
+// A run of `length` zeros as bijective base 2 digits, least significant first
+fn push_run(symbols: &mut Vec<u16>, mut length: usize) {
+    while length > 0 {
+        if length & 1 == 1 {
+            symbols.push(RUN_A);
+            length = (length - 1) / 2;
+        } else {
+            symbols.push(RUN_B);
+            length = (length - 2) / 2;
+        }
+    }
+}
+
+// Undo `move_to_front`, failing if the result would not be `length` bytes
+pub fn inverse_move_to_front(symbols: &[u16], length: usize) -> Result<Vec<u8>> {
+    let too_long = || Error::CorruptData(format!("move-to-front data decodes to more than {} bytes", length));
+    let mut order: Vec<u8> = (0..=255).collect();
+    let mut output = Vec::with_capacity(length);
+    let mut run = 0usize;
+    let mut weight = 1usize;
+    for &symbol in symbols {
+        if symbol == RUN_A || symbol == RUN_B {
+            run += weight << symbol;
+            weight <<= 1;
+            if run > length {
+                return Err(too_long());
+            }
+            continue;
+        }
+        if output.len() + run >= length {
+            return Err(too_long());
+        }
+        output.resize(output.len() + run, order[0]);
+        run = 0;
+        weight = 1;
+
+        let index = symbol as usize - 1;
+        if index > 255 {
+            return Err(Error::CorruptData(format!("invalid move-to-front symbol {}", symbol)));
+        }
+        let byte = order[index];
+        order.copy_within(0..index, 1);
+        order[0] = byte;
+        output.push(byte);
+    }
+    if output.len() + run != length {
+        return Err(Error::CorruptData(format!("move-to-front data doesn't decode to {} bytes", length)));
+    }
+    output.resize(length, order[0]);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_suffix_array(data: &[u8]) -> Vec<u32> {
+        let mut suffixes: Vec<u32> = (0..data.len() as u32).collect();
+        suffixes.sort_by_key(|&i| &data[i as usize..]);
+        suffixes
+    }
+
+    #[test]
+    fn test_suffix_array() {
+        assert_eq!(suffix_array(b"banana"), vec![5, 3, 1, 0, 4, 2]);
+        for data in [&b""[..], b"a", b"aaaaaaaaaa", b"abababab", b"mississippi", b"the quick brown fox jumps over the lazy dog"] {
+            assert_eq!(suffix_array(data), naive_suffix_array(data), "{:?}", data);
+        }
+        let pseudo_random: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 28) as u8).collect();
+        assert_eq!(suffix_array(&pseudo_random), naive_suffix_array(&pseudo_random));
+    }
+
+    #[test]
+    fn test_bwt_round_trip() {
+        // The classic example, with the marker sorting first
+        assert_eq!(forward(b"banana"), (b"annbaa".to_vec(), 4));
+
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(20);
+        for data in [&b"a"[..], b"aaaaaaaa", b"banana", &text] {
+            let (transformed, primary) = forward(data);
+            assert_eq!(inverse(&transformed, primary).unwrap(), data);
+        }
+
+        assert!(inverse(b"annbaa", 0).is_err());
+        assert!(inverse(b"annbaa", 7).is_err());
+        assert!(inverse(b"", 1).is_err());
+    }
+
+    #[test]
+    fn test_cyclic_bwt_round_trip() {
+        assert_eq!(forward_cyclic(b"banana"), (b"nnbaaa".to_vec(), 3));
+        assert_eq!(inverse_cyclic(b"nnbaaa", 3).unwrap(), b"banana");
+
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(20);
+        for data in [&b"a"[..], b"aaaaaaaa", b"abababab", b"abcabcabcab", &text] {
+            let (transformed, primary) = forward_cyclic(data);
+            assert_eq!(inverse_cyclic(&transformed, primary).unwrap(), data);
+        }
+
+        assert!(inverse_cyclic(b"nnbaaa", 6).is_err());
+        assert!(inverse_cyclic(b"", 0).is_err());
+    }
+
+    #[test]
+    fn test_move_to_front_round_trip() {
+        assert_eq!(move_to_front(b"aaab"), vec![98, RUN_B, 99]);
+        // Runs of 1 to 4 zeros
+        assert_eq!(move_to_front(&[0]), vec![RUN_A]);
+        assert_eq!(move_to_front(&[0, 0]), vec![RUN_B]);
+        assert_eq!(move_to_front(&[0, 0, 0]), vec![RUN_A, RUN_A]);
+        assert_eq!(move_to_front(&[0, 0, 0, 0]), vec![RUN_B, RUN_A]);
+
+        let (transformed, _) = forward(&b"she sells sea shells by the sea shore".repeat(10));
+        for data in [&b"\x00"[..], &[7; 1000], &transformed] {
+            let symbols = move_to_front(data);
+            assert_eq!(inverse_move_to_front(&symbols, data.len()).unwrap(), data);
+        }
+
+        let symbols = move_to_front(b"abcabc");
+        assert!(inverse_move_to_front(&symbols, 5).is_err());
+        assert!(inverse_move_to_front(&symbols, 7).is_err());
+        assert!(inverse_move_to_front(&[257], 1).is_err());
+    }
+}
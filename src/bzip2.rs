@@ -0,0 +1,591 @@
+// The bzip2 file format. A stream is the magic bytes "BZh" and a level digit,
+// then blocks and finally an end-of-stream marker with the combined CRC of
+// the blocks. Everything after the magic is bit-packed, most significant bit
+// first, and only the end of the stream is padded to a byte. A block holds
+// the input with long runs shortened, put through the Burrows-Wheeler
+// transform, move-to-front and zero-run coding, and Huffman coded with up to
+// six tables that take turns every 50 symbols. A file may hold several
+// streams one after the other; they decompress to the concatenation of their
+// contents.
+
+use std::io::{Read, Write};
+use crate::bitio::{BitReader, BitWriter};
+use crate::bwt::{self, RUN_A, RUN_B};
+use crate::checksum::Bzip2Crc;
+use crate::error::{Error, Result};
+use crate::huffman::{canonical_codes, multi_table_code_lengths, GROUP_SIZE, MAX_TABLES};
+use crate::huffman_table::HuffmanDecodeTable;
+
+pub const MAGIC: [u8; 3] = *b"BZh";
+
+// A block holds at most the level times this many bytes once runs are
+// shortened. Levels go from 1 to 9.
+const LEVEL_UNIT: usize = 100_000;
+const MAX_LEVEL: u8 = 9;
+
+// bzip2 stops filling a block this far short of the limit
+const BLOCK_SLACK: usize = 19;
+
+// The first digits of pi and of its square root
+const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+const END_OF_STREAM_MAGIC: u64 = 0x1772_4538_5090;
+
+// bzip2 writes codes of up to 17 bits but reads codes of up to 20
+const MAX_WRITE_CODE_LENGTH: u8 = 17;
+const MAX_READ_CODE_LENGTH: u8 = 20;
+
+// Selectors past this many are read but ignored, as in bzip2 1.0.8. A full
+// block never needs more.
+const MAX_SELECTORS: usize = 18_002;
+
+// Runs of 4 to 255 equal bytes are stored as four of them and a count of the
+// rest, before the transform
+const RUN_THRESHOLD: usize = 4;
+const MAX_RUN: usize = 255;
+
+// Versions before 0.9.5 could flip the lowest bit of some bytes of a block
+// before the transform, to avoid the slow cases of their sorting. The gaps
+// between flipped bytes come from this table.
+const RANDOM_NUMBERS: [u16; 512] = [
+    619, 720, 127, 481, 931, 816, 813, 233, 566, 247, 985, 724, 205, 454, 863, 491,
+    741, 242, 949, 214, 733, 859, 335, 708, 621, 574, 73, 654, 730, 472, 419, 436,
+    278, 496, 867, 210, 399, 680, 480, 51, 878, 465, 811, 169, 869, 675, 611, 697,
+    867, 561, 862, 687, 507, 283, 482, 129, 807, 591, 733, 623, 150, 238, 59, 379,
+    684, 877, 625, 169, 643, 105, 170, 607, 520, 932, 727, 476, 693, 425, 174, 647,
+    73, 122, 335, 530, 442, 853, 695, 249, 445, 515, 909, 545, 703, 919, 874, 474,
+    882, 500, 594, 612, 641, 801, 220, 162, 819, 984, 589, 513, 495, 799, 161, 604,
+    958, 533, 221, 400, 386, 867, 600, 782, 382, 596, 414, 171, 516, 375, 682, 485,
+    911, 276, 98, 553, 163, 354, 666, 933, 424, 341, 533, 870, 227, 730, 475, 186,
+    263, 647, 537, 686, 600, 224, 469, 68, 770, 919, 190, 373, 294, 822, 808, 206,
+    184, 943, 795, 384, 383, 461, 404, 758, 839, 887, 715, 67, 618, 276, 204, 918,
+    873, 777, 604, 560, 951, 160, 578, 722, 79, 804, 96, 409, 713, 940, 652, 934,
+    970, 447, 318, 353, 859, 672, 112, 785, 645, 863, 803, 350, 139, 93, 354, 99,
+    820, 908, 609, 772, 154, 274, 580, 184, 79, 626, 630, 742, 653, 282, 762, 623,
+    680, 81, 927, 626, 789, 125, 411, 521, 938, 300, 821, 78, 343, 175, 128, 250,
+    170, 774, 972, 275, 999, 639, 495, 78, 352, 126, 857, 956, 358, 619, 580, 124,
+    737, 594, 701, 612, 669, 112, 134, 694, 363, 992, 809, 743, 168, 974, 944, 375,
+    748, 52, 600, 747, 642, 182, 862, 81, 344, 805, 988, 739, 511, 655, 814, 334,
+    249, 515, 897, 955, 664, 981, 649, 113, 974, 459, 893, 228, 433, 837, 553, 268,
+    926, 240, 102, 654, 459, 51, 686, 754, 806, 760, 493, 403, 415, 394, 687, 700,
+    946, 670, 656, 610, 738, 392, 760, 799, 887, 653, 978, 321, 576, 617, 626, 502,
+    894, 679, 243, 440, 680, 879, 194, 572, 640, 724, 926, 56, 204, 700, 707, 151,
+    457, 449, 797, 195, 791, 558, 945, 679, 297, 59, 87, 824, 713, 663, 412, 693,
+    342, 606, 134, 108, 571, 364, 631, 212, 174, 643, 304, 329, 343, 97, 430, 751,
+    497, 314, 983, 374, 822, 928, 140, 206, 73, 263, 980, 736, 876, 478, 430, 305,
+    170, 514, 364, 692, 829, 82, 855, 953, 676, 246, 369, 970, 294, 750, 807, 827,
+    150, 790, 288, 923, 804, 378, 215, 828, 592, 281, 565, 555, 710, 82, 896, 831,
+    547, 261, 524, 462, 293, 465, 502, 56, 661, 821, 976, 991, 658, 869, 905, 758,
+    745, 193, 768, 550, 608, 933, 378, 286, 215, 979, 792, 961, 61, 688, 793, 644,
+    986, 403, 106, 366, 905, 644, 372, 567, 466, 434, 645, 210, 389, 550, 919, 135,
+    780, 773, 635, 389, 707, 100, 626, 958, 165, 504, 920, 176, 193, 713, 857, 265,
+    203, 50, 668, 108, 645, 990, 626, 197, 510, 357, 358, 850, 858, 364, 936, 638,];
+
+// The level whose blocks fit `block_size` bytes, so the default block size
+// gives level 9
+pub fn level_for_block_size(block_size: usize) -> u8 {
+    block_size.div_ceil(LEVEL_UNIT).clamp(1, MAX_LEVEL as usize) as u8
+}
+
+pub fn write_stream_header<W: Write>(writer: &mut W, level: u8) -> Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[b'0' + level])?;
+    Ok(())
+}
+
+// Read a stream header and return its level. Returns None if the input ends
+// before its first byte, which is how a file of several streams ends.
+pub fn read_stream_header<R: Read>(reader: &mut R) -> Result<Option<u8>> {
+    let mut header = [0u8; 4];
+    if reader.read(&mut header[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).map_err(|_| not_bzip2())?;
+    if header[..3] != MAGIC {
+        return Err(not_bzip2());
+    }
+    match header[3] {
+        b'1'..=b'9' => Ok(Some(header[3] - b'0')),
+        level => Err(Error::InvalidHeader(format!("invalid bzip2 level {:?}", level as char))),
+    }
+}
+
+fn not_bzip2() -> Error {
+    Error::InvalidHeader("not a bzip2 file (bad magic bytes)".to_string())
+}
+
+// The stream CRC after another block
+pub fn combine_crc(combined: u32, block_crc: u32) -> u32 {
+    combined.rotate_left(1) ^ block_crc
+}
+
+// The end-of-stream marker and the combined CRC, padded to a byte
+pub fn write_stream_end<W: Write>(mut bit_writer: BitWriter<W>, combined_crc: u32) -> Result<()> {
+    bit_writer.write_bits(END_OF_STREAM_MAGIC, 48)?;
+    bit_writer.write_bits(combined_crc as u64, 32)?;
+    bit_writer.finish()?;
+    Ok(())
+}
+
+// Read the combined CRC after the end-of-stream marker and check it against
+// the CRCs of the blocks
+pub fn check_stream_crc<R: Read>(bit_reader: &mut BitReader<R>, combined_crc: u32) -> Result<()> {
+    let expected = bit_reader.read_bits(32)? as u32;
+    if expected != combined_crc {
+        return Err(Error::ChecksumMismatch { expected: expected as u64, actual: combined_crc as u64 });
+    }
+    bit_reader.align_to_byte();
+    Ok(())
+}
+
+// Cuts the input into blocks, shortening runs on the way. A block ends when
+// the next run might not fit the level any more; runs never span blocks.
+pub struct BlockSplitter {
+    limit: usize,
+    blocks: Vec<PendingBlock>,
+    current: Vec<u8>,
+    // CRC of the original bytes in `current`
+    crc: Bzip2Crc,
+    run_byte: u8,
+    run_length: usize,
+}
+
+impl BlockSplitter {
+    pub fn new(level: u8) -> Self {
+        BlockSplitter {
+            limit: level as usize * LEVEL_UNIT - BLOCK_SLACK,
+            blocks: Vec::new(),
+            current: Vec::new(),
+            crc: Bzip2Crc::new(),
+            run_byte: 0,
+            run_length: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        for &byte in data {
+            if self.run_length > 0 && (byte != self.run_byte || self.run_length == MAX_RUN) {
+                self.end_run();
+            }
+            self.run_byte = byte;
+            self.run_length += 1;
+        }
+    }
+
+    // End the last block at the end of the input
+    pub fn finish(&mut self) {
+        if self.run_length > 0 {
+            self.end_run();
+        }
+        if !self.current.is_empty() {
+            self.end_block();
+        }
+    }
+
+    // Number of blocks that are complete
+    pub fn ready(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn take_blocks(&mut self) -> Vec<PendingBlock> {
+        std::mem::take(&mut self.blocks)
+    }
+
+    fn end_run(&mut self) {
+        // A run takes up to five bytes
+        if self.current.len() + RUN_THRESHOLD + 1 > self.limit {
+            self.end_block();
+        }
+        let stored = self.run_length.min(RUN_THRESHOLD);
+        self.current.resize(self.current.len() + stored, self.run_byte);
+        if self.run_length >= RUN_THRESHOLD {
+            self.current.push((self.run_length - RUN_THRESHOLD) as u8);
+        }
+        self.crc.update(&[self.run_byte; MAX_RUN][..self.run_length]);
+        self.run_length = 0;
+    }
+
+    fn end_block(&mut self) {
+        let crc = std::mem::take(&mut self.crc).finalize();
+        self.blocks.push(PendingBlock { data: std::mem::take(&mut self.current), crc });
+    }
+}
+
+// A block with its runs shortened, waiting to be encoded
+pub struct PendingBlock {
+    data: Vec<u8>,
+    // CRC of the original bytes
+    crc: u32,
+}
+
+impl PendingBlock {
+    // Encode the block into a buffer of its own, so blocks can be encoded in
+    // parallel and joined afterwards
+    pub fn encode(&self) -> Result<EncodedBlock> {
+        let mut in_use = [false; 256];
+        for &byte in &self.data {
+            in_use[byte as usize] = true;
+        }
+        // Move-to-front starts from the byte values in use, in order
+        let used: Vec<u8> = (0..=255).filter(|&byte| in_use[byte as usize]).collect();
+        let mut index = [0u8; 256];
+        for (position, &byte) in used.iter().enumerate() {
+            index[byte as usize] = position as u8;
+        }
+
+        let (transformed, primary) = bwt::forward_cyclic(&self.data);
+        let indexed: Vec<u8> = transformed.iter().map(|&byte| index[byte as usize]).collect();
+        let mut symbols = bwt::move_to_front(&indexed);
+        let end_of_block = used.len() as u16 + 1;
+        symbols.push(end_of_block);
+        let (tables, selectors) = multi_table_code_lengths(&symbols, used.len() + 2, MAX_WRITE_CODE_LENGTH, true);
+        let codes = tables.iter().map(|lengths| canonical_codes(lengths)).collect::<Result<Vec<_>>>()?;
+
+        let mut bit_writer = BitWriter::new(Vec::with_capacity(self.data.len() / 3));
+        bit_writer.write_bits(BLOCK_MAGIC, 48)?;
+        bit_writer.write_bits(self.crc as u64, 32)?;
+        // Never randomised
+        bit_writer.write_bit(false)?;
+        bit_writer.write_bits(primary as u64, 24)?;
+
+        // The byte values in use: a bit for each range of 16 values, then a
+        // bit for each value of the ranges with any in use
+        let ranges: Vec<&[bool]> = in_use.chunks(16).collect();
+        for range in &ranges {
+            bit_writer.write_bit(range.contains(&true))?;
+        }
+        for range in ranges.iter().filter(|range| range.contains(&true)) {
+            for &used in range.iter() {
+                bit_writer.write_bit(used)?;
+            }
+        }
+
+        // Selectors, move-to-front coded and written in unary
+        bit_writer.write_bits(tables.len() as u64, 3)?;
+        bit_writer.write_bits(selectors.len() as u64, 15)?;
+        let mut order: Vec<u8> = (0..tables.len() as u8).collect();
+        for &selector in &selectors {
+            // `order` always holds every table number, and the selectors come
+            // from our own choice of table, never from the input
+            let position = order.iter().position(|&table| table == selector).expect("selector names a table");
+            bit_writer.write_bits(((1 << position) - 1) << 1, position as u32 + 1)?;
+            order.copy_within(0..position, 1);
+            order[0] = selector;
+        }
+
+        // Code lengths as steps from the one before: 10 adds one, 11 takes one
+        // away and 0 moves on to the next symbol
+        for lengths in &tables {
+            let mut current = lengths[0];
+            bit_writer.write_bits(current as u64, 5)?;
+            for &length in lengths {
+                while current < length {
+                    bit_writer.write_bits(0b10, 2)?;
+                    current += 1;
+                }
+                while current > length {
+                    bit_writer.write_bits(0b11, 2)?;
+                    current -= 1;
+                }
+                bit_writer.write_bit(false)?;
+            }
+        }
+
+        for (group, &selector) in symbols.chunks(GROUP_SIZE).zip(&selectors) {
+            for &symbol in group {
+                let (code, length) = codes[selector as usize][symbol as usize];
+                bit_writer.write_bits(code, length as u32)?;
+            }
+        }
+        let bit_count = bit_writer.bits_written();
+        Ok(EncodedBlock { bytes: bit_writer.finish()?, bit_count, crc: self.crc })
+    }
+}
+
+// An encoded block. Blocks aren't byte aligned, so the last byte may be
+// partly padding.
+pub struct EncodedBlock {
+    bytes: Vec<u8>,
+    bit_count: u64,
+    crc: u32,
+}
+
+impl EncodedBlock {
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    pub fn write_to<W: Write>(&self, bit_writer: &mut BitWriter<W>) -> Result<()> {
+        let whole = (self.bit_count / 8) as usize;
+        for &byte in &self.bytes[..whole] {
+            bit_writer.write_bits(byte as u64, 8)?;
+        }
+        let rest = (self.bit_count % 8) as u32;
+        if rest > 0 {
+            bit_writer.write_bits((self.bytes[whole] >> (8 - rest)) as u64, rest)?;
+        }
+        Ok(())
+    }
+}
+
+// A block read from a stream, decoded as far as the Burrows-Wheeler transform.
+// Undoing the transform is the slow part, so it is left to `decode`.
+pub struct Bzip2Block {
+    transformed: Vec<u8>,
+    primary: u32,
+    randomised: bool,
+    // CRC of the original bytes
+    crc: u32,
+}
+
+// Read the next block of a stream, or None at the end-of-stream marker, which
+// the combined CRC follows
+pub fn read_block<R: Read>(bit_reader: &mut BitReader<R>, level: u8) -> Result<Option<Bzip2Block>> {
+    match bit_reader.read_bits(48)? {
+        BLOCK_MAGIC => {},
+        END_OF_STREAM_MAGIC => return Ok(None),
+        _ => return Err(Error::CorruptData("missing bzip2 block header".to_string())),
+    }
+    let crc = bit_reader.read_bits(32)? as u32;
+    let randomised = bit_reader.read_bit()?;
+    let primary = bit_reader.read_bits(24)? as u32;
+
+    let ranges = bit_reader.read_bits(16)?;
+    let mut used = Vec::new();
+    for range in (0..16).filter(|range| ranges & (0x8000 >> range) != 0) {
+        let values = bit_reader.read_bits(16)?;
+        used.extend((0..16).filter(|value| values & (0x8000 >> value) != 0).map(|value| (range * 16 + value) as u8));
+    }
+    if used.is_empty() {
+        return Err(Error::CorruptData("bzip2 block uses no byte values".to_string()));
+    }
+    let alphabet_size = used.len() + 2;
+
+    let table_count = bit_reader.read_bits(3)? as usize;
+    if !(2..=MAX_TABLES).contains(&table_count) {
+        return Err(Error::CorruptData(format!("invalid Huffman table count {}", table_count)));
+    }
+    let selector_count = bit_reader.read_bits(15)? as usize;
+    if selector_count == 0 {
+        return Err(Error::CorruptData("bzip2 block has no selectors".to_string()));
+    }
+    let mut order: Vec<usize> = (0..table_count).collect();
+    let mut selectors = Vec::with_capacity(selector_count.min(MAX_SELECTORS));
+    for _ in 0..selector_count {
+        let mut position = 0;
+        while bit_reader.read_bit()? {
+            position += 1;
+            if position == table_count {
+                return Err(Error::CorruptData("invalid Huffman table selector".to_string()));
+            }
+        }
+        let selector = order[position];
+        order.copy_within(0..position, 1);
+        order[0] = selector;
+        if selectors.len() < MAX_SELECTORS {
+            selectors.push(selector);
+        }
+    }
+
+    let mut tables = Vec::with_capacity(table_count);
+    for _ in 0..table_count {
+        let mut lengths = Vec::with_capacity(alphabet_size);
+        let mut current = bit_reader.read_bits(5)? as u8;
+        for _ in 0..alphabet_size {
+            loop {
+                if !(1..=MAX_READ_CODE_LENGTH).contains(&current) {
+                    return Err(Error::CorruptData(format!("invalid code length {}", current)));
+                }
+                if !bit_reader.read_bit()? {
+                    break;
+                }
+                match bit_reader.read_bit()? {
+                    false => current += 1,
+                    true => current -= 1,
+                }
+            }
+            lengths.push(current);
+        }
+        tables.push(HuffmanDecodeTable::from_code_lengths(&lengths)?);
+    }
+
+    let max_length = level as usize * LEVEL_UNIT;
+    let symbols = read_symbols(bit_reader, &tables, &selectors, used.len() as u16 + 1, max_length)?;
+    let length = decoded_length(&symbols, max_length)
+        .ok_or_else(|| Error::CorruptData(format!("bzip2 block holds more than {} bytes", max_length)))?;
+    let indexed = bwt::inverse_move_to_front(&symbols, length)?;
+    let transformed = indexed.iter().map(|&index| used[index as usize]).collect();
+    Ok(Some(Bzip2Block { transformed, primary, randomised, crc }))
+}
+
+// Huffman coded symbols up to the end-of-block symbol, which isn't included.
+// Each symbol stands for at least one byte, so there can't be more than
+// `max_length` of them.
+fn read_symbols<R: Read>(
+    bit_reader: &mut BitReader<R>,
+    tables: &[HuffmanDecodeTable],
+    selectors: &[usize],
+    end_of_block: u16,
+    max_length: usize,
+) -> Result<Vec<u16>> {
+    let mut symbols = Vec::new();
+    // The stream doesn't say how many bits the block takes
+    let mut remaining = u64::MAX;
+    for &selector in selectors {
+        for _ in 0..GROUP_SIZE {
+            let symbol = tables[selector].decode_symbol(bit_reader, &mut remaining)?;
+            if symbol == end_of_block {
+                return Ok(symbols);
+            }
+            if symbols.len() == max_length {
+                return Err(Error::CorruptData(format!("bzip2 block holds more than {} bytes", max_length)));
+            }
+            symbols.push(symbol);
+        }
+    }
+    Err(Error::CorruptData("bzip2 block runs out of selectors".to_string()))
+}
+
+// The number of bytes zero-run coded move-to-front symbols stand for, or None
+// if that is more than `limit`
+fn decoded_length(symbols: &[u16], limit: usize) -> Option<usize> {
+    let mut length = 0usize;
+    let mut weight = 1usize;
+    for &symbol in symbols {
+        if symbol == RUN_A || symbol == RUN_B {
+            length += weight << symbol;
+            weight <<= 1;
+        } else {
+            length += 1;
+            weight = 1;
+        }
+        if length > limit {
+            return None;
+        }
+    }
+    Some(length)
+}
+
+impl Bzip2Block {
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    // The size of the block before its runs are expanded again
+    pub fn transformed_length(&self) -> usize {
+        self.transformed.len()
+    }
+
+    // Undo the transform and the run shortening, check the block CRC and
+    // write out the original bytes
+    pub fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut data = bwt::inverse_cyclic(&self.transformed, self.primary)?;
+        if self.randomised {
+            toggle_random_bits(&mut data);
+        }
+
+        let mut output = Vec::with_capacity(data.len() + data.len() / 4);
+        let mut bytes = data.iter();
+        let mut run = 0;
+        while let Some(&byte) = bytes.next() {
+            run = if output.last() == Some(&byte) { run + 1 } else { 1 };
+            output.push(byte);
+            // Four equal bytes are followed by a count of further copies, and
+            // the byte after that starts a new run
+            if run == RUN_THRESHOLD {
+                if let Some(&count) = bytes.next() {
+                    output.resize(output.len() + count as usize, byte);
+                }
+                run = 0;
+            }
+        }
+
+        let mut crc = Bzip2Crc::new();
+        crc.update(&output);
+        if crc.finalize() != self.crc {
+            return Err(Error::ChecksumMismatch { expected: self.crc as u64, actual: crc.finalize() as u64 });
+        }
+        writer.write_all(&output)?;
+        Ok(())
+    }
+}
+
+// Flip the bits a randomised block flipped. Doing it again flips them back.
+fn toggle_random_bits(data: &mut [u8]) {
+    let mut countdown = 0;
+    let mut next_number = 0;
+    for byte in data {
+        if countdown == 0 {
+            countdown = RANDOM_NUMBERS[next_number];
+            next_number = (next_number + 1) % RANDOM_NUMBERS.len();
+        }
+        countdown -= 1;
+        if countdown == 1 {
+            *byte ^= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(level: u8, data: &[u8]) -> Vec<PendingBlock> {
+        let mut splitter = BlockSplitter::new(level);
+        splitter.push(data);
+        splitter.finish();
+        splitter.take_blocks()
+    }
+
+    #[test]
+    fn test_stream_header() {
+        let mut bytes = Vec::new();
+        write_stream_header(&mut bytes, 9).unwrap();
+        assert_eq!(bytes, b"BZh9");
+        assert_eq!(read_stream_header(&mut &bytes[..]).unwrap(), Some(9));
+        assert_eq!(read_stream_header(&mut &b""[..]).unwrap(), None);
+        assert!(matches!(read_stream_header(&mut &b"BZh0"[..]), Err(Error::InvalidHeader(_))));
+        assert!(matches!(read_stream_header(&mut &b"BZ"[..]), Err(Error::InvalidHeader(_))));
+
+        assert_eq!(level_for_block_size(1024), 1);
+        assert_eq!(level_for_block_size(250_000), 3);
+        assert_eq!(level_for_block_size(1024 * 1024), 9);
+    }
+
+    #[test]
+    fn test_runs_are_shortened() {
+        // Four bytes and a count, with runs capped at 255
+        let blocks = split(1, &[b'a'; 300]);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].data, [b'a', b'a', b'a', b'a', 251, b'a', b'a', b'a', b'a', 41]);
+        let blocks = split(1, b"aaabbbbc");
+        assert_eq!(blocks[0].data, b"aaabbbb\0c");
+
+        let mut crc = Bzip2Crc::new();
+        crc.update(&[b'a'; 300]);
+        assert_eq!(split(1, &[b'a'; 300])[0].crc, crc.finalize());
+    }
+
+    #[test]
+    fn test_blocks_fit_the_level() {
+        let data: Vec<u8> = (0..250_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let blocks = split(1, &data);
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks.iter().all(|block| block.data.len() <= LEVEL_UNIT - BLOCK_SLACK));
+    }
+
+    #[test]
+    fn test_random_bits_toggle_back() {
+        let mut data = vec![0u8; 2000];
+        toggle_random_bits(&mut data);
+        // The first gap is 619, so byte 617 is the first to flip
+        assert_eq!(data.iter().position(|&byte| byte != 0), Some(617));
+        toggle_random_bits(&mut data);
+        assert!(data.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_decoded_length() {
+        assert_eq!(decoded_length(&[RUN_A, RUN_B, 5, RUN_B], 100), Some(1 + 4 + 1 + 2));
+        assert_eq!(decoded_length(&[RUN_B; 40], 1_000_000), None);
+    }
+}
@@ -1,30 +1,135 @@
-use std::{fs::File, io::{self, BufReader, BufWriter}};
+use std::io::{self, IsTerminal, Write};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use compression_tool::cli::{self, Args, Input, OptionSpec, Output};
 use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::Format;
 
-fn main() -> io::Result<()> {
-    // Get the arguments (compressed file and decompressed output file)
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 || args.len() > 3 {
-        println!("Usage: ccunzip <input_file> [output_file]");
-        return Ok(());
+const USAGE: &str = "Usage: ccunzip [-c] [-k] [-f] [-T threads] [--format cczip|deflate|gzip|zlib|bzip2|compress] [--legacy] [--progress] [input_file|-] [output_file|-]";
+
+const OPTIONS: &[OptionSpec] = &[
+    OptionSpec { short: Some('c'), long: "stdout", takes_value: false },
+    OptionSpec { short: Some('k'), long: "keep", takes_value: false },
+    OptionSpec { short: Some('f'), long: "force", takes_value: false },
+    OptionSpec { short: Some('h'), long: "help", takes_value: false },
+    // Number of blocks to work on at the same time (0 uses every core)
+    OptionSpec { short: Some('T'), long: "threads", takes_value: true },
+    // Stream format: our own container, raw DEFLATE, gzip, zlib, bzip2 or
+    // compress (.Z). gzip, bzip2 and .Z files are recognised without it.
+    OptionSpec { short: None, long: "format", takes_value: true },
+    // Files written before the container header existed have to be requested explicitly
+    OptionSpec { short: None, long: "legacy", takes_value: false },
+    // Show on stderr how far decoding has got
+    OptionSpec { short: None, long: "progress", takes_value: false },
+];
+
+fn main() {
+    let args = match cli::parse_args(std::env::args().skip(1), OPTIONS) {
+        Ok(args) if args.positionals.len() <= 2 => args,
+        Ok(_) => usage_error("too many arguments"),
+        Err(message) => usage_error(&message),
+    };
+    if args.flag("help") {
+        println!("{}", USAGE);
+        return;
     }
+    let threads = cli::parse_threads(args.value("threads")).unwrap_or_else(|message| usage_error(&message));
+    let format = cli::parse_format(args.value("format")).unwrap_or_else(|message| usage_error(&message));
+
+    // Read the named file, or stdin when there is none or it is `-`
+    let input = Input::from_arg(args.positionals.first());
+    let output = Output::choose(&input, args.positionals.get(1), args.flag("stdout"), |path| {
+        decompressed_path(path, format)
+    });
 
-    let input_file_path = &args[1];    // Path to the compressed file
-    let output_file_path = if args.len() == 3 {
-        &args[2]
+    if input == Input::Stdin && io::stdin().is_terminal() && !args.flag("force") {
+        usage_error("refusing to read compressed data from a terminal; use -f to force it");
+    }
+
+    if let Err(err) = run(&args, threads, format, &input, &output) {
+        eprintln!("ccunzip: {}", err);
+        process::exit(err.exit_code());
+    }
+}
+
+fn run(args: &Args, threads: usize, format: Format, input: &Input, output: &Output) -> compression_tool::Result<()> {
+    let mut reader = input.open()?;
+    let mut writer = output.create(args.flag("force"))?;
+
+    let mut decompression_tool = DecompressionTool::new().threads(threads).format(format);
+    let shown = Arc::new(AtomicU64::new(NOTHING_SHOWN));
+    if args.flag("progress") {
+        decompression_tool = decompression_tool.progress(show_progress(Arc::clone(&shown)));
+    }
+    let result = if args.flag("legacy") {
+        decompression_tool.decompress_legacy(&mut reader, &mut writer)
     } else {
-        &format!("{}.decompressed", input_file_path)
+        decompression_tool.decompress(&mut reader, &mut writer)
     };
+    let result = result.and_then(|()| Ok(writer.flush()?));
+    if shown.load(Ordering::Relaxed) != NOTHING_SHOWN {
+        eprintln!();
+    }
+    if result.is_err() {
+        // Don't leave a half-written file behind
+        drop(writer);
+        output.discard();
+    }
+    result?;
 
-    let input_file = File::open(input_file_path)?;
-    let mut reader = BufReader::new(input_file);
+    // The decompressed file replaces the compressed one unless asked to keep it
+    if let (Input::File(path), Output::File(_)) = (input, output) {
+        if !args.flag("keep") {
+            std::fs::remove_file(path)?;
+        }
+    }
 
-    let output_file = File::create(output_file_path)?;
-    let mut writer = BufWriter::new(&output_file);
+    // Status goes to stderr so it never mixes with data on stdout
+    if let Output::File(path) = output {
+        eprintln!("ccunzip: decompressed to {}", path);
+    }
+    Ok(())
+}
 
-    let decompression_tool = DecompressionTool::new();
-    decompression_tool.decompress(&mut reader, &mut writer);
+const NOTHING_SHOWN: u64 = u64::MAX;
 
-    println!("Decompression completed successfully!");
-    Ok(())
+// Progress on one line of stderr, rewritten whenever the percentage goes up,
+// or the number of MiB when the header doesn't record the length. `shown`
+// holds what was last shown.
+fn show_progress(shown: Arc<AtomicU64>) -> impl Fn(u64, Option<u64>) + Send + Sync {
+    move |written, total| {
+        let step = match total {
+            Some(total) if total > 0 => (written as u128 * 100 / total as u128) as u64,
+            _ => written >> 20,
+        };
+        if shown.swap(step, Ordering::Relaxed) != step {
+            match total {
+                Some(_) => eprint!("\rccunzip: {}%", step),
+                None => eprint!("\rccunzip: {} MiB", step),
+            }
+        }
+    }
+}
+
+// `file.compressed` (or the extension of the format) decompresses to `file`;
+// anything else gets a suffix so the input is never overwritten
+fn decompressed_path(path: &str, format: Format) -> String {
+    // Without --format, gzip, bzip2 and .Z files are detected by their contents
+    let formats: &[Format] = match format {
+        Format::Cczip => &[Format::Cczip, Format::Gzip, Format::Bzip2, Format::Compress],
+        _ => &[format],
+    };
+    for format in formats {
+        if let Some(stem) = path.strip_suffix(&format!(".{}", format.extension())).filter(|stem| !stem.is_empty()) {
+            return stem.to_string();
+        }
+    }
+    format!("{}.decompressed", path)
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("ccunzip: {}", message);
+    eprintln!("{}", USAGE);
+    process::exit(cli::USAGE_EXIT_CODE);
 }
@@ -1,33 +1,132 @@
-use std::fs::File;
-use std::io;
-use std::io::{BufReader, BufWriter, Write};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process;
+use std::time::UNIX_EPOCH;
+use compression_tool::checksum::ChecksumKind;
+use compression_tool::cli::{self, Args, Input, OptionSpec, Output};
 use compression_tool::compression::CompressionTool;
 
-fn main() -> io::Result<()> {
-    // Get the arguments (input file and compressed output file)
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 || args.len() > 3 {
-        println!("Usage: cczip <input_file> [output_file]");
-        return Ok(());
-    }
+const USAGE: &str = "Usage: cczip [-c] [-k] [-f] [-T threads] [--format cczip|deflate|gzip|zlib|bzip2|compress] [--coder huffman|range|fse|order1|auto] [--bwt] [--lzw] [--lzw-bits 9-16] [--rle off|on|auto] [--adaptive] [--crc64] [input_file|-] [output_file|-]";
+
+const OPTIONS: &[OptionSpec] = &[
+    OptionSpec { short: Some('c'), long: "stdout", takes_value: false },
+    OptionSpec { short: Some('k'), long: "keep", takes_value: false },
+    OptionSpec { short: Some('f'), long: "force", takes_value: false },
+    OptionSpec { short: Some('h'), long: "help", takes_value: false },
+    // Number of blocks to work on at the same time (0 uses every core)
+    OptionSpec { short: Some('T'), long: "threads", takes_value: true },
+    // Stream format: our own container, raw DEFLATE, gzip, zlib, bzip2 or compress (.Z)
+    OptionSpec { short: None, long: "format", takes_value: true },
+    // Entropy coder for the bytes of each block, or auto for the smallest per block
+    OptionSpec { short: None, long: "coder", takes_value: true },
+    // bzip2-style Burrows-Wheeler transform before Huffman coding
+    OptionSpec { short: None, long: "bwt", takes_value: false },
+    // LZW blocks, as in compress(1), instead of Huffman coding
+    OptionSpec { short: None, long: "lzw", takes_value: false },
+    // Widest LZW code for --lzw and --format compress
+    OptionSpec { short: None, long: "lzw-bits", takes_value: true },
+    // Run-length encode blocks first: off, on, or auto to decide from the input
+    OptionSpec { short: None, long: "rle", takes_value: true },
+    // Code the input with one adaptive Huffman code instead of per-block tables
+    OptionSpec { short: None, long: "adaptive", takes_value: false },
+    // Store a CRC-64 instead of the default CRC-32 in the trailer
+    OptionSpec { short: None, long: "crc64", takes_value: false },
+];
 
-    let input_file_path = &args[1];        // Path to the input file
-    let output_file_path = if args.len() == 3 {
-        &args[2]
-    } else {
-        &format!("{}.compressed", input_file_path)
+fn main() {
+    let args = match cli::parse_args(std::env::args().skip(1), OPTIONS) {
+        Ok(args) if args.positionals.len() <= 2 => args,
+        Ok(_) => usage_error("too many arguments"),
+        Err(message) => usage_error(&message),
     };
+    if args.flag("help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let threads = cli::parse_threads(args.value("threads")).unwrap_or_else(|message| usage_error(&message));
+    let format = cli::parse_format(args.value("format")).unwrap_or_else(|message| usage_error(&message));
+    let coder = cli::parse_coder(args.value("coder")).unwrap_or_else(|message| usage_error(&message));
+    let lzw_bits = cli::parse_lzw_bits(args.value("lzw-bits")).unwrap_or_else(|message| usage_error(&message));
+    let run_length = cli::parse_run_length(args.value("rle")).unwrap_or_else(|message| usage_error(&message));
+    let compression_tool = CompressionTool::new()
+        .threads(threads)
+        .format(format)
+        .entropy_coder(coder)
+        .lzw_max_bits(lzw_bits)
+        .run_length(run_length);
+
+    // Read the named file, or stdin when there is none or it is `-`
+    let input = Input::from_arg(args.positionals.first());
+    let output = Output::choose(&input, args.positionals.get(1), args.flag("stdout"), |path| {
+        format!("{}.{}", path, format.extension())
+    });
+
+    // Like gzip, don't spray binary data over a terminal unless forced to
+    if output == Output::Stdout && io::stdout().is_terminal() && !args.flag("force") {
+        usage_error("refusing to write compressed data to a terminal; use -f to force it");
+    }
+
+    if let Err(err) = run(&args, compression_tool, &input, &output) {
+        eprintln!("cczip: {}", err);
+        process::exit(err.exit_code());
+    }
+}
 
-    let input_file = File::open(input_file_path)?;
-    let mut reader = BufReader::new(input_file);
+fn run(args: &Args, mut compression_tool: CompressionTool, input: &Input, output: &Output) -> compression_tool::Result<()> {
+    let mut reader = input.open()?;
+    let mut writer = output.create(args.flag("force"))?;
 
-    let mut compression_tool = CompressionTool::new();
-    let output_file = File::create(output_file_path)?;
-    let mut writer = BufWriter::new(&output_file);
+    let checksum = if args.flag("crc64") { ChecksumKind::Crc64 } else { ChecksumKind::Crc32 };
+    compression_tool = compression_tool.checksum(checksum);
+    if args.flag("bwt") {
+        compression_tool = compression_tool.bwt(true);
+    }
+    if args.flag("lzw") {
+        compression_tool = compression_tool.lzw(true);
+    }
+    if args.flag("adaptive") {
+        compression_tool = compression_tool.adaptive_huffman(true);
+    }
+    // gzip headers record the name and modification time of the original file
+    if let Input::File(path) = input {
+        if let Some(name) = Path::new(path).file_name().and_then(|name| name.to_str()) {
+            compression_tool = compression_tool.file_name(name);
+        }
+        let metadata = fs::metadata(path)?;
+        // Regular files say how long they are, which goes in the header
+        if metadata.is_file() {
+            compression_tool = compression_tool.original_length(metadata.len());
+        }
+        let modified = metadata.modified().ok();
+        if let Some(since_epoch) = modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+            compression_tool = compression_tool.modification_time(since_epoch.as_secs() as u32);
+        }
+    }
+    let result = compression_tool.compress(&mut reader, &mut writer).and_then(|()| Ok(writer.flush()?));
+    if result.is_err() {
+        // Don't leave a half-written file behind
+        drop(writer);
+        output.discard();
+    }
+    result?;
 
-    compression_tool.compress(&mut reader, &mut writer);
-    writer.flush()?;
+    // The compressed file replaces the original unless asked to keep it
+    if let (Input::File(path), Output::File(_)) = (input, output) {
+        if !args.flag("keep") {
+            fs::remove_file(path)?;
+        }
+    }
 
-    println!("Compression completed successfully!");
+    // Status goes to stderr so it never mixes with data on stdout
+    if let Output::File(path) = output {
+        eprintln!("cczip: compressed to {}", path);
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("cczip: {}", message);
+    eprintln!("{}", USAGE);
+    process::exit(cli::USAGE_EXIT_CODE);
+}
@@ -0,0 +1,349 @@
+use std::io::{self, Write};
+
+// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320), as used by gzip and zip
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+// CRC-64/XZ (ECMA-182, reflected polynomial 0xC96C5795D7870F42), as used by xz
+const CRC64_POLYNOMIAL: u64 = 0xC96C_5795_D787_0F42;
+
+// CRC-32 with the same polynomial, unreflected (0x04C11DB7, most significant
+// bit first), as used by bzip2
+const CRC32_BZIP2_POLYNOMIAL: u32 = 0x04C1_1DB7;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+const CRC32_BZIP2_TABLE: [u32; 256] = crc32_bzip2_table();
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC32_POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc32_bzip2_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ CRC32_BZIP2_POLYNOMIAL } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC64_POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = CRC32_TABLE[((self.state ^ byte as u32) & 0xFF) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        self.state ^ 0xFFFF_FFFF
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bzip2Crc {
+    state: u32,
+}
+
+impl Bzip2Crc {
+    pub fn new() -> Self {
+        Bzip2Crc { state: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = CRC32_BZIP2_TABLE[((self.state >> 24) ^ byte as u32) as usize] ^ (self.state << 8);
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        self.state ^ 0xFFFF_FFFF
+    }
+}
+
+impl Default for Bzip2Crc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc64 {
+    state: u64,
+}
+
+impl Crc64 {
+    pub fn new() -> Self {
+        Crc64 { state: 0xFFFF_FFFF_FFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = CRC64_TABLE[((self.state ^ byte as u64) & 0xFF) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    pub fn finalize(&self) -> u64 {
+        self.state ^ 0xFFFF_FFFF_FFFF_FFFF
+    }
+}
+
+impl Default for Crc64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Adler-32 (RFC 1950), as used by zlib: two sums modulo the largest prime
+// below 2^16, the second summing the running values of the first
+const ADLER32_MODULUS: u32 = 65521;
+
+// Bytes that can be summed before the sums have to be reduced to stay in a u32
+const ADLER32_CHUNK: usize = 5552;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(ADLER32_CHUNK) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER32_MODULUS;
+            self.b %= ADLER32_MODULUS;
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Which checksum of the original data is stored in the trailer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    Crc32,
+    Crc64,
+}
+
+impl ChecksumKind {
+    // Number of bytes the checksum takes up in the trailer
+    pub fn size(&self) -> usize {
+        match self {
+            ChecksumKind::Crc32 => 4,
+            ChecksumKind::Crc64 => 8,
+        }
+    }
+
+    // Parse a checksum as it is stored in the trailer
+    pub fn from_bytes(&self, bytes: &[u8]) -> u64 {
+        let mut value = [0u8; 8];
+        value[..self.size()].copy_from_slice(&bytes[..self.size()]);
+        u64::from_le_bytes(value)
+    }
+}
+
+// Running checksum of either kind
+#[derive(Debug, Clone, Copy)]
+pub enum Checksum {
+    Crc32(Crc32),
+    Crc64(Crc64),
+}
+
+impl Checksum {
+    pub fn new(kind: ChecksumKind) -> Self {
+        match kind {
+            ChecksumKind::Crc32 => Checksum::Crc32(Crc32::new()),
+            ChecksumKind::Crc64 => Checksum::Crc64(Crc64::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Checksum::Crc32(crc) => crc.update(data),
+            Checksum::Crc64(crc) => crc.update(data),
+        }
+    }
+
+    pub fn kind(&self) -> ChecksumKind {
+        match self {
+            Checksum::Crc32(_) => ChecksumKind::Crc32,
+            Checksum::Crc64(_) => ChecksumKind::Crc64,
+        }
+    }
+
+    // The finished checksum, widened to 64 bits
+    pub fn value(&self) -> u64 {
+        match self {
+            Checksum::Crc32(crc) => crc.finalize() as u64,
+            Checksum::Crc64(crc) => crc.finalize(),
+        }
+    }
+
+    // The finished checksum as it is stored in the trailer (little-endian)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.value().to_le_bytes()[..self.kind().size()].to_vec()
+    }
+}
+
+// Writer adapter that checksums everything written through it
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W, kind: ChecksumKind) -> Self {
+        ChecksumWriter {
+            inner,
+            checksum: Checksum::new(kind),
+        }
+    }
+
+    pub fn checksum(&self) -> &Checksum {
+        &self.checksum
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Standard check values for the ASCII string "123456789"
+    #[test]
+    fn test_crc32_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finalize(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_bzip2_crc_check_value() {
+        let mut crc = Bzip2Crc::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finalize(), 0xFC89_1918);
+    }
+
+    #[test]
+    fn test_crc64_check_value() {
+        let mut crc = Crc64::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finalize(), 0x995D_C9BB_DF19_39FA);
+    }
+
+    #[test]
+    fn test_adler32_check_value() {
+        let mut adler = Adler32::new();
+        adler.update(b"123456789");
+        assert_eq!(adler.finalize(), 0x091E_01DE);
+
+        // Long runs of 0xFF push the sums as close to overflowing as they get
+        let mut adler = Adler32::new();
+        adler.update(&vec![0xFF; 100_000]);
+        let (mut a, mut b) = (1u64, 0u64);
+        for _ in 0..100_000 {
+            a = (a + 0xFF) % 65521;
+            b = (b + a) % 65521;
+        }
+        assert_eq!(adler.finalize() as u64, b << 16 | a);
+    }
+
+    #[test]
+    fn test_incremental_update_matches_single_update() {
+        let mut whole = Crc32::new();
+        whole.update(b"hello, world");
+
+        let mut parts = Crc32::new();
+        parts.update(b"hello");
+        parts.update(b", world");
+
+        assert_eq!(whole.finalize(), parts.finalize());
+    }
+
+    #[test]
+    fn test_checksum_writer() {
+        let mut output = Vec::new();
+        let mut writer = ChecksumWriter::new(&mut output, ChecksumKind::Crc32);
+        writer.write_all(b"123456789").unwrap();
+        assert_eq!(writer.checksum().value(), 0xCBF4_3926);
+        assert_eq!(writer.checksum().to_bytes(), 0xCBF4_3926u32.to_le_bytes().to_vec());
+        assert_eq!(ChecksumKind::Crc32.from_bytes(&writer.checksum().to_bytes()), 0xCBF4_3926);
+        assert_eq!(output, b"123456789");
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use crate::format::{EntropyCoder, Format, RunLength};
+use crate::lzw;
+
+// Exit code for invalid command line usage
+pub const USAGE_EXIT_CODE: i32 = 1;
+
+// An option the command line parser knows about
+pub struct OptionSpec {
+    pub short: Option<char>,
+    pub long: &'static str,
+    pub takes_value: bool,
+}
+
+// Parsed command line: options by long name (with their value, if any) and
+// the remaining positional arguments
+#[derive(Debug, Default)]
+pub struct Args {
+    options: HashMap<&'static str, String>,
+    pub positionals: Vec<String>,
+}
+
+impl Args {
+    pub fn flag(&self, long: &str) -> bool {
+        self.options.contains_key(long)
+    }
+
+    pub fn value(&self, long: &str) -> Option<&str> {
+        self.options.get(long).map(|value| value.as_str())
+    }
+}
+
+// Parse arguments the way gzip does: short flags can be combined (`-kf`),
+// values follow an option directly or as the next argument (`-T4`, `-T 4`,
+// `--threads=4`, `--threads 4`), a lone `-` is a positional argument and
+// everything after `--` is positional.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I, specs: &[OptionSpec]) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            parsed.positionals.extend(args.by_ref());
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            let (name, inline_value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let spec = specs
+                .iter()
+                .find(|spec| spec.long == name)
+                .ok_or_else(|| format!("unknown option --{}", name))?;
+
+            let value = match (spec.takes_value, inline_value) {
+                (true, Some(value)) => value,
+                (true, None) => args.next().ok_or_else(|| format!("option --{} needs a value", name))?,
+                (false, Some(_)) => return Err(format!("option --{} doesn't take a value", name)),
+                (false, None) => String::new(),
+            };
+            parsed.options.insert(spec.long, value);
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            let mut chars = arg[1..].chars();
+            while let Some(short) = chars.next() {
+                let spec = specs
+                    .iter()
+                    .find(|spec| spec.short == Some(short))
+                    .ok_or_else(|| format!("unknown option -{}", short))?;
+
+                if !spec.takes_value {
+                    parsed.options.insert(spec.long, String::new());
+                    continue;
+                }
+
+                // The rest of the argument, or the next one, is the value
+                let rest: String = chars.by_ref().collect();
+                let value = if rest.is_empty() {
+                    args.next().ok_or_else(|| format!("option -{} needs a value", short))?
+                } else {
+                    rest
+                };
+                parsed.options.insert(spec.long, value);
+            }
+        } else {
+            parsed.positionals.push(arg);
+        }
+    }
+
+    Ok(parsed)
+}
+
+// Parse a thread count. `0` means one thread per available core.
+pub fn parse_threads(value: Option<&str>) -> Result<usize, String> {
+    let threads = match value {
+        None => return Ok(1),
+        Some(value) => value.parse::<usize>().map_err(|_| format!("invalid thread count {}", value))?,
+    };
+    if threads == 0 {
+        return Ok(std::thread::available_parallelism().map_or(1, |cores| cores.get()));
+    }
+    Ok(threads)
+}
+
+// Parse a stream format name, defaulting to our own container
+pub fn parse_format(value: Option<&str>) -> Result<Format, String> {
+    match value {
+        None => Ok(Format::Cczip),
+        Some(name) => Format::from_name(name).ok_or_else(|| format!("unknown format {}", name)),
+    }
+}
+
+// Parse the widest LZW code, defaulting to 16 bits as compress(1) does
+pub fn parse_lzw_bits(value: Option<&str>) -> Result<u8, String> {
+    let Some(value) = value else {
+        return Ok(lzw::MAX_CODE_BITS);
+    };
+    match value.parse::<u8>() {
+        Ok(bits) if (lzw::MIN_CODE_BITS..=lzw::MAX_CODE_BITS).contains(&bits) => Ok(bits),
+        _ => Err(format!("LZW code width must be {} to {} bits, not {}", lzw::MIN_CODE_BITS, lzw::MAX_CODE_BITS, value)),
+    }
+}
+
+// Parse an entropy coder name, defaulting to Huffman coding
+pub fn parse_coder(value: Option<&str>) -> Result<EntropyCoder, String> {
+    match value {
+        None => Ok(EntropyCoder::Huffman),
+        Some(name) => EntropyCoder::from_name(name).ok_or_else(|| format!("unknown entropy coder {}", name)),
+    }
+}
+
+// Parse a run-length encoding setting, defaulting to auto
+pub fn parse_run_length(value: Option<&str>) -> Result<RunLength, String> {
+    match value {
+        None => Ok(RunLength::Auto),
+        Some(name) => RunLength::from_name(name).ok_or_else(|| format!("--rle takes off, on or auto, not {}", name)),
+    }
+}
+
+// Where data is read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Stdin,
+    File(String),
+}
+
+// Where data is written to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Stdout,
+    File(String),
+}
+
+impl Input {
+    pub fn from_arg(arg: Option<&String>) -> Self {
+        match arg {
+            None => Input::Stdin,
+            Some(path) if path == "-" => Input::Stdin,
+            Some(path) => Input::File(path.clone()),
+        }
+    }
+
+    pub fn open(&self) -> io::Result<Box<dyn Read>> {
+        Ok(match self {
+            Input::Stdin => Box::new(BufReader::new(io::stdin().lock())),
+            Input::File(path) => Box::new(BufReader::new(File::open(path)?)),
+        })
+    }
+}
+
+impl Output {
+    // Pick the output: stdout when asked for or when reading stdin, otherwise
+    // the explicit path or the one derived from the input file name
+    pub fn choose(input: &Input, explicit: Option<&String>, to_stdout: bool, derive: impl Fn(&str) -> String) -> Self {
+        match (explicit, input) {
+            (Some(path), _) if path == "-" => Output::Stdout,
+            (Some(path), _) => Output::File(path.clone()),
+            (None, _) if to_stdout => Output::Stdout,
+            (None, Input::Stdin) => Output::Stdout,
+            (None, Input::File(path)) => Output::File(derive(path)),
+        }
+    }
+
+    // Open the output for writing. Existing files are only replaced when
+    // `force` is set.
+    pub fn create(&self, force: bool) -> io::Result<Box<dyn Write>> {
+        Ok(match self {
+            Output::Stdout => Box::new(BufWriter::new(io::stdout().lock())),
+            Output::File(path) => {
+                let file = if force {
+                    File::create(path)?
+                } else {
+                    OpenOptions::new().write(true).create_new(true).open(path).map_err(|err| {
+                        if err.kind() == io::ErrorKind::AlreadyExists {
+                            io::Error::new(err.kind(), format!("{} already exists; use -f to overwrite it", path))
+                        } else {
+                            err
+                        }
+                    })?
+                };
+                Box::new(BufWriter::new(file))
+            },
+        })
+    }
+
+    // Remove a partially written output file after a failure
+    pub fn discard(&self) {
+        if let Output::File(path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPECS: &[OptionSpec] = &[
+        OptionSpec { short: Some('c'), long: "stdout", takes_value: false },
+        OptionSpec { short: Some('k'), long: "keep", takes_value: false },
+        OptionSpec { short: Some('f'), long: "force", takes_value: false },
+        OptionSpec { short: Some('T'), long: "threads", takes_value: true },
+        OptionSpec { short: None, long: "crc64", takes_value: false },
+    ];
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()), SPECS)
+    }
+
+    #[test]
+    fn test_short_and_long_flags() {
+        let args = parse(&["-kf", "--stdout", "--crc64", "input.txt"]).unwrap();
+        assert!(args.flag("keep"));
+        assert!(args.flag("force"));
+        assert!(args.flag("stdout"));
+        assert!(args.flag("crc64"));
+        assert_eq!(args.positionals, vec!["input.txt"]);
+    }
+
+    #[test]
+    fn test_option_values() {
+        assert_eq!(parse(&["-T4"]).unwrap().value("threads"), Some("4"));
+        assert_eq!(parse(&["-T", "4"]).unwrap().value("threads"), Some("4"));
+        assert_eq!(parse(&["--threads=4"]).unwrap().value("threads"), Some("4"));
+        assert_eq!(parse(&["--threads", "4"]).unwrap().value("threads"), Some("4"));
+        assert_eq!(parse(&["-cT", "2"]).unwrap().value("threads"), Some("2"));
+        assert!(parse(&["--threads"]).is_err());
+    }
+
+    #[test]
+    fn test_dash_and_double_dash_are_positional() {
+        let args = parse(&["-", "--", "-k"]).unwrap();
+        assert!(!args.flag("keep"));
+        assert_eq!(args.positionals, vec!["-", "-k"]);
+    }
+
+    #[test]
+    fn test_unknown_options_are_rejected() {
+        assert!(parse(&["-x"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["--keep=yes"]).is_err());
+    }
+
+    #[test]
+    fn test_thread_counts() {
+        assert_eq!(parse_threads(None), Ok(1));
+        assert_eq!(parse_threads(Some("4")), Ok(4));
+        assert!(parse_threads(Some("0")).unwrap() >= 1);
+        assert!(parse_threads(Some("many")).is_err());
+        assert!(parse_threads(Some("-1")).is_err());
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(parse_format(None), Ok(Format::Cczip));
+        assert_eq!(parse_format(Some("deflate")), Ok(Format::Deflate));
+        assert_eq!(parse_format(Some("gzip")), Ok(Format::Gzip));
+        assert_eq!(parse_format(Some("zlib")), Ok(Format::Zlib));
+        assert_eq!(parse_format(Some("bzip2")), Ok(Format::Bzip2));
+        assert_eq!(parse_format(Some("compress")), Ok(Format::Compress));
+        assert!(parse_format(Some("zip")).is_err());
+
+        assert_eq!(parse_coder(None), Ok(EntropyCoder::Huffman));
+        assert_eq!(parse_coder(Some("range")), Ok(EntropyCoder::Range));
+        assert_eq!(parse_coder(Some("fse")), Ok(EntropyCoder::Fse));
+        assert_eq!(parse_coder(Some("order1")), Ok(EntropyCoder::Order1));
+        assert_eq!(parse_coder(Some("auto")), Ok(EntropyCoder::Auto));
+        assert!(parse_coder(Some("arithmetic")).is_err());
+
+        assert_eq!(parse_run_length(None), Ok(RunLength::Auto));
+        assert_eq!(parse_run_length(Some("on")), Ok(RunLength::On));
+        assert_eq!(parse_run_length(Some("off")), Ok(RunLength::Off));
+        assert!(parse_run_length(Some("yes")).is_err());
+
+        assert_eq!(parse_lzw_bits(None), Ok(16));
+        assert_eq!(parse_lzw_bits(Some("12")), Ok(12));
+        assert!(parse_lzw_bits(Some("8")).is_err());
+        assert!(parse_lzw_bits(Some("17")).is_err());
+    }
+
+    #[test]
+    fn test_output_choice() {
+        let file = Input::File("data.txt".to_string());
+        let derive = |path: &str| format!("{}.compressed", path);
+
+        assert_eq!(Output::choose(&file, None, false, derive), Output::File("data.txt.compressed".to_string()));
+        assert_eq!(Output::choose(&file, None, true, derive), Output::Stdout);
+        assert_eq!(Output::choose(&Input::Stdin, None, false, derive), Output::Stdout);
+        assert_eq!(Output::choose(&file, Some(&"-".to_string()), false, derive), Output::Stdout);
+        assert_eq!(Output::choose(&file, Some(&"out".to_string()), true, derive), Output::File("out".to_string()));
+    }
+}
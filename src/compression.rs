@@ -1,116 +1,956 @@
-use std::{io::{Write}};
-use std::collections::{BTreeMap, HashMap};
-use std::io::{Read, Seek, SeekFrom};
-use std::collections::BinaryHeap;
-use crate::huffman::{HuffmanInternalNode, HuffmanLeafNode, HuffmanNode};
+use std::io::{Read, Write};
+use std::{slice, thread};
+use crate::bitio::BitWriter;
+use crate::bwt::{self, MAX_BWT_BLOCK_SIZE, MTF_ALPHABET_SIZE};
+use crate::bzip2::{self, BlockSplitter};
+use crate::checksum::{Adler32, Checksum, ChecksumKind, Crc32};
+use crate::error::{Error, Result};
+use crate::deflate;
+use crate::format::{
+    EntropyCoder, Format, Header, RunLength, ADAPTIVE_ALPHABET_SIZE, ADAPTIVE_END_OF_STREAM, BLOCK_BWT, BLOCK_END_OF_STREAM, BLOCK_FSE,
+    BLOCK_HUFFMAN, BLOCK_LZ77, BLOCK_LZW, BLOCK_ORDER1, BLOCK_RANGE, BLOCK_REPEAT, FLAG_ADAPTIVE_HUFFMAN, FLAG_CHECKSUM_CRC32, FLAG_CHECKSUM_CRC64,
+    FLAG_RUN_LENGTH, MAX_BLOCK_SIZE,
+};
+use crate::fse::{self, FseEncoder};
+use crate::gzip::{self, GzipHeader};
+use crate::huffman::{
+    canonical_codes, encode_code_lengths, length_limited_code_lengths, multi_table_code_lengths, AdaptiveHuffman, GROUP_SIZE,
+    MAX_SUPPORTED_CODE_LENGTH,
+};
+use crate::lz77::{
+    value_to_code, MatchFinder, Token, DEFAULT_WINDOW_SIZE, DISTANCE_ALPHABET_SIZE, LITERAL_LENGTH_ALPHABET_SIZE, MAX_WINDOW_SIZE,
+    MIN_MATCH, MIN_WINDOW_SIZE,
+};
+use crate::lzw::{self, LzwEncoder, ZHeader};
+use crate::rle;
+use crate::range_coder::{cumulative_frequencies, encode_frequencies, normalize_frequencies, RangeEncoder};
+use crate::zlib::{self, ZlibHeader};
+
+// Longest Huffman code the compressor produces unless told otherwise
+pub const DEFAULT_MAX_CODE_LENGTH: u8 = 15;
+
+// Number of input bytes that share a Huffman table unless told otherwise
+pub const DEFAULT_BLOCK_SIZE: usize = 1024 * 1024;
+
+// Fewest bytes a context needs before an order-1 block considers giving it a
+// Huffman table of its own
+const MIN_CONTEXT_SAMPLES: u64 = 64;
+
+// Smallest block size the compressor accepts
+pub const MIN_BLOCK_SIZE: usize = 1024;
 
 pub struct CompressionTool {
+    checksum: ChecksumKind,
+    max_code_length: u8,
+    block_size: usize,
+    threads: usize,
+    lz77: bool,
+    window_size: usize,
+    lazy_matching: bool,
+    format: Format,
+    file_name: Option<String>,
+    modification_time: u32,
+    dictionary: Vec<u8>,
+    adaptive_huffman: bool,
+    entropy_coder: EntropyCoder,
+    bwt: bool,
+    lzw: bool,
+    lzw_max_bits: u8,
+    run_length: RunLength,
+    original_length: Option<u64>,
 }
 
 impl CompressionTool {
     pub fn new() -> Self {
         CompressionTool {
+            checksum: ChecksumKind::Crc32,
+            max_code_length: DEFAULT_MAX_CODE_LENGTH,
+            block_size: DEFAULT_BLOCK_SIZE,
+            threads: 1,
+            lz77: true,
+            window_size: DEFAULT_WINDOW_SIZE,
+            lazy_matching: true,
+            format: Format::Cczip,
+            file_name: None,
+            modification_time: 0,
+            dictionary: Vec::new(),
+            adaptive_huffman: false,
+            entropy_coder: EntropyCoder::Huffman,
+            bwt: false,
+            lzw: false,
+            lzw_max_bits: lzw::MAX_CODE_BITS,
+            run_length: RunLength::Auto,
+            original_length: None,
         }
-    }    
+    }
+
+    // Limit the length of the Huffman codes. Every byte value needs its own
+    // code, so the limit must be at least 8 bits.
+    pub fn max_code_length(mut self, max_code_length: u8) -> Self {
+        assert!(
+            (8..=MAX_SUPPORTED_CODE_LENGTH).contains(&max_code_length),
+            "maximum code length must be between 8 and {}",
+            MAX_SUPPORTED_CODE_LENGTH
+        );
+        self.max_code_length = max_code_length;
+        self
+    }
 
-    pub fn compress<R: Read + Seek, W: Write>(&mut self, reader: &mut R, writer: &mut W) {
-        let mut buffer: [u8; 1024] = [0u8; 1024];
-        let mut frequency_map: BTreeMap<char, i32> = BTreeMap::new();
+    // Select the checksum of the original data stored in the trailer
+    pub fn checksum(mut self, kind: ChecksumKind) -> Self {
+        self.checksum = kind;
+        self
+    }
 
-        // Count frequencies
-        loop {
-            let bytes_read = reader.read(&mut buffer).unwrap();
-            if bytes_read == 0 {
-                break;
+    // Set how many input bytes go into each block. Every block gets its own
+    // Huffman table, so smaller blocks adapt faster to changing data at the
+    // cost of more table overhead.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        assert!(
+            (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE as usize).contains(&block_size),
+            "block size must be between {} and {} bytes",
+            MIN_BLOCK_SIZE,
+            MAX_BLOCK_SIZE
+        );
+        self.block_size = block_size;
+        self
+    }
+
+    // Compress up to `threads` blocks at the same time. Blocks are encoded
+    // independently and written in order, so the output doesn't depend on the
+    // number of threads.
+    pub fn threads(mut self, threads: usize) -> Self {
+        assert!(threads >= 1, "at least one thread is needed");
+        self.threads = threads;
+        self
+    }
+
+    // Look for repeated strings with LZ77 before Huffman coding. Blocks where
+    // this doesn't pay off are still stored with plain Huffman codes.
+    pub fn lz77(mut self, lz77: bool) -> Self {
+        self.lz77 = lz77;
+        self
+    }
+
+    // How far back LZ77 looks for repeated strings. Matches never cross block
+    // boundaries, so windows larger than the block size don't help.
+    pub fn window_size(mut self, window_size: usize) -> Self {
+        assert!(
+            window_size.is_power_of_two() && (MIN_WINDOW_SIZE..=MAX_WINDOW_SIZE).contains(&window_size),
+            "window size must be a power of two between {} and {}",
+            MIN_WINDOW_SIZE,
+            MAX_WINDOW_SIZE
+        );
+        self.window_size = window_size;
+        self
+    }
+
+    // Check whether the next position starts a longer match before taking a
+    // match. Slower, but finds better matches.
+    pub fn lazy_matching(mut self, lazy_matching: bool) -> Self {
+        self.lazy_matching = lazy_matching;
+        self
+    }
+
+    // Code the whole input as one adaptive Huffman stream (Vitter's
+    // algorithm) instead of blocks with their own code tables. The code
+    // adapts symbol by symbol, so no tables are stored, but the stream is
+    // coded on a single thread and without LZ77.
+    pub fn adaptive_huffman(mut self, adaptive_huffman: bool) -> Self {
+        self.adaptive_huffman = adaptive_huffman;
+        self
+    }
+
+    // Entropy coder for the bytes of each block, or Auto to pick the smallest
+    // for each block. LZ77 blocks are still used when they come out smaller.
+    pub fn entropy_coder(mut self, entropy_coder: EntropyCoder) -> Self {
+        self.entropy_coder = entropy_coder;
+        self
+    }
+
+    // Run blocks through the Burrows-Wheeler transform, move-to-front and
+    // zero-run coding before Huffman coding them with several tables, like
+    // bzip2. Compresses text much better, at the cost of speed. Takes the
+    // place of LZ77 and the entropy coder setting; blocks over
+    // MAX_BWT_BLOCK_SIZE are transformed in parts.
+    pub fn bwt(mut self, bwt: bool) -> Self {
+        self.bwt = bwt;
+        self
+    }
+
+    // Compress blocks with LZW, as compress(1) does, instead of Huffman
+    // coding. Fast and simple to decode, but compresses less. Takes the place
+    // of LZ77 and the entropy coder setting.
+    pub fn lzw(mut self, lzw: bool) -> Self {
+        self.lzw = lzw;
+        self
+    }
+
+    // Widest LZW code, from 9 to 16 bits, for LZW blocks and .Z files. Narrower
+    // codes keep the decoder's dictionary small, for decoders short of memory.
+    pub fn lzw_max_bits(mut self, lzw_max_bits: u8) -> Self {
+        assert!(
+            (lzw::MIN_CODE_BITS..=lzw::MAX_CODE_BITS).contains(&lzw_max_bits),
+            "LZW codes must be between {} and {} bits wide",
+            lzw::MIN_CODE_BITS,
+            lzw::MAX_CODE_BITS
+        );
+        self.lzw_max_bits = lzw_max_bits;
+        self
+    }
+
+    // Run-length encode every block before coding it, which turns long runs
+    // of one byte into a few bytes each. Auto, the default, decides from the
+    // first batch of blocks. Doesn't apply to adaptive Huffman streams.
+    pub fn run_length(mut self, run_length: RunLength) -> Self {
+        self.run_length = run_length;
+        self
+    }
+
+    // Length of the input, when known before compressing, to record in the
+    // header. Decoders then hold the output to it exactly and can report
+    // progress. Compressing fails if the input turns out to be another length.
+    pub fn original_length(mut self, original_length: u64) -> Self {
+        self.original_length = Some(original_length);
+        self
+    }
+
+    // Select the output format. Raw DEFLATE streams carry no checksum, and
+    // their LZ77 window is always 32 KiB.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    // Name of the original file, recorded in gzip headers
+    pub fn file_name(mut self, file_name: &str) -> Self {
+        self.file_name = Some(file_name.to_string());
+        self
+    }
+
+    // Modification time of the original file in Unix seconds, recorded in
+    // gzip headers. 0 means unknown.
+    pub fn modification_time(mut self, modification_time: u32) -> Self {
+        self.modification_time = modification_time;
+        self
+    }
+
+    // Preset dictionary that matches may reach into, for the deflate and
+    // zlib formats. The decoder needs the same dictionary. Only the last
+    // 32 KiB of it are used.
+    pub fn dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.dictionary = dictionary.to_vec();
+        self
+    }
+
+    pub fn compress<R: Read, W: Write>(&mut self, reader: &mut R, writer: &mut W) -> Result<()> {
+        match self.format {
+            Format::Cczip => self.compress_blocks(reader, writer),
+            Format::Deflate => self.compress_deflate(reader, writer, &self.dictionary, |_| {}),
+            Format::Gzip => self.compress_gzip(reader, writer),
+            Format::Zlib => self.compress_zlib(reader, writer),
+            Format::Bzip2 => self.compress_bzip2(reader, writer),
+            Format::Compress => self.compress_lzw(reader, writer),
+        }
+    }
+
+    fn compress_blocks<R: Read, W: Write>(&mut self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let mut blocks: Vec<Vec<u8>> = vec![Vec::new(); self.threads];
+        let mut checksum = Checksum::new(self.checksum);
+        // Read one byte past a length given up front, to notice longer input
+        let limit = self.original_length.map_or(u64::MAX, |length| length.saturating_add(1));
+        let mut reader = reader.take(limit);
+        let mut length = 0;
+
+        // Read the first blocks before writing anything, so run-length encoding
+        // can be decided on. Empty input makes a file with no blocks at all.
+        let mut count = self.read_batch_of_length(&mut reader, &mut blocks, &mut length)?;
+
+        // Write the container header
+        let mut flags = match self.checksum {
+            ChecksumKind::Crc32 => FLAG_CHECKSUM_CRC32,
+            ChecksumKind::Crc64 => FLAG_CHECKSUM_CRC64,
+        };
+        if self.adaptive_huffman {
+            flags |= FLAG_ADAPTIVE_HUFFMAN;
+        }
+        let run_length = !self.adaptive_huffman
+            && match self.run_length {
+                RunLength::Off => false,
+                RunLength::On => true,
+                RunLength::Auto => {
+                    // Repeat blocks are never encoded, so they don't count
+                    let encoded = || blocks[..count].iter().filter(|block| !is_repeat(block));
+                    let total: usize = encoded().map(Vec::len).sum();
+                    let saved: usize = encoded().map(|block| rle::saving(block)).sum();
+                    saved > 0 && saved * 4 >= total
+                },
+            };
+        if run_length {
+            flags |= FLAG_RUN_LENGTH;
+        }
+        let mut header = Header::new(flags, self.block_size as u32);
+        if let Some(original_length) = self.original_length {
+            header = header.with_original_length(original_length);
+        }
+        header.write_to(writer)?;
+
+        if self.adaptive_huffman {
+            // One stream for the whole input, which marks its own end
+            let mut encoder = AdaptiveHuffman::new(ADAPTIVE_ALPHABET_SIZE);
+            let mut bit_writer = BitWriter::new(&mut *writer);
+            while count > 0 {
+                for block in &blocks[..count] {
+                    checksum.update(block);
+                    for &byte in block {
+                        encoder.encode(byte as u16, &mut bit_writer)?;
+                    }
+                }
+                count = self.read_batch_of_length(&mut reader, &mut blocks, &mut length)?;
+            }
+            encoder.encode(ADAPTIVE_END_OF_STREAM, &mut bit_writer)?;
+            bit_writer.finish()?;
+        } else {
+            // Compress the input one batch of blocks at a time
+            while count > 0 {
+                self.compress_batch(&blocks[..count], run_length, &mut checksum, writer)?;
+                count = self.read_batch_of_length(&mut reader, &mut blocks, &mut length)?;
             }
+            // Mark the end of the stream
+            writer.write_all(&[BLOCK_END_OF_STREAM])?;
+        }
+
+        // Finish with the checksum of the original data so the decoder can
+        // verify it
+        writer.write_all(&checksum.to_bytes())?;
+        Ok(())
+    }
 
-            // Count character frequencies in the buffer
-            for &byte in &buffer[..bytes_read] {
-                let ch = byte as char;
-                *frequency_map.entry(ch).or_insert(0) += 1;
+    // Read the next batch of blocks, adding their bytes to `length`. With a
+    // length given up front, fail as soon as the input turns out longer, or
+    // ends short of it, before any of the batch is written.
+    fn read_batch_of_length<R: Read>(&self, reader: &mut R, blocks: &mut [Vec<u8>], length: &mut u64) -> Result<usize> {
+        let count = read_batch(reader, blocks, self.block_size)?;
+        *length += blocks[..count].iter().map(|block| block.len() as u64).sum::<u64>();
+        if let Some(expected) = self.original_length {
+            let ended = count < blocks.len() || blocks[count - 1].len() < self.block_size;
+            if *length > expected || (ended && *length < expected) {
+                return Err(Error::LengthMismatch { expected, actual: *length });
             }
         }
+        Ok(count)
+    }
 
-        // Write frequency map size to header
-        let num_chars: u32 = frequency_map.len() as u32; // Correct size of the map
-        let _ = writer.write_all(&num_chars.to_le_bytes()); // Write the size as a little-endian 4-byte integer
-        
-        // Now write map to header
-        for (ch, count) in &frequency_map {
-            let _ = writer.write_all(&[*ch as u8]); // Write the character (1 byte)
-            let _ = writer.write_all(&count.to_le_bytes()); // Write the frequency (4 bytes)
+    // Compress a batch of blocks, one thread per block, and write them in order
+    fn compress_batch<W: Write>(&self, blocks: &[Vec<u8>], run_length: bool, checksum: &mut Checksum, writer: &mut W) -> Result<()> {
+        if let [block] = blocks {
+            checksum.update(block);
+            return self.compress_contents(block, run_length, writer);
         }
 
-        let _ = writer.write_all(&[0x00]);
+        let encoded = encode_in_parallel(
+            blocks,
+            |block| {
+                let mut encoded = Vec::with_capacity(block.len() / 2);
+                self.compress_contents(block, run_length, &mut encoded)?;
+                Ok(encoded)
+            },
+            // Checksum the batch while the workers encode it
+            || {
+                for block in blocks {
+                    checksum.update(block);
+                }
+            },
+        );
 
-        let mut heap: BinaryHeap<HuffmanNode> = BinaryHeap::new();
-        for (ch, count) in frequency_map {
-            let leaf: HuffmanLeafNode = HuffmanLeafNode::new(count, ch);
-            heap.push(HuffmanNode::Leaf(leaf));
+        for block in encoded {
+            writer.write_all(&block?)?;
         }
+        Ok(())
+    }
 
-        // Now, generate the Huffman tree, encode the data, and write it incrementally
-        while heap.len() > 1 {
-            // Pop the two nodes with the smallest frequencies
-            let left: HuffmanNode = heap.pop().unwrap();
-            let right: HuffmanNode = heap.pop().unwrap();
+    // A single gzip member around a DEFLATE stream
+    fn compress_gzip<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        GzipHeader::new(self.modification_time, self.file_name.as_deref()).write_to(writer)?;
+        let mut crc = Crc32::new();
+        let mut size = 0u64;
+        self.compress_deflate(reader, writer, &[], |data| {
+            crc.update(data);
+            size += data.len() as u64;
+        })?;
+        gzip::write_trailer(writer, crc.finalize(), size)
+    }
 
-            // Combine the two nodes into an internal node
-            let combined_weight: i32 = left.weight() + right.weight();
-            let internal_node: HuffmanInternalNode = HuffmanInternalNode::new(combined_weight, left, right);
-            heap.push(HuffmanNode::Internal(internal_node));
-        }
+    // A zlib stream around a DEFLATE stream
+    fn compress_zlib<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let dictionary_id = (!self.dictionary.is_empty()).then(|| zlib::dictionary_id(&self.dictionary));
+        ZlibHeader::new(dictionary_id).write_to(writer)?;
+        let mut adler = Adler32::new();
+        self.compress_deflate(reader, writer, &self.dictionary, |data| adler.update(data))?;
+        writer.write_all(&adler.finalize().to_be_bytes())?;
+        Ok(())
+    }
 
-        let root = heap.pop().unwrap();
+    // A single bzip2 stream. Its level is the smallest whose blocks hold the
+    // block size, and blocks are cut where bzip2 would cut them at that level.
+    fn compress_bzip2<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let level = bzip2::level_for_block_size(self.block_size);
+        bzip2::write_stream_header(writer, level)?;
+        let mut bit_writer = BitWriter::new(&mut *writer);
+        let mut splitter = BlockSplitter::new(level);
+        let mut combined_crc = 0;
+        let mut input = Vec::new();
+        loop {
+            read_block(reader, &mut input, self.block_size)?;
+            splitter.push(&input);
+            if input.is_empty() {
+                splitter.finish();
+            }
+            if input.is_empty() || splitter.ready() >= self.threads {
+                for batch in splitter.take_blocks().chunks(self.threads) {
+                    for block in encode_in_parallel(batch, |block| block.encode(), || {}) {
+                        let block = block?;
+                        combined_crc = bzip2::combine_crc(combined_crc, block.crc());
+                        block.write_to(&mut bit_writer)?;
+                    }
+                }
+            }
+            if input.is_empty() {
+                return bzip2::write_stream_end(bit_writer, combined_crc);
+            }
+        }
+    }
 
-        // Generate the prefix codes for each character
-        let mut codes: HashMap<char, String> = HashMap::new();
-        root.generate_prefix_codes(&mut codes);
+    // A .Z file: the header and one LZW stream over the whole input. Each code
+    // builds on the dictionary so far, so this runs on a single thread.
+    fn compress_lzw<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        ZHeader::new(self.lzw_max_bits).write_to(writer)?;
+        let mut encoder = LzwEncoder::new(&mut *writer, self.lzw_max_bits);
+        let mut input = Vec::new();
+        loop {
+            read_block(reader, &mut input, self.block_size)?;
+            if input.is_empty() {
+                encoder.finish()?;
+                return Ok(());
+            }
+            encoder.write(&input)?;
+        }
+    }
 
-        // Now write the compressed data after the header
-        let mut compressed_bits: Vec<u8> = Vec::new();
+    // Compress into a raw DEFLATE stream, passing every block of input to
+    // `observe` so wrappers can checksum it. Matches may reach into `dictionary`.
+    // Each block is compressed separately with the 32 KiB before it as history,
+    // so blocks can be compressed in parallel and the output doesn't depend on
+    // the number of threads.
+    fn compress_deflate<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        dictionary: &[u8],
+        mut observe: impl FnMut(&[u8]),
+    ) -> Result<()> {
+        let mut blocks: Vec<Vec<u8>> = vec![Vec::new(); self.threads];
+        let mut history = dictionary[dictionary.len().saturating_sub(deflate::WINDOW_SIZE)..].to_vec();
 
-        // Go back to the start of the file
-        reader.seek(SeekFrom::Start(0)).unwrap();
         loop {
-            let bytes_read = reader.read(&mut buffer).unwrap();
-            if bytes_read == 0 {
-                break;
+            let count = read_batch(reader, &mut blocks, self.block_size)?;
+            if count == 0 {
+                // The last block read was full, so it couldn't end the stream
+                writer.write_all(&deflate::EMPTY_FINAL_BLOCK)?;
+                return Ok(());
+            }
+
+            // A batch that isn't full ends the input, and its last block ends
+            // the stream
+            let ends_here = count < blocks.len() || blocks[count - 1].len() < self.block_size;
+
+            // Each chunk is the block with the history before it, where the
+            // block starts and whether it ends the stream
+            let mut chunks = Vec::with_capacity(count);
+            for (index, block) in blocks[..count].iter().enumerate() {
+                let start = history.len();
+                let mut chunk = Vec::with_capacity(start + block.len());
+                chunk.extend_from_slice(&history);
+                chunk.extend_from_slice(block);
+                history = chunk[chunk.len().saturating_sub(deflate::WINDOW_SIZE)..].to_vec();
+                chunks.push((chunk, start, ends_here && index + 1 == count));
             }
 
-            for &byte in &buffer[..bytes_read] {
-                if let Some(code) = codes.get(&(byte as char)) {
-                    for bit in code.chars() {
-                        compressed_bits.push(if bit == '1' { 1 } else { 0 });
+            let encoded = encode_in_parallel(
+                &chunks,
+                |(chunk, start, last)| Ok(deflate::compress_chunk(chunk, *start, *last, self.lazy_matching)?),
+                || {
+                    for block in &blocks[..count] {
+                        observe(block);
                     }
+                },
+            );
+            for chunk in encoded {
+                writer.write_all(&chunk?)?;
+            }
+
+            if ends_here {
+                return Ok(());
+            }
+        }
+    }
+
+    // Write a block of input, run-length encoded first if the header says so.
+    // Each block is encoded on its own, so the decoder undoes it block by block.
+    fn compress_contents<W: Write>(&self, block: &[u8], run_length: bool, writer: &mut W) -> Result<()> {
+        // One byte repeated takes the same few bytes however long the block
+        if is_repeat(block) {
+            writer.write_all(&[BLOCK_REPEAT])?;
+            writer.write_all(&(block.len() as u32).to_le_bytes())?;
+            writer.write_all(&[block[0]])?;
+            return Ok(());
+        }
+        if !run_length {
+            return self.compress_block(block, writer);
+        }
+        // The transform would split an encoded block that is too long for it,
+        // so split the input instead, into parts that encode short enough
+        let part_size = if self.bwt { rle::max_input_length(MAX_BWT_BLOCK_SIZE) } else { block.len() };
+        for part in block.chunks(part_size) {
+            self.compress_block(&rle::encode(part), writer)?;
+        }
+        Ok(())
+    }
+
+    // Write one self-contained block, with LZ77 if that comes out smaller
+    fn compress_block<W: Write>(&self, block: &[u8], writer: &mut W) -> Result<()> {
+        if self.bwt {
+            // The transform takes at most MAX_BWT_BLOCK_SIZE bytes at a time
+            for chunk in block.chunks(MAX_BWT_BLOCK_SIZE) {
+                self.write_bwt_block(chunk, writer)?;
+            }
+            return Ok(());
+        }
+        if self.lzw {
+            return self.write_lzw_block(block, writer);
+        }
+
+        // Count frequencies
+        let mut frequency_map: [u64; 256] = [0u64; 256];
+        for &byte in block {
+            frequency_map[byte as usize] += 1;
+        }
+
+        let lz77 = if self.lz77 { self.tokenize(block) } else { None };
+
+        // The bytes as they are, with the chosen entropy coder, or with each
+        // coder in turn to keep the smallest
+        let coders = match self.entropy_coder {
+            EntropyCoder::Auto => &[EntropyCoder::Huffman, EntropyCoder::Range, EntropyCoder::Fse, EntropyCoder::Order1][..],
+            ref coder => slice::from_ref(coder),
+        };
+        let mut plain: Option<Vec<u8>> = None;
+        for &coder in coders {
+            let mut encoded = Vec::with_capacity(block.len() / 2);
+            self.write_plain_block(coder, block, &frequency_map, &mut encoded)?;
+            if plain.as_ref().is_none_or(|plain| encoded.len() < plain.len()) {
+                plain = Some(encoded);
+            }
+        }
+        let plain = plain.expect("at least one entropy coder");
+
+        if let Some((tokens, plan)) = &lz77 {
+            // Block type, original length and payload length come on top of the plan
+            if plan.size() + 9 < plain.len() as u64 {
+                return Self::write_lz77_block(block.len(), tokens, plan, writer);
+            }
+        }
+        writer.write_all(&plain)?;
+        Ok(())
+    }
+
+    // Write a block of the bytes themselves with one entropy coder
+    fn write_plain_block<W: Write>(&self, coder: EntropyCoder, block: &[u8], frequency_map: &[u64], writer: &mut W) -> Result<()> {
+        match coder {
+            EntropyCoder::Huffman => {
+                // Derive the code length of every byte, keeping codes within the limit
+                let code_lengths = length_limited_code_lengths(frequency_map, self.max_code_length);
+                let encoded_lengths = encode_code_lengths(&code_lengths);
+                Self::write_huffman_block(block, frequency_map, &code_lengths, &encoded_lengths, writer)
+            },
+            EntropyCoder::Range => {
+                let frequencies = normalize_frequencies(frequency_map);
+                Self::write_range_block(block.len(), &encode_frequencies(&frequencies), &range_code(block, &frequencies), writer)
+            },
+            EntropyCoder::Fse => Self::write_fse_block(block, frequency_map, writer),
+            EntropyCoder::Order1 => self.write_order1_block(block, frequency_map, writer),
+            EntropyCoder::Auto => unreachable!("auto stands for trying every other coder"),
+        }
+    }
+
+    // LZ77 tokens for a block and the plan for coding them, if LZ77 found
+    // anything worth coding
+    fn tokenize(&self, block: &[u8]) -> Option<(Vec<Token>, Lz77Plan)> {
+        let window_size = self.window_size.min(block.len().next_power_of_two()).max(MIN_WINDOW_SIZE);
+        let tokens = MatchFinder::new(window_size, self.lazy_matching).tokenize(block);
+        let plan = self.plan_lz77_block(&tokens)?;
+        Some((tokens, plan))
+    }
+
+    // A range coded block: its type, original length, frequency table,
+    // payload length in bytes and the payload
+    fn write_range_block<W: Write>(original_length: usize, encoded_frequencies: &[u8], payload: &[u8], writer: &mut W) -> Result<()> {
+        writer.write_all(&[BLOCK_RANGE])?;
+        writer.write_all(&(original_length as u32).to_le_bytes())?;
+        writer.write_all(encoded_frequencies)?;
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(payload)?;
+        Ok(())
+    }
+
+    // An FSE block: its type, original length, table log, normalized counts,
+    // payload length in bits and the payload
+    fn write_fse_block<W: Write>(block: &[u8], frequency_map: &[u64], writer: &mut W) -> Result<()> {
+        let table_log = fse::table_log(frequency_map);
+        let counts = fse::normalize_counts(frequency_map, table_log);
+        let (payload, payload_bits) = FseEncoder::new(&counts, table_log).encode(block)?;
+
+        writer.write_all(&[BLOCK_FSE])?;
+        writer.write_all(&(block.len() as u32).to_le_bytes())?;
+        writer.write_all(&[table_log])?;
+        let mut bit_writer = BitWriter::new(&mut *writer);
+        fse::write_counts(&counts, table_log, &mut bit_writer)?;
+        bit_writer.finish()?;
+        writer.write_all(&(payload_bits as u32).to_le_bytes())?;
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+
+    // A BWT block: the Burrows-Wheeler transform of the bytes, move-to-front
+    // and zero-run coded, then Huffman coded with a table per group of symbols.
+    // The block holds its type, original length, the BWT primary index, the
+    // number of symbols, the number of tables, their code length tables, the
+    // payload length in bits and the payload: the selectors followed by the
+    // symbol codes.
+    fn write_bwt_block<W: Write>(&self, block: &[u8], writer: &mut W) -> Result<()> {
+        let (transformed, primary) = bwt::forward(block);
+        let symbols = bwt::move_to_front(&transformed);
+        // All 257 symbols may be in use, which takes codes of up to 9 bits
+        let (tables, selectors) = multi_table_code_lengths(&symbols, MTF_ALPHABET_SIZE, self.max_code_length.max(9), false);
+        let codes = tables.iter().map(|lengths| canonical_codes(lengths)).collect::<Result<Vec<_>>>()?;
+
+        let mut bit_writer = BitWriter::new(Vec::new());
+        // Selectors, move-to-front coded and written in unary
+        let mut order: Vec<u8> = (0..tables.len() as u8).collect();
+        for &selector in &selectors {
+            let position = order.iter().position(|&table| table == selector).unwrap();
+            bit_writer.write_bits(((1 << position) - 1) << 1, position as u32 + 1)?;
+            order.copy_within(0..position, 1);
+            order[0] = selector;
+        }
+        for (group, &selector) in symbols.chunks(GROUP_SIZE).zip(&selectors) {
+            for &symbol in group {
+                let (code, length) = codes[selector as usize][symbol as usize];
+                bit_writer.write_bits(code, length as u32)?;
+            }
+        }
+        let payload_bits = bit_writer.bits_written();
+        let payload = bit_writer.finish()?;
+
+        writer.write_all(&[BLOCK_BWT])?;
+        writer.write_all(&(block.len() as u32).to_le_bytes())?;
+        writer.write_all(&primary.to_le_bytes())?;
+        writer.write_all(&(symbols.len() as u32).to_le_bytes())?;
+        writer.write_all(&[tables.len() as u8])?;
+        for lengths in &tables {
+            writer.write_all(&encode_code_lengths(lengths))?;
+        }
+        writer.write_all(&(payload_bits as u32).to_le_bytes())?;
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+
+    // An LZW block: type, original length, the widest code, the payload length
+    // in bytes and the payload, which holds the codes as in a .Z file
+    fn write_lzw_block<W: Write>(&self, block: &[u8], writer: &mut W) -> Result<()> {
+        let mut encoder = LzwEncoder::new(Vec::with_capacity(block.len() / 2), self.lzw_max_bits);
+        encoder.write(block)?;
+        let payload = encoder.finish()?;
+
+        writer.write_all(&[BLOCK_LZW])?;
+        writer.write_all(&(block.len() as u32).to_le_bytes())?;
+        writer.write_all(&[self.lzw_max_bits])?;
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+
+    // An order-1 block: Huffman codes that depend on the byte before. Contexts
+    // seen often enough to pay for a table of their own get one; the rest
+    // share a table built from their combined counts. The block holds its
+    // type, original length, a bitmap of the contexts with their own table,
+    // the shared code length table, the own tables in context order, the
+    // payload length in bits and the payload.
+    fn write_order1_block<W: Write>(&self, block: &[u8], frequency_map: &[u64], writer: &mut W) -> Result<()> {
+        // Count every byte under the byte before it. The first byte of a
+        // block follows an imaginary zero byte.
+        let mut context_frequencies = vec![[0u64; 256]; 256];
+        let mut previous = 0u8;
+        for &byte in block {
+            context_frequencies[previous as usize][byte as usize] += 1;
+            previous = byte;
+        }
+
+        // A context keeps its own table only if that beats the order-0 code of
+        // the whole block, table included
+        let order0_lengths = length_limited_code_lengths(frequency_map, self.max_code_length);
+        let mut own_tables: Vec<Option<Vec<u8>>> = vec![None; 256];
+        let mut shared_frequencies = [0u64; 256];
+        for (context, frequencies) in context_frequencies.iter().enumerate() {
+            if frequencies.iter().sum::<u64>() >= MIN_CONTEXT_SAMPLES {
+                let lengths = length_limited_code_lengths(frequencies, self.max_code_length);
+                let own_bits = payload_bits(frequencies, &lengths) + 8 * encode_code_lengths(&lengths).len() as u64;
+                if own_bits < payload_bits(frequencies, &order0_lengths) {
+                    own_tables[context] = Some(lengths);
+                    continue;
                 }
             }
+            for (shared, &count) in shared_frequencies.iter_mut().zip(frequencies) {
+                *shared += count;
+            }
+        }
+        let shared_lengths = length_limited_code_lengths(&shared_frequencies, self.max_code_length);
+
+        // The code of each context, and the payload size they add up to
+        let shared_codes = canonical_codes(&shared_lengths)?;
+        let mut codes = Vec::with_capacity(256);
+        let mut total_bits = 0;
+        for (context, own_lengths) in own_tables.iter().enumerate() {
+            let lengths = own_lengths.as_ref().unwrap_or(&shared_lengths);
+            total_bits += payload_bits(&context_frequencies[context], lengths);
+            codes.push(match own_lengths {
+                Some(lengths) => canonical_codes(lengths)?,
+                None => shared_codes.clone(),
+            });
+        }
+
+        writer.write_all(&[BLOCK_ORDER1])?;
+        writer.write_all(&(block.len() as u32).to_le_bytes())?;
+        let mut bitmap = [0u8; 32];
+        for (context, own_lengths) in own_tables.iter().enumerate() {
+            if own_lengths.is_some() {
+                bitmap[context / 8] |= 1 << (context % 8);
+            }
         }
+        writer.write_all(&bitmap)?;
+        writer.write_all(&encode_code_lengths(&shared_lengths))?;
+        for lengths in own_tables.iter().flatten() {
+            writer.write_all(&encode_code_lengths(lengths))?;
+        }
+        writer.write_all(&(total_bits as u32).to_le_bytes())?;
+
+        let mut bit_writer = BitWriter::new(&mut *writer);
+        let mut previous = 0u8;
+        for &byte in block {
+            let (code, length) = codes[previous as usize][byte as usize];
+            bit_writer.write_bits(code, length as u32)?;
+            previous = byte;
+        }
+        bit_writer.finish()?;
+        Ok(())
+    }
+
+    // A block of plain Huffman codes: its type, original length, code length
+    // table, payload length in bits and the payload itself
+    fn write_huffman_block<W: Write>(
+        block: &[u8],
+        frequency_map: &[u64],
+        code_lengths: &[u8],
+        encoded_lengths: &[u8],
+        writer: &mut W,
+    ) -> Result<()> {
+        let codes = canonical_codes(code_lengths)?;
+
+        writer.write_all(&[BLOCK_HUFFMAN])?;
+        writer.write_all(&(block.len() as u32).to_le_bytes())?;
+
+        // Only the code lengths go into the block header; the decoder derives
+        // the same canonical codes from them
+        writer.write_all(encoded_lengths)?;
+
+        // The decoder needs to know where the payload ends, so record its length in bits
+        writer.write_all(&(payload_bits(frequency_map, code_lengths) as u32).to_le_bytes())?;
+
+        let mut bit_writer = BitWriter::new(&mut *writer);
+        for &byte in block {
+            let (code, length) = codes[byte as usize];
+            bit_writer.write_bits(code, length as u32)?;
+        }
+
+        // Pad the last byte with zero bits
+        bit_writer.finish()?;
+        Ok(())
+    }
 
-        // Calculate padding to make the bit length a multiple of 8
-        let padding_bits = (8 - compressed_bits.len() % 8) % 8;
+    // Code lengths and sizes for coding `tokens`, or None if LZ77 found
+    // nothing or the literal/length alphabet doesn't fit the code length limit
+    fn plan_lz77_block(&self, tokens: &[Token]) -> Option<Lz77Plan> {
+        let mut literal_frequencies = vec![0u64; LITERAL_LENGTH_ALPHABET_SIZE];
+        let mut distance_frequencies = vec![0u64; DISTANCE_ALPHABET_SIZE];
+        let mut extra_bits = 0u64;
+        for &token in tokens {
+            match token {
+                Token::Literal(byte) => literal_frequencies[byte as usize] += 1,
+                Token::Match { length, distance } => {
+                    let (length_code, length_extra_bits, _) = value_to_code(length as u32 - MIN_MATCH as u32);
+                    let (distance_code, distance_extra_bits, _) = value_to_code(distance - 1);
+                    literal_frequencies[256 + length_code as usize] += 1;
+                    distance_frequencies[distance_code as usize] += 1;
+                    extra_bits += (length_extra_bits + distance_extra_bits) as u64;
+                },
+            }
+        }
 
-        if padding_bits > 0 {
-            // Prepend padding bits (insert them before the last byte)
-            // Insert `padding_bits` number of zeroes at the beginning of compressed_bits
-            let padding = vec![0; padding_bits]; // Create the padding vector
-            compressed_bits.splice(compressed_bits.len() - compressed_bits.len() % 8..compressed_bits.len() - compressed_bits.len() % 8, padding);
+        let used_literals = literal_frequencies.iter().filter(|&&count| count > 0).count();
+        if distance_frequencies.iter().all(|&count| count == 0) || used_literals > 1 << self.max_code_length {
+            return None;
         }
 
-        // Convert the bit vector to a byte vector
-        let mut result: Vec<u8> = Vec::new();
-        for chunk in compressed_bits.chunks(8) {
-            let mut byte: u8 = 0;
-            for (i, &bit) in chunk.iter().enumerate() {
-                byte |= bit << (7 - i); // Shift each bit into the correct position
+        let literal_lengths = length_limited_code_lengths(&literal_frequencies, self.max_code_length);
+        let distance_lengths = length_limited_code_lengths(&distance_frequencies, self.max_code_length);
+        let payload_bits = payload_bits(&literal_frequencies, &literal_lengths)
+            + payload_bits(&distance_frequencies, &distance_lengths)
+            + extra_bits;
+
+        Some(Lz77Plan {
+            encoded_literal_lengths: encode_code_lengths(&literal_lengths),
+            encoded_distance_lengths: encode_code_lengths(&distance_lengths),
+            literal_lengths,
+            distance_lengths,
+            payload_bits,
+        })
+    }
+
+    // An LZ77 block: its type, original length, the literal/length and
+    // distance code length tables, payload length in bits and the payload.
+    // Each match is a length code, its extra bits, a distance code and its
+    // extra bits.
+    fn write_lz77_block<W: Write>(original_length: usize, tokens: &[Token], plan: &Lz77Plan, writer: &mut W) -> Result<()> {
+        let literal_codes = canonical_codes(&plan.literal_lengths)?;
+        let distance_codes = canonical_codes(&plan.distance_lengths)?;
+
+        writer.write_all(&[BLOCK_LZ77])?;
+        writer.write_all(&(original_length as u32).to_le_bytes())?;
+        writer.write_all(&plan.encoded_literal_lengths)?;
+        writer.write_all(&plan.encoded_distance_lengths)?;
+        writer.write_all(&(plan.payload_bits as u32).to_le_bytes())?;
+
+        let mut bit_writer = BitWriter::new(&mut *writer);
+        for &token in tokens {
+            match token {
+                Token::Literal(byte) => {
+                    let (code, length) = literal_codes[byte as usize];
+                    bit_writer.write_bits(code, length as u32)?;
+                },
+                Token::Match { length, distance } => {
+                    let (length_code, length_extra_bits, length_extra) = value_to_code(length as u32 - MIN_MATCH as u32);
+                    let (code, code_length) = literal_codes[256 + length_code as usize];
+                    bit_writer.write_bits(code, code_length as u32)?;
+                    bit_writer.write_bits(length_extra as u64, length_extra_bits)?;
+
+                    let (distance_code, distance_extra_bits, distance_extra) = value_to_code(distance - 1);
+                    let (code, code_length) = distance_codes[distance_code as usize];
+                    bit_writer.write_bits(code, code_length as u32)?;
+                    bit_writer.write_bits(distance_extra as u64, distance_extra_bits)?;
+                },
             }
-            result.push(byte);
         }
 
-        // Insert the padding information at the beginning of the result
-        result.insert(0, padding_bits as u8);
+        bit_writer.finish()?;
+        Ok(())
+    }
+}
+
+// Everything needed to write an LZ77 block, worked out before deciding
+// whether to write one
+struct Lz77Plan {
+    literal_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    encoded_literal_lengths: Vec<u8>,
+    encoded_distance_lengths: Vec<u8>,
+    payload_bits: u64,
+}
+
+impl Lz77Plan {
+    // Bytes the block takes apart from the fields every block has
+    fn size(&self) -> u64 {
+        (self.encoded_literal_lengths.len() + self.encoded_distance_lengths.len()) as u64 + self.payload_bits.div_ceil(8)
+    }
+}
+
+// Range code the bytes of a block with the given scaled frequencies
+fn range_code(block: &[u8], frequencies: &[u32]) -> Vec<u8> {
+    let cumulative = cumulative_frequencies(frequencies);
+    let mut encoder = RangeEncoder::new();
+    for &byte in block {
+        encoder.encode(cumulative[byte as usize], frequencies[byte as usize]);
+    }
+    encoder.finish()
+}
+
+// Number of bits the codes of all symbols take together
+fn payload_bits(frequencies: &[u64], code_lengths: &[u8]) -> u64 {
+    frequencies
+        .iter()
+        .zip(code_lengths)
+        .map(|(&count, &length)| count * length as u64)
+        .sum()
+}
+
+// Run `encode` on every item, one thread per item, and collect the results in
+// order. `meanwhile` runs on the calling thread while the workers are busy.
+fn encode_in_parallel<T: Sync, E: Send>(
+    items: &[T],
+    encode: impl Fn(&T) -> Result<E> + Sync,
+    meanwhile: impl FnOnce(),
+) -> Vec<Result<E>> {
+    if let [item] = items {
+        meanwhile();
+        return vec![encode(item)];
+    }
+
+    thread::scope(|scope| {
+        let encode = &encode;
+        let workers: Vec<_> = items.iter().map(|item| scope.spawn(move || encode(item))).collect();
+        meanwhile();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("compression thread panicked"))
+            .collect()
+    })
+}
+
+// Fill as many of `blocks` as the input allows and return how many were filled
+fn read_batch<R: Read>(reader: &mut R, blocks: &mut [Vec<u8>], block_size: usize) -> Result<usize> {
+    for (count, block) in blocks.iter_mut().enumerate() {
+        read_block(reader, block, block_size)?;
+        if block.is_empty() {
+            return Ok(count);
+        }
+    }
+    Ok(blocks.len())
+}
+
+// Whether a block is one byte value over and over, which a repeat block holds
+fn is_repeat(block: &[u8]) -> bool {
+    block.iter().all(|&byte| byte == block[0])
+}
+
+// Fill `block` with up to `block_size` bytes, stopping early only at the end of
+// the input
+fn read_block<R: Read>(reader: &mut R, block: &mut Vec<u8>, block_size: usize) -> Result<()> {
+    block.clear();
+    reader.take(block_size as u64).read_to_end(block)?;
+    Ok(())
+}
 
-        // Write the result to the writer (file or other output)
-        writer.write_all(&result).unwrap();
+impl Default for CompressionTool {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -1,121 +1,1073 @@
-use crate::huffman::{HuffmanLeafNode, HuffmanInternalNode, HuffmanNode};
-use std::{collections::{BinaryHeap, BTreeMap}, io::{Read, Seek, Write}};
+use crate::bitio::{BitReader, LsbBitReader};
+use crate::bwt::{self, MAX_BWT_BLOCK_SIZE, MTF_ALPHABET_SIZE};
+use crate::bzip2::{self, Bzip2Block};
+use crate::checksum::{Adler32, ChecksumKind, ChecksumWriter};
+use crate::error::{Error, Result};
+use crate::deflate;
+use crate::format::{
+    Format, Header, ADAPTIVE_ALPHABET_SIZE, ADAPTIVE_END_OF_STREAM, BLOCK_BWT, BLOCK_END_OF_STREAM, BLOCK_FSE, BLOCK_HUFFMAN,
+    BLOCK_LZ77, BLOCK_LZW, BLOCK_ORDER1, BLOCK_RANGE, BLOCK_REPEAT, FLAG_ADAPTIVE_HUFFMAN, FLAG_RUN_LENGTH,
+};
+use crate::fse::{self, FseDecoder};
+use crate::gzip::{self, GzipHeader};
+use crate::huffman::{decode_code_lengths, AdaptiveHuffman, HuffmanNode, DECODE_BUFFER_SIZE, GROUP_SIZE, MAX_TABLES};
+use crate::huffman_table::HuffmanDecodeTable;
+use crate::lz77::{code_to_value, DISTANCE_ALPHABET_SIZE, LITERAL_LENGTH_ALPHABET_SIZE, MIN_MATCH};
+use crate::lzw::{self, LzwDecoder, ZHeader};
+use crate::rle;
+use crate::range_coder::{cumulative_frequencies, decode_frequencies, RangeDecoder, FREQUENCY_TOTAL};
+use crate::zlib::{self, ZlibHeader};
+use std::{collections::BTreeMap, io::{self, Cursor, Read, Write}, thread};
+
+// Called with the number of bytes decoded so far and, if the header records
+// it, the total there will be
+type Progress = Box<dyn Fn(u64, Option<u64>) + Send + Sync>;
 
 pub struct DecompressionTool {
+    threads: usize,
+    format: Format,
+    dictionary: Vec<u8>,
+    progress: Option<Progress>,
 }
 
 impl DecompressionTool {
     pub fn new() -> Self {
         DecompressionTool {
+            threads: 1,
+            format: Format::Cczip,
+            dictionary: Vec::new(),
+            progress: None,
+        }
+    }
+
+    // Select the input format
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    // Preset dictionary for deflate and zlib streams compressed with one
+    pub fn dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.dictionary = dictionary.to_vec();
+        self
+    }
+
+    // Report how far decoding has got, every time decoded data is written.
+    // Only our own container reports progress.
+    pub fn progress<F: Fn(u64, Option<u64>) + Send + Sync + 'static>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    // Decode up to `threads` blocks at the same time
+    pub fn threads(mut self, threads: usize) -> Self {
+        assert!(threads >= 1, "at least one thread is needed");
+        self.threads = threads;
+        self
+    }
+
+    pub fn decompress<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        match self.format {
+            Format::Cczip => {
+                // gzip, bzip2 and .Z files are recognised by their magic bytes
+                let mut prefix = [0u8; 3];
+                let filled = read_prefix(reader, &mut prefix)?;
+                let mut reader = (&prefix[..filled]).chain(reader);
+                if prefix[..filled].starts_with(&gzip::MAGIC) {
+                    Self::decompress_gzip(&mut reader, writer)
+                } else if prefix[..filled] == bzip2::MAGIC {
+                    self.decompress_bzip2(&mut reader, writer)
+                } else if prefix[..filled].starts_with(&lzw::MAGIC) {
+                    Self::decompress_lzw(&mut reader, writer)
+                } else {
+                    self.decompress_blocks(&mut reader, writer)
+                }
+            },
+            Format::Deflate => self.decompress_deflate(reader, writer),
+            Format::Gzip => Self::decompress_gzip(reader, writer),
+            Format::Zlib => self.decompress_zlib(reader, writer),
+            Format::Bzip2 => self.decompress_bzip2(reader, writer),
+            Format::Compress => Self::decompress_lzw(reader, writer),
         }
     }
-    
-    pub fn decompress<R: Read + Seek, W: Write>(&self, reader: &mut R, writer: &mut W) {
-        // Step 1: Read the frequency map from the header
-        let mut frequency_map: BTreeMap<char, i32> = BTreeMap::new();
 
-        let mut num_chars_bytes: [u8; 4] = [0u8; 4];
-        let _ = reader.read_exact(&mut num_chars_bytes);
-        let num_chars: usize = u32::from_le_bytes(num_chars_bytes) as usize;
+    fn decompress_blocks<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        // Step 1: Validate the container header before touching the body
+        let header = Header::read_from(reader)?;
 
-        for _ in 0..num_chars {
-            let mut char_buffer = [0u8; 1];
-            let _  = reader.read_exact(&mut char_buffer);
-            let ch = char_buffer[0] as char;
+        // Step 2: Decode the body, checksumming the output as it is written
+        match header.checksum_kind() {
+            Some(kind) => {
+                let mut checksum_writer = ChecksumWriter::new(&mut *writer, kind);
+                self.decompress_measured(&header, reader, &mut checksum_writer)?;
+
+                // Step 3: Compare against the checksum in the trailer
+                let mut trailer = vec![0u8; kind.size()];
+                reader.read_exact(&mut trailer)?;
+                let expected = kind.from_bytes(&trailer);
+                let actual = checksum_writer.checksum().value();
+                if expected != actual {
+                    return Err(Error::ChecksumMismatch { expected, actual });
+                }
+            },
+            None => self.decompress_measured(&header, reader, writer)?,
+        }
+
+        // Nothing may follow the trailer
+        expect_end(reader)
+    }
+
+    // A raw DEFLATE stream, which has to be decoded from start to end in order
+    fn decompress_deflate<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let mut bit_reader = LsbBitReader::new(reader);
+        deflate::decompress_with_dictionary(&mut bit_reader, writer, &self.dictionary, deflate::WINDOW_SIZE)?;
+
+        // Nothing may follow the final block
+        bit_reader.align_to_byte();
+        expect_end(&mut bit_reader)
+    }
+
+    // A zlib stream: header, DEFLATE stream and Adler-32 trailer
+    fn decompress_zlib<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let header = ZlibHeader::read_from(reader)?;
+        let dictionary: &[u8] = match header.dictionary_id {
+            None => &[],
+            Some(id) if self.dictionary.is_empty() => {
+                return Err(Error::InvalidHeader(format!("the stream needs preset dictionary {:08x}", id)));
+            },
+            Some(id) if zlib::dictionary_id(&self.dictionary) != id => {
+                return Err(Error::InvalidHeader(format!("the stream needs preset dictionary {:08x}, not this one", id)));
+            },
+            Some(_) => &self.dictionary,
+        };
+
+        let mut bit_reader = LsbBitReader::new(reader);
+        let mut adler_writer = Adler32Writer { inner: writer, adler: Adler32::new() };
+        // Matches may only reach as far back as the window the header declares
+        let window_size = 1 << header.window_bits;
+        deflate::decompress_with_dictionary(&mut bit_reader, &mut adler_writer, dictionary, window_size)?;
+        bit_reader.align_to_byte();
+        zlib::check_trailer(&mut bit_reader, adler_writer.adler.finalize())?;
+        expect_end(&mut bit_reader)
+    }
+
+    // A gzip file: one or more members, each a header, a DEFLATE stream and
+    // a trailer. The members decompress to the concatenation of their contents.
+    fn decompress_gzip<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<()> {
+        let mut bit_reader = LsbBitReader::new(reader);
+        let mut members = 0;
+        while GzipHeader::read_from(&mut bit_reader)?.is_some() {
+            let mut counting_writer = CountingWriter {
+                inner: ChecksumWriter::new(&mut *writer, ChecksumKind::Crc32),
+                count: 0,
+            };
+            deflate::decompress(&mut bit_reader, &mut counting_writer)?;
+            bit_reader.align_to_byte();
+            let crc = counting_writer.inner.checksum().value() as u32;
+            gzip::check_trailer(&mut bit_reader, crc, counting_writer.count)?;
+            members += 1;
+        }
+        if members == 0 {
+            return Err(Error::InvalidHeader("not a gzip file (bad magic bytes)".to_string()));
+        }
+        Ok(())
+    }
+
+    // A bzip2 file: one or more streams, each a header, blocks and the combined
+    // CRC of the blocks. Only reading a block finds where the next one starts,
+    // so blocks are read one by one, but the inverse transforms of a batch run
+    // in parallel.
+    fn decompress_bzip2<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let mut streams = 0;
+        while let Some(level) = bzip2::read_stream_header(reader)? {
+            let mut bit_reader = BitReader::new(&mut *reader);
+            let mut combined_crc = 0;
+            let mut batch = Vec::with_capacity(self.threads);
+            loop {
+                match bzip2::read_block(&mut bit_reader, level) {
+                    Ok(Some(block)) => {
+                        combined_crc = bzip2::combine_crc(combined_crc, block.crc());
+                        batch.push(Block::Bzip2(block));
+                    },
+                    Ok(None) => break,
+                    Err(err) => {
+                        Self::decode_batch(&batch, false, writer)?;
+                        return Err(err);
+                    },
+                }
+                if batch.len() == self.threads {
+                    Self::decode_batch(&batch, false, writer)?;
+                    batch.clear();
+                }
+            }
+            Self::decode_batch(&batch, false, writer)?;
+            bzip2::check_stream_crc(&mut bit_reader, combined_crc)?;
+            streams += 1;
+        }
+        if streams == 0 {
+            return Err(Error::InvalidHeader("not a bzip2 file (bad magic bytes)".to_string()));
+        }
+        Ok(())
+    }
+
+    // A .Z file: the header and LZW codes up to the end of the input, which
+    // is the only end the stream has
+    fn decompress_lzw<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<()> {
+        let header = ZHeader::read_from(reader)?;
+        LzwDecoder::new(header.max_code_bits, header.block_mode).decode(reader, writer, u64::MAX)?;
+        Ok(())
+    }
+
+    // Decode the body, reporting progress and holding it to the original
+    // length if the header records one. Decoding stops as soon as the output
+    // would grow past that length.
+    fn decompress_measured<R: Read, W: Write>(&self, header: &Header, reader: &mut R, writer: &mut W) -> Result<()> {
+        let mut output = ProgressWriter {
+            inner: writer,
+            written: 0,
+            limit: header.original_length,
+            exceeded: false,
+            progress: self.progress.as_deref(),
+        };
+        let result = self.decompress_body(header, reader, &mut output);
+        let Some(original_length) = header.original_length else {
+            return result;
+        };
+        if output.exceeded {
+            return Err(Error::CorruptData(format!("data decodes to more than its length of {} bytes", original_length)));
+        }
+        result?;
+        if output.written != original_length {
+            return Err(Error::CorruptData(format!(
+                "data decodes to {} bytes instead of its length of {}",
+                output.written, original_length
+            )));
+        }
+        Ok(())
+    }
+
+    fn decompress_body<R: Read, W: Write>(&self, header: &Header, reader: &mut R, writer: &mut W) -> Result<()> {
+        if header.version == 1 {
+            return Self::decompress_single_table(reader, writer);
+        }
+        if header.flags & FLAG_ADAPTIVE_HUFFMAN != 0 {
+            return Self::decompress_adaptive(reader, writer);
+        }
+
+        // Read blocks until the end-of-stream marker, decoding them in batches.
+        // A block that can't be read is only reported once the blocks before
+        // it have been decoded, so errors don't depend on the batch size.
+        let run_length = header.flags & FLAG_RUN_LENGTH != 0;
+        let mut batch = Vec::with_capacity(self.threads);
+        loop {
+            match Self::read_block(header, reader) {
+                Ok(Some(block)) => batch.push(block),
+                Ok(None) => return Self::decode_batch(&batch, run_length, writer),
+                Err(err) => {
+                    Self::decode_batch(&batch, run_length, writer)?;
+                    return Err(err);
+                },
+            }
+            if batch.len() == self.threads {
+                Self::decode_batch(&batch, run_length, writer)?;
+                batch.clear();
+            }
+        }
+    }
+
+    // Read the next block without decoding it, or None at the end of the stream
+    fn read_block<R: Read>(header: &Header, reader: &mut R) -> Result<Option<Block>> {
+        let mut block_type = [0u8; 1];
+        reader.read_exact(&mut block_type)?;
+        match block_type[0] {
+            BLOCK_END_OF_STREAM => Ok(None),
+            BLOCK_HUFFMAN => Ok(Some(Block::Huffman(HuffmanBlock::read_from(header, reader)?))),
+            BLOCK_LZ77 => Ok(Some(Block::Lz77(Lz77Block::read_from(header, reader)?))),
+            BLOCK_RANGE => Ok(Some(Block::Range(RangeBlock::read_from(header, reader)?))),
+            BLOCK_FSE => Ok(Some(Block::Fse(FseBlock::read_from(header, reader)?))),
+            BLOCK_ORDER1 => Ok(Some(Block::Order1(Order1Block::read_from(header, reader)?))),
+            BLOCK_BWT => Ok(Some(Block::Bwt(BwtBlock::read_from(header, reader)?))),
+            BLOCK_LZW => Ok(Some(Block::Lzw(LzwBlock::read_from(header, reader)?))),
+            BLOCK_REPEAT => Ok(Some(Block::Repeat(RepeatBlock::read_from(header, reader)?))),
+            other => Err(Error::CorruptData(format!("unknown block type {}", other))),
+        }
+    }
+
+    // Decode a batch of blocks, one thread per block, and write them in order
+    fn decode_batch<W: Write>(batch: &[Block], run_length: bool, writer: &mut W) -> Result<()> {
+        if batch.len() <= 1 {
+            return batch.iter().try_for_each(|block| block.decode_contents(run_length, writer));
+        }
+
+        let decoded: Vec<Result<Vec<u8>>> = thread::scope(|scope| {
+            let workers: Vec<_> = batch
+                .iter()
+                .map(|block| {
+                    scope.spawn(move || {
+                        let mut decoded = Vec::with_capacity(block.original_length() as usize);
+                        block.decode_contents(run_length, &mut decoded)?;
+                        Ok(decoded)
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("decompression thread panicked"))
+                .collect()
+        });
+
+        for block in decoded {
+            writer.write_all(&block?)?;
+        }
+        Ok(())
+    }
+
+    // Version 1 files hold one code length table for the whole file, the
+    // payload length in bits and the payload
+    fn decompress_single_table<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<()> {
+        let code_lengths = decode_code_lengths(reader, 256)?;
+        let decode_table = HuffmanDecodeTable::from_code_lengths(&code_lengths)?;
+
+        let mut payload_bits_bytes = [0u8; 8];
+        reader.read_exact(&mut payload_bits_bytes)?;
+        let payload_bits = u64::from_le_bytes(payload_bits_bytes);
+
+        let mut bit_reader = BitReader::new(&mut *reader);
+        decode_table.decode(&mut bit_reader, payload_bits, writer)?;
+        bit_reader.align_to_byte();
+        Ok(())
+    }
+
+    // A single adaptive Huffman stream, up to its end-of-stream symbol
+    fn decompress_adaptive<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<()> {
+        let mut decoder = AdaptiveHuffman::new(ADAPTIVE_ALPHABET_SIZE);
+        let mut bit_reader = BitReader::new(&mut *reader);
+        let mut buffer = Vec::with_capacity(DECODE_BUFFER_SIZE);
+        loop {
+            let symbol = decoder.decode(&mut bit_reader)?;
+            if symbol == ADAPTIVE_END_OF_STREAM {
+                break;
+            }
+            buffer.push(symbol as u8);
+            if buffer.len() == DECODE_BUFFER_SIZE {
+                writer.write_all(&buffer)?;
+                buffer.clear();
+            }
+        }
+        writer.write_all(&buffer)?;
+        bit_reader.align_to_byte();
+        Ok(())
+    }
+
+    // Decompress a file written before the container header was introduced.
+    // These files have no magic bytes, so there is no way to tell them apart
+    // from arbitrary data; callers have to ask for this path explicitly.
+    pub fn decompress_legacy<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<()> {
+        let huffman_tree = Self::read_frequency_table(reader)?;
+
+        // The old encoder kept everything in memory, so legacy files are read whole
+        let mut compressed_data = Vec::new();
+        reader.read_to_end(&mut compressed_data)?;
+        if compressed_data.is_empty() {
+            return Err(Error::Truncated);
+        }
+
+        // The first byte is the number of zero bits the old encoder spliced in
+        // at the start of the last byte. Shift them to the end so the payload
+        // reads as a plain bit stream.
+        let padding_bits = compressed_data[0] as u32;
+        let mut payload = compressed_data.split_off(1);
+        if padding_bits > 7 || (padding_bits > 0 && payload.is_empty()) {
+            return Err(Error::CorruptData(format!("invalid padding count {}", padding_bits)));
+        }
+        if let Some(last_byte) = payload.last_mut() {
+            *last_byte <<= padding_bits;
+        }
+        let payload_bits = payload.len() as u64 * 8 - padding_bits as u64;
+
+        // The old encoder gave a lone symbol the empty code and wrote no
+        // payload at all, so its count in the table is all there is to go on
+        if let (HuffmanNode::Leaf(leaf), 0) = (&huffman_tree, payload_bits) {
+            return write_repeated(writer, leaf.value(), leaf.weight() as u64);
+        }
+
+        let mut bit_reader = BitReader::new(Cursor::new(payload));
+        huffman_tree.decode(&mut bit_reader, payload_bits, writer)
+    }
+
+    // Read the legacy frequency table and rebuild the tree the way the old
+    // encoder built it
+    fn read_frequency_table<R: Read>(reader: &mut R) -> Result<HuffmanNode> {
+        let mut frequency_map: BTreeMap<u8, i32> = BTreeMap::new();
+        let mut total = 0u64;
+
+        let mut num_symbols_bytes: [u8; 4] = [0u8; 4];
+        reader.read_exact(&mut num_symbols_bytes)?;
+        let num_symbols: usize = u32::from_le_bytes(num_symbols_bytes) as usize;
+
+        // A byte alphabet has at most 256 symbols
+        if num_symbols == 0 || num_symbols > 256 {
+            return Err(Error::InvalidHeader(format!("invalid symbol count {}", num_symbols)));
+        }
+
+        for _ in 0..num_symbols {
+            let mut symbol = [0u8; 1];
+            reader.read_exact(&mut symbol)?;
+            let symbol = symbol[0];
 
             let mut count_bytes = [0u8; 4];
-            let _ = reader.read_exact(&mut count_bytes);
+            reader.read_exact(&mut count_bytes)?;
             let count = i32::from_le_bytes(count_bytes);
 
-            frequency_map.insert(ch, count);
+            if count <= 0 {
+                return Err(Error::InvalidHeader(format!("invalid frequency {} for symbol {}", count, symbol)));
+            }
+            frequency_map.insert(symbol, count);
+            total += count as u64;
+        }
+
+        // The old encoder counted the whole input in an i32
+        if total > i32::MAX as u64 {
+            return Err(Error::InvalidHeader(format!("symbol frequencies add up to {}", total)));
         }
 
         // Skip the delimiter
-        let _  = reader.read_exact(&mut [0u8; 1]);
+        reader.read_exact(&mut [0u8; 1])?;
+
+        Ok(HuffmanNode::from_frequencies(&frequency_map).expect("symbols are present and their total fits"))
+    }
+}
 
-        // Step 2: Rebuild the Huffman tree from the frequency map
-        let mut heap: BinaryHeap<HuffmanNode> = BinaryHeap::new();
+// A block read from the stream but not decoded yet, so it can be handed to
+// another thread
+enum Block {
+    Huffman(HuffmanBlock),
+    Lz77(Lz77Block),
+    Range(RangeBlock),
+    Fse(FseBlock),
+    Order1(Order1Block),
+    Bwt(BwtBlock),
+    Bzip2(Bzip2Block),
+    Lzw(LzwBlock),
+    Repeat(RepeatBlock),
+}
 
-        // Push each character into the heap as a leaf node
-        for (ch, count) in frequency_map {
-            let leaf = HuffmanLeafNode::new(count, ch);
-            heap.push(HuffmanNode::Leaf(leaf));
+impl Block {
+    fn original_length(&self) -> u32 {
+        match self {
+            Block::Huffman(block) => block.original_length,
+            Block::Lz77(block) => block.original_length,
+            Block::Range(block) => block.original_length,
+            Block::Fse(block) => block.original_length,
+            Block::Order1(block) => block.original_length,
+            Block::Bwt(block) => block.original_length,
+            Block::Bzip2(block) => block.transformed_length() as u32,
+            Block::Lzw(block) => block.original_length,
+            Block::Repeat(block) => block.original_length,
         }
+    }
 
-        // Rebuild the Huffman tree
-        while heap.len() > 1 {
-            let left = heap.pop().unwrap();
-            let right = heap.pop().unwrap();
-            let combined_weight = left.weight() + right.weight();
-            let internal_node = HuffmanInternalNode::new(combined_weight, left, right);
-            heap.push(HuffmanNode::Internal(internal_node));
+    // Decode the block and expand its runs if every block's bytes are run-length
+    // encoded, which repeat blocks never are
+    fn decode_contents<W: Write>(&self, run_length: bool, writer: &mut W) -> Result<()> {
+        if !run_length || matches!(self, Block::Repeat(_)) {
+            return self.decode(writer);
         }
+        let mut decoder = rle::Decoder::new(writer);
+        self.decode(&mut decoder)?;
+        decoder.finish()?;
+        Ok(())
+    }
 
-        let huffman_tree = heap.pop().unwrap(); // root
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Block::Huffman(block) => block.decode(writer),
+            Block::Lz77(block) => block.decode(writer),
+            Block::Range(block) => block.decode(writer),
+            Block::Fse(block) => block.decode(writer),
+            Block::Order1(block) => block.decode(writer),
+            Block::Bwt(block) => block.decode(writer),
+            Block::Bzip2(block) => block.decode(writer),
+            Block::Lzw(block) => block.decode(writer),
+            Block::Repeat(block) => block.decode(writer),
+        }
+    }
+}
 
-        // Step 3: Read the compressed data
-        let mut compressed_data = Vec::new();
-        let _ = reader.read_to_end(&mut compressed_data);
-
-        // Step4: Decode the data using the Huffman tree
-        let mut current_node: &HuffmanNode = &huffman_tree;
-
-        // Extract the padding bits from the first byte (which indicates how many bits were padded)
-        let padding_bits = compressed_data[0] as usize; // First byte indicates padding
-        let mut bits: Vec<bool> = compressed_data[1..]
-            .iter() // Use `iter()` to iterate over the bytes
-            .flat_map(|byte| (0..8).map(move |i| (byte >> (7u8 - i)) & 1u8 == 1u8)) // Convert bytes to bits
-            .collect();
-
-        // Handle the last byte padding
-        if padding_bits > 0 {
-            // We want to remove the padding bits from the start of the last byte.
-            let last_byte = &compressed_data[compressed_data.len() - 1];
-            let mut last_byte_bits = (0..8)
-                .map(|i| (last_byte >> (7 - i)) & 1u8 == 1u8)
-                .collect::<Vec<bool>>();
-
-            // Truncate the padding bits from the start of the last byte
-            last_byte_bits = last_byte_bits[padding_bits..].to_vec();
-
-            // Remove the last byte's bits from the original bitstream and append the truncated bits
-            bits = bits[..bits.len() - 8].to_vec();
-            bits.extend(last_byte_bits);
-        }
-
-        // Traverse the Huffman tree to decode the bits
-        let mut current_bit = 0;
-        while current_bit < bits.len() {
-            let bit = bits[current_bit];
-            current_bit += 1;
-
-            // Move down the tree based on the bit
-            current_node = match current_node {
-                HuffmanNode::Leaf(leaf) => {
-                    let _ = writer.write_all(&[leaf.value() as u8]);  // Append the decoded character
-                    &huffman_tree // Reset to the root of the tree for the next character
-                },
-                HuffmanNode::Internal(internal) => {
-                    // Traverse the internal node based on the bit (0 = left, 1 = right)
-                    if bit {
-                        let next_node = &internal.right(); // Move to the right child if the bit is 1
-                        if let HuffmanNode::Leaf(leaf) = next_node {
-                            let _ = writer.write_all(&[leaf.value() as u8]);
-                            &huffman_tree // Reset to the root of the tree for the next character
-                        } else {
-                            next_node // Continue moving down the internal node tree
-                        }
-                    } else {
-                        let next_node = &internal.left(); // Move to the left child if the bit is 0
-                        if let HuffmanNode::Leaf(leaf) = next_node {
-                            let _ = writer.write_all(&[leaf.value() as u8]);
-                            &huffman_tree // Reset to the root of the tree for the next character
-                        } else {
-                            next_node // Continue moving down the internal node tree
-                        }
-                    }
-                },
-            };
+// A block with its own Huffman table: original length, code length table,
+// payload length in bits and the payload
+struct HuffmanBlock {
+    original_length: u32,
+    code_lengths: Vec<u8>,
+    payload_bits: u64,
+    payload: Vec<u8>,
+}
+
+impl HuffmanBlock {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        let code_lengths = decode_code_lengths(reader, 256)?;
+
+        // No symbol takes more bits than the longest code, which also bounds
+        // how much a corrupt length can make us read
+        let longest_code = code_lengths.iter().copied().max().unwrap_or(0) as u64;
+        let (payload_bits, payload) = read_payload(reader, original_length as u64 * longest_code)?;
+
+        Ok(HuffmanBlock { original_length, code_lengths, payload_bits, payload })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let decode_table = HuffmanDecodeTable::from_code_lengths(&self.code_lengths)?;
+
+        // Count what comes out so a block can't decode to more or less than it claims
+        let mut counting_writer = CountingWriter { inner: writer, count: 0 };
+        let mut bit_reader = BitReader::new(&self.payload[..]);
+        decode_table.decode(&mut bit_reader, self.payload_bits, &mut counting_writer)?;
+
+        if counting_writer.count != self.original_length as u64 {
+            return Err(Error::CorruptData(format!(
+                "block decoded to {} bytes instead of {}",
+                counting_writer.count, self.original_length
+            )));
+        }
+        Ok(())
+    }
+}
+
+// An LZ77 block: original length, literal/length and distance code length
+// tables, payload length in bits and the payload
+struct Lz77Block {
+    original_length: u32,
+    literal_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    payload_bits: u64,
+    payload: Vec<u8>,
+}
+
+impl Lz77Block {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        let literal_lengths = decode_code_lengths(reader, LITERAL_LENGTH_ALPHABET_SIZE)?;
+        let distance_lengths = decode_code_lengths(reader, DISTANCE_ALPHABET_SIZE)?;
+
+        // A token never takes more than the longest codes plus their extra bits
+        let longest_literal = literal_lengths.iter().copied().max().unwrap_or(0) as u64;
+        let longest_distance = distance_lengths.iter().copied().max().unwrap_or(0) as u64;
+        let max_bits = original_length as u64 * (longest_literal + longest_distance + 32);
+        let (payload_bits, payload) = read_payload(reader, max_bits)?;
+
+        Ok(Lz77Block { original_length, literal_lengths, distance_lengths, payload_bits, payload })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let literal_table = HuffmanDecodeTable::from_code_lengths(&self.literal_lengths)?;
+        // Blocks without matches have no distance codes
+        let distance_table = if self.distance_lengths.iter().all(|&length| length == 0) {
+            None
+        } else {
+            Some(HuffmanDecodeTable::from_code_lengths(&self.distance_lengths)?)
+        };
+
+        let original_length = self.original_length as usize;
+        let mut output: Vec<u8> = Vec::with_capacity(original_length);
+        let mut bit_reader = BitReader::new(&self.payload[..]);
+        let mut remaining = self.payload_bits;
+
+        while output.len() < original_length {
+            let symbol = literal_table.decode_symbol(&mut bit_reader, &mut remaining)?;
+            if symbol < 256 {
+                output.push(symbol as u8);
+                continue;
+            }
+
+            let length = read_code_value(&mut bit_reader, &mut remaining, symbol - 256)? as usize + MIN_MATCH;
+            let distance_table = distance_table
+                .as_ref()
+                .ok_or_else(|| Error::CorruptData("match in a block without distance codes".to_string()))?;
+            let distance_code = distance_table.decode_symbol(&mut bit_reader, &mut remaining)?;
+            let distance = read_code_value(&mut bit_reader, &mut remaining, distance_code)? as usize + 1;
+
+            if distance > output.len() {
+                return Err(Error::CorruptData(format!("match distance {} reaches before the block", distance)));
+            }
+            if output.len() + length > original_length {
+                return Err(Error::CorruptData(format!("block decoded to more than {} bytes", original_length)));
+            }
+
+            // Copy byte by byte when the match overlaps the bytes it produces
+            let start = output.len() - distance;
+            if distance >= length {
+                output.extend_from_within(start..start + length);
+            } else {
+                for i in start..start + length {
+                    output.push(output[i]);
+                }
+            }
+        }
+
+        if remaining != 0 {
+            return Err(Error::CorruptData("block has unused payload bits".to_string()));
+        }
+        writer.write_all(&output)?;
+        Ok(())
+    }
+}
+
+// A range coded block: original length, frequency table, payload length in
+// bytes and the payload
+struct RangeBlock {
+    original_length: u32,
+    frequencies: Vec<u32>,
+    payload: Vec<u8>,
+}
+
+impl RangeBlock {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        let frequencies = decode_frequencies(reader, 256)?;
+
+        let mut payload_length_bytes = [0u8; 4];
+        reader.read_exact(&mut payload_length_bytes)?;
+        let payload_length = u32::from_le_bytes(payload_length_bytes) as u64;
+        // No symbol costs more than FREQUENCY_BITS, so two bytes a symbol is plenty
+        if payload_length > original_length as u64 * 2 + 8 {
+            return Err(Error::CorruptData(format!("invalid payload length of {} bytes", payload_length)));
+        }
+        let payload = read_bytes(reader, payload_length)?;
+
+        Ok(RangeBlock { original_length, frequencies, payload })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        // Map every slot of the scaled total to the symbol that owns it
+        let cumulative = cumulative_frequencies(&self.frequencies);
+        let mut symbols = Vec::with_capacity(FREQUENCY_TOTAL as usize);
+        for (symbol, &frequency) in self.frequencies.iter().enumerate() {
+            symbols.resize(symbols.len() + frequency as usize, symbol as u8);
+        }
+
+        let mut output = Vec::with_capacity(self.original_length as usize);
+        let mut decoder = RangeDecoder::new(&self.payload)?;
+        for _ in 0..self.original_length {
+            let symbol = symbols[decoder.frequency() as usize];
+            decoder.consume(cumulative[symbol as usize], self.frequencies[symbol as usize])?;
+            output.push(symbol);
+        }
+
+        if !decoder.is_finished() {
+            return Err(Error::CorruptData("block has unused payload bytes".to_string()));
         }
+        writer.write_all(&output)?;
+        Ok(())
+    }
+}
+
+// An FSE block: original length, table log, normalized counts, payload length
+// in bits and the payload
+struct FseBlock {
+    original_length: u32,
+    table_log: u8,
+    counts: Vec<u32>,
+    payload_bits: u64,
+    payload: Vec<u8>,
+}
+
+impl FseBlock {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        let mut table_log = [0u8; 1];
+        reader.read_exact(&mut table_log)?;
+        let table_log = table_log[0];
+        fse::check_table_log(table_log)?;
+
+        let mut bit_reader = BitReader::new(&mut *reader);
+        let counts = fse::read_counts(&mut bit_reader, table_log, 256)?;
+        bit_reader.align_to_byte();
+
+        // No symbol takes more bits than the table log, plus the final state
+        let max_bits = (original_length as u64 + 1) * table_log as u64;
+        let (payload_bits, payload) = read_payload(reader, max_bits)?;
+
+        Ok(FseBlock { original_length, table_log, counts, payload_bits, payload })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut output = Vec::with_capacity(self.original_length as usize);
+        FseDecoder::new(&self.counts, self.table_log).decode(
+            &self.payload,
+            self.payload_bits,
+            self.original_length as usize,
+            |symbol| output.push(symbol as u8),
+        )?;
+        writer.write_all(&output)?;
+        Ok(())
+    }
+}
+
+// An order-1 block: original length, a bitmap of the contexts (previous
+// bytes) with their own code table, the shared code length table, the own
+// tables in context order, payload length in bits and the payload
+struct Order1Block {
+    original_length: u32,
+    // Index into `code_lengths` for every context; 0 is the shared table
+    table_of_context: Vec<usize>,
+    code_lengths: Vec<Vec<u8>>,
+    payload_bits: u64,
+    payload: Vec<u8>,
+}
+
+impl Order1Block {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        let mut bitmap = [0u8; 32];
+        reader.read_exact(&mut bitmap)?;
+
+        let mut code_lengths = vec![decode_code_lengths(reader, 256)?];
+        let mut table_of_context = vec![0; 256];
+        for (context, table) in table_of_context.iter_mut().enumerate() {
+            if bitmap[context / 8] & 1 << (context % 8) != 0 {
+                *table = code_lengths.len();
+                code_lengths.push(decode_code_lengths(reader, 256)?);
+            }
+        }
+
+        let longest_code = code_lengths.iter().flatten().copied().max().unwrap_or(0) as u64;
+        let (payload_bits, payload) = read_payload(reader, original_length as u64 * longest_code)?;
+
+        Ok(Order1Block { original_length, table_of_context, code_lengths, payload_bits, payload })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        // The shared table may be unused, and then it is empty
+        let tables = self
+            .code_lengths
+            .iter()
+            .map(|lengths| match lengths.iter().all(|&length| length == 0) {
+                true => Ok(None),
+                false => HuffmanDecodeTable::from_code_lengths(lengths).map(Some),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Switch tables on the byte decoded last, starting from an imaginary zero byte
+        let mut output = Vec::with_capacity(self.original_length as usize);
+        let mut bit_reader = BitReader::new(&self.payload[..]);
+        let mut remaining = self.payload_bits;
+        let mut previous = 0u8;
+        for _ in 0..self.original_length {
+            let table = tables[self.table_of_context[previous as usize]]
+                .as_ref()
+                .ok_or_else(|| Error::CorruptData(format!("no code table for bytes after {}", previous)))?;
+            previous = table.decode_symbol(&mut bit_reader, &mut remaining)? as u8;
+            output.push(previous);
+        }
+
+        if remaining != 0 {
+            return Err(Error::CorruptData("block has unused payload bits".to_string()));
+        }
+        writer.write_all(&output)?;
+        Ok(())
+    }
+}
+
+// A repeat block: original length and the byte that makes up the whole block
+struct RepeatBlock {
+    original_length: u32,
+    byte: u8,
+}
+
+impl RepeatBlock {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        if original_length > header.block_size {
+            return Err(Error::CorruptData(format!("invalid block length {}", original_length)));
+        }
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        Ok(RepeatBlock { original_length, byte: byte[0] })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write_repeated(writer, self.byte, self.original_length as u64)
+    }
+}
+
+// Write `byte` `count` times, a chunk at a time however large the count
+fn write_repeated<W: Write>(writer: &mut W, byte: u8, count: u64) -> Result<()> {
+    let chunk = [byte; 4096];
+    let mut remaining = count;
+    while remaining > 0 {
+        let length = remaining.min(chunk.len() as u64) as usize;
+        writer.write_all(&chunk[..length])?;
+        remaining -= length as u64;
+    }
+    Ok(())
+}
+
+// An LZW block: original length, the widest code, payload length in bytes and
+// the payload
+struct LzwBlock {
+    original_length: u32,
+    max_code_bits: u8,
+    payload: Vec<u8>,
+}
+
+impl LzwBlock {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        let mut fields = [0u8; 5];
+        reader.read_exact(&mut fields)?;
+        let max_code_bits = fields[0];
+        if !(lzw::MIN_CODE_BITS..=lzw::MAX_CODE_BITS).contains(&max_code_bits) {
+            return Err(Error::CorruptData(format!("invalid maximum LZW code width of {} bits", max_code_bits)));
+        }
+        let payload_length = u32::from_le_bytes([fields[1], fields[2], fields[3], fields[4]]) as u64;
+        // At most one 16-bit code a byte, and the padding at width changes and
+        // clears never adds up to more than half as much again
+        if payload_length > original_length as u64 * 3 + 32 {
+            return Err(Error::CorruptData(format!("invalid payload length of {} bytes", payload_length)));
+        }
+        let payload = read_bytes(reader, payload_length)?;
+        Ok(LzwBlock { original_length, max_code_bits, payload })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let limit = self.original_length as u64;
+        let written = LzwDecoder::new(self.max_code_bits, true).decode(&self.payload[..], writer, limit)?;
+        if written != limit {
+            return Err(Error::CorruptData(format!("LZW block decodes to {} bytes instead of {}", written, limit)));
+        }
+        Ok(())
+    }
+}
+
+// A BWT block: original length, primary index, number of symbols, number of
+// tables, their code length tables, payload length in bits and the payload,
+// which holds the selectors and then the symbol codes
+struct BwtBlock {
+    original_length: u32,
+    primary: u32,
+    symbol_count: u32,
+    code_lengths: Vec<Vec<u8>>,
+    payload_bits: u64,
+    payload: Vec<u8>,
+}
+
+impl BwtBlock {
+    fn read_from<R: Read>(header: &Header, reader: &mut R) -> Result<Self> {
+        let original_length = read_original_length(header, reader)?;
+        if original_length as usize > MAX_BWT_BLOCK_SIZE {
+            return Err(Error::CorruptData(format!("BWT block of {} bytes is too large", original_length)));
+        }
+        let mut fields = [0u8; 9];
+        reader.read_exact(&mut fields)?;
+        let primary = u32::from_le_bytes([fields[0], fields[1], fields[2], fields[3]]);
+        // Zero-run coding never makes more symbols than bytes
+        let symbol_count = u32::from_le_bytes([fields[4], fields[5], fields[6], fields[7]]);
+        if symbol_count == 0 || symbol_count > original_length {
+            return Err(Error::CorruptData(format!("invalid BWT symbol count {}", symbol_count)));
+        }
+        let table_count = fields[8] as usize;
+        if !(2..=MAX_TABLES).contains(&table_count) {
+            return Err(Error::CorruptData(format!("invalid Huffman table count {}", table_count)));
+        }
+        let code_lengths = (0..table_count)
+            .map(|_| decode_code_lengths(reader, MTF_ALPHABET_SIZE))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Each selector takes at most one bit per table, each symbol at most the longest code
+        let longest_code = code_lengths.iter().flatten().copied().max().unwrap_or(0) as u64;
+        let groups = (symbol_count as u64).div_ceil(GROUP_SIZE as u64);
+        let max_bits = groups * table_count as u64 + symbol_count as u64 * longest_code;
+        let (payload_bits, payload) = read_payload(reader, max_bits)?;
+
+        Ok(BwtBlock { original_length, primary, symbol_count, code_lengths, payload_bits, payload })
+    }
+
+    fn decode<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let tables = self
+            .code_lengths
+            .iter()
+            .map(|lengths| HuffmanDecodeTable::from_code_lengths(lengths))
+            .collect::<Result<Vec<_>>>()?;
+        let mut bit_reader = BitReader::new(&self.payload[..]);
+        let mut remaining = self.payload_bits;
+
+        // Selectors, move-to-front coded in unary
+        let group_count = (self.symbol_count as usize).div_ceil(GROUP_SIZE);
+        let mut order: Vec<usize> = (0..tables.len()).collect();
+        let mut selectors = Vec::with_capacity(group_count);
+        for _ in 0..group_count {
+            let mut position = 0;
+            loop {
+                if remaining == 0 {
+                    return Err(Error::CorruptData("payload ends in the middle of a selector".to_string()));
+                }
+                remaining -= 1;
+                if !bit_reader.read_bit()? {
+                    break;
+                }
+                position += 1;
+                if position == tables.len() {
+                    return Err(Error::CorruptData("invalid Huffman table selector".to_string()));
+                }
+            }
+            let selector = order[position];
+            order.copy_within(0..position, 1);
+            order[0] = selector;
+            selectors.push(selector);
+        }
+
+        let mut symbols = Vec::with_capacity(self.symbol_count as usize);
+        for (group, &selector) in selectors.iter().enumerate() {
+            let group_length = GROUP_SIZE.min(self.symbol_count as usize - group * GROUP_SIZE);
+            for _ in 0..group_length {
+                symbols.push(tables[selector].decode_symbol(&mut bit_reader, &mut remaining)?);
+            }
+        }
+        if remaining != 0 {
+            return Err(Error::CorruptData("block has unused payload bits".to_string()));
+        }
+
+        let transformed = bwt::inverse_move_to_front(&symbols, self.original_length as usize)?;
+        writer.write_all(&bwt::inverse(&transformed, self.primary)?)?;
+        Ok(())
+    }
+}
+
+// The number of original bytes in a block, which is never more than the
+// block size from the header
+fn read_original_length<R: Read>(header: &Header, reader: &mut R) -> Result<u32> {
+    let mut length_bytes = [0u8; 4];
+    reader.read_exact(&mut length_bytes)?;
+    let original_length = u32::from_le_bytes(length_bytes);
+    if original_length == 0 || original_length > header.max_block_length() {
+        return Err(Error::CorruptData(format!("invalid block length {}", original_length)));
+    }
+    Ok(original_length)
+}
+
+// Read the payload length in bits and the payload itself. A length above
+// `max_bits` can't come from a valid block.
+fn read_payload<R: Read>(reader: &mut R, max_bits: u64) -> Result<(u64, Vec<u8>)> {
+    let mut payload_bits_bytes = [0u8; 4];
+    reader.read_exact(&mut payload_bits_bytes)?;
+    let payload_bits = u32::from_le_bytes(payload_bits_bytes) as u64;
+    if payload_bits > max_bits {
+        return Err(Error::CorruptData(format!("invalid payload length of {} bits", payload_bits)));
+    }
+
+    Ok((payload_bits, read_bytes(reader, payload_bits.div_ceil(8))?))
+}
+
+// Read exactly `length` bytes without trusting `length` with an allocation up front
+fn read_bytes<R: Read>(reader: &mut R, length: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < length {
+        return Err(Error::Truncated);
+    }
+    Ok(bytes)
+}
+
+// Read the extra bits that follow a length or distance code and return the
+// value they stand for
+fn read_code_value<R: Read>(bit_reader: &mut BitReader<R>, remaining: &mut u64, code: u16) -> Result<u32> {
+    let (base, extra_bits) = code_to_value(code);
+    if extra_bits as u64 > *remaining {
+        return Err(Error::CorruptData("payload ends in the middle of a code".to_string()));
+    }
+    *remaining -= extra_bits as u64;
+    Ok(base + bit_reader.read_bits(extra_bits)? as u32)
+}
+
+// Read up to `prefix.len()` bytes, fewer only at the end of the input.
+// Returns the number of bytes read.
+fn read_prefix<R: Read>(reader: &mut R, prefix: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < prefix.len() {
+        match reader.read(&mut prefix[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Fail unless the input ends here
+fn expect_end<R: Read>(reader: &mut R) -> Result<()> {
+    if reader.read(&mut [0u8; 1])? != 0 {
+        return Err(Error::CorruptData("unexpected data after the end of the stream".to_string()));
+    }
+    Ok(())
+}
+
+// Writer adapter that computes the Adler-32 of everything written through it
+struct Adler32Writer<W: Write> {
+    inner: W,
+    adler: Adler32,
+}
+
+impl<W: Write> Write for Adler32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.adler.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Writer adapter that reports progress and refuses to write past `limit`
+struct ProgressWriter<'a, W: Write> {
+    inner: W,
+    written: u64,
+    limit: Option<u64>,
+    exceeded: bool,
+    progress: Option<&'a (dyn Fn(u64, Option<u64>) + Send + Sync)>,
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.limit.is_some_and(|limit| self.written + buf.len() as u64 > limit) {
+            self.exceeded = true;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "output longer than the original length"));
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        if let Some(progress) = self.progress {
+            progress(self.written, self.limit);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Writer adapter that counts the bytes written through it
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Default for DecompressionTool {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -0,0 +1,673 @@
+// Raw DEFLATE streams (RFC 1951), the format inside gzip, zlib and zip files.
+//
+// A stream is a sequence of blocks, each starting with a "final block" bit and
+// a two-bit block type: stored (plain bytes), fixed Huffman codes, or dynamic
+// Huffman codes described at the start of the block. Literals and match lengths
+// share one alphabet and distances have their own, like in our LZ77 blocks,
+// but with DEFLATE's own code tables and bit packing.
+
+use std::io::{self, Read, Write};
+use crate::bitio::{LsbBitReader, LsbBitWriter};
+use crate::error::{Error, Result};
+use crate::huffman::{canonical_codes, length_limited_code_lengths};
+use crate::huffman_table::HuffmanDecodeTable;
+use crate::lz77::{MatchFinder, Token};
+
+// Matches reach at most this far back
+pub const WINDOW_SIZE: usize = 32 * 1024;
+
+// An empty final block with fixed Huffman codes, which ends a stream
+pub const EMPTY_FINAL_BLOCK: [u8; 2] = [0x03, 0x00];
+
+const BLOCK_STORED: u64 = 0;
+const BLOCK_FIXED: u64 = 1;
+const BLOCK_DYNAMIC: u64 = 2;
+
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+const MAX_STORED_LENGTH: usize = 65535;
+
+// Tokens per block; every block gets Huffman codes fitted to its own tokens
+const BLOCK_TOKENS: usize = 16 * 1024;
+
+const END_OF_BLOCK: usize = 256;
+const LITERAL_LENGTH_CODES: usize = 286;
+const DISTANCE_CODES: usize = 30;
+const CODE_LENGTH_CODES: usize = 19;
+
+// Smallest length and distance of each length and distance code, and the
+// number of extra bits that follow the code
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// Order the code length code lengths are sent in, most likely used first
+const CODE_LENGTH_ORDER: [usize; CODE_LENGTH_CODES] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn length_code(length: u16) -> usize {
+    LENGTH_BASE.partition_point(|&base| base <= length) - 1
+}
+
+fn distance_code(distance: u32) -> usize {
+    DISTANCE_BASE.partition_point(|&base| base as u32 <= distance) - 1
+}
+
+// Code lengths of the fixed Huffman codes. The literal/length alphabet has two
+// and the distance alphabet two codes that never occur, which keeps both
+// codes complete.
+fn fixed_literal_lengths() -> Vec<u8> {
+    (0..288)
+        .map(|symbol| match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        })
+        .collect()
+}
+
+fn fixed_distance_lengths() -> Vec<u8> {
+    vec![5; 32]
+}
+
+// Compress `data[start..]` into a sequence of DEFLATE blocks, with
+// `data[..start]` as history that matches may reach into. Unless `last` is
+// set, the chunk ends with an empty stored block so that it ends on a byte
+// boundary and the next chunk can simply be appended; otherwise its final
+// block ends the stream.
+pub fn compress_chunk(data: &[u8], start: usize, last: bool, lazy_matching: bool) -> io::Result<Vec<u8>> {
+    let tokens = MatchFinder::new(WINDOW_SIZE, lazy_matching).tokenize_from(data, start);
+    let mut writer = LsbBitWriter::new(Vec::with_capacity((data.len() - start) / 2));
+
+    if tokens.is_empty() {
+        write_block(&[], &[], last, &mut writer)?;
+    }
+    let block_count = tokens.len().div_ceil(BLOCK_TOKENS);
+    let mut position = start;
+    for (index, block_tokens) in tokens.chunks(BLOCK_TOKENS).enumerate() {
+        let length: usize = block_tokens
+            .iter()
+            .map(|token| match token {
+                Token::Literal(_) => 1,
+                Token::Match { length, .. } => *length as usize,
+            })
+            .sum();
+        write_block(&data[position..position + length], block_tokens, last && index + 1 == block_count, &mut writer)?;
+        position += length;
+    }
+
+    if !last {
+        write_stored_block(&[], false, &mut writer)?;
+    }
+    writer.finish()
+}
+
+// Write one block as whichever of stored, fixed or dynamic comes out smallest
+fn write_block<W: Write>(bytes: &[u8], tokens: &[Token], last: bool, writer: &mut LsbBitWriter<W>) -> io::Result<()> {
+    let mut literal_frequencies = vec![0u64; LITERAL_LENGTH_CODES];
+    let mut distance_frequencies = vec![0u64; DISTANCE_CODES];
+    let mut extra_bits = 0u64;
+    for &token in tokens {
+        match token {
+            Token::Literal(byte) => literal_frequencies[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                let length_code = length_code(length);
+                let distance_code = distance_code(distance);
+                literal_frequencies[257 + length_code] += 1;
+                distance_frequencies[distance_code] += 1;
+                extra_bits += (LENGTH_EXTRA_BITS[length_code] + DISTANCE_EXTRA_BITS[distance_code]) as u64;
+            },
+        }
+    }
+    literal_frequencies[END_OF_BLOCK] += 1;
+
+    let fixed_literal_lengths = fixed_literal_lengths();
+    let fixed_distance_lengths = fixed_distance_lengths();
+    let fixed_bits = 3
+        + code_bits(&literal_frequencies, &fixed_literal_lengths)
+        + code_bits(&distance_frequencies, &fixed_distance_lengths)
+        + extra_bits;
+
+    let dynamic = DynamicCodes::new(&literal_frequencies, &distance_frequencies);
+    let dynamic_bits = 3
+        + dynamic.header_bits
+        + code_bits(&literal_frequencies, &dynamic.literal_lengths)
+        + code_bits(&distance_frequencies, &dynamic.distance_lengths)
+        + extra_bits;
+
+    if stored_bits(bytes.len(), writer.bits_written()) < fixed_bits.min(dynamic_bits) {
+        return write_stored_block(bytes, last, writer);
+    }
+
+    writer.write_bits(last as u64, 1)?;
+    if fixed_bits <= dynamic_bits {
+        writer.write_bits(BLOCK_FIXED, 2)?;
+        write_tokens(tokens, &fixed_literal_lengths, &fixed_distance_lengths, writer)
+    } else {
+        writer.write_bits(BLOCK_DYNAMIC, 2)?;
+        dynamic.write_header(writer)?;
+        write_tokens(tokens, &dynamic.literal_lengths, &dynamic.distance_lengths, writer)
+    }
+}
+
+// Number of bits the codes of all symbols take together
+fn code_bits(frequencies: &[u64], code_lengths: &[u8]) -> u64 {
+    frequencies
+        .iter()
+        .zip(code_lengths)
+        .map(|(&count, &length)| count * length as u64)
+        .sum()
+}
+
+// Number of bits `length` bytes take as stored blocks, starting `position`
+// bits into the stream
+fn stored_bits(length: usize, position: u64) -> u64 {
+    let mut end = position;
+    let pieces = length.div_ceil(MAX_STORED_LENGTH).max(1);
+    for piece in 0..pieces {
+        let piece_length = (length - piece * MAX_STORED_LENGTH).min(MAX_STORED_LENGTH);
+        end = (end + 3).div_ceil(8) * 8 + 32 + piece_length as u64 * 8;
+    }
+    end - position
+}
+
+// Stored blocks: the header bits, padding to a byte boundary, the length and
+// its complement, and the bytes themselves
+fn write_stored_block<W: Write>(bytes: &[u8], last: bool, writer: &mut LsbBitWriter<W>) -> io::Result<()> {
+    let pieces = bytes.len().div_ceil(MAX_STORED_LENGTH).max(1);
+    for piece in 0..pieces {
+        let piece_bytes = &bytes[(piece * MAX_STORED_LENGTH).min(bytes.len())..((piece + 1) * MAX_STORED_LENGTH).min(bytes.len())];
+        writer.write_bits((last && piece + 1 == pieces) as u64, 1)?;
+        writer.write_bits(BLOCK_STORED, 2)?;
+        writer.align_to_byte()?;
+        writer.write_bits(piece_bytes.len() as u64, 16)?;
+        writer.write_bits(!piece_bytes.len() as u64 & 0xFFFF, 16)?;
+        for &byte in piece_bytes {
+            writer.write_bits(byte as u64, 8)?;
+        }
+    }
+    Ok(())
+}
+
+// Write the tokens of a Huffman block followed by the end-of-block code
+fn write_tokens<W: Write>(tokens: &[Token], literal_lengths: &[u8], distance_lengths: &[u8], writer: &mut LsbBitWriter<W>) -> io::Result<()> {
+    let literal_codes = canonical_codes(literal_lengths).expect("literal/length code lengths are valid");
+    let distance_codes = canonical_codes(distance_lengths).expect("distance code lengths are valid");
+
+    for &token in tokens {
+        match token {
+            Token::Literal(byte) => {
+                let (code, length) = literal_codes[byte as usize];
+                writer.write_code(code, length as u32)?;
+            },
+            Token::Match { length, distance } => {
+                let length_code = length_code(length);
+                let (code, code_length) = literal_codes[257 + length_code];
+                writer.write_code(code, code_length as u32)?;
+                writer.write_bits((length - LENGTH_BASE[length_code]) as u64, LENGTH_EXTRA_BITS[length_code] as u32)?;
+
+                let distance_code = distance_code(distance);
+                let (code, code_length) = distance_codes[distance_code];
+                writer.write_code(code, code_length as u32)?;
+                writer.write_bits(
+                    (distance - DISTANCE_BASE[distance_code] as u32) as u64,
+                    DISTANCE_EXTRA_BITS[distance_code] as u32,
+                )?;
+            },
+        }
+    }
+
+    let (code, length) = literal_codes[END_OF_BLOCK];
+    writer.write_code(code, length as u32)
+}
+
+// Code lengths for `frequencies` limited to `max_length` bits, with at least
+// two codes so that every code is complete. Some decoders reject the
+// single-code case.
+fn complete_code_lengths(frequencies: &[u64], max_length: u8) -> Vec<u8> {
+    let mut frequencies = frequencies.to_vec();
+    for symbol in 0..2 {
+        if frequencies.iter().filter(|&&count| count > 0).count() < 2 && frequencies[symbol] == 0 {
+            frequencies[symbol] = 1;
+        }
+    }
+    length_limited_code_lengths(&frequencies, max_length)
+}
+
+// The Huffman codes of a dynamic block and how they are described in its header:
+// the literal/length and distance code lengths are run-length coded with
+// symbols 0 to 18, which are in turn Huffman coded with the code length code
+struct DynamicCodes {
+    literal_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    literal_count: usize,
+    distance_count: usize,
+    code_length_lengths: Vec<u8>,
+    code_length_count: usize,
+    // Run-length coded code lengths: symbol and the value of its extra bits
+    runs: Vec<(u8, u8)>,
+    header_bits: u64,
+}
+
+impl DynamicCodes {
+    fn new(literal_frequencies: &[u64], distance_frequencies: &[u64]) -> Self {
+        let literal_lengths = complete_code_lengths(literal_frequencies, MAX_CODE_LENGTH);
+        let distance_lengths = complete_code_lengths(distance_frequencies, MAX_CODE_LENGTH);
+
+        // Trailing unused codes don't need to be sent
+        let literal_count = literal_lengths.iter().rposition(|&length| length > 0).map_or(0, |last| last + 1).max(257);
+        let distance_count = distance_lengths.iter().rposition(|&length| length > 0).map_or(0, |last| last + 1).max(1);
+
+        let mut all_lengths = literal_lengths[..literal_count].to_vec();
+        all_lengths.extend_from_slice(&distance_lengths[..distance_count]);
+        let runs = run_length_code(&all_lengths);
+
+        let mut code_length_frequencies = [0u64; CODE_LENGTH_CODES];
+        for &(symbol, _) in &runs {
+            code_length_frequencies[symbol as usize] += 1;
+        }
+        let code_length_lengths = complete_code_lengths(&code_length_frequencies, MAX_CODE_LENGTH_CODE_LENGTH);
+        let code_length_count = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| code_length_lengths[symbol] > 0)
+            .map_or(0, |last| last + 1)
+            .max(4);
+
+        let header_bits = 5 + 5 + 4 + 3 * code_length_count as u64
+            + runs
+                .iter()
+                .map(|&(symbol, _)| code_length_lengths[symbol as usize] as u64 + run_extra_bits(symbol) as u64)
+                .sum::<u64>();
+
+        DynamicCodes {
+            literal_lengths,
+            distance_lengths,
+            literal_count,
+            distance_count,
+            code_length_lengths,
+            code_length_count,
+            runs,
+            header_bits,
+        }
+    }
+
+    fn write_header<W: Write>(&self, writer: &mut LsbBitWriter<W>) -> io::Result<()> {
+        writer.write_bits((self.literal_count - 257) as u64, 5)?;
+        writer.write_bits((self.distance_count - 1) as u64, 5)?;
+        writer.write_bits((self.code_length_count - 4) as u64, 4)?;
+        for &symbol in &CODE_LENGTH_ORDER[..self.code_length_count] {
+            writer.write_bits(self.code_length_lengths[symbol] as u64, 3)?;
+        }
+
+        let codes = canonical_codes(&self.code_length_lengths).expect("code length code lengths are valid");
+        for &(symbol, extra) in &self.runs {
+            let (code, length) = codes[symbol as usize];
+            writer.write_code(code, length as u32)?;
+            writer.write_bits(extra as u64, run_extra_bits(symbol))?;
+        }
+        Ok(())
+    }
+}
+
+// Number of extra bits after a code length symbol: 16 repeats the previous
+// length 3 to 6 times, 17 and 18 stand for 3 to 10 and 11 to 138 zeros
+fn run_extra_bits(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+fn run_length_code(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&other| other == length).count();
+
+        if length == 0 && run >= 11 {
+            let count = run.min(138);
+            runs.push((18, (count - 11) as u8));
+            i += count;
+        } else if length == 0 && run >= 3 {
+            let count = run.min(10);
+            runs.push((17, (count - 3) as u8));
+            i += count;
+        } else if length != 0 && run >= 4 {
+            // Send the length once, then repeat it
+            runs.push((length, 0));
+            let count = (run - 1).min(6);
+            runs.push((16, (count - 3) as u8));
+            i += 1 + count;
+        } else {
+            runs.push((length, 0));
+            i += 1;
+        }
+    }
+    runs
+}
+
+// Decode a DEFLATE stream up to and including its final block. The reader is
+// left right after the last bit of the stream; bytes after it (a gzip trailer,
+// for instance) can be read from it once aligned to a byte.
+pub fn decompress<R: Read, W: Write>(reader: &mut LsbBitReader<R>, writer: &mut W) -> Result<()> {
+    decompress_with_dictionary(reader, writer, &[], WINDOW_SIZE)
+}
+
+// Decode a DEFLATE stream whose matches may reach into a preset dictionary,
+// as if the dictionary had been decoded just before the stream, but no
+// further back than `window_size` bytes (at most WINDOW_SIZE)
+pub fn decompress_with_dictionary<R: Read, W: Write>(
+    reader: &mut LsbBitReader<R>,
+    writer: &mut W,
+    dictionary: &[u8],
+    window_size: usize,
+) -> Result<()> {
+    let dictionary = &dictionary[dictionary.len().saturating_sub(window_size)..];
+    let mut buffer = Vec::with_capacity(4 * WINDOW_SIZE);
+    buffer.extend_from_slice(dictionary);
+    let mut window = OutputWindow { buffer, unwritten_history: dictionary.len(), window_size, writer };
+    let mut fixed_tables: Option<(HuffmanDecodeTable, HuffmanDecodeTable)> = None;
+
+    loop {
+        let last = reader.read_bits(1)? == 1;
+        match reader.read_bits(2)? {
+            BLOCK_STORED => decompress_stored_block(reader, &mut window)?,
+            BLOCK_FIXED => {
+                if fixed_tables.is_none() {
+                    fixed_tables = Some((
+                        HuffmanDecodeTable::from_code_lengths(&fixed_literal_lengths())?,
+                        HuffmanDecodeTable::from_code_lengths(&fixed_distance_lengths())?,
+                    ));
+                }
+                let (literal_table, distance_table) = fixed_tables.as_ref().unwrap();
+                decompress_huffman_block(reader, literal_table, Some(distance_table), &mut window)?;
+            },
+            BLOCK_DYNAMIC => {
+                let (literal_table, distance_table) = read_dynamic_tables(reader)?;
+                decompress_huffman_block(reader, &literal_table, distance_table.as_ref(), &mut window)?;
+            },
+            _ => return Err(Error::CorruptData("invalid DEFLATE block type".to_string())),
+        }
+        if last {
+            break;
+        }
+    }
+
+    window.flush()
+}
+
+fn decompress_stored_block<R: Read, W: Write>(reader: &mut LsbBitReader<R>, window: &mut OutputWindow<W>) -> Result<()> {
+    reader.align_to_byte();
+    let length = reader.read_bits(16)? as usize;
+    let complement = reader.read_bits(16)? as usize;
+    if length != !complement & 0xFFFF {
+        return Err(Error::CorruptData("stored block length doesn't match its complement".to_string()));
+    }
+
+    let start = window.buffer.len();
+    window.buffer.resize(start + length, 0);
+    reader.read_exact(&mut window.buffer[start..])?;
+    window.write_out_history()
+}
+
+// Read the code length code, then the literal/length and distance code lengths
+// it codes. Blocks without matches may leave out the distance codes.
+fn read_dynamic_tables<R: Read>(reader: &mut LsbBitReader<R>) -> Result<(HuffmanDecodeTable, Option<HuffmanDecodeTable>)> {
+    let literal_count = reader.read_bits(5)? as usize + 257;
+    let distance_count = reader.read_bits(5)? as usize + 1;
+    let code_length_count = reader.read_bits(4)? as usize + 4;
+    if literal_count > LITERAL_LENGTH_CODES || distance_count > DISTANCE_CODES {
+        return Err(Error::CorruptData("too many codes in dynamic block".to_string()));
+    }
+
+    let mut code_length_lengths = [0u8; CODE_LENGTH_CODES];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_length_lengths[symbol] = reader.read_bits(3)? as u8;
+    }
+    let code_length_table = HuffmanDecodeTable::from_code_lengths(&code_length_lengths)?;
+
+    let total = literal_count + distance_count;
+    let mut lengths: Vec<u8> = Vec::with_capacity(total);
+    let mut remaining = u64::MAX;
+    while lengths.len() < total {
+        let symbol = code_length_table.decode_symbol(reader, &mut remaining)?;
+        let (value, count) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| Error::CorruptData("repeated code length without a previous length".to_string()))?;
+                (previous, 3 + reader.read_bits(2)? as usize)
+            },
+            17 => (0, 3 + reader.read_bits(3)? as usize),
+            _ => (0, 11 + reader.read_bits(7)? as usize),
+        };
+        if lengths.len() + count > total {
+            return Err(Error::CorruptData("code lengths overrun the alphabet".to_string()));
+        }
+        lengths.resize(lengths.len() + count, value);
+    }
+
+    let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+    if literal_lengths[END_OF_BLOCK] == 0 {
+        return Err(Error::CorruptData("dynamic block has no end-of-block code".to_string()));
+    }
+    let literal_table = HuffmanDecodeTable::from_code_lengths(literal_lengths)?;
+    let distance_table = if distance_lengths.iter().all(|&length| length == 0) {
+        None
+    } else {
+        Some(HuffmanDecodeTable::from_code_lengths(distance_lengths)?)
+    };
+    Ok((literal_table, distance_table))
+}
+
+fn decompress_huffman_block<R: Read, W: Write>(
+    reader: &mut LsbBitReader<R>,
+    literal_table: &HuffmanDecodeTable,
+    distance_table: Option<&HuffmanDecodeTable>,
+    window: &mut OutputWindow<W>,
+) -> Result<()> {
+    // DEFLATE doesn't record how long a block is; the end-of-block code ends it
+    let mut remaining = u64::MAX;
+    loop {
+        let symbol = literal_table.decode_symbol(reader, &mut remaining)? as usize;
+        if symbol == END_OF_BLOCK {
+            return window.write_out_history();
+        }
+        if symbol < END_OF_BLOCK {
+            window.buffer.push(symbol as u8);
+        } else {
+            let length_code = symbol - 257;
+            if length_code >= LENGTH_BASE.len() {
+                return Err(Error::CorruptData(format!("invalid length code {}", symbol)));
+            }
+            let length = LENGTH_BASE[length_code] as usize + reader.read_bits(LENGTH_EXTRA_BITS[length_code] as u32)? as usize;
+
+            let distance_table =
+                distance_table.ok_or_else(|| Error::CorruptData("match in a block without distance codes".to_string()))?;
+            let distance_code = distance_table.decode_symbol(reader, &mut remaining)? as usize;
+            if distance_code >= DISTANCE_CODES {
+                return Err(Error::CorruptData(format!("invalid distance code {}", distance_code)));
+            }
+            let distance =
+                DISTANCE_BASE[distance_code] as usize + reader.read_bits(DISTANCE_EXTRA_BITS[distance_code] as u32)? as usize;
+            window.copy_match(distance, length)?;
+        }
+
+        // Long blocks are written out as they go, whatever they are made of
+        if window.buffer.len() >= 4 * WINDOW_SIZE {
+            window.write_out_history()?;
+        }
+    }
+}
+
+// Decoded output that hasn't been written yet. At least the last WINDOW_SIZE
+// bytes are kept around for matches to copy from.
+struct OutputWindow<'a, W: Write> {
+    buffer: Vec<u8>,
+    // Leading bytes of the buffer that are only history, not output: the
+    // preset dictionary
+    unwritten_history: usize,
+    // Farthest back a match may reach, which a zlib header may set lower
+    window_size: usize,
+    writer: &'a mut W,
+}
+
+impl<W: Write> OutputWindow<'_, W> {
+    fn copy_match(&mut self, distance: usize, length: usize) -> Result<()> {
+        if distance > self.window_size {
+            return Err(Error::CorruptData(format!("match distance {} is beyond the {}-byte window", distance, self.window_size)));
+        }
+        if distance > self.buffer.len() {
+            return Err(Error::CorruptData(format!("match distance {} reaches before the start of the data", distance)));
+        }
+        let start = self.buffer.len() - distance;
+        if distance >= length {
+            self.buffer.extend_from_within(start..start + length);
+        } else {
+            // The match overlaps the bytes it produces
+            for i in start..start + length {
+                self.buffer.push(self.buffer[i]);
+            }
+        }
+        Ok(())
+    }
+
+    // Write out everything but the window once enough has piled up
+    fn write_out_history(&mut self) -> Result<()> {
+        if self.buffer.len() >= 3 * WINDOW_SIZE {
+            let keep_from = self.buffer.len() - WINDOW_SIZE;
+            if keep_from > self.unwritten_history {
+                self.writer.write_all(&self.buffer[self.unwritten_history..keep_from])?;
+            }
+            self.unwritten_history = self.unwritten_history.saturating_sub(keep_from);
+            self.buffer.drain(..keep_from);
+        }
+        Ok(())
+    }
+
+    fn flush(self) -> Result<()> {
+        self.writer.write_all(&self.buffer[self.unwritten_history..])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn inflate(compressed: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        decompress(&mut LsbBitReader::new(Cursor::new(compressed)), &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_code_tables() {
+        assert_eq!(length_code(3), 0);
+        assert_eq!(length_code(10), 7);
+        assert_eq!(length_code(11), 8);
+        assert_eq!(length_code(257), 27);
+        assert_eq!(length_code(258), 28);
+        assert_eq!(distance_code(1), 0);
+        assert_eq!(distance_code(6), 4);
+        assert_eq!(distance_code(32768), 29);
+    }
+
+    #[test]
+    fn test_run_length_code() {
+        let lengths = [8, 8, 8, 8, 8, 8, 8, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0];
+        let runs = run_length_code(&lengths);
+        assert_eq!(runs, vec![(8, 0), (16, 3), (8, 0), (18, 1), (5, 0), (0, 0), (0, 0)]);
+    }
+
+    // Every block type round-trips, with and without history
+    #[test]
+    fn test_block_types_round_trip() {
+        let text = b"It was the best of times, it was the worst of times. ".repeat(50);
+        let mut noise = Vec::new();
+        let mut state = 5u64;
+        for _ in 0..100_000 {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            noise.push((state >> 56) as u8);
+        }
+
+        for data in [&b"a"[..], b"abc", &text, &noise] {
+            let compressed = compress_chunk(data, 0, true, true).unwrap();
+            assert_eq!(inflate(&compressed).unwrap(), data);
+        }
+
+        // Random bytes come out as stored blocks, five bytes of header per block
+        let compressed = compress_chunk(&noise, 0, true, true).unwrap();
+        assert!(compressed.len() <= noise.len() + 5 * noise.len().div_ceil(BLOCK_TOKENS));
+
+        // Fixed blocks for short inputs: "abc" with fixed codes plus the header
+        let mut writer = LsbBitWriter::new(Vec::new());
+        write_block(b"abc", &[Token::Literal(b'a'), Token::Literal(b'b'), Token::Literal(b'c')], true, &mut writer).unwrap();
+        assert_eq!(writer.finish().unwrap(), vec![0x4B, 0x4C, 0x4A, 0x06, 0x00]);
+    }
+
+    // A block of nothing but literals is written out while it is decoded,
+    // not held in memory until its end
+    #[test]
+    fn test_literal_only_block_streams() {
+        struct LargestWrite(Vec<u8>, usize);
+
+        impl Write for LargestWrite {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.extend_from_slice(buf);
+                self.1 = self.1.max(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut state = 9u64;
+        let data: Vec<u8> = (0..300_000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                b'a' + (state >> 62) as u8
+            })
+            .collect();
+        let tokens: Vec<Token> = data.iter().map(|&byte| Token::Literal(byte)).collect();
+        let mut writer = LsbBitWriter::new(Vec::new());
+        write_block(&data, &tokens, true, &mut writer).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        let mut output = LargestWrite(Vec::new(), 0);
+        decompress(&mut LsbBitReader::new(Cursor::new(compressed)), &mut output).unwrap();
+        assert_eq!(output.0, data);
+        assert!(output.1 < 4 * WINDOW_SIZE, "{} bytes in one write", output.1);
+    }
+
+    // Chunks end on a byte boundary and can be concatenated
+    #[test]
+    fn test_chunks_concatenate() {
+        let data = b"one chunk after another, one chunk after another ".repeat(100);
+        let middle = data.len() / 2;
+        let mut compressed = compress_chunk(&data[..middle], 0, false, true).unwrap();
+        compressed.extend(compress_chunk(&data, middle, true, true).unwrap());
+        assert_eq!(inflate(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_empty_streams() {
+        assert_eq!(inflate(&EMPTY_FINAL_BLOCK).unwrap(), b"");
+        assert_eq!(inflate(&compress_chunk(&[], 0, true, true).unwrap()).unwrap(), b"");
+    }
+}
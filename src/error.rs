@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io;
+
+// Everything that can go wrong while compressing or decompressing
+#[derive(Debug)]
+pub enum Error {
+    // Reading the input or writing the output failed
+    Io(io::Error),
+    // The input is not a compressed file, or its header is malformed
+    InvalidHeader(String),
+    // The file was written by a newer version of the tool
+    UnsupportedVersion(u8),
+    // The header sets flags this version doesn't know about
+    UnsupportedFlags(u16),
+    // The input ended before the decoder was done with it
+    Truncated,
+    // The payload doesn't decode into valid data
+    CorruptData(String),
+    // The decoded data doesn't match the checksum stored in the trailer
+    ChecksumMismatch { expected: u64, actual: u64 },
+    // The input to compress isn't the length it was said to be. Longer input
+    // is noticed one byte past the length given, so `actual` is at most that.
+    LengthMismatch { expected: u64, actual: u64 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // Process exit code the command line tools report for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 2,
+            Error::InvalidHeader(_) => 3,
+            Error::UnsupportedVersion(_) => 4,
+            Error::UnsupportedFlags(_) => 5,
+            Error::Truncated => 6,
+            Error::CorruptData(_) => 7,
+            Error::ChecksumMismatch { .. } => 8,
+            Error::LengthMismatch { .. } => 9,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::InvalidHeader(message) => write!(f, "invalid header: {}", message),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            Error::UnsupportedFlags(flags) => write!(f, "unsupported header flags {:#06x}", flags),
+            Error::Truncated => write!(f, "unexpected end of input, the file is truncated"),
+            Error::CorruptData(message) => write!(f, "corrupt data: {}", message),
+            Error::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum mismatch: expected {:#x}, got {:#x}", expected, actual)
+            },
+            Error::LengthMismatch { expected, actual } if actual > expected => {
+                write!(f, "input is longer than the {} bytes given", expected)
+            },
+            Error::LengthMismatch { expected, actual } => write!(f, "input is {} bytes long, not the {} given", actual, expected),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        // Running out of input half-way through a read means the file was cut short
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Truncated
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unexpected_eof_maps_to_truncated() {
+        let err: Error = io::Error::new(io::ErrorKind::UnexpectedEof, "eof").into();
+        assert!(matches!(err, Error::Truncated));
+
+        let err: Error = io::Error::new(io::ErrorKind::PermissionDenied, "denied").into();
+        assert!(matches!(err, Error::Io(_)));
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct_and_non_zero() {
+        let errors = [
+            Error::Io(io::Error::other("io")),
+            Error::InvalidHeader(String::new()),
+            Error::UnsupportedVersion(2),
+            Error::UnsupportedFlags(0x8000),
+            Error::Truncated,
+            Error::CorruptData(String::new()),
+            Error::ChecksumMismatch { expected: 0, actual: 1 },
+            Error::LengthMismatch { expected: 0, actual: 1 },
+        ];
+
+        let mut codes: Vec<i32> = errors.iter().map(|err| err.exit_code()).collect();
+        assert!(codes.iter().all(|&code| code != 0));
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+}
@@ -0,0 +1,457 @@
+use std::io::{self, Read, Write};
+use crate::checksum::ChecksumKind;
+use crate::error::{Error, Result};
+use crate::rle;
+
+// Magic bytes at the start of every compressed file ("\xCCZIP").
+// The first byte can never start a legacy file, whose first four bytes are a
+// little-endian symbol count of at most 256.
+pub const MAGIC: [u8; 4] = [0xCC, b'Z', b'I', b'P'];
+
+// Current version of the container format. Version 1 holds a single Huffman
+// table for the whole file; version 2 splits the data into blocks.
+pub const FORMAT_VERSION: u8 = 2;
+
+// Size of the fixed part of the header: magic, version, flags and header length
+pub const FIXED_HEADER_LENGTH: u16 = 9;
+
+// Size of the version 2 header, which adds the block size
+pub const BLOCK_HEADER_LENGTH: u16 = FIXED_HEADER_LENGTH + 4;
+
+// Block types. Every block starts with one of these; the stream ends with an
+// end-of-stream marker followed by the trailer.
+pub const BLOCK_END_OF_STREAM: u8 = 0;
+pub const BLOCK_HUFFMAN: u8 = 1;
+// LZ77 literals, lengths and distances, Huffman coded
+pub const BLOCK_LZ77: u8 = 2;
+// Bytes range coded with a stored frequency table
+pub const BLOCK_RANGE: u8 = 3;
+// Bytes coded with FSE (tANS) and a stored table of normalized counts
+pub const BLOCK_FSE: u8 = 4;
+// Huffman codes chosen by the previous byte (order-1 context modelling)
+pub const BLOCK_ORDER1: u8 = 5;
+// Burrows-Wheeler transformed, move-to-front and zero-run coded, then Huffman
+// coded with several tables (bzip2 style)
+pub const BLOCK_BWT: u8 = 6;
+// LZW codes as in compress(1), without the .Z header
+pub const BLOCK_LZW: u8 = 7;
+// One byte repeated, stored as the byte alone. Never run-length encoded.
+pub const BLOCK_REPEAT: u8 = 8;
+
+// Largest block size a file may declare
+pub const MAX_BLOCK_SIZE: u32 = 64 * 1024 * 1024;
+
+// The trailer holds a CRC-32 of the original data
+pub const FLAG_CHECKSUM_CRC32: u16 = 0x0001;
+// The trailer holds a CRC-64 of the original data
+pub const FLAG_CHECKSUM_CRC64: u16 = 0x0002;
+
+// The body is a single adaptive Huffman stream instead of a sequence of blocks
+pub const FLAG_ADAPTIVE_HUFFMAN: u16 = 0x0004;
+
+// Every block holds its bytes run-length encoded, to be expanded after decoding
+pub const FLAG_RUN_LENGTH: u16 = 0x0008;
+
+// The header ends with the length of the original data, which the decoded
+// data must match exactly
+pub const FLAG_ORIGINAL_LENGTH: u16 = 0x0010;
+
+// Flags understood by this version of the decoder
+pub const KNOWN_FLAGS: u16 =
+    FLAG_CHECKSUM_CRC32 | FLAG_CHECKSUM_CRC64 | FLAG_ADAPTIVE_HUFFMAN | FLAG_RUN_LENGTH | FLAG_ORIGINAL_LENGTH;
+
+// Bytes in the longest varint, which holds 64 bits 7 at a time
+const MAX_VARINT_LENGTH: usize = 10;
+
+// Symbols of the adaptive Huffman stream: the 256 byte values and a marker
+// for the end of the stream
+pub const ADAPTIVE_ALPHABET_SIZE: usize = 257;
+pub const ADAPTIVE_END_OF_STREAM: u16 = 256;
+
+// Stream formats the tools read and write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    // Our own container: header, blocks and checksum trailer
+    #[default]
+    Cczip,
+    // A raw DEFLATE stream (RFC 1951) without any header or checksum
+    Deflate,
+    // A gzip file (RFC 1952): DEFLATE with a header and a CRC-32 trailer
+    Gzip,
+    // A zlib stream (RFC 1950): DEFLATE with a small header and an Adler-32
+    Zlib,
+    // A bzip2 file: Burrows-Wheeler transformed blocks with CRC-32s
+    Bzip2,
+    // A .Z file as written by compress(1): LZW codes behind a 3-byte header
+    Compress,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cczip" => Some(Format::Cczip),
+            "deflate" => Some(Format::Deflate),
+            "gzip" => Some(Format::Gzip),
+            "zlib" => Some(Format::Zlib),
+            "bzip2" => Some(Format::Bzip2),
+            "compress" => Some(Format::Compress),
+            _ => None,
+        }
+    }
+
+    // File name extension for files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Cczip => "compressed",
+            Format::Deflate => "deflate",
+            Format::Gzip => "gz",
+            Format::Zlib => "zz",
+            Format::Bzip2 => "bz2",
+            Format::Compress => "Z",
+        }
+    }
+}
+
+// Entropy coders for the bytes of a block. Each block records the one it was
+// written with in its type, so files can mix them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EntropyCoder {
+    // Canonical Huffman codes, stored as code lengths
+    #[default]
+    Huffman,
+    // A range coder, stored as scaled frequencies. Closer to the entropy than
+    // Huffman on skewed data, but slower to decode.
+    Range,
+    // A table-based ANS coder (FSE), stored as normalized counts. Close to the
+    // range coder on size and to Huffman on speed.
+    Fse,
+    // Huffman codes with a table per previous byte, for data where each byte
+    // says a lot about the next, like text
+    Order1,
+    // Whichever of the above comes out smallest, block by block
+    Auto,
+}
+
+impl EntropyCoder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "huffman" => Some(EntropyCoder::Huffman),
+            "range" => Some(EntropyCoder::Range),
+            "fse" => Some(EntropyCoder::Fse),
+            "order1" => Some(EntropyCoder::Order1),
+            "auto" => Some(EntropyCoder::Auto),
+            _ => None,
+        }
+    }
+}
+
+// Whether blocks are run-length encoded before they are coded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RunLength {
+    Off,
+    On,
+    // On if it would shrink the first blocks of the input by at least a quarter
+    #[default]
+    Auto,
+}
+
+impl RunLength {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(RunLength::Off),
+            "on" => Some(RunLength::On),
+            "auto" => Some(RunLength::Auto),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u16,
+    // Total length of the header in bytes, including the fixed part.
+    // Decoders skip any bytes past the fields they know about.
+    pub header_length: u16,
+    // Largest number of original bytes in a block (0 for version 1 files)
+    pub block_size: u32,
+    // Length of the original data, a little-endian base-128 varint after the
+    // block size. Only there when the compressor knew it before starting.
+    pub original_length: Option<u64>,
+}
+
+impl Header {
+    pub fn new(flags: u16, block_size: u32) -> Self {
+        Header {
+            version: FORMAT_VERSION,
+            flags,
+            header_length: BLOCK_HEADER_LENGTH,
+            block_size,
+            original_length: None,
+        }
+    }
+
+    // Record the length of the original data
+    pub fn with_original_length(mut self, original_length: u64) -> Self {
+        self.flags |= FLAG_ORIGINAL_LENGTH;
+        self.original_length = Some(original_length);
+        self.header_length = BLOCK_HEADER_LENGTH + encode_varint(original_length).len() as u16;
+        self
+    }
+
+    // Most bytes a block can hold: the block size, plus whatever run-length
+    // encoding may have added to it
+    pub fn max_block_length(&self) -> u32 {
+        if self.flags & FLAG_RUN_LENGTH != 0 {
+            rle::max_encoded_length(self.block_size as usize) as u32
+        } else {
+            self.block_size
+        }
+    }
+
+    // Which checksum, if any, the trailer holds
+    pub fn checksum_kind(&self) -> Option<ChecksumKind> {
+        if self.flags & FLAG_CHECKSUM_CRC64 != 0 {
+            Some(ChecksumKind::Crc64)
+        } else if self.flags & FLAG_CHECKSUM_CRC32 != 0 {
+            Some(ChecksumKind::Crc32)
+        } else {
+            None
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[self.version])?;
+        writer.write_all(&self.flags.to_le_bytes())?;
+        writer.write_all(&self.header_length.to_le_bytes())?;
+        if self.version >= 2 {
+            writer.write_all(&self.block_size.to_le_bytes())?;
+            if let Some(original_length) = self.original_length {
+                writer.write_all(&encode_varint(original_length))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        // Anything that doesn't start with the magic bytes, including input
+        // too short to hold them, is not a compressed file
+        let mut fixed = [0u8; FIXED_HEADER_LENGTH as usize];
+        let mut filled = 0;
+        while filled < MAGIC.len() {
+            match reader.read(&mut fixed[filled..MAGIC.len()])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled < MAGIC.len() || fixed[0..4] != MAGIC {
+            return Err(Error::InvalidHeader("not a compressed file (bad magic bytes)".to_string()));
+        }
+        reader.read_exact(&mut fixed[4..])?;
+
+        let mut header = Header {
+            version: fixed[4],
+            flags: u16::from_le_bytes([fixed[5], fixed[6]]),
+            header_length: u16::from_le_bytes([fixed[7], fixed[8]]),
+            block_size: 0,
+            original_length: None,
+        };
+
+        if header.version == 0 || header.version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+        if header.flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::UnsupportedFlags(header.flags));
+        }
+        if header.flags & FLAG_CHECKSUM_CRC32 != 0 && header.flags & FLAG_CHECKSUM_CRC64 != 0 {
+            return Err(Error::InvalidHeader("conflicting checksum flags".to_string()));
+        }
+        let mut known_length = if header.version >= 2 { BLOCK_HEADER_LENGTH } else { FIXED_HEADER_LENGTH };
+        if header.header_length < known_length {
+            return Err(Error::InvalidHeader(format!("invalid header length {}", header.header_length)));
+        }
+
+        if header.version >= 2 {
+            let mut block_size = [0u8; 4];
+            reader.read_exact(&mut block_size)?;
+            header.block_size = u32::from_le_bytes(block_size);
+            if header.block_size == 0 || header.block_size > MAX_BLOCK_SIZE {
+                return Err(Error::InvalidHeader(format!("invalid block size {}", header.block_size)));
+            }
+            if header.flags & FLAG_ORIGINAL_LENGTH != 0 {
+                let (original_length, length) = read_varint(reader)?;
+                header.original_length = Some(original_length);
+                known_length += length as u16;
+                if header.header_length < known_length {
+                    return Err(Error::InvalidHeader(format!("invalid header length {}", header.header_length)));
+                }
+            }
+        }
+
+        // Skip header fields added by newer revisions that we don't know about
+        let extra = (header.header_length - known_length) as u64;
+        let skipped = io::copy(&mut reader.by_ref().take(extra), &mut io::sink())?;
+        if skipped != extra {
+            return Err(Error::Truncated);
+        }
+
+        Ok(header)
+    }
+}
+
+// Little-endian base-128: seven bits a byte, the top bit set on every byte but the last
+fn encode_varint(mut value: u64) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(MAX_VARINT_LENGTH);
+    while value >= 0x80 {
+        encoded.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    encoded.push(value as u8);
+    encoded
+}
+
+// Read a varint and the number of bytes it took, refusing any that don't fit
+// in 64 bits or that carry needless zero bytes at the end
+fn read_varint<R: Read>(reader: &mut R) -> Result<(u64, usize)> {
+    let mut value = 0u64;
+    for length in 1..=MAX_VARINT_LENGTH {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        let bits = (byte[0] & 0x7F) as u64;
+        let shift = 7 * (length - 1);
+        if (shift > 0 && byte[0] == 0) || (shift == 63 && bits > 1) {
+            return Err(Error::InvalidHeader("invalid original length".to_string()));
+        }
+        value |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok((value, length));
+        }
+    }
+    Err(Error::InvalidHeader("invalid original length".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = Header::new(0, 1024);
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), BLOCK_HEADER_LENGTH as usize);
+        assert_eq!(&bytes[0..4], &MAGIC);
+
+        let read_back = Header::read_from(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(read_back, header);
+    }
+
+    #[test]
+    fn test_checksum_flags() {
+        assert_eq!(Header::new(0, 1024).checksum_kind(), None);
+        assert_eq!(Header::new(FLAG_CHECKSUM_CRC32, 1024).checksum_kind(), Some(ChecksumKind::Crc32));
+        assert_eq!(Header::new(FLAG_CHECKSUM_CRC64, 1024).checksum_kind(), Some(ChecksumKind::Crc64));
+
+        let mut bytes = Vec::new();
+        Header::new(FLAG_CHECKSUM_CRC32 | FLAG_CHECKSUM_CRC64, 1024).write_to(&mut bytes).unwrap();
+        assert!(Header::read_from(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_run_length_blocks_may_outgrow_the_block_size() {
+        assert_eq!(Header::new(FLAG_CHECKSUM_CRC32, 1024).max_block_length(), 1024);
+        assert_eq!(Header::new(FLAG_CHECKSUM_CRC32 | FLAG_RUN_LENGTH, 1024).max_block_length(), 1029);
+    }
+
+    #[test]
+    fn test_original_length_round_trip() {
+        for original_length in [0, 1, 127, 128, 300, 1 << 40, u64::MAX] {
+            let header = Header::new(FLAG_CHECKSUM_CRC32, 1024).with_original_length(original_length);
+            let mut bytes = Vec::new();
+            header.write_to(&mut bytes).unwrap();
+            assert_eq!(bytes.len(), header.header_length as usize);
+            assert_eq!(Header::read_from(&mut Cursor::new(bytes)).unwrap(), header);
+        }
+        assert_eq!(encode_varint(300), [0xAC, 0x02]);
+        assert_eq!(encode_varint(u64::MAX).len(), MAX_VARINT_LENGTH);
+    }
+
+    #[test]
+    fn test_invalid_original_lengths_are_rejected() {
+        let mut fixed = Vec::new();
+        Header::new(FLAG_ORIGINAL_LENGTH, 1024).write_to(&mut fixed).unwrap();
+        // Too long for 64 bits, a needless zero byte, and a header length too
+        // short for the varint
+        let invalid: [&[u8]; 3] = [&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02], &[0x85, 0x00], &[0x85, 0x01]];
+        for varint in invalid {
+            let mut bytes = fixed.clone();
+            bytes.extend_from_slice(varint);
+            let result = Header::read_from(&mut Cursor::new(bytes));
+            assert!(matches!(result, Err(Error::InvalidHeader(_))), "{:?}", varint);
+        }
+        // Cut short inside the varint
+        let mut bytes = fixed.clone();
+        bytes.push(0x80);
+        assert!(matches!(Header::read_from(&mut Cursor::new(bytes)), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn test_bad_magic_is_rejected() {
+        let bytes = b"hello, world".to_vec();
+        let err = Header::read_from(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(matches!(err, Error::InvalidHeader(_)));
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let mut bytes = Vec::new();
+        Header::new(0, 1024).write_to(&mut bytes).unwrap();
+        bytes[4] = FORMAT_VERSION + 1;
+        let err = Header::read_from(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedVersion(_)));
+    }
+
+    #[test]
+    fn test_version_1_header_has_no_block_size() {
+        let bytes = vec![0xCC, b'Z', b'I', b'P', 1, 0x01, 0x00, 9, 0, 42];
+        let mut cursor = Cursor::new(bytes);
+        let header = Header::read_from(&mut cursor).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.block_size, 0);
+        assert_eq!(header.checksum_kind(), Some(ChecksumKind::Crc32));
+        assert_eq!(cursor.position(), 9);
+    }
+
+    #[test]
+    fn test_invalid_block_size_is_rejected() {
+        let mut bytes = Vec::new();
+        Header::new(0, 0).write_to(&mut bytes).unwrap();
+        assert!(matches!(Header::read_from(&mut Cursor::new(bytes)), Err(Error::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let mut bytes = Vec::new();
+        Header::new(0, 1024).write_to(&mut bytes).unwrap();
+        bytes.truncate(6);
+        let err = Header::read_from(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(matches!(err, Error::Truncated));
+    }
+
+    #[test]
+    fn test_unknown_header_fields_are_skipped() {
+        let mut bytes = Vec::new();
+        let mut header = Header::new(0, 1024);
+        header.header_length = BLOCK_HEADER_LENGTH + 3;
+        header.write_to(&mut bytes).unwrap();
+        bytes.extend_from_slice(&[1, 2, 3, 42]);
+
+        let mut cursor = Cursor::new(bytes);
+        Header::read_from(&mut cursor).unwrap();
+        let mut rest = Vec::new();
+        cursor.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![42]);
+    }
+}
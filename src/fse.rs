@@ -0,0 +1,353 @@
+// Finite State Entropy: a table-based asymmetric numeral system (tANS) coder
+// in the style of FSE. Symbol counts are scaled to the size of a state table;
+// each step of the coder is a table lookup and a handful of raw bits, so it
+// decodes about as fast as Huffman codes while spending fractional bits per
+// symbol like an arithmetic coder.
+//
+// ANS works last in, first out: the encoder walks the symbols backwards and
+// the decoder reads them forwards. The encoder starts in state 0 and writes
+// its final state first, so a decoder that consumed the stream correctly ends
+// up back in state 0.
+
+use std::io::{Read, Write};
+use crate::bitio::{BitReader, BitWriter};
+use crate::error::{Error, Result};
+use crate::range_coder::{cumulative_frequencies, scale_frequencies};
+
+// Limits on log2 of the state table size
+pub const MIN_TABLE_LOG: u8 = 5;
+pub const MAX_TABLE_LOG: u8 = 12;
+
+// Table size used when the block is big enough to make use of it
+const DEFAULT_TABLE_LOG: u8 = 11;
+
+// Pick a state table size for the given symbol counts: the default, smaller
+// for short blocks, but always with room for every symbol twice over
+pub fn table_log(frequencies: &[u64]) -> u8 {
+    let total: u64 = frequencies.iter().sum();
+    let used = frequencies.iter().filter(|&&count| count > 0).count();
+    let fits_block = (u64::BITS - total.saturating_sub(1).leading_zeros()) as u8;
+    let needed = (usize::BITS - used.leading_zeros()) as u8 + 1;
+    DEFAULT_TABLE_LOG.min(fits_block).max(needed).clamp(MIN_TABLE_LOG, MAX_TABLE_LOG)
+}
+
+// Scale symbol counts to add up to the state table size
+pub fn normalize_counts(frequencies: &[u64], table_log: u8) -> Vec<u32> {
+    scale_frequencies(frequencies, 1 << table_log)
+}
+
+// Write normalized counts. Each count is no larger than what is left of the
+// table, so it takes only as many bits as that remainder needs. A zero count
+// is followed by an 8-bit number of further zero counts. Counts after the
+// table is used up are zero and left out.
+pub fn write_counts<W: Write>(counts: &[u32], table_log: u8, bit_writer: &mut BitWriter<W>) -> Result<()> {
+    let mut remaining = 1u32 << table_log;
+    let mut i = 0;
+    while remaining > 0 {
+        let count = counts[i];
+        bit_writer.write_bits(count as u64, bit_length(remaining))?;
+        remaining -= count;
+        i += 1;
+        if count == 0 {
+            let run = counts[i..].iter().take(255).take_while(|&&count| count == 0).count();
+            bit_writer.write_bits(run as u64, 8)?;
+            i += run;
+        }
+    }
+    Ok(())
+}
+
+// Read counts written by write_counts for an alphabet of `alphabet_size`
+// symbols
+pub fn read_counts<R: Read>(bit_reader: &mut BitReader<R>, table_log: u8, alphabet_size: usize) -> Result<Vec<u32>> {
+    let mut counts = Vec::with_capacity(alphabet_size);
+    let mut remaining = 1u32 << table_log;
+    while remaining > 0 {
+        if counts.len() == alphabet_size {
+            return Err(Error::InvalidHeader("normalized counts overrun the alphabet".to_string()));
+        }
+        let count = bit_reader.read_bits(bit_length(remaining))? as u32;
+        if count > remaining {
+            return Err(Error::InvalidHeader("normalized counts add up to more than the table size".to_string()));
+        }
+        remaining -= count;
+        counts.push(count);
+        if count == 0 {
+            let run = bit_reader.read_bits(8)? as usize;
+            if counts.len() + run > alphabet_size {
+                return Err(Error::InvalidHeader("normalized counts overrun the alphabet".to_string()));
+            }
+            counts.resize(counts.len() + run, 0);
+        }
+    }
+    counts.resize(alphabet_size, 0);
+    Ok(counts)
+}
+
+// Check a table log read from a file
+pub fn check_table_log(table_log: u8) -> Result<()> {
+    if !(MIN_TABLE_LOG..=MAX_TABLE_LOG).contains(&table_log) {
+        return Err(Error::InvalidHeader(format!("invalid FSE table log {}", table_log)));
+    }
+    Ok(())
+}
+
+// Number of bits needed to write `value`
+fn bit_length(value: u32) -> u32 {
+    u32::BITS - value.leading_zeros()
+}
+
+// Lay out the symbols over the state table. Stepping by a bit over half the
+// table scatters each symbol's states, so every symbol has states across the
+// whole range.
+fn spread_symbols(counts: &[u32], table_log: u8) -> Vec<u16> {
+    let size = 1usize << table_log;
+    let step = (size >> 1) + (size >> 3) + 3;
+    let mut spread = vec![0u16; size];
+    let mut position = 0;
+    for (symbol, &count) in counts.iter().enumerate() {
+        for _ in 0..count {
+            spread[position] = symbol as u16;
+            position = (position + step) & (size - 1);
+        }
+    }
+    debug_assert_eq!(position, 0);
+    spread
+}
+
+// How to move the encoder from one state to the next for a symbol
+#[derive(Clone, Copy)]
+struct SymbolTransform {
+    // Adding this to the state and shifting right by 16 gives the number of
+    // bits to write out
+    delta_bits: u32,
+    // Offset from the shifted state to the symbol's entries in the state list
+    delta_state: i32,
+}
+
+pub struct FseEncoder {
+    table_log: u8,
+    // Every symbol's states, symbol by symbol, in table order
+    states: Vec<u16>,
+    transforms: Vec<SymbolTransform>,
+}
+
+impl FseEncoder {
+    // Build the encoder for counts that add up to 2^table_log
+    pub fn new(counts: &[u32], table_log: u8) -> Self {
+        let size = 1u32 << table_log;
+        let spread = spread_symbols(counts, table_log);
+
+        let mut next = cumulative_frequencies(counts);
+        let mut states = vec![0u16; size as usize];
+        for (position, &symbol) in spread.iter().enumerate() {
+            states[next[symbol as usize] as usize] = (size as usize + position) as u16;
+            next[symbol as usize] += 1;
+        }
+
+        let mut transforms = Vec::with_capacity(counts.len());
+        let mut start = 0i32;
+        for &count in counts {
+            let transform = match count {
+                // Never encoded; any value will do
+                0 => SymbolTransform { delta_bits: 0, delta_state: 0 },
+                1 => SymbolTransform {
+                    delta_bits: ((table_log as u32) << 16) - size,
+                    delta_state: start - 1,
+                },
+                _ => {
+                    let max_bits = table_log as u32 - (31 - (count - 1).leading_zeros());
+                    SymbolTransform {
+                        delta_bits: (max_bits << 16) - (count << max_bits),
+                        delta_state: start - count as i32,
+                    }
+                },
+            };
+            transforms.push(transform);
+            start += count as i32;
+        }
+
+        FseEncoder { table_log, states, transforms }
+    }
+
+    // Encode `symbols` and return the payload with its length in bits
+    pub fn encode<T: Copy + Into<usize>>(&self, symbols: &[T]) -> Result<(Vec<u8>, u64)> {
+        let size = 1u32 << self.table_log;
+
+        // Walk the symbols backwards, keeping the bits each step writes so
+        // they can go out in the order the decoder reads them
+        let mut state = size;
+        let mut steps: Vec<u32> = Vec::with_capacity(symbols.len());
+        for &symbol in symbols.iter().rev() {
+            let transform = self.transforms[symbol.into()];
+            let bits = (state + transform.delta_bits) >> 16;
+            steps.push((state & ((1 << bits) - 1)) << 8 | bits);
+            state = self.states[((state >> bits) as i32 + transform.delta_state) as usize] as u32;
+        }
+
+        let mut bit_writer = BitWriter::new(Vec::new());
+        bit_writer.write_bits((state - size) as u64, self.table_log as u32)?;
+        for &step in steps.iter().rev() {
+            bit_writer.write_bits((step >> 8) as u64, step & 0xFF)?;
+        }
+        let payload_bits = bit_writer.bits_written();
+        Ok((bit_writer.finish()?, payload_bits))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct DecodeEntry {
+    symbol: u16,
+    bits: u8,
+    // The next state, before adding the bits read
+    base: u16,
+}
+
+pub struct FseDecoder {
+    table_log: u8,
+    table: Vec<DecodeEntry>,
+}
+
+impl FseDecoder {
+    // Build the decoder for counts that add up to 2^table_log
+    pub fn new(counts: &[u32], table_log: u8) -> Self {
+        let size = 1u32 << table_log;
+        let spread = spread_symbols(counts, table_log);
+
+        let mut next = counts.to_vec();
+        let table = spread
+            .iter()
+            .map(|&symbol| {
+                let rank = next[symbol as usize];
+                next[symbol as usize] += 1;
+                let bits = table_log as u32 - (31 - rank.leading_zeros());
+                DecodeEntry { symbol, bits: bits as u8, base: ((rank << bits) - size) as u16 }
+            })
+            .collect();
+
+        FseDecoder { table_log, table }
+    }
+
+    // Decode `length` symbols from a payload of `payload_bits` bits, passing
+    // each one to `emit`
+    pub fn decode(&self, payload: &[u8], payload_bits: u64, length: usize, mut emit: impl FnMut(u16)) -> Result<()> {
+        let ends_early = || Error::CorruptData("payload ends in the middle of a code".to_string());
+        let mut bit_reader = BitReader::new(payload);
+        let mut remaining = payload_bits.checked_sub(self.table_log as u64).ok_or_else(ends_early)?;
+        let mut state = bit_reader.read_bits(self.table_log as u32)? as usize;
+
+        for _ in 0..length {
+            let entry = self.table[state];
+            emit(entry.symbol);
+            remaining = remaining.checked_sub(entry.bits as u64).ok_or_else(ends_early)?;
+            state = entry.base as usize + bit_reader.read_bits(entry.bits as u32)? as usize;
+        }
+
+        if remaining != 0 || state != 0 {
+            return Err(Error::CorruptData("FSE payload doesn't end in the initial state".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(symbols: &[u8]) -> Vec<u64> {
+        let mut counts = vec![0u64; 256];
+        for &symbol in symbols {
+            counts[symbol as usize] += 1;
+        }
+        counts
+    }
+
+    fn round_trip(symbols: &[u8]) -> (Vec<u8>, u64) {
+        let frequencies = counts(symbols);
+        let table_log = table_log(&frequencies);
+        let normalized = normalize_counts(&frequencies, table_log);
+        let (payload, payload_bits) = FseEncoder::new(&normalized, table_log).encode(symbols).unwrap();
+
+        let mut decoded = Vec::new();
+        FseDecoder::new(&normalized, table_log)
+            .decode(&payload, payload_bits, symbols.len(), |symbol| decoded.push(symbol as u8))
+            .unwrap();
+        assert_eq!(decoded, symbols);
+        (payload, payload_bits)
+    }
+
+    #[test]
+    fn test_table_log() {
+        assert_eq!(table_log(&counts(b"ab")), MIN_TABLE_LOG);
+        assert_eq!(table_log(&counts(&[b'a'; 100_000])), DEFAULT_TABLE_LOG);
+        let every_byte: Vec<u8> = (0..=255).collect();
+        assert_eq!(table_log(&counts(&every_byte)), 10);
+    }
+
+    #[test]
+    fn test_counts_round_trip() {
+        for symbols in [&b"an example with a few distinct symbols"[..], b"z", &(0..=255).collect::<Vec<u8>>()] {
+            let frequencies = counts(symbols);
+            let table_log = table_log(&frequencies);
+            let normalized = normalize_counts(&frequencies, table_log);
+
+            let mut bit_writer = BitWriter::new(Vec::new());
+            write_counts(&normalized, table_log, &mut bit_writer).unwrap();
+            let written = bit_writer.finish().unwrap();
+            let mut bit_reader = BitReader::new(&written[..]);
+            assert_eq!(read_counts(&mut bit_reader, table_log, 256).unwrap(), normalized);
+        }
+
+        // Counts that don't fit the table, or run past the alphabet
+        let mut bit_writer = BitWriter::new(Vec::new());
+        bit_writer.write_bits(33, 6).unwrap();
+        let written = bit_writer.finish().unwrap();
+        assert!(matches!(read_counts(&mut BitReader::new(&written[..]), 5, 256), Err(Error::InvalidHeader(_))));
+        let mut bit_writer = BitWriter::new(Vec::new());
+        write_counts(&[0, 0, 32], 5, &mut bit_writer).unwrap();
+        let written = bit_writer.finish().unwrap();
+        assert!(matches!(read_counts(&mut BitReader::new(&written[..]), 5, 2), Err(Error::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_fse_round_trips() {
+        round_trip(&b"the quick brown fox jumps over the lazy dog".repeat(50));
+        round_trip(&(0..=255).cycle().take(5000).collect::<Vec<u8>>());
+        round_trip(b"x");
+
+        // A single symbol needs no bits beyond the final state
+        let (_, payload_bits) = round_trip(&[b'x'; 10_000]);
+        assert_eq!(payload_bits, DEFAULT_TABLE_LOG as u64);
+    }
+
+    // On a skewed source the output stays close to the entropy, well under the
+    // one bit per symbol a Huffman code needs
+    #[test]
+    fn test_fse_approaches_entropy() {
+        let symbols: Vec<u8> = (0..100_000u32).map(|i| if i % 50 == 0 { 1 } else { 0 }).collect();
+        let entropy_bits: f64 = counts(&symbols)
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| -(count as f64) * (count as f64 / symbols.len() as f64).log2())
+            .sum();
+
+        let (_, payload_bits) = round_trip(&symbols);
+        assert!((payload_bits as f64) < entropy_bits * 1.02 + 64.0, "{} vs {}", payload_bits, entropy_bits);
+    }
+
+    #[test]
+    fn test_damaged_payloads_are_rejected() {
+        let symbols = b"abracadabra".repeat(100);
+        let frequencies = counts(&symbols);
+        let table_log = table_log(&frequencies);
+        let normalized = normalize_counts(&frequencies, table_log);
+        let (payload, payload_bits) = FseEncoder::new(&normalized, table_log).encode(&symbols).unwrap();
+        let decoder = FseDecoder::new(&normalized, table_log);
+
+        assert!(decoder.decode(&payload, payload_bits - 1, symbols.len(), |_| {}).is_err());
+        assert!(decoder.decode(&payload, payload_bits, symbols.len() - 1, |_| {}).is_err());
+        let mut damaged = payload.clone();
+        damaged[0] ^= 0x80;
+        assert!(decoder.decode(&damaged, payload_bits, symbols.len(), |_| {}).is_err());
+    }
+}
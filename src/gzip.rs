@@ -0,0 +1,244 @@
+// The gzip file format (RFC 1952): a header, a raw DEFLATE stream and a
+// trailer with the CRC-32 and the size of the original data. A file may hold
+// several such members one after the other; they decompress to the
+// concatenation of their contents.
+
+use std::io::{Read, Write};
+use crate::checksum::Crc32;
+use crate::error::{Error, Result};
+
+pub const MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+// The only compression method gzip defines
+const METHOD_DEFLATE: u8 = 8;
+
+// Header flags
+const FLAG_TEXT: u8 = 0x01;
+const FLAG_HEADER_CRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+const KNOWN_FLAGS: u8 = FLAG_TEXT | FLAG_HEADER_CRC | FLAG_EXTRA | FLAG_NAME | FLAG_COMMENT;
+
+// Operating system field: the file system the name and mtime come from
+const OS_UNIX: u8 = 3;
+const OS_UNKNOWN: u8 = 255;
+
+// Size of the trailer: CRC-32 and ISIZE, both little-endian
+pub const TRAILER_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GzipHeader {
+    // Modification time of the original file in Unix seconds, 0 if unknown
+    pub mtime: u32,
+    // Original file name, without any directory
+    pub name: Option<Vec<u8>>,
+    pub comment: Option<Vec<u8>>,
+    // Contents of the FEXTRA field, kept as is
+    pub extra: Option<Vec<u8>>,
+    pub os: u8,
+}
+
+impl GzipHeader {
+    pub fn new(mtime: u32, name: Option<&str>) -> Self {
+        GzipHeader {
+            mtime,
+            name: name.map(|name| name.as_bytes().to_vec()),
+            comment: None,
+            extra: None,
+            os: if cfg!(unix) { OS_UNIX } else { OS_UNKNOWN },
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut flags = 0;
+        if self.extra.is_some() {
+            flags |= FLAG_EXTRA;
+        }
+        if self.name.is_some() {
+            flags |= FLAG_NAME;
+        }
+        if self.comment.is_some() {
+            flags |= FLAG_COMMENT;
+        }
+
+        let mut bytes = Vec::with_capacity(10);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&[METHOD_DEFLATE, flags]);
+        bytes.extend_from_slice(&self.mtime.to_le_bytes());
+        // Extra flags describe the compression level; we don't claim either
+        bytes.extend_from_slice(&[0, self.os]);
+        if let Some(extra) = &self.extra {
+            bytes.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(extra);
+        }
+        // Names and comments are zero-terminated, so they can't hold a zero byte
+        for text in [&self.name, &self.comment].into_iter().flatten() {
+            if text.contains(&0) {
+                return Err(Error::InvalidHeader("gzip names and comments can't contain zero bytes".to_string()));
+            }
+            bytes.extend_from_slice(text);
+            bytes.push(0);
+        }
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    // Read a member header. Returns None if the input ends before its first
+    // byte, which is how a multi-member file ends.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>> {
+        let mut fixed = [0u8; 10];
+        if reader.read(&mut fixed[..1])? == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut fixed[1..2]).map_err(|_| not_gzip())?;
+        if fixed[..2] != MAGIC {
+            return Err(not_gzip());
+        }
+        reader.read_exact(&mut fixed[2..])?;
+        if fixed[2] != METHOD_DEFLATE {
+            return Err(Error::InvalidHeader(format!("unknown gzip compression method {}", fixed[2])));
+        }
+        let flags = fixed[3];
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::UnsupportedFlags(flags as u16));
+        }
+
+        // Keep every header byte for the optional header CRC
+        let mut raw = fixed.to_vec();
+        let mut header = GzipHeader {
+            mtime: u32::from_le_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+            os: fixed[9],
+            ..GzipHeader::default()
+        };
+        if flags & FLAG_EXTRA != 0 {
+            let length = read_bytes(reader, 2, &mut raw)?;
+            let length = u16::from_le_bytes([length[0], length[1]]) as usize;
+            header.extra = Some(read_bytes(reader, length, &mut raw)?);
+        }
+        if flags & FLAG_NAME != 0 {
+            header.name = Some(read_zero_terminated(reader, &mut raw)?);
+        }
+        if flags & FLAG_COMMENT != 0 {
+            header.comment = Some(read_zero_terminated(reader, &mut raw)?);
+        }
+        if flags & FLAG_HEADER_CRC != 0 {
+            let stored = read_bytes(reader, 2, &mut Vec::new())?;
+            let expected = u16::from_le_bytes([stored[0], stored[1]]);
+            let mut crc = Crc32::new();
+            crc.update(&raw);
+            let actual = crc.finalize() as u16;
+            if expected != actual {
+                return Err(Error::ChecksumMismatch { expected: expected as u64, actual: actual as u64 });
+            }
+        }
+        Ok(Some(header))
+    }
+}
+
+// Write the trailer of a member
+pub fn write_trailer<W: Write>(writer: &mut W, crc: u32, size: u64) -> Result<()> {
+    writer.write_all(&crc.to_le_bytes())?;
+    // ISIZE holds the size modulo 2^32
+    writer.write_all(&(size as u32).to_le_bytes())?;
+    Ok(())
+}
+
+// Read the trailer of a member and check it against the data it decoded to
+pub fn check_trailer<R: Read>(reader: &mut R, crc: u32, size: u64) -> Result<()> {
+    let mut trailer = [0u8; TRAILER_LENGTH];
+    reader.read_exact(&mut trailer)?;
+    let expected = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    if expected != crc {
+        return Err(Error::ChecksumMismatch { expected: expected as u64, actual: crc as u64 });
+    }
+    let expected_size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if expected_size != size as u32 {
+        return Err(Error::CorruptData(format!(
+            "gzip member should hold {} bytes (mod 2^32) but holds {}",
+            expected_size, size
+        )));
+    }
+    Ok(())
+}
+
+fn not_gzip() -> Error {
+    Error::InvalidHeader("not a gzip file (bad magic bytes)".to_string())
+}
+
+fn read_bytes<R: Read>(reader: &mut R, length: usize, raw: &mut Vec<u8>) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; length];
+    reader.read_exact(&mut bytes)?;
+    raw.extend_from_slice(&bytes);
+    Ok(bytes)
+}
+
+fn read_zero_terminated<R: Read>(reader: &mut R, raw: &mut Vec<u8>) -> Result<Vec<u8>> {
+    let mut text = Vec::new();
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        raw.push(byte[0]);
+        if byte[0] == 0 {
+            return Ok(text);
+        }
+        text.push(byte[0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_round_trip() {
+        let mut header = GzipHeader::new(1_700_000_000, Some("notes.txt"));
+        header.comment = Some(b"a comment".to_vec());
+        header.extra = Some(vec![b'A', b'B', 2, 0, 1, 2]);
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], &[0x1F, 0x8B, 8, FLAG_EXTRA | FLAG_NAME | FLAG_COMMENT]);
+
+        let read_back = GzipHeader::read_from(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(read_back, Some(header));
+    }
+
+    #[test]
+    fn test_header_crc() {
+        let mut bytes = Vec::new();
+        GzipHeader::new(0, Some("a")).write_to(&mut bytes).unwrap();
+        bytes[3] |= FLAG_HEADER_CRC;
+        let mut crc = Crc32::new();
+        crc.update(&bytes);
+        bytes.extend_from_slice(&(crc.finalize() as u16).to_le_bytes());
+        assert!(GzipHeader::read_from(&mut Cursor::new(bytes.clone())).is_ok());
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let err = GzipHeader::read_from(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(matches!(err, Error::ChecksumMismatch { .. }));
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        assert_eq!(GzipHeader::read_from(&mut Cursor::new(Vec::new())).unwrap(), None);
+        let err = GzipHeader::read_from(&mut Cursor::new(b"PK\x03\x04".to_vec())).unwrap_err();
+        assert!(matches!(err, Error::InvalidHeader(_)));
+        let err = GzipHeader::read_from(&mut Cursor::new(vec![0x1F, 0x8B, 8, 0x20, 0, 0, 0, 0, 0, 3])).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFlags(0x20)));
+        let err = GzipHeader::read_from(&mut Cursor::new(vec![0x1F, 0x8B, 8, FLAG_NAME, 0, 0, 0, 0, 0, 3, b'a'])).unwrap_err();
+        assert!(matches!(err, Error::Truncated));
+    }
+
+    #[test]
+    fn test_trailer() {
+        let mut crc = Crc32::new();
+        crc.update(b"hello");
+        let mut bytes = Vec::new();
+        write_trailer(&mut bytes, crc.finalize(), 5).unwrap();
+        assert!(check_trailer(&mut &bytes[..], crc.finalize(), 5).is_ok());
+        assert!(matches!(check_trailer(&mut &bytes[..], crc.finalize(), 6), Err(Error::CorruptData(_))));
+        assert!(matches!(check_trailer(&mut &bytes[..], 0, 5), Err(Error::ChecksumMismatch { .. })));
+    }
+}
@@ -1,8 +1,59 @@
 use std::cmp::Ordering;
+use std::fmt;
 use std::fmt::Debug;
+use std::fmt::Display;
 use std::fmt::Formatter;
-use std::fmt::Result;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::io::{Read, Write};
+use crate::bitio::{BitReader, BitWriter};
+use crate::error::{Error, Result};
+
+// Codes are handed around as u64 bit patterns, so no code may be longer than this
+pub const MAX_SUPPORTED_CODE_LENGTH: u8 = 63;
+
+// Decoded bytes are collected in a buffer of this size before being written out
+pub(crate) const DECODE_BUFFER_SIZE: usize = 64 * 1024;
+
+// A symbol of the alphabet a tree codes: a byte, or something wider such as an
+// LZ77 length code or a word token. Symbols double as indices into the tables
+// kept per symbol, like code lengths.
+pub trait Symbol: Copy + Ord + Hash + Debug + Display {
+    // The symbol numbered `index`, or None if the type can't hold it
+    fn from_index(index: usize) -> Option<Self>;
+
+    fn index(self) -> usize;
+}
+
+impl Symbol for u8 {
+    fn from_index(index: usize) -> Option<Self> {
+        u8::try_from(index).ok()
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Symbol for u16 {
+    fn from_index(index: usize) -> Option<Self> {
+        u16::try_from(index).ok()
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Symbol for u32 {
+    fn from_index(index: usize) -> Option<Self> {
+        u32::try_from(index).ok()
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
 
 struct HuffmanBaseNode {
     is_leaf: bool,
@@ -18,13 +69,13 @@ impl HuffmanBaseNode {
     }
 }
 
-pub struct HuffmanLeafNode {
+pub struct HuffmanLeafNode<S = u8> {
     base: HuffmanBaseNode,
-    element: char,
+    element: S,
 }
 
-impl HuffmanLeafNode {
-    pub fn new(wt: i32, el: char) -> Self {
+impl<S: Symbol> HuffmanLeafNode<S> {
+    pub fn new(wt: i32, el: S) -> Self {
         HuffmanLeafNode {
             base: HuffmanBaseNode {
                 is_leaf: true,
@@ -34,7 +85,7 @@ impl HuffmanLeafNode {
         }
     }
 
-    pub fn value(&self) -> char {
+    pub fn value(&self) -> S {
         self.element
     }
 
@@ -43,14 +94,14 @@ impl HuffmanLeafNode {
     }
 }
 
-pub struct HuffmanInternalNode {
+pub struct HuffmanInternalNode<S = u8> {
     base: HuffmanBaseNode,
-    left: Box<HuffmanNode>,
-    right: Box<HuffmanNode>,
+    left: Box<HuffmanNode<S>>,
+    right: Box<HuffmanNode<S>>,
 }
 
-impl HuffmanInternalNode {
-    pub fn new (wt: i32, l: HuffmanNode, r: HuffmanNode) -> Self {
+impl<S: Symbol> HuffmanInternalNode<S> {
+    pub fn new (wt: i32, l: HuffmanNode<S>, r: HuffmanNode<S>) -> Self {
         HuffmanInternalNode {
             base: HuffmanBaseNode {
                 is_leaf: false,
@@ -61,10 +112,10 @@ impl HuffmanInternalNode {
         }
     }
 
-    pub fn left(&self) -> &HuffmanNode {
+    pub fn left(&self) -> &HuffmanNode<S> {
         &self.left
     }
-    pub fn right(&self) -> &HuffmanNode {
+    pub fn right(&self) -> &HuffmanNode<S> {
         &self.right
     }
 
@@ -73,12 +124,74 @@ impl HuffmanInternalNode {
     }
 }
 
-pub enum HuffmanNode {
-    Leaf(HuffmanLeafNode),
-    Internal(HuffmanInternalNode),
+// A Huffman tree over symbols of type `S`, bytes unless said otherwise
+pub enum HuffmanNode<S = u8> {
+    Leaf(HuffmanLeafNode<S>),
+    Internal(HuffmanInternalNode<S>),
 }
 
-impl HuffmanNode {
+impl<S: Symbol> HuffmanNode<S> {
+    // Build a Huffman tree by repeatedly combining the two lightest nodes.
+    // Returns None when there are no symbols to build a tree from, or when
+    // the weights add up to more than an i32 holds.
+    pub fn from_frequencies(frequency_map: &BTreeMap<S, i32>) -> Option<HuffmanNode<S>> {
+        let mut heap: BinaryHeap<HuffmanNode<S>> = BinaryHeap::new();
+        for (&symbol, &count) in frequency_map {
+            heap.push(HuffmanNode::Leaf(HuffmanLeafNode::new(count, symbol)));
+        }
+
+        while heap.len() > 1 {
+            // Pop the two nodes with the smallest frequencies
+            let left = heap.pop().unwrap();
+            let right = heap.pop().unwrap();
+
+            // Combine the two nodes into an internal node
+            let combined_weight = left.weight().checked_add(right.weight())?;
+            heap.push(HuffmanNode::Internal(HuffmanInternalNode::new(combined_weight, left, right)));
+        }
+
+        heap.pop()
+    }
+
+    // Rebuild the tree for a set of canonical code lengths (indexed by symbol,
+    // 0 meaning the symbol is unused). The shape only depends on the lengths,
+    // not on how the encoder broke ties between equal weights.
+    pub fn from_code_lengths(lengths: &[u8]) -> Result<HuffmanNode<S>> {
+        let codes = canonical_codes(lengths)?;
+        let mut entries: Vec<(u64, u8, S)> = Vec::new();
+        for (index, &(code, length)) in codes.iter().enumerate().filter(|(_, &(_, length))| length > 0) {
+            let symbol = S::from_index(index)
+                .ok_or_else(|| Error::InvalidHeader(format!("code length table has a code for symbol {}", index)))?;
+            entries.push((code, length, symbol));
+        }
+
+        match entries.len() {
+            0 => Err(Error::InvalidHeader("code length table has no symbols".to_string())),
+            // A lone symbol is the whole tree; every bit in the payload stands for it
+            1 => Ok(HuffmanNode::Leaf(HuffmanLeafNode::new(0, entries[0].2))),
+            _ => {
+                // Sort the codes as bit strings so each subtree is a contiguous range
+                entries.sort_by_key(|&(code, length, _)| code << (MAX_SUPPORTED_CODE_LENGTH - length));
+                Ok(Self::build_from_codes(&entries, 0))
+            },
+        }
+    }
+
+    // Split the (sorted, complete) codes on the bit at `depth` and recurse
+    fn build_from_codes(entries: &[(u64, u8, S)], depth: u8) -> HuffmanNode<S> {
+        if entries.len() == 1 && entries[0].1 == depth {
+            return HuffmanNode::Leaf(HuffmanLeafNode::new(0, entries[0].2));
+        }
+
+        let split = entries
+            .iter()
+            .position(|&(code, length, _)| (code >> (length - depth - 1)) & 1 == 1)
+            .unwrap_or(entries.len());
+        let left = Self::build_from_codes(&entries[..split], depth + 1);
+        let right = Self::build_from_codes(&entries[split..], depth + 1);
+        HuffmanNode::Internal(HuffmanInternalNode::new(0, left, right))
+    }
+
     pub fn weight(&self) -> i32 {
         match self {
             HuffmanNode::Leaf(leaf) => leaf.base.weight,
@@ -93,24 +206,24 @@ impl HuffmanNode {
         }
     }
 
-    pub fn value(&self) -> Option<char> {
+    pub fn value(&self) -> Option<S> {
         match self {
             HuffmanNode::Leaf(leaf) => Some(leaf.value()),
             _ => None,
         }
     }
 
-    pub fn left(&self) -> Option<&HuffmanNode> {
+    pub fn left(&self) -> Option<&HuffmanNode<S>> {
         match self {
             HuffmanNode::Leaf(_) => None,
-            HuffmanNode::Internal(internal) => Some(&internal.left()),
+            HuffmanNode::Internal(internal) => Some(internal.left()),
         }
     }
 
-    pub fn right(&self) -> Option<&HuffmanNode> {
+    pub fn right(&self) -> Option<&HuffmanNode<S>> {
         match self {
             HuffmanNode::Leaf(_) => None,
-            HuffmanNode::Internal(internal) => Some(&internal.right()),
+            HuffmanNode::Internal(internal) => Some(internal.right()),
         }
     }
 
@@ -143,12 +256,38 @@ impl HuffmanNode {
         }
     }
 
-    // Function to generate the prefix codes
-    pub fn generate_prefix_codes(&self, codes: &mut HashMap<char, String>) {
-        self.generate_prefix_codes_helper("".to_string(), codes);
+    // Function to generate the prefix codes. A tree that is a single leaf
+    // gets the code "0", as an empty code would leave nothing to decode.
+    pub fn generate_prefix_codes(&self, codes: &mut HashMap<S, String>) {
+        match self {
+            HuffmanNode::Leaf(leaf) => {
+                codes.insert(leaf.value(), "0".to_string());
+            },
+            HuffmanNode::Internal(_) => self.generate_prefix_codes_helper("".to_string(), codes),
+        }
+    }
+
+    // Function to generate the code length (depth in the tree) of every symbol,
+    // indexed by symbol value
+    pub fn generate_code_lengths(&self, lengths: &mut [u8]) {
+        match self {
+            // A single symbol still needs one bit per occurrence
+            HuffmanNode::Leaf(leaf) => lengths[leaf.value().index()] = 1,
+            HuffmanNode::Internal(_) => self.generate_code_lengths_helper(0, lengths),
+        }
+    }
+
+    fn generate_code_lengths_helper(&self, depth: u8, lengths: &mut [u8]) {
+        match self {
+            HuffmanNode::Leaf(leaf) => lengths[leaf.value().index()] = depth,
+            HuffmanNode::Internal(internal) => {
+                internal.left.generate_code_lengths_helper(depth + 1, lengths);
+                internal.right.generate_code_lengths_helper(depth + 1, lengths);
+            },
+        }
     }
 
-    fn generate_prefix_codes_helper(&self, prefix: String, codes: &mut HashMap<char, String>) {
+    fn generate_prefix_codes_helper(&self, prefix: String, codes: &mut HashMap<S, String>) {
         match self {
             HuffmanNode::Leaf(leaf) => {
                 // Store the prefix code for the leaf node
@@ -166,32 +305,549 @@ impl HuffmanNode {
     
 }
 
+impl HuffmanNode<u8> {
+    // Reference decoder: walk the tree one bit at a time for `payload_bits`
+    // bits, writing out a symbol every time a leaf is reached
+    pub fn decode<R: Read, W: Write>(&self, bit_reader: &mut BitReader<R>, payload_bits: u64, writer: &mut W) -> Result<()> {
+        let mut output: Vec<u8> = Vec::with_capacity(DECODE_BUFFER_SIZE);
+        let mut current_node: &HuffmanNode<u8> = self;
+
+        for _ in 0..payload_bits {
+            let bit = bit_reader.read_bit()?;
+
+            // Move down the tree based on the bit (0 = left, 1 = right). A tree
+            // that is a single leaf spends one zero bit per symbol.
+            let next_node = match current_node {
+                HuffmanNode::Leaf(_) if bit => {
+                    return Err(Error::CorruptData("invalid code in payload".to_string()));
+                },
+                HuffmanNode::Leaf(_) => current_node,
+                HuffmanNode::Internal(internal) => if bit { internal.right() } else { internal.left() },
+            };
+
+            if let HuffmanNode::Leaf(leaf) = next_node {
+                output.push(leaf.value());
+                if output.len() == DECODE_BUFFER_SIZE {
+                    writer.write_all(&output)?;
+                    output.clear();
+                }
+                current_node = self; // Reset to the root of the tree for the next symbol
+            } else {
+                current_node = next_node; // Continue moving down the internal node tree
+            }
+        }
+
+        // The payload must end on a symbol boundary
+        if !std::ptr::eq(current_node, self) {
+            return Err(Error::CorruptData("payload ends in the middle of a code".to_string()));
+        }
+
+        writer.write_all(&output)?;
+        Ok(())
+    }
+}
+
+// Assign canonical codes to a set of code lengths (indexed by symbol, 0 meaning
+// unused). Codes are handed out in order of increasing length, ties broken by
+// symbol value, so the lengths alone are enough to reconstruct them.
+// Returns (code, length) per symbol; the lengths must form a complete prefix
+// code, or consist of a single symbol of length 1.
+pub fn canonical_codes(lengths: &[u8]) -> Result<Vec<(u64, u8)>> {
+    let max_length = lengths.iter().copied().max().unwrap_or(0);
+    if max_length > MAX_SUPPORTED_CODE_LENGTH {
+        return Err(Error::InvalidHeader(format!("code length {} is too long", max_length)));
+    }
+
+    // Check the Kraft sum so that every bit pattern decodes to exactly one symbol
+    let used = lengths.iter().filter(|&&length| length > 0).count();
+    let kraft_sum: u128 = lengths
+        .iter()
+        .filter(|&&length| length > 0)
+        .map(|&length| 1u128 << (MAX_SUPPORTED_CODE_LENGTH - length))
+        .sum();
+    let complete = kraft_sum == 1u128 << MAX_SUPPORTED_CODE_LENGTH;
+    let single = used == 1 && max_length == 1;
+    if used > 0 && !complete && !single {
+        return Err(Error::InvalidHeader("code lengths don't form a complete prefix code".to_string()));
+    }
+
+    // Count the codes of each length and find the first code of each length
+    let mut length_counts = vec![0u64; max_length as usize + 1];
+    for &length in lengths {
+        if length > 0 {
+            length_counts[length as usize] += 1;
+        }
+    }
+    let mut next_code = vec![0u64; max_length as usize + 1];
+    let mut code = 0u64;
+    for length in 1..=max_length as usize {
+        code = (code + length_counts[length - 1]) << 1;
+        next_code[length] = code;
+    }
+
+    let mut codes = vec![(0u64, 0u8); lengths.len()];
+    for (symbol, &length) in lengths.iter().enumerate() {
+        if length > 0 {
+            codes[symbol] = (next_code[length as usize], length);
+            next_code[length as usize] += 1;
+        }
+    }
+    Ok(codes)
+}
+
+// Compute optimal code lengths (indexed by symbol, 0 for unused symbols) under
+// the constraint that no code is longer than `max_length`, using the
+// package-merge algorithm. Each symbol's code length is the number of times it
+// ends up in the 2n - 2 cheapest items after max_length - 1 rounds of pairing
+// up ("packaging") the current list and merging it back with the leaves.
+//
+// Leaves join each list lightest first, and packages are made from the front
+// of the list before, so the cheapest items of a list are always its lightest
+// few leaves and first few packages. Rather than carry the symbols inside
+// every package, each round only records which of its items were leaves, and
+// the selections are counted by walking the rounds back from the last one.
+//
+// Panics if max_length is 0, above MAX_SUPPORTED_CODE_LENGTH, or too small to
+// give every used symbol its own code.
+pub fn length_limited_code_lengths(frequencies: &[u64], max_length: u8) -> Vec<u8> {
+    assert!(
+        max_length > 0 && max_length <= MAX_SUPPORTED_CODE_LENGTH,
+        "maximum code length must be between 1 and {}",
+        MAX_SUPPORTED_CODE_LENGTH
+    );
+
+    let mut lengths = vec![0u8; frequencies.len()];
+    let mut leaves: Vec<(u64, usize)> = frequencies
+        .iter()
+        .enumerate()
+        .filter(|(_, &weight)| weight > 0)
+        .map(|(symbol, &weight)| (weight, symbol))
+        .collect();
+
+    match leaves.len() {
+        0 => return lengths,
+        // A single symbol still needs one bit per occurrence
+        1 => {
+            lengths[leaves[0].1] = 1;
+            return lengths;
+        },
+        n => assert!(
+            (n as u128) <= 1u128 << max_length,
+            "{} symbols don't fit in codes of at most {} bits",
+            n,
+            max_length
+        ),
+    }
+
+    // Sort by weight, ties broken by symbol so the result is deterministic
+    leaves.sort();
+
+    // The weights of the current list, and for every round whether each item
+    // of its merged list is a leaf (true) or a package
+    let mut current: Vec<u64> = leaves.iter().map(|&(weight, _)| weight).collect();
+    let mut rounds: Vec<Vec<bool>> = Vec::with_capacity(max_length as usize - 1);
+    for _ in 1..max_length {
+        let packages: Vec<u64> = current.chunks_exact(2).map(|pair| pair[0] + pair[1]).collect();
+
+        // Merge the packages with a fresh copy of the leaves, leaves first on ties
+        let mut merged = Vec::with_capacity(leaves.len() + packages.len());
+        let mut is_leaf = Vec::with_capacity(leaves.len() + packages.len());
+        let (mut leaf, mut package) = (0, 0);
+        while leaf < leaves.len() || package < packages.len() {
+            let take_leaf = package == packages.len() || (leaf < leaves.len() && leaves[leaf].0 <= packages[package]);
+            if take_leaf {
+                merged.push(leaves[leaf].0);
+                leaf += 1;
+            } else {
+                merged.push(packages[package]);
+                package += 1;
+            }
+            is_leaf.push(take_leaf);
+        }
+        current = merged;
+        rounds.push(is_leaf);
+    }
+
+    // The first `selected` items of a round are its lightest leaves and its
+    // first packages, which in turn are made of the first two items per
+    // package of the round before
+    let mut selected = 2 * leaves.len() - 2;
+    for is_leaf in rounds.iter().rev() {
+        let selected_leaves = is_leaf[..selected].iter().filter(|&&leaf| leaf).count();
+        for &(_, symbol) in &leaves[..selected_leaves] {
+            lengths[symbol] += 1;
+        }
+        selected = 2 * (selected - selected_leaves);
+    }
+    // The first list is the leaves alone
+    for &(_, symbol) in &leaves[..selected] {
+        lengths[symbol] += 1;
+    }
+    lengths
+}
+
+// Encode a code length table compactly: a non-zero byte is the length of the
+// next symbol, a zero byte is followed by the number of unused symbols in a row
+// minus one
+pub fn encode_code_lengths(lengths: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        if lengths[i] != 0 {
+            encoded.push(lengths[i]);
+            i += 1;
+            continue;
+        }
+
+        let run = lengths[i..].iter().take(256).take_while(|&&length| length == 0).count();
+        encoded.push(0);
+        encoded.push((run - 1) as u8);
+        i += run;
+    }
+    encoded
+}
+
+// Read a code length table written by encode_code_lengths
+pub fn decode_code_lengths<R: Read>(reader: &mut R, alphabet_size: usize) -> Result<Vec<u8>> {
+    let mut lengths = Vec::with_capacity(alphabet_size);
+    while lengths.len() < alphabet_size {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        if byte[0] != 0 {
+            lengths.push(byte[0]);
+            continue;
+        }
+
+        reader.read_exact(&mut byte)?;
+        let run = byte[0] as usize + 1;
+        if lengths.len() + run > alphabet_size {
+            return Err(Error::InvalidHeader("code length table overruns the alphabet".to_string()));
+        }
+        lengths.resize(lengths.len() + run, 0);
+    }
+    Ok(lengths)
+}
+
+// Symbols per group when several tables take turns, as in bzip2
+pub const GROUP_SIZE: usize = 50;
+
+// Most tables a symbol stream is split over
+pub const MAX_TABLES: usize = 6;
+
+// Rounds of refining the tables and the choice of table for each group
+const TABLE_REFINEMENT_ROUNDS: usize = 4;
+
+// Code lengths for several Huffman tables over `symbols`, and the table each
+// group of GROUP_SIZE symbols uses (its selector). Longer streams get more
+// tables. As in bzip2, the tables start out favouring slices of the alphabet
+// with equal shares of the symbols, then each round sends every group to the
+// table that codes it cheapest and rebuilds the tables from the groups they
+// got. Every table codes every symbol the stream uses, so a group may pick
+// any table, and with `code_unused` every symbol of the alphabet, as bzip2
+// requires.
+pub fn multi_table_code_lengths(
+    symbols: &[u16],
+    alphabet_size: usize,
+    max_length: u8,
+    code_unused: bool,
+) -> (Vec<Vec<u8>>, Vec<u8>) {
+    let table_count = match symbols.len() {
+        0..200 => 2,
+        200..600 => 3,
+        600..1200 => 4,
+        1200..2400 => 5,
+        _ => MAX_TABLES,
+    };
+    let mut frequencies = vec![0u64; alphabet_size];
+    for &symbol in symbols {
+        frequencies[symbol as usize] += 1;
+    }
+
+    // Cheap inside a table's slice, expensive outside it
+    let mut lengths = vec![vec![max_length; alphabet_size]; table_count];
+    let mut remaining = symbols.len() as u64;
+    let mut start = 0;
+    for (table, table_lengths) in lengths.iter_mut().enumerate() {
+        let share = remaining / (table_count - table) as u64;
+        let mut taken = 0;
+        let mut end = start;
+        while end < alphabet_size && (taken < share || table == table_count - 1) {
+            taken += frequencies[end];
+            end += 1;
+        }
+        table_lengths[start..end].fill(0);
+        remaining -= taken;
+        start = end;
+    }
+
+    let mut selectors = Vec::with_capacity(symbols.len().div_ceil(GROUP_SIZE));
+    for _ in 0..TABLE_REFINEMENT_ROUNDS {
+        selectors.clear();
+        let mut table_frequencies = vec![vec![0u64; alphabet_size]; table_count];
+        for group in symbols.chunks(GROUP_SIZE) {
+            let cost = |table: &Vec<u8>| group.iter().map(|&symbol| table[symbol as usize] as u64).sum::<u64>();
+            let best = (0..table_count).min_by_key(|&table| cost(&lengths[table])).unwrap();
+            selectors.push(best as u8);
+            for &symbol in group {
+                table_frequencies[best][symbol as usize] += 1;
+            }
+        }
+
+        for (table_lengths, counts) in lengths.iter_mut().zip(&mut table_frequencies) {
+            for (count, &total) in counts.iter_mut().zip(&frequencies) {
+                if total > 0 || code_unused {
+                    *count += 1;
+                }
+            }
+            *table_lengths = length_limited_code_lengths(counts, max_length);
+        }
+    }
+    (lengths, selectors)
+}
+
+// Adaptive Huffman coding with Vitter's algorithm (Algorithm V). Encoder and
+// decoder start from the same empty tree and update it after every symbol,
+// so no code table has to be sent. Symbols that haven't been seen yet are sent
+// as the code of the NYT ("not yet transmitted") leaf followed by the symbol
+// in plain binary.
+//
+// The tree keeps Vitter's implicit numbering: nodes are ordered by weight,
+// leaves come before internal nodes of the same weight, and a parent always
+// comes after its children. Moving a node to its place in that order before
+// incrementing its weight keeps the code optimal after every symbol, and
+// minimises both the sum and the maximum of the code lengths.
+pub struct AdaptiveHuffman {
+    nodes: Vec<AdaptiveNode>,
+    // Node ids in the implicit numbering, lowest first; the root is last
+    order: Vec<usize>,
+    // Position of every node in `order`
+    position: Vec<usize>,
+    // Leaf of every symbol seen so far, or NO_NODE
+    leaves: Vec<usize>,
+    nyt: usize,
+    // Bits in the plain binary form of a new symbol
+    symbol_bits: u32,
+    // Scratch space for the bits of a code, which are found leaf to root
+    path: Vec<bool>,
+}
+
+const NO_NODE: usize = usize::MAX;
+
+struct AdaptiveNode {
+    weight: u64,
+    parent: usize,
+    // Left and right child of an internal node; None for leaves
+    children: Option<[usize; 2]>,
+    // Symbol of a leaf (unused for NYT and internal nodes)
+    symbol: u16,
+}
+
+impl AdaptiveHuffman {
+    // An empty tree for symbols 0 to alphabet_size - 1
+    pub fn new(alphabet_size: usize) -> Self {
+        assert!((2..=1 << 16).contains(&alphabet_size), "alphabet must have between 2 and 65536 symbols");
+        AdaptiveHuffman {
+            nodes: vec![AdaptiveNode { weight: 0, parent: NO_NODE, children: None, symbol: 0 }],
+            order: vec![0],
+            position: vec![0],
+            leaves: vec![NO_NODE; alphabet_size],
+            nyt: 0,
+            symbol_bits: usize::BITS - (alphabet_size - 1).leading_zeros(),
+            path: Vec::new(),
+        }
+    }
+
+    // Write the code of `symbol` and update the tree
+    pub fn encode<W: Write>(&mut self, symbol: u16, bit_writer: &mut BitWriter<W>) -> Result<()> {
+        let leaf = self.leaves[symbol as usize];
+        if leaf == NO_NODE {
+            self.write_path(self.nyt, bit_writer)?;
+            bit_writer.write_bits(symbol as u64, self.symbol_bits)?;
+        } else {
+            self.write_path(leaf, bit_writer)?;
+        }
+        self.update(symbol as usize);
+        Ok(())
+    }
+
+    // Read the code of the next symbol and update the tree
+    pub fn decode<R: Read>(&mut self, bit_reader: &mut BitReader<R>) -> Result<u16> {
+        let mut node = *self.order.last().unwrap();
+        while let Some(children) = self.nodes[node].children {
+            node = children[bit_reader.read_bit()? as usize];
+        }
+
+        let symbol = if node == self.nyt {
+            let symbol = bit_reader.read_bits(self.symbol_bits)? as usize;
+            if symbol >= self.leaves.len() || self.leaves[symbol] != NO_NODE {
+                return Err(Error::CorruptData(format!("invalid new symbol {}", symbol)));
+            }
+            symbol
+        } else {
+            self.nodes[node].symbol as usize
+        };
+        self.update(symbol);
+        Ok(symbol as u16)
+    }
+
+    fn write_path<W: Write>(&mut self, mut node: usize, bit_writer: &mut BitWriter<W>) -> Result<()> {
+        self.path.clear();
+        while self.nodes[node].parent != NO_NODE {
+            let parent = self.nodes[node].parent;
+            self.path.push(self.nodes[parent].children.unwrap()[1] == node);
+            node = parent;
+        }
+        for &bit in self.path.iter().rev() {
+            bit_writer.write_bit(bit)?;
+        }
+        Ok(())
+    }
+
+    // Count one more occurrence of `symbol`
+    fn update(&mut self, symbol: usize) {
+        let mut leaf_to_increment = NO_NODE;
+        let mut node;
+
+        if self.leaves[symbol] == NO_NODE {
+            // Step 1: Split the NYT leaf into a new NYT leaf and a leaf for the
+            // symbol, both of weight 0 and at the bottom of the numbering
+            let old_nyt = self.nyt;
+            let nyt = self.add_node(old_nyt, 0);
+            let leaf = self.add_node(old_nyt, symbol as u16);
+            self.nodes[old_nyt].children = Some([nyt, leaf]);
+            self.nyt = nyt;
+            self.leaves[symbol] = leaf;
+            self.order.splice(0..0, [nyt, leaf]);
+            self.position.resize(self.nodes.len(), 0);
+            for (position, &id) in self.order.iter().enumerate() {
+                self.position[id] = position;
+            }
+            node = old_nyt;
+            leaf_to_increment = leaf;
+        } else {
+            // Step 1: Move the leaf to the top of its block. A leaf next to the
+            // NYT leaf shares its weight with its parent, so it is incremented
+            // after the parent to stay below it.
+            node = self.leaves[symbol];
+            self.swap(node, self.block_leader(node));
+            let parent = self.nodes[node].parent;
+            if parent != NO_NODE && self.nodes[parent].children.unwrap()[0] == self.nyt {
+                leaf_to_increment = node;
+                node = parent;
+            }
+        }
+
+        // Step 2: Increment every node on the way up to the root
+        while node != NO_NODE {
+            node = self.slide_and_increment(node);
+        }
+        if leaf_to_increment != NO_NODE {
+            self.slide_and_increment(leaf_to_increment);
+        }
+    }
+
+    fn add_node(&mut self, parent: usize, symbol: u16) -> usize {
+        self.nodes.push(AdaptiveNode { weight: 0, parent, children: None, symbol });
+        self.nodes.len() - 1
+    }
+
+    // The highest numbered node of the same weight and kind (leaf or internal)
+    fn block_leader(&self, node: usize) -> usize {
+        let mut leader = node;
+        for &next in &self.order[self.position[node] + 1..] {
+            if !self.same_block(node, next) {
+                break;
+            }
+            leader = next;
+        }
+        leader
+    }
+
+    fn same_block(&self, a: usize, b: usize) -> bool {
+        self.nodes[a].weight == self.nodes[b].weight && self.nodes[a].children.is_none() == self.nodes[b].children.is_none()
+    }
+
+    // Move `node` past the nodes that would come before it with its weight
+    // incremented, increment it and return the next node to increment: the
+    // new parent of a leaf, or the old parent of an internal node
+    fn slide_and_increment(&mut self, node: usize) -> usize {
+        self.swap(node, self.block_leader(node));
+        let previous_parent = self.nodes[node].parent;
+        let weight = self.nodes[node].weight;
+        let is_leaf = self.nodes[node].children.is_none();
+
+        // A leaf slides past the internal nodes of its weight, an internal
+        // node past the leaves of the next weight
+        while let Some(&next) = self.order.get(self.position[node] + 1) {
+            let next_is_leaf = self.nodes[next].children.is_none();
+            let slides_past = if is_leaf {
+                !next_is_leaf && self.nodes[next].weight == weight
+            } else {
+                next_is_leaf && self.nodes[next].weight == weight + 1
+            };
+            if !slides_past {
+                break;
+            }
+            self.swap(node, next);
+        }
+
+        self.nodes[node].weight += 1;
+        if is_leaf { self.nodes[node].parent } else { previous_parent }
+    }
+
+    // Exchange two nodes, with their subtrees, in the tree and in the
+    // numbering. Neither may be an ancestor of the other.
+    fn swap(&mut self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        let (parent_a, parent_b) = (self.nodes[a].parent, self.nodes[b].parent);
+        let children_a = self.nodes[parent_a].children.as_mut().unwrap();
+        let index_a = (children_a[1] == a) as usize;
+        if parent_a == parent_b {
+            children_a.swap(0, 1);
+        } else {
+            children_a[index_a] = b;
+            let children_b = self.nodes[parent_b].children.as_mut().unwrap();
+            let index_b = (children_b[1] == b) as usize;
+            children_b[index_b] = a;
+            self.nodes[a].parent = parent_b;
+            self.nodes[b].parent = parent_a;
+        }
+
+        let (position_a, position_b) = (self.position[a], self.position[b]);
+        self.order.swap(position_a, position_b);
+        self.position[a] = position_b;
+        self.position[b] = position_a;
+    }
+}
+
 // Implementing Ord and PartialOrd for the HuffmanNode so we can use BinaryHeap
-impl Ord for HuffmanNode {
+impl<S: Symbol> Ord for HuffmanNode<S> {
     fn cmp(&self, other: &Self) -> Ordering {
         other.weight().cmp(&self.weight())
     }
 }
 
-impl PartialOrd for HuffmanNode {
+impl<S: Symbol> PartialOrd for HuffmanNode<S> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Eq for HuffmanNode {
+impl<S: Symbol> Eq for HuffmanNode<S> {
 
 }
 
-impl PartialEq for HuffmanNode {
+impl<S: Symbol> PartialEq for HuffmanNode<S> {
     fn eq(&self, other: &Self) -> bool {
         self.weight() == other.weight()
     }
 }
 
 // For printing the tree 
-impl Debug for HuffmanNode {
-    fn fmt(&self, f: &mut Formatter) -> Result {
+impl<S: Symbol> Debug for HuffmanNode<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             HuffmanNode::Leaf(leaf) => write!(f, "Leaf({} : {})", leaf.value(), leaf.base.weight()),
             HuffmanNode::Internal(internal) => write!(f, "Internal({}, left: {:?}, right: {:?})", internal.base.weight(), internal.left(), internal.right()),
@@ -206,16 +862,16 @@ mod tests {
     // Test HuffmanLeafNode creation
     #[test]
     fn test_leaf_node_creation() {
-        let leaf = HuffmanLeafNode::new(3, 'a');
-        assert_eq!(leaf.value(), 'a');
+        let leaf = HuffmanLeafNode::new(3, b'a');
+        assert_eq!(leaf.value(), b'a');
         assert_eq!(leaf.weight(), 3);
     }
 
     // Test HuffmanInternalNode creation
     #[test]
     fn test_internal_node_creation() {
-        let left = HuffmanNode::Leaf(HuffmanLeafNode::new(2, 'b'));
-        let right = HuffmanNode::Leaf(HuffmanLeafNode::new(3, 'a'));
+        let left = HuffmanNode::Leaf(HuffmanLeafNode::new(2, b'b'));
+        let right = HuffmanNode::Leaf(HuffmanLeafNode::new(3, b'a'));
         let internal_node = HuffmanInternalNode::new(5, left, right);
         assert_eq!(internal_node.base.weight, 5);
     }
@@ -230,9 +886,9 @@ mod tests {
         //             /      \
         //        Leaf(b)   Leaf(c)
 
-        let node_a = HuffmanNode::Leaf(HuffmanLeafNode::new(5, 'a'));
-        let node_b = HuffmanNode::Leaf(HuffmanLeafNode::new(3, 'b'));
-        let node_c = HuffmanNode::Leaf(HuffmanLeafNode::new(2, 'c'));
+        let node_a = HuffmanNode::Leaf(HuffmanLeafNode::new(5, b'a'));
+        let node_b = HuffmanNode::Leaf(HuffmanLeafNode::new(3, b'b'));
+        let node_c = HuffmanNode::Leaf(HuffmanLeafNode::new(2, b'c'));
 
         let internal1 = HuffmanInternalNode::new(
             node_b.weight() + node_c.weight(),
@@ -250,25 +906,258 @@ mod tests {
 
         // Expected prefix codes:
         let mut expected_codes = HashMap::new();
-        expected_codes.insert('a', "0".to_string());
-        expected_codes.insert('b', "10".to_string());
-        expected_codes.insert('c', "11".to_string());
+        expected_codes.insert(b'a', "0".to_string());
+        expected_codes.insert(b'b', "10".to_string());
+        expected_codes.insert(b'c', "11".to_string());
 
         // Test if the generated prefix codes match the expected ones
         assert_eq!(codes, expected_codes);
     }
 
+    // Code lengths are the depths of the leaves
+    #[test]
+    fn test_generate_code_lengths() {
+        let mut frequency_map = BTreeMap::new();
+        frequency_map.insert(b'a', 5);
+        frequency_map.insert(b'b', 3);
+        frequency_map.insert(b'c', 2);
+        let root = HuffmanNode::from_frequencies(&frequency_map).unwrap();
+
+        let mut lengths = vec![0u8; 256];
+        root.generate_code_lengths(&mut lengths);
+        assert_eq!(lengths[b'a' as usize], 1);
+        assert_eq!(lengths[b'b' as usize], 2);
+        assert_eq!(lengths[b'c' as usize], 2);
+        assert_eq!(lengths.iter().filter(|&&length| length > 0).count(), 3);
+    }
+
+    // Weights too heavy to add up make no tree, rather than overflow
+    #[test]
+    fn test_from_frequencies_overflow() {
+        let frequency_map: BTreeMap<u8, i32> = [(b'a', i32::MAX), (b'b', 1)].into_iter().collect();
+        assert!(HuffmanNode::from_frequencies(&frequency_map).is_none());
+    }
+
+    // A single symbol still gets a one-bit code
+    #[test]
+    fn test_single_symbol_code_length() {
+        let node_a = HuffmanNode::Leaf(HuffmanLeafNode::new(5, b'a'));
+        let mut lengths = vec![0u8; 256];
+        node_a.generate_code_lengths(&mut lengths);
+        assert_eq!(lengths[b'a' as usize], 1);
+    }
+
+    // Canonical codes as in the DEFLATE specification (RFC 1951, section 3.2.2)
+    #[test]
+    fn test_canonical_codes() {
+        let lengths = [3, 3, 3, 3, 3, 2, 4, 4];
+        let codes = canonical_codes(&lengths).unwrap();
+        let expected = [
+            (0b010, 3), (0b011, 3), (0b100, 3), (0b101, 3),
+            (0b110, 3), (0b00, 2), (0b1110, 4), (0b1111, 4),
+        ];
+        assert_eq!(codes, expected);
+    }
+
+    // Lengths that leave gaps or oversubscribe the code space are rejected
+    #[test]
+    fn test_canonical_codes_rejects_invalid_lengths() {
+        assert!(canonical_codes(&[1, 2]).is_err());
+        assert!(canonical_codes(&[1, 1, 1]).is_err());
+        assert!(canonical_codes(&[64, 1]).is_err());
+        assert!(canonical_codes(&[0, 1, 0]).is_ok());
+    }
+
+    // The tree rebuilt from code lengths hands out the canonical codes
+    #[test]
+    fn test_from_code_lengths_matches_canonical_codes() {
+        let mut lengths = vec![0u8; 256];
+        lengths[b'a' as usize] = 2;
+        lengths[b'b' as usize] = 1;
+        lengths[b'c' as usize] = 3;
+        lengths[b'd' as usize] = 3;
+
+        let root: HuffmanNode = HuffmanNode::from_code_lengths(&lengths).unwrap();
+        let mut codes = HashMap::new();
+        root.generate_prefix_codes(&mut codes);
+
+        assert_eq!(codes[&b'b'], "0");
+        assert_eq!(codes[&b'a'], "10");
+        assert_eq!(codes[&b'c'], "110");
+        assert_eq!(codes[&b'd'], "111");
+    }
+
+    // Wider alphabets share the same tree builder, such as DEFLATE's length
+    // codes 257 to 285 or word tokens past 65535
+    #[test]
+    fn test_wide_symbols() {
+        let frequency_map: BTreeMap<u16, i32> = (257..=285u16).map(|symbol| (symbol, 300 - symbol as i32)).collect();
+        let root = HuffmanNode::from_frequencies(&frequency_map).unwrap();
+        let mut lengths = vec![0u8; 286];
+        root.generate_code_lengths(&mut lengths);
+        assert!(lengths[..257].iter().all(|&length| length == 0));
+        assert!(lengths[257..].iter().all(|&length| length > 0));
+
+        let rebuilt = HuffmanNode::<u16>::from_code_lengths(&lengths).unwrap();
+        let mut codes = HashMap::new();
+        rebuilt.generate_prefix_codes(&mut codes);
+        assert_eq!(codes.len(), 29);
+        assert!(codes.iter().all(|(&symbol, code)| code.len() == lengths[symbol as usize] as usize));
+
+        let mut frequency_map = BTreeMap::new();
+        frequency_map.insert(70_000u32, 5);
+        frequency_map.insert(1_000_000u32, 3);
+        frequency_map.insert(7u32, 2);
+        let mut codes = HashMap::new();
+        HuffmanNode::from_frequencies(&frequency_map).unwrap().generate_prefix_codes(&mut codes);
+        assert_eq!(codes[&70_000].len(), 1);
+        assert_eq!(codes[&1_000_000].len(), 2);
+    }
+
+    // A byte tree can't take a code for a symbol past 255
+    #[test]
+    fn test_code_lengths_past_the_alphabet() {
+        let mut lengths = vec![0u8; 300];
+        lengths[b'a' as usize] = 1;
+        lengths[299] = 1;
+        assert!(matches!(HuffmanNode::<u8>::from_code_lengths(&lengths), Err(Error::InvalidHeader(_))));
+        assert!(HuffmanNode::<u16>::from_code_lengths(&lengths).is_ok());
+    }
+
+    // Same frequencies in a different tie-breaking order give the same canonical tree
+    #[test]
+    fn test_canonical_tree_is_independent_of_tie_breaking() {
+        let mut frequency_map = BTreeMap::new();
+        for &symbol in b"abcde" {
+            frequency_map.insert(symbol, 1);
+        }
+        let root = HuffmanNode::from_frequencies(&frequency_map).unwrap();
+        let mut lengths = vec![0u8; 256];
+        root.generate_code_lengths(&mut lengths);
+
+        let first: HuffmanNode = HuffmanNode::from_code_lengths(&lengths).unwrap();
+        let second = HuffmanNode::from_code_lengths(&lengths).unwrap();
+        let mut first_codes = HashMap::new();
+        let mut second_codes = HashMap::new();
+        first.generate_prefix_codes(&mut first_codes);
+        second.generate_prefix_codes(&mut second_codes);
+        assert_eq!(first_codes, second_codes);
+    }
+
+    fn fibonacci_frequencies(count: usize) -> Vec<u64> {
+        let mut frequencies = vec![1u64, 1];
+        while frequencies.len() < count {
+            let next = frequencies[frequencies.len() - 1] + frequencies[frequencies.len() - 2];
+            frequencies.push(next);
+        }
+        frequencies
+    }
+
+    fn huffman_code_lengths(frequencies: &[u64]) -> Vec<u8> {
+        let frequency_map: BTreeMap<u16, i32> = frequencies
+            .iter()
+            .enumerate()
+            .filter(|(_, &weight)| weight > 0)
+            .map(|(symbol, &weight)| (symbol as u16, weight as i32))
+            .collect();
+        let mut lengths = vec![0u8; frequencies.len()];
+        HuffmanNode::from_frequencies(&frequency_map).unwrap().generate_code_lengths(&mut lengths);
+        lengths
+    }
+
+    fn cost(frequencies: &[u64], lengths: &[u8]) -> u64 {
+        frequencies.iter().zip(lengths).map(|(&weight, &length)| weight * length as u64).sum()
+    }
+
+    // Fibonacci weights make the unconstrained tree as deep as possible
+    #[test]
+    fn test_length_limit_on_fibonacci_frequencies() {
+        let frequencies = fibonacci_frequencies(40);
+        let unconstrained = huffman_code_lengths(&frequencies);
+        assert_eq!(*unconstrained.iter().max().unwrap(), 39);
+
+        for max_length in [6, 8, 12, 15] {
+            let lengths = length_limited_code_lengths(&frequencies, max_length);
+            assert_eq!(*lengths.iter().max().unwrap(), max_length);
+            assert!(lengths.iter().all(|&length| length > 0));
+            // Still a complete prefix code
+            assert!(canonical_codes(&lengths).is_ok());
+            assert!(cost(&frequencies, &lengths) >= cost(&frequencies, &unconstrained));
+        }
+    }
+
+    // When the limit doesn't bind, package-merge is as good as plain Huffman
+    #[test]
+    fn test_length_limit_matches_huffman_when_not_binding() {
+        let frequencies = [45u64, 13, 12, 16, 9, 5, 0, 0, 7, 1];
+        let unconstrained = huffman_code_lengths(&frequencies);
+        let lengths = length_limited_code_lengths(&frequencies, 15);
+        assert_eq!(cost(&frequencies, &lengths), cost(&frequencies, &unconstrained));
+        assert_eq!(lengths[6], 0);
+        assert_eq!(lengths[7], 0);
+    }
+
+    // The tightest possible limit gives every symbol the same length
+    #[test]
+    fn test_length_limit_at_minimum() {
+        let frequencies = fibonacci_frequencies(16);
+        let lengths = length_limited_code_lengths(&frequencies, 4);
+        assert!(lengths.iter().all(|&length| length == 4));
+    }
+
+    // Wide alphabets stay cheap, as packages don't carry their symbols around
+    #[test]
+    fn test_length_limit_on_a_wide_alphabet() {
+        let frequencies: Vec<u64> = (0..4096u64).map(|symbol| 1 + symbol * symbol % 997).collect();
+        let lengths = length_limited_code_lengths(&frequencies, 15);
+        assert!(lengths.iter().all(|&length| (1..=15).contains(&length)));
+        assert!(canonical_codes(&lengths).is_ok());
+        let unconstrained = huffman_code_lengths(&frequencies);
+        assert!(cost(&frequencies, &lengths) >= cost(&frequencies, &unconstrained));
+        let loose = length_limited_code_lengths(&frequencies, 30);
+        assert_eq!(cost(&frequencies, &loose), cost(&frequencies, &unconstrained));
+    }
+
+    #[test]
+    fn test_length_limit_degenerate_alphabets() {
+        assert_eq!(length_limited_code_lengths(&[0, 0, 0], 15), vec![0, 0, 0]);
+        assert_eq!(length_limited_code_lengths(&[0, 7, 0], 15), vec![0, 1, 0]);
+        assert_eq!(length_limited_code_lengths(&[3, 7], 1), vec![1, 1]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_length_limit_too_small_for_alphabet() {
+        length_limited_code_lengths(&[1, 1, 1, 1, 1], 2);
+    }
+
+    #[test]
+    fn test_code_length_table_round_trip() {
+        let mut lengths = vec![0u8; 256];
+        lengths[10] = 4;
+        lengths[32..127].fill(7);
+        lengths[255] = 12;
+
+        let encoded = encode_code_lengths(&lengths);
+        assert!(encoded.len() < 256);
+        let decoded = decode_code_lengths(&mut std::io::Cursor::new(encoded), 256).unwrap();
+        assert_eq!(decoded, lengths);
+
+        // An all-zero table is a single run of 256
+        assert_eq!(encode_code_lengths(&[0u8; 256]), vec![0, 255]);
+    }
+
     // Test with an empty tree (single node case)
     #[test]
     fn test_single_node_tree() {
         // Single node tree (just one leaf node)
-        let node_a = HuffmanNode::Leaf(HuffmanLeafNode::new(5, 'a'));
+        let node_a = HuffmanNode::Leaf(HuffmanLeafNode::new(5, b'a'));
 
         let mut codes = HashMap::new();
         node_a.generate_prefix_codes(&mut codes);
 
         let mut expected_codes = HashMap::new();
-        expected_codes.insert('a', "".to_string());  // No prefix for a single node
+        expected_codes.insert(b'a', "0".to_string());  // One bit per symbol, as the decoder expects
 
         // Test if the generated prefix codes match the expected ones
         assert_eq!(codes, expected_codes);
@@ -284,10 +1173,10 @@ mod tests {
         //   /    \     /     \
         //  Leaf(a) Leaf(b) Leaf(c) Leaf(d)
 
-        let node_a = HuffmanNode::Leaf(HuffmanLeafNode::new(5, 'a'));
-        let node_b = HuffmanNode::Leaf(HuffmanLeafNode::new(3, 'b'));
-        let node_c = HuffmanNode::Leaf(HuffmanLeafNode::new(2, 'c'));
-        let node_d = HuffmanNode::Leaf(HuffmanLeafNode::new(1, 'd'));
+        let node_a = HuffmanNode::Leaf(HuffmanLeafNode::new(5, b'a'));
+        let node_b = HuffmanNode::Leaf(HuffmanLeafNode::new(3, b'b'));
+        let node_c = HuffmanNode::Leaf(HuffmanLeafNode::new(2, b'c'));
+        let node_d = HuffmanNode::Leaf(HuffmanLeafNode::new(1, b'd'));
 
         let internal1 = HuffmanNode::Internal(HuffmanInternalNode::new(
             node_a.weight() + node_b.weight(),
@@ -313,13 +1202,142 @@ mod tests {
 
         // Expected prefix codes for the given tree
         let mut expected_codes = HashMap::new();
-        expected_codes.insert('a', "00".to_string());
-        expected_codes.insert('b', "01".to_string());
-        expected_codes.insert('c', "10".to_string());
-        expected_codes.insert('d', "11".to_string());
+        expected_codes.insert(b'a', "00".to_string());
+        expected_codes.insert(b'b', "01".to_string());
+        expected_codes.insert(b'c', "10".to_string());
+        expected_codes.insert(b'd', "11".to_string());
 
         // Test if the generated prefix codes match the expected ones
         assert_eq!(codes, expected_codes);
     }
-}
 
+    // Check the sibling property in Vitter's form and return the code length
+    // of every symbol seen so far
+    fn check_adaptive_tree(tree: &AdaptiveHuffman) -> Vec<u8> {
+        for (position, &node) in tree.order.iter().enumerate() {
+            assert_eq!(tree.position[node], position);
+            let weight = tree.nodes[node].weight;
+            if let Some(children) = tree.nodes[node].children {
+                assert_eq!(weight, tree.nodes[children[0]].weight + tree.nodes[children[1]].weight);
+                assert!(children.iter().all(|&child| tree.position[child] < position && tree.nodes[child].parent == node));
+            }
+            if let Some(&next) = tree.order.get(position + 1) {
+                let next_weight = tree.nodes[next].weight;
+                assert!(weight <= next_weight, "weights decrease at position {}", position);
+                if weight == next_weight {
+                    assert!(tree.nodes[node].children.is_none() || tree.nodes[next].children.is_some(), "leaf after internal node");
+                }
+            }
+        }
+
+        let mut lengths = vec![0u8; tree.leaves.len()];
+        for (symbol, &leaf) in tree.leaves.iter().enumerate() {
+            let mut node = leaf;
+            while node != NO_NODE && tree.nodes[node].parent != NO_NODE {
+                lengths[symbol] += 1;
+                node = tree.nodes[node].parent;
+            }
+        }
+        lengths
+    }
+
+    fn adaptive_round_trip(alphabet_size: usize, symbols: &[u16]) -> Vec<u8> {
+        let mut encoder = AdaptiveHuffman::new(alphabet_size);
+        let mut bit_writer = BitWriter::new(Vec::new());
+        for &symbol in symbols {
+            encoder.encode(symbol, &mut bit_writer).unwrap();
+        }
+        let encoded = bit_writer.finish().unwrap();
+
+        let mut decoder = AdaptiveHuffman::new(alphabet_size);
+        let mut bit_reader = BitReader::new(&encoded[..]);
+        for &symbol in symbols {
+            assert_eq!(decoder.decode(&mut bit_reader).unwrap(), symbol);
+        }
+        encoded
+    }
+
+    #[test]
+    fn test_adaptive_round_trip() {
+        let text: Vec<u16> = b"abracadabra, the adaptive huffman tree grows as it goes".iter().map(|&byte| byte as u16).collect();
+        adaptive_round_trip(256, &text);
+        adaptive_round_trip(257, &[256, 0, 255, 256, 256]);
+        adaptive_round_trip(2, &[1, 1, 1, 0, 1, 0, 0]);
+
+        // A single symbol repeated costs one bit per repeat once it is known
+        let encoded = adaptive_round_trip(256, &[7; 801]);
+        assert_eq!(encoded.len(), (8 + 800usize).div_ceil(8));
+    }
+
+    // After every symbol the tree is a Huffman tree for the counts so far. The
+    // NYT leaf of weight 0 costs an extra bit for the rarest symbol on top of
+    // the cost of a Huffman code for the symbols alone.
+    #[test]
+    fn test_adaptive_tree_stays_optimal() {
+        let mut state = 12345u64;
+        let mut tree = AdaptiveHuffman::new(16);
+        let mut counts = vec![0u64; 16];
+        for step in 0..3000 {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            // Skewed towards small symbols, and shifting over time
+            let symbol = (((state >> 33) % 16) * ((state >> 40) % 16) / 16 + step / 1000) as usize % 16;
+            tree.update(symbol);
+            counts[symbol] += 1;
+
+            let lengths = check_adaptive_tree(&tree);
+            let used = counts.iter().filter(|&&count| count > 0).count();
+            if used >= 2 {
+                let rarest = counts.iter().filter(|&&count| count > 0).min().unwrap();
+                let optimal = cost(&counts, &huffman_code_lengths(&counts));
+                assert_eq!(cost(&counts, &lengths), optimal + rarest, "step {}", step);
+            }
+        }
+    }
+
+    #[test]
+    fn test_adaptive_rejects_invalid_new_symbols() {
+        // The NYT code of an empty tree is empty, so this is symbol 300
+        let mut bit_writer = BitWriter::new(Vec::new());
+        bit_writer.write_bits(300, 9).unwrap();
+        let encoded = bit_writer.finish().unwrap();
+        let mut decoder = AdaptiveHuffman::new(257);
+        assert!(matches!(decoder.decode(&mut BitReader::new(&encoded[..])), Err(Error::CorruptData(_))));
+    }
+
+    #[test]
+    fn test_multi_table_code_lengths() {
+        // Two stretches with different statistics
+        let mut symbols: Vec<u16> = (0..3000u16).map(|i| i % 4).collect();
+        symbols.extend((0..3000u16).map(|i| 10 + i % 8));
+        let (tables, selectors) = multi_table_code_lengths(&symbols, 20, 15, false);
+        assert_eq!(tables.len(), MAX_TABLES);
+        assert_eq!(selectors.len(), symbols.len().div_ceil(GROUP_SIZE));
+
+        // Every table codes every symbol in use, and no others
+        for lengths in &tables {
+            for symbol in 0..20u16 {
+                assert_eq!(lengths[symbol as usize] > 0, symbols.contains(&symbol), "symbol {}", symbol);
+            }
+        }
+
+        // Switching tables beats the best single table by a wide margin
+        let switched: u64 = symbols
+            .chunks(GROUP_SIZE)
+            .zip(&selectors)
+            .map(|(group, &table)| group.iter().map(|&symbol| tables[table as usize][symbol as usize] as u64).sum::<u64>())
+            .sum();
+        let mut counts = vec![0u64; 20];
+        for &symbol in &symbols {
+            counts[symbol as usize] += 1;
+        }
+        let single = cost(&counts, &length_limited_code_lengths(&counts, 15));
+        assert!(switched * 10 < single * 9, "{} vs {}", switched, single);
+
+        let (tables, selectors) = multi_table_code_lengths(&[7; 10], 20, 15, false);
+        assert_eq!((tables.len(), selectors), (2, vec![0]));
+
+        // bzip2 wants a code for every symbol of the alphabet
+        let (tables, _) = multi_table_code_lengths(&[7; 10], 20, 15, true);
+        assert!(tables.iter().flatten().all(|&length| length > 0));
+    }
+}
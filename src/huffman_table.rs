@@ -0,0 +1,433 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use crate::bitio::{BitReader, CodeBits};
+use crate::error::{Error, Result};
+use crate::huffman::{canonical_codes, DECODE_BUFFER_SIZE};
+
+// Number of bits the primary table is indexed by
+pub const PRIMARY_TABLE_BITS: u8 = 11;
+
+// Secondary tables are indexed by at most this many bits; codes that are
+// longer still continue in further tables
+const SECONDARY_TABLE_BITS: u8 = 8;
+
+// Most symbols a single primary table entry decodes at once
+const MAX_SYMBOLS_PER_ENTRY: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    // No code starts with these bits
+    Invalid,
+    // One or more complete codes. `ends[i]` is the number of bits (counted
+    // from the start of this table's index) used up once symbols[i] is decoded.
+    Symbols {
+        count: u8,
+        symbols: [u16; MAX_SYMBOLS_PER_ENTRY],
+        ends: [u8; MAX_SYMBOLS_PER_ENTRY],
+    },
+    // The code is longer than this table; continue with the subtable at
+    // `start`, indexed by the next `bits` bits
+    Subtable { start: u32, bits: u8 },
+}
+
+impl Entry {
+    fn single(symbol: u16, length: u8) -> Self {
+        let mut symbols = [0u16; MAX_SYMBOLS_PER_ENTRY];
+        let mut ends = [0u8; MAX_SYMBOLS_PER_ENTRY];
+        symbols[0] = symbol;
+        ends[0] = length;
+        Entry::Symbols { count: 1, symbols, ends }
+    }
+}
+
+// Lookup-table Huffman decoder. The next PRIMARY_TABLE_BITS bits of the stream
+// index a primary table whose entries either decode one or more short codes at
+// once or point to a secondary table for the rest of a longer code.
+pub struct HuffmanDecodeTable {
+    // All tables, primary first, laid out one after another
+    entries: Vec<Entry>,
+    primary_bits: u8,
+    // Number of symbols the code lengths were given for
+    alphabet_size: usize,
+}
+
+impl HuffmanDecodeTable {
+    // Build the tables for a set of canonical code lengths (indexed by symbol,
+    // 0 meaning the symbol is unused). Alphabets may have up to 65536 symbols.
+    pub fn from_code_lengths(lengths: &[u8]) -> Result<Self> {
+        debug_assert!(lengths.len() <= 1 << 16);
+        let codes: Vec<(u64, u8, u16)> = canonical_codes(lengths)?
+            .iter()
+            .enumerate()
+            .filter(|(_, &(_, length))| length > 0)
+            .map(|(symbol, &(code, length))| (code, length, symbol as u16))
+            .collect();
+
+        let max_length = match codes.iter().map(|&(_, length, _)| length).max() {
+            Some(max_length) => max_length,
+            None => return Err(Error::InvalidHeader("code length table has no symbols".to_string())),
+        };
+
+        let primary_bits = max_length.min(PRIMARY_TABLE_BITS);
+        let mut table = HuffmanDecodeTable {
+            entries: Vec::new(),
+            primary_bits,
+            alphabet_size: lengths.len(),
+        };
+        table.build_level(&codes, 0, primary_bits);
+        table.combine_primary_entries();
+        Ok(table)
+    }
+
+    // Fill a table indexed by `bits` bits for codes whose first `consumed` bits
+    // have already been matched. Returns where the table starts.
+    fn build_level(&mut self, codes: &[(u64, u8, u16)], consumed: u8, bits: u8) -> u32 {
+        let start = self.entries.len();
+        self.entries.resize(start + (1 << bits), Entry::Invalid);
+
+        // Codes that fit in this table fill every entry they are a prefix of;
+        // longer ones are grouped by their first `bits` remaining bits
+        let mut longer: BTreeMap<usize, Vec<(u64, u8, u16)>> = BTreeMap::new();
+        for &(code, length, symbol) in codes {
+            let remaining = length - consumed;
+            let rest = code & ((1u64 << remaining) - 1);
+            if remaining <= bits {
+                let first = (rest << (bits - remaining)) as usize;
+                for index in first..first + (1 << (bits - remaining)) {
+                    self.entries[start + index] = Entry::single(symbol, remaining);
+                }
+            } else {
+                let prefix = (rest >> (remaining - bits)) as usize;
+                longer.entry(prefix).or_default().push((code, length, symbol));
+            }
+        }
+
+        for (prefix, group) in longer {
+            let longest = group.iter().map(|&(_, length, _)| length).max().unwrap();
+            let sub_bits = (longest - consumed - bits).min(SECONDARY_TABLE_BITS);
+            let sub_start = self.build_level(&group, consumed + bits, sub_bits);
+            self.entries[start + prefix] = Entry::Subtable { start: sub_start, bits: sub_bits };
+        }
+
+        start as u32
+    }
+
+    // Let primary entries decode several short codes at once: if a code leaves
+    // enough of the index over for another complete code, append that symbol too
+    fn combine_primary_entries(&mut self) {
+        let bits = self.primary_bits;
+        let size = 1usize << bits;
+        let singles: Vec<Entry> = self.entries[..size].to_vec();
+
+        for index in 0..size {
+            let Entry::Symbols { mut count, mut symbols, mut ends } = singles[index] else {
+                continue;
+            };
+
+            while (count as usize) < MAX_SYMBOLS_PER_ENTRY {
+                let used = ends[count as usize - 1];
+                if used >= bits {
+                    break;
+                }
+                // Shift out the bits already used; the bits shifted in are not
+                // real, so the next code must fit in what is left
+                let next_index = (index << used) & (size - 1);
+                match singles[next_index] {
+                    Entry::Symbols { symbols: next_symbols, ends: next_ends, .. } if used + next_ends[0] <= bits => {
+                        symbols[count as usize] = next_symbols[0];
+                        ends[count as usize] = used + next_ends[0];
+                        count += 1;
+                    },
+                    _ => break,
+                }
+            }
+
+            self.entries[index] = Entry::Symbols { count, symbols, ends };
+        }
+    }
+
+    // Decode `payload_bits` bits from the stream, writing out the symbols as
+    // bytes. Only for byte alphabets; wider ones go through decode_symbol.
+    pub fn decode<R: Read, W: Write>(&self, bit_reader: &mut BitReader<R>, payload_bits: u64, writer: &mut W) -> Result<()> {
+        if self.alphabet_size > 256 {
+            return Err(Error::InvalidHeader(format!("a table of {} symbols doesn't decode to bytes", self.alphabet_size)));
+        }
+        let mut output: Vec<u8> = Vec::with_capacity(DECODE_BUFFER_SIZE);
+        let mut remaining = payload_bits;
+
+        while remaining > 0 {
+            // Never read past the end of the payload; bits beyond it count as
+            // zeros, and any code that would need them is rejected below
+            let mut bits = self.primary_bits as u32;
+            let mut available = remaining.min(bits as u64) as u32;
+            let mut entry = self.entries[(bit_reader.peek_bits(available)? << (bits - available)) as usize];
+
+            loop {
+                match entry {
+                    Entry::Symbols { count, symbols, ends } => {
+                        let mut used = 0;
+                        for i in 0..count as usize {
+                            if ends[i] as u32 > available {
+                                break;
+                            }
+                            output.push(symbols[i] as u8);
+                            used = ends[i] as u32;
+                        }
+                        if used == 0 {
+                            return Err(Error::CorruptData("payload ends in the middle of a code".to_string()));
+                        }
+                        bit_reader.consume(used);
+                        remaining -= used as u64;
+                        break;
+                    },
+                    Entry::Subtable { start, bits: sub_bits } => {
+                        if available < bits {
+                            return Err(Error::CorruptData("payload ends in the middle of a code".to_string()));
+                        }
+                        bit_reader.consume(bits);
+                        remaining -= bits as u64;
+
+                        bits = sub_bits as u32;
+                        available = remaining.min(bits as u64) as u32;
+                        let index = (bit_reader.peek_bits(available)? << (bits - available)) as usize;
+                        entry = self.entries[start as usize + index];
+                    },
+                    Entry::Invalid => {
+                        return Err(Error::CorruptData("invalid code in payload".to_string()));
+                    },
+                }
+            }
+
+            if output.len() + MAX_SYMBOLS_PER_ENTRY > DECODE_BUFFER_SIZE {
+                writer.write_all(&output)?;
+                output.clear();
+            }
+        }
+
+        writer.write_all(&output)?;
+        Ok(())
+    }
+
+    // Decode a single symbol, taking its bits out of the `remaining` payload
+    // bits. Streams without a known length pass u64::MAX.
+    pub fn decode_symbol<B: CodeBits>(&self, bit_reader: &mut B, remaining: &mut u64) -> Result<u16> {
+        let mut bits = self.primary_bits as u32;
+        let mut available = (*remaining).min(bits as u64) as u32;
+        let mut entry = self.entries[(bit_reader.peek_code_bits(available)? << (bits - available)) as usize];
+
+        loop {
+            match entry {
+                Entry::Symbols { symbols, ends, .. } => {
+                    let used = ends[0] as u32;
+                    if used > available {
+                        return Err(Error::CorruptData("payload ends in the middle of a code".to_string()));
+                    }
+                    bit_reader.consume_code_bits(used)?;
+                    *remaining -= used as u64;
+                    return Ok(symbols[0]);
+                },
+                Entry::Subtable { start, bits: sub_bits } => {
+                    if available < bits {
+                        return Err(Error::CorruptData("payload ends in the middle of a code".to_string()));
+                    }
+                    bit_reader.consume_code_bits(bits)?;
+                    *remaining -= bits as u64;
+
+                    bits = sub_bits as u32;
+                    available = (*remaining).min(bits as u64) as u32;
+                    let index = (bit_reader.peek_code_bits(available)? << (bits - available)) as usize;
+                    entry = self.entries[start as usize + index];
+                },
+                Entry::Invalid => {
+                    return Err(Error::CorruptData("invalid code in payload".to_string()));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitio::BitWriter;
+    use crate::huffman::{length_limited_code_lengths, HuffmanNode};
+    use std::io::Cursor;
+
+    // Small deterministic pseudo-random generator so the tests are reproducible
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+    }
+
+    fn encode(lengths: &[u8], symbols: &[u8]) -> (Vec<u8>, u64) {
+        let codes = canonical_codes(lengths).unwrap();
+        let mut writer = BitWriter::new(Vec::new());
+        for &symbol in symbols {
+            let (code, length) = codes[symbol as usize];
+            writer.write_bits(code, length as u32).unwrap();
+        }
+        let bits = writer.bits_written();
+        (writer.finish().unwrap(), bits)
+    }
+
+    fn decode_with_table(lengths: &[u8], bytes: &[u8], bits: u64) -> Result<Vec<u8>> {
+        let table = HuffmanDecodeTable::from_code_lengths(lengths)?;
+        let mut output = Vec::new();
+        table.decode(&mut BitReader::new(Cursor::new(bytes)), bits, &mut output)?;
+        Ok(output)
+    }
+
+    fn decode_with_tree(lengths: &[u8], bytes: &[u8], bits: u64) -> Result<Vec<u8>> {
+        let tree: HuffmanNode = HuffmanNode::from_code_lengths(lengths)?;
+        let mut output = Vec::new();
+        tree.decode(&mut BitReader::new(Cursor::new(bytes)), bits, &mut output)?;
+        Ok(output)
+    }
+
+    // Frequencies drawn at random, plus a Fibonacci tail for very long codes
+    fn random_frequencies(rng: &mut Lcg, symbols: usize, fibonacci_tail: usize) -> Vec<u64> {
+        let mut frequencies: Vec<u64> = (0..256)
+            .map(|symbol| if symbol < symbols { rng.next() % 1000 } else { 0 })
+            .collect();
+        let (mut a, mut b) = (1u64, 1u64);
+        for frequency in frequencies.iter_mut().rev().take(fibonacci_tail) {
+            *frequency = a;
+            (a, b) = (b, a + b);
+        }
+        frequencies
+    }
+
+    // The table decoder must agree with the tree walker on valid streams
+    #[test]
+    fn test_differential_against_tree_walker() {
+        let mut rng = Lcg(42);
+        for round in 0..40 {
+            let symbols = 2 + (rng.next() % 255) as usize;
+            let tail = if round % 2 == 0 { 0 } else { 40 };
+            let max_length = [9, 12, 15, 24, 40][round % 5];
+            let frequencies = random_frequencies(&mut rng, symbols, tail);
+            let lengths = length_limited_code_lengths(&frequencies, max_length);
+
+            let used: Vec<u8> = (0..=255u8).filter(|&symbol| lengths[symbol as usize] > 0).collect();
+            let input: Vec<u8> = (0..2000).map(|_| used[(rng.next() % used.len() as u64) as usize]).collect();
+            let (bytes, bits) = encode(&lengths, &input);
+
+            let from_table = decode_with_table(&lengths, &bytes, bits).unwrap();
+            let from_tree = decode_with_tree(&lengths, &bytes, bits).unwrap();
+            assert_eq!(from_table, input);
+            assert_eq!(from_tree, input);
+        }
+    }
+
+    // On arbitrary bit strings both decoders succeed or fail together
+    #[test]
+    fn test_differential_on_random_bits() {
+        let mut rng = Lcg(7);
+        for round in 0..200 {
+            let frequencies = random_frequencies(&mut rng, 2 + round % 60, round % 30);
+            let lengths = length_limited_code_lengths(&frequencies, 30);
+
+            let bytes: Vec<u8> = (0..64).map(|_| rng.next() as u8).collect();
+            let bits = rng.next() % (bytes.len() as u64 * 8);
+
+            let from_table = decode_with_table(&lengths, &bytes, bits);
+            let from_tree = decode_with_tree(&lengths, &bytes, bits);
+            match (from_table, from_tree) {
+                (Ok(table_output), Ok(tree_output)) => assert_eq!(table_output, tree_output),
+                (Err(_), Err(_)) => {},
+                (table_result, tree_result) => panic!("decoders disagree: {:?} vs {:?}", table_result, tree_result),
+            }
+        }
+    }
+
+    // Short codes get packed several to an entry
+    #[test]
+    fn test_multiple_symbols_per_lookup() {
+        let mut lengths = vec![0u8; 256];
+        lengths[b'a' as usize] = 1;
+        lengths[b'b' as usize] = 2;
+        lengths[b'c' as usize] = 3;
+        lengths[b'd' as usize] = 3;
+        let table = HuffmanDecodeTable::from_code_lengths(&lengths).unwrap();
+
+        // 001 decodes as "a", "a"; the final 1 starts a code that doesn't fit
+        match table.entries[0b001] {
+            Entry::Symbols { count, symbols, ends } => {
+                assert_eq!(count, 2);
+                assert_eq!(&symbols[..2], &[b'a' as u16; 2]);
+                assert_eq!(&ends[..2], &[1, 2]);
+            },
+            entry => panic!("unexpected entry {:?}", entry),
+        }
+    }
+
+    // Symbols beyond a byte decode one at a time, and the payload length is
+    // enforced the same way
+    #[test]
+    fn test_decode_symbol_large_alphabet() {
+        let mut rng = Lcg(3);
+        let frequencies: Vec<u64> = (0..300).map(|_| rng.next() % 50).collect();
+        let lengths = length_limited_code_lengths(&frequencies, 15);
+        let codes = canonical_codes(&lengths).unwrap();
+
+        let used: Vec<u16> = (0..300u16).filter(|&symbol| lengths[symbol as usize] > 0).collect();
+        let input: Vec<u16> = (0..1000).map(|_| used[(rng.next() % used.len() as u64) as usize]).collect();
+        let mut writer = BitWriter::new(Vec::new());
+        for &symbol in &input {
+            let (code, length) = codes[symbol as usize];
+            writer.write_bits(code, length as u32).unwrap();
+        }
+        let bits = writer.bits_written();
+        let bytes = writer.finish().unwrap();
+
+        let table = HuffmanDecodeTable::from_code_lengths(&lengths).unwrap();
+        let mut bit_reader = BitReader::new(Cursor::new(&bytes));
+        let mut remaining = bits;
+        for &symbol in &input {
+            assert_eq!(table.decode_symbol(&mut bit_reader, &mut remaining).unwrap(), symbol);
+        }
+        assert_eq!(remaining, 0);
+        assert!(table.decode_symbol(&mut bit_reader, &mut remaining).is_err());
+    }
+
+    // Byte decoding refuses a table over a wider alphabet rather than
+    // truncating its symbols
+    #[test]
+    fn test_decode_rejects_wide_alphabet() {
+        let mut lengths = vec![0u8; 300];
+        lengths[1] = 1;
+        lengths[299] = 1;
+
+        assert!(matches!(decode_with_table(&lengths, &[0x40], 2), Err(Error::InvalidHeader(_))));
+    }
+
+    // A single symbol only decodes from zero bits
+    #[test]
+    fn test_single_symbol_code() {
+        let mut lengths = vec![0u8; 256];
+        lengths[b'z' as usize] = 1;
+
+        assert_eq!(decode_with_table(&lengths, &[0x00], 5).unwrap(), b"zzzzz");
+        assert!(decode_with_table(&lengths, &[0x80], 1).is_err());
+        assert!(decode_with_tree(&lengths, &[0x80], 1).is_err());
+    }
+
+    // Codes cut off by the end of the payload are errors, even when the bits
+    // after the payload would complete them
+    #[test]
+    fn test_payload_ending_mid_code() {
+        let mut lengths = vec![0u8; 256];
+        lengths[0] = 1;
+        lengths[1] = 2;
+        lengths[2] = 2;
+        let (bytes, bits) = encode(&lengths, &[1, 2, 1]);
+
+        assert_eq!(decode_with_table(&lengths, &bytes, bits).unwrap(), vec![1, 2, 1]);
+        assert!(decode_with_table(&lengths, &bytes, bits - 1).is_err());
+        assert!(decode_with_tree(&lengths, &bytes, bits - 1).is_err());
+    }
+}
@@ -1,3 +1,21 @@
+pub mod bitio;
+pub mod bwt;
+pub mod bzip2;
+pub mod checksum;
+pub mod cli;
 pub mod compression;
 pub mod decompression;
-pub mod huffman;
\ No newline at end of file
+pub mod deflate;
+pub mod error;
+pub mod format;
+pub mod fse;
+pub mod gzip;
+pub mod huffman;
+pub mod huffman_table;
+pub mod lz77;
+pub mod lzw;
+pub mod range_coder;
+pub mod rle;
+pub mod zlib;
+
+pub use error::{Error, Result};
@@ -0,0 +1,314 @@
+// LZ77 match finding. The input is turned into a sequence of literals and
+// back references (length, distance) into the data seen before, which the
+// block coder then Huffman-codes as two alphabets: literals and lengths share
+// one, distances get their own.
+
+// Shortest and longest back reference
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
+
+// Window sizes the match finder accepts. The window has to be a power of two.
+pub const MIN_WINDOW_SIZE: usize = 1 << 8;
+pub const MAX_WINDOW_SIZE: usize = 1 << 24;
+pub const DEFAULT_WINDOW_SIZE: usize = 1 << 15;
+
+// Literal/length alphabet: the 256 byte values followed by the length codes
+pub const NUM_LENGTH_CODES: usize = 16;
+pub const LITERAL_LENGTH_ALPHABET_SIZE: usize = 256 + NUM_LENGTH_CODES;
+
+// Distance alphabet, enough for distances up to MAX_WINDOW_SIZE
+pub const DISTANCE_ALPHABET_SIZE: usize = 48;
+
+const HASH_BITS: u32 = 15;
+
+// Positions tried per match search
+const MAX_CHAIN_LENGTH: usize = 128;
+
+// Stop searching once a match is this long
+const NICE_MATCH: usize = 128;
+
+// Don't look for a better match at the next position once a match is this long
+const LAZY_MATCH_LIMIT: usize = 32;
+
+// A minimum length match this far back costs more than three literals
+const TOO_FAR: usize = 4096;
+
+const NO_POSITION: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u32 },
+}
+
+// Split a value into a code and extra bits on a logarithmic scale: values
+// below 4 get a code of their own, after that every power of two is split
+// into two codes whose extra bits pick the value within the range.
+// Returns (code, number of extra bits, extra bits).
+pub fn value_to_code(value: u32) -> (u16, u32, u32) {
+    if value < 4 {
+        return (value as u16, 0, 0);
+    }
+    let log = 31 - value.leading_zeros();
+    let half = (value >> (log - 1)) & 1;
+    let extra_bits = log - 1;
+    ((2 * log + half) as u16, extra_bits, value & ((1 << extra_bits) - 1))
+}
+
+// The smallest value of a code and the number of extra bits that follow it
+pub fn code_to_value(code: u16) -> (u32, u32) {
+    let code = code as u32;
+    if code < 4 {
+        return (code, 0);
+    }
+    let log = code / 2;
+    let half = code & 1;
+    ((2 | half) << (log - 1), log - 1)
+}
+
+// Hash-chain match finder. `head` holds the most recent position for each
+// hash of the next MIN_MATCH bytes and `prev` links every position to the
+// previous one with the same hash, within the window.
+pub struct MatchFinder {
+    window_size: usize,
+    lazy_matching: bool,
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl MatchFinder {
+    pub fn new(window_size: usize, lazy_matching: bool) -> Self {
+        assert!(
+            window_size.is_power_of_two() && (MIN_WINDOW_SIZE..=MAX_WINDOW_SIZE).contains(&window_size),
+            "window size must be a power of two between {} and {}",
+            MIN_WINDOW_SIZE,
+            MAX_WINDOW_SIZE
+        );
+        MatchFinder {
+            window_size,
+            lazy_matching,
+            head: vec![NO_POSITION; 1 << HASH_BITS],
+            prev: vec![NO_POSITION; window_size],
+        }
+    }
+
+    // Turn `data` into tokens. Matches never reach before the start of `data`,
+    // so every block can be decoded on its own.
+    pub fn tokenize(&mut self, data: &[u8]) -> Vec<Token> {
+        self.tokenize_from(data, 0)
+    }
+
+    // Turn `data[start..]` into tokens. Matches may reach back into the
+    // history in `data[..start]`, as far as the window allows.
+    pub fn tokenize_from(&mut self, data: &[u8], start: usize) -> Vec<Token> {
+        self.head.fill(NO_POSITION);
+        for position in start.saturating_sub(self.window_size)..start {
+            self.insert(data, position);
+        }
+
+        let mut tokens = Vec::with_capacity((data.len() - start) / 4);
+        let mut position = start;
+
+        // Match already found at `position` by the lazy evaluation
+        let mut pending: Option<(usize, usize)> = None;
+
+        while position < data.len() {
+            let (length, distance) = match pending.take() {
+                Some(found) => found,
+                None => {
+                    let found = self.longest_match(data, position);
+                    self.insert(data, position);
+                    found
+                },
+            };
+
+            if length < MIN_MATCH {
+                tokens.push(Token::Literal(data[position]));
+                position += 1;
+                continue;
+            }
+
+            // Lazy matching: if the next position starts a longer match, emit
+            // a literal here and take that one instead
+            if self.lazy_matching && length < LAZY_MATCH_LIMIT && position + 1 < data.len() {
+                let next = self.longest_match(data, position + 1);
+                self.insert(data, position + 1);
+                if next.0 > length {
+                    tokens.push(Token::Literal(data[position]));
+                    position += 1;
+                    pending = Some(next);
+                    continue;
+                }
+                for skipped in position + 2..position + length {
+                    self.insert(data, skipped);
+                }
+            } else {
+                for skipped in position + 1..position + length {
+                    self.insert(data, skipped);
+                }
+            }
+
+            tokens.push(Token::Match { length: length as u16, distance: distance as u32 });
+            position += length;
+        }
+        tokens
+    }
+
+    fn hash(data: &[u8], position: usize) -> usize {
+        let value = (data[position] as u32) << 16 | (data[position + 1] as u32) << 8 | data[position + 2] as u32;
+        (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], position: usize) {
+        if position + MIN_MATCH > data.len() {
+            return;
+        }
+        let hash = Self::hash(data, position);
+        self.prev[position & (self.window_size - 1)] = self.head[hash];
+        self.head[hash] = position as u32;
+    }
+
+    // Longest match for `position` among the earlier positions with the same
+    // hash, as (length, distance); the length is 0 if there is none
+    fn longest_match(&self, data: &[u8], position: usize) -> (usize, usize) {
+        if position + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let max_length = MAX_MATCH.min(data.len() - position);
+        let mut best_length = MIN_MATCH - 1;
+        let mut best_distance = 0;
+
+        let mut candidate = self.head[Self::hash(data, position)];
+        let mut chain = MAX_CHAIN_LENGTH;
+        while candidate != NO_POSITION && chain > 0 {
+            let start = candidate as usize;
+            let distance = position - start;
+            if distance > self.window_size {
+                break;
+            }
+
+            // Only compare in full if this candidate could beat the best so far
+            if data[start + best_length] == data[position + best_length] {
+                let length = data[start..start + max_length]
+                    .iter()
+                    .zip(&data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = distance;
+                    if length >= NICE_MATCH.min(max_length) {
+                        break;
+                    }
+                }
+            }
+
+            // Links older than the window may have been overwritten by newer positions
+            let next = self.prev[start & (self.window_size - 1)];
+            if next == NO_POSITION || next as usize >= start {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+
+        if best_length < MIN_MATCH || (best_length == MIN_MATCH && best_distance > TOO_FAR) {
+            return (0, 0);
+        }
+        (best_length, best_distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rebuild the data from its tokens
+    fn expand(tokens: &[Token]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for &token in tokens {
+            match token {
+                Token::Literal(byte) => output.push(byte),
+                Token::Match { length, distance } => {
+                    let start = output.len() - distance as usize;
+                    for i in 0..length as usize {
+                        output.push(output[start + i]);
+                    }
+                },
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn test_codes_round_trip() {
+        for value in 0..70_000u32 {
+            let (code, extra_bits, extra) = value_to_code(value);
+            let (base, base_extra_bits) = code_to_value(code);
+            assert_eq!(extra_bits, base_extra_bits);
+            assert_eq!(base + extra, value);
+            assert!(extra < 1 << extra_bits || extra_bits == 0);
+        }
+        assert_eq!(value_to_code((MAX_MATCH - MIN_MATCH) as u32).0 as usize, NUM_LENGTH_CODES - 1);
+        assert_eq!(value_to_code(MAX_WINDOW_SIZE as u32 - 1).0 as usize, DISTANCE_ALPHABET_SIZE - 1);
+    }
+
+    #[test]
+    fn test_repetitive_input_becomes_matches() {
+        let data = b"abcabcabcabcabcabcabcabc".repeat(20);
+        let tokens = MatchFinder::new(DEFAULT_WINDOW_SIZE, true).tokenize(&data);
+        assert_eq!(expand(&tokens), data);
+        assert!(tokens.len() < 10);
+        assert_eq!(tokens[3], Token::Match { length: 258, distance: 3 });
+    }
+
+    #[test]
+    fn test_matches_stay_within_window() {
+        let mut data: Vec<u8> = (0..=255u8).collect();
+        data.extend((0..1000u32).map(|i| (i * 7 % 251) as u8));
+        data.extend(0..=255u8);
+        let tokens = MatchFinder::new(MIN_WINDOW_SIZE, true).tokenize(&data);
+        assert_eq!(expand(&tokens), data);
+        for token in tokens {
+            if let Token::Match { distance, .. } = token {
+                assert!(distance as usize <= MIN_WINDOW_SIZE);
+            }
+        }
+    }
+
+    // Lazy matching prefers a literal followed by a longer match
+    #[test]
+    fn test_lazy_matching_finds_longer_match() {
+        let data = b"abcXbcdefghYabcdefgh";
+        let greedy = MatchFinder::new(DEFAULT_WINDOW_SIZE, false).tokenize(data);
+        let lazy = MatchFinder::new(DEFAULT_WINDOW_SIZE, true).tokenize(data);
+        assert_eq!(expand(&greedy), data);
+        assert_eq!(expand(&lazy), data);
+        assert!(greedy.contains(&Token::Match { length: 3, distance: 12 }));
+        assert!(lazy.contains(&Token::Match { length: 7, distance: 9 }));
+        assert!(lazy.len() <= greedy.len());
+    }
+
+    // Matches reach into the history but the tokens only cover the rest
+    #[test]
+    fn test_tokenize_from_history() {
+        let data = b"the quick brown fox|the quick brown fox";
+        let tokens = MatchFinder::new(DEFAULT_WINDOW_SIZE, true).tokenize_from(data, 20);
+        assert_eq!(tokens, vec![Token::Match { length: 19, distance: 20 }]);
+    }
+
+    #[test]
+    fn test_random_data_round_trips() {
+        let mut state = 1u64;
+        let data: Vec<u8> = (0..50_000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                b"abcd"[(state >> 62) as usize]
+            })
+            .collect();
+        for lazy in [false, true] {
+            let tokens = MatchFinder::new(1024, lazy).tokenize(&data);
+            assert_eq!(expand(&tokens), data);
+        }
+    }
+}
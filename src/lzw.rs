@@ -0,0 +1,423 @@
+// LZW as in compress(1) and its .Z files. Codes 0 to 255 stand for single
+// bytes, code 256 clears the dictionary, and every later code stands for the
+// string of an earlier code followed by one more byte. Codes start 9 bits
+// wide and grow by a bit whenever the dictionary outgrows them, up to a
+// maximum between 9 and 16 bits, and are packed least significant bit first.
+// Like compress, the writer pads to a multiple of eight codes before every
+// width change and after every clear code, since readers skip to that point.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use crate::bitio::LsbBitWriter;
+use crate::error::{Error, Result};
+
+pub const MAGIC: [u8; 2] = [0x1F, 0x9D];
+
+// Code widths
+pub const MIN_CODE_BITS: u8 = 9;
+pub const MAX_CODE_BITS: u8 = 16;
+
+const CLEAR: u32 = 256;
+
+// The header byte holds the maximum code width in its low bits. Block mode
+// reserves code 256 for clearing the dictionary; only very old files lack it.
+const CODE_BITS_MASK: u8 = 0x1F;
+const FLAG_BLOCK_MODE: u8 = 0x80;
+const FLAG_RESERVED: u8 = 0x60;
+
+// Once the dictionary is full, the compression ratio is checked every this
+// many input bytes and the dictionary cleared when it gets worse
+const CHECK_GAP: u64 = 10_000;
+
+// Bytes of output gathered before they are written
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZHeader {
+    pub max_code_bits: u8,
+    pub block_mode: bool,
+}
+
+impl ZHeader {
+    pub fn new(max_code_bits: u8) -> Self {
+        ZHeader { max_code_bits, block_mode: true }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut flags = self.max_code_bits;
+        if self.block_mode {
+            flags |= FLAG_BLOCK_MODE;
+        }
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[flags])?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut header = [0u8; 3];
+        reader.read_exact(&mut header).map_err(|_| not_compressed())?;
+        if header[..2] != MAGIC {
+            return Err(not_compressed());
+        }
+        let flags = header[2];
+        if flags & FLAG_RESERVED != 0 {
+            return Err(Error::UnsupportedFlags(flags as u16));
+        }
+        let max_code_bits = flags & CODE_BITS_MASK;
+        if !(MIN_CODE_BITS..=MAX_CODE_BITS).contains(&max_code_bits) {
+            return Err(Error::InvalidHeader(format!("invalid maximum code width of {} bits", max_code_bits)));
+        }
+        Ok(ZHeader { max_code_bits, block_mode: flags & FLAG_BLOCK_MODE != 0 })
+    }
+}
+
+fn not_compressed() -> Error {
+    Error::InvalidHeader("not a .Z file (bad magic bytes)".to_string())
+}
+
+// Largest code that fits in `bits` bits
+fn max_code(bits: u8) -> u32 {
+    (1 << bits) - 1
+}
+
+// Widest code actually written. compress(1) only stops widening at 9 bits
+// once the code width has been raised to the maximum, so with a 9-bit
+// maximum it still moves on to 10-bit codes when the 512 entries run out,
+// and every decoder expects that.
+fn widest_code_bits(max_code_bits: u8) -> u8 {
+    max_code_bits.max(MIN_CODE_BITS + 1)
+}
+
+// Codes to pad a segment of `codes` codes with to reach a multiple of eight
+fn padding_codes(codes: u64) -> u64 {
+    (8 - codes % 8) % 8
+}
+
+// Compresses a stream of bytes into codes, always in block mode
+pub struct LzwEncoder<W: Write> {
+    bit_writer: LsbBitWriter<W>,
+    max_code_bits: u8,
+    code_bits: u8,
+    next_code: u32,
+    dictionary: HashMap<(u32, u8), u32>,
+    // Code of the longest dictionary string the pending input matches
+    current: Option<u32>,
+    // Codes written since the last width change or clear
+    segment_codes: u64,
+    bytes_in: u64,
+    checkpoint: u64,
+    // Best ratio of input to output since the last clear, in 1/256ths
+    best_ratio: u64,
+}
+
+impl<W: Write> LzwEncoder<W> {
+    pub fn new(writer: W, max_code_bits: u8) -> Self {
+        assert!((MIN_CODE_BITS..=MAX_CODE_BITS).contains(&max_code_bits), "LZW codes must be 9 to 16 bits wide");
+        LzwEncoder {
+            bit_writer: LsbBitWriter::new(writer),
+            max_code_bits,
+            code_bits: MIN_CODE_BITS,
+            next_code: CLEAR + 1,
+            dictionary: HashMap::new(),
+            current: None,
+            segment_codes: 0,
+            bytes_in: 0,
+            checkpoint: CHECK_GAP,
+            best_ratio: 0,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        for &byte in data {
+            self.bytes_in += 1;
+            let Some(prefix) = self.current else {
+                self.current = Some(byte as u32);
+                continue;
+            };
+            if let Some(&code) = self.dictionary.get(&(prefix, byte)) {
+                self.current = Some(code);
+                continue;
+            }
+
+            self.write_code(prefix)?;
+            self.current = Some(byte as u32);
+            if self.next_code <= max_code(self.max_code_bits) {
+                self.dictionary.insert((prefix, byte), self.next_code);
+                self.next_code += 1;
+            } else if self.bytes_in >= self.checkpoint {
+                self.check_ratio()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write the last code, pad to a byte and hand back the writer
+    pub fn finish(mut self) -> Result<W> {
+        if let Some(code) = self.current.take() {
+            self.write_code(code)?;
+        }
+        Ok(self.bit_writer.finish()?)
+    }
+
+    fn write_code(&mut self, code: u32) -> Result<()> {
+        self.bit_writer.write_bits(code as u64, self.code_bits as u32)?;
+        self.segment_codes += 1;
+        // The reader widens its codes once the next entry no longer fits
+        if self.next_code > max_code(self.code_bits) && self.code_bits < widest_code_bits(self.max_code_bits) {
+            self.end_segment()?;
+            self.code_bits += 1;
+        }
+        Ok(())
+    }
+
+    // With the dictionary full, start over if the data has drifted away from it
+    fn check_ratio(&mut self) -> Result<()> {
+        self.checkpoint = self.bytes_in + CHECK_GAP;
+        let bytes_out = (self.bit_writer.bits_written() / 8).max(1);
+        let ratio = (self.bytes_in << 8) / bytes_out;
+        if ratio > self.best_ratio {
+            self.best_ratio = ratio;
+            return Ok(());
+        }
+
+        self.best_ratio = 0;
+        self.dictionary.clear();
+        self.next_code = CLEAR + 1;
+        self.bit_writer.write_bits(CLEAR as u64, self.code_bits as u32)?;
+        self.segment_codes += 1;
+        self.end_segment()?;
+        self.code_bits = MIN_CODE_BITS;
+        Ok(())
+    }
+
+    fn end_segment(&mut self) -> Result<()> {
+        for _ in 0..padding_codes(self.segment_codes) {
+            self.bit_writer.write_bits(0, self.code_bits as u32)?;
+        }
+        self.segment_codes = 0;
+        Ok(())
+    }
+}
+
+// Reads codes least significant bit first, noticing where the input ends
+struct CodeReader<R: Read> {
+    inner: R,
+    buffer: u64,
+    bit_count: u32,
+}
+
+impl<R: Read> CodeReader<R> {
+    // The next code, or None if the input ends before all of its bits
+    fn read(&mut self, bits: u8) -> Result<Option<u32>> {
+        while self.bit_count < bits as u32 {
+            let mut byte = [0u8; 1];
+            if self.inner.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            self.buffer |= (byte[0] as u64) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let code = (self.buffer & max_code(bits) as u64) as u32;
+        self.buffer >>= bits;
+        self.bit_count -= bits as u32;
+        Ok(Some(code))
+    }
+
+    // Skip the padding after a segment of `codes` codes. Returns false if the
+    // input ends first.
+    fn skip_padding(&mut self, codes: u64, bits: u8) -> Result<bool> {
+        for _ in 0..padding_codes(codes) {
+            if self.read(bits)?.is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// Decompresses codes back into bytes
+pub struct LzwDecoder {
+    max_code_bits: u8,
+    block_mode: bool,
+}
+
+impl LzwDecoder {
+    pub fn new(max_code_bits: u8, block_mode: bool) -> Self {
+        assert!((MIN_CODE_BITS..=MAX_CODE_BITS).contains(&max_code_bits), "LZW codes must be 9 to 16 bits wide");
+        LzwDecoder { max_code_bits, block_mode }
+    }
+
+    // Decode codes until the input runs out and return the number of bytes
+    // written, failing if that would be more than `limit`
+    pub fn decode<R: Read, W: Write>(&self, reader: R, writer: &mut W, limit: u64) -> Result<u64> {
+        let first_code = if self.block_mode { CLEAR + 1 } else { CLEAR };
+        let table_size = 1usize << self.max_code_bits;
+        let mut prefixes = vec![0u16; table_size];
+        let mut suffixes = vec![0u8; table_size];
+
+        let mut code_reader = CodeReader { inner: reader, buffer: 0, bit_count: 0 };
+        let mut code_bits = MIN_CODE_BITS;
+        let mut next_code = first_code;
+        let mut segment_codes = 0;
+        // The previous code and the first byte of its string
+        let mut previous: Option<(u32, u8)> = None;
+        let mut string = Vec::new();
+        let mut output = Vec::with_capacity(OUTPUT_BUFFER_SIZE);
+        let mut written = 0u64;
+
+        loop {
+            if next_code > max_code(code_bits) && code_bits < widest_code_bits(self.max_code_bits) {
+                if !code_reader.skip_padding(segment_codes, code_bits)? {
+                    break;
+                }
+                segment_codes = 0;
+                code_bits += 1;
+            }
+            let Some(code) = code_reader.read(code_bits)? else {
+                break;
+            };
+            segment_codes += 1;
+
+            if self.block_mode && code == CLEAR {
+                if !code_reader.skip_padding(segment_codes, code_bits)? {
+                    break;
+                }
+                segment_codes = 0;
+                code_bits = MIN_CODE_BITS;
+                next_code = first_code;
+                previous = None;
+                continue;
+            }
+
+            // Spell out the string backwards. The one code that isn't in the
+            // dictionary yet is the entry about to be made, which is the
+            // previous string followed by its own first byte.
+            string.clear();
+            let mut link = code;
+            match previous {
+                None if code > 255 => {
+                    return Err(Error::CorruptData(format!("LZW stream starts with code {}", code)));
+                },
+                Some((previous_code, previous_first)) if code == next_code => {
+                    string.push(previous_first);
+                    link = previous_code;
+                },
+                _ if code >= next_code => {
+                    return Err(Error::CorruptData(format!("LZW code {} isn't in the dictionary yet", code)));
+                },
+                _ => {},
+            }
+            while link > 255 {
+                string.push(suffixes[link as usize]);
+                link = prefixes[link as usize] as u32;
+            }
+            let first = link as u8;
+            string.push(first);
+
+            written += string.len() as u64;
+            if written > limit {
+                return Err(Error::CorruptData(format!("LZW data decodes to more than {} bytes", limit)));
+            }
+            output.extend(string.iter().rev());
+            if output.len() >= OUTPUT_BUFFER_SIZE {
+                writer.write_all(&output)?;
+                output.clear();
+            }
+
+            if let Some((previous_code, _)) = previous {
+                if (next_code as usize) < table_size {
+                    prefixes[next_code as usize] = previous_code as u16;
+                    suffixes[next_code as usize] = first;
+                    next_code += 1;
+                }
+            }
+            previous = Some((code, first));
+        }
+
+        writer.write_all(&output)?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &[u8], max_code_bits: u8) -> Vec<u8> {
+        let mut encoder = LzwEncoder::new(Vec::new(), max_code_bits);
+        encoder.write(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decode(encoded: &[u8], max_code_bits: u8) -> Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        LzwDecoder::new(max_code_bits, true).decode(encoded, &mut decoded, u64::MAX)?;
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_codes_grow() {
+        // "abababa": a, b, ab (257), aba (259)
+        let encoded = encode(b"abababa", 16);
+        let mut reader = CodeReader { inner: &encoded[..], buffer: 0, bit_count: 0 };
+        let codes: Vec<u32> = (0..4).map(|_| reader.read(9).unwrap().unwrap()).collect();
+        assert_eq!(codes, [97, 98, 257, 259]);
+        assert_eq!(decode(&encoded, 16).unwrap(), b"abababa");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = b"the quick brown fox jumps over the lazy dog, again and again. ".repeat(300);
+        let noise: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        for data in [&b""[..], b"a", &[0; 100_000], &text, &noise] {
+            for bits in [MIN_CODE_BITS, 12, MAX_CODE_BITS] {
+                assert_eq!(decode(&encode(data, bits), bits).unwrap(), data, "{} bits", bits);
+            }
+        }
+        assert!(encode(&text, 16).len() * 4 < text.len());
+    }
+
+    #[test]
+    fn test_clear_code_resets_the_dictionary() {
+        // Text then noise: once the small dictionary is full of text the ratio
+        // drops and the encoder starts over
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(1000);
+        let noise: Vec<u8> = (0..50_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let mut encoder = LzwEncoder::new(Vec::new(), 9);
+        encoder.write(&text).unwrap();
+        let text_ratio = encoder.best_ratio;
+        encoder.write(&noise).unwrap();
+        // Without a clear the best ratio could only have gone up
+        assert!(encoder.best_ratio < text_ratio, "{} vs {}", encoder.best_ratio, text_ratio);
+        let encoded = encoder.finish().unwrap();
+        let data = [text, noise].concat();
+        assert_eq!(decode(&encoded, 9).unwrap(), data);
+    }
+
+    #[test]
+    fn test_invalid_codes() {
+        // A first code that isn't a byte, and a code past the next entry
+        let mut bit_writer = LsbBitWriter::new(Vec::new());
+        bit_writer.write_bits(300, 9).unwrap();
+        assert!(matches!(decode(&bit_writer.finish().unwrap(), 16), Err(Error::CorruptData(_))));
+        let mut bit_writer = LsbBitWriter::new(Vec::new());
+        bit_writer.write_bits(97, 9).unwrap();
+        bit_writer.write_bits(258, 9).unwrap();
+        assert!(matches!(decode(&bit_writer.finish().unwrap(), 16), Err(Error::CorruptData(_))));
+
+        let mut decoded = Vec::new();
+        let result = LzwDecoder::new(16, true).decode(&encode(b"too long", 16)[..], &mut decoded, 5);
+        assert!(matches!(result, Err(Error::CorruptData(_))));
+    }
+
+    #[test]
+    fn test_header() {
+        let mut bytes = Vec::new();
+        ZHeader::new(16).write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, [0x1F, 0x9D, 0x90]);
+        assert_eq!(ZHeader::read_from(&mut &bytes[..]).unwrap(), ZHeader::new(16));
+        assert!(matches!(ZHeader::read_from(&mut &[0x1F, 0x8B, 0x90][..]), Err(Error::InvalidHeader(_))));
+        assert!(matches!(ZHeader::read_from(&mut &[0x1F, 0x9D, 0x88][..]), Err(Error::InvalidHeader(_))));
+        assert!(matches!(ZHeader::read_from(&mut &[0x1F, 0x9D, 0xF0][..]), Err(Error::UnsupportedFlags(0xF0))));
+    }
+}
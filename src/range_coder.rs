@@ -0,0 +1,358 @@
+// A range coder: arithmetic coding done a byte at a time, in the
+// carry-propagating form LZMA uses. Each symbol costs close to -log2(p) bits,
+// so unlike Huffman codes it doesn't lose up to a bit per symbol when one
+// symbol dominates.
+//
+// Symbol probabilities are frequencies scaled to a total of FREQUENCY_TOTAL.
+// Encoder and decoder must use the same scaled table, so blocks store it.
+
+use std::io::Read;
+use crate::error::{Error, Result};
+
+// Scaled frequencies add up to 2^FREQUENCY_BITS
+pub const FREQUENCY_BITS: u32 = 15;
+pub const FREQUENCY_TOTAL: u32 = 1 << FREQUENCY_BITS;
+
+// The range is kept above this, so scaling it by a frequency stays accurate
+const TOP: u32 = 1 << 24;
+
+// Bytes the encoder flushes at the end and the decoder reads up front
+const FLUSH_BYTES: usize = 5;
+
+// Scale symbol counts to add up to FREQUENCY_TOTAL
+pub fn normalize_frequencies(frequencies: &[u64]) -> Vec<u32> {
+    scale_frequencies(frequencies, FREQUENCY_TOTAL)
+}
+
+// Scale symbol counts to add up to `target`. Every symbol that occurs keeps a
+// frequency of at least one so it can still be coded.
+pub fn scale_frequencies(frequencies: &[u64], target: u32) -> Vec<u32> {
+    let total: u64 = frequencies.iter().sum();
+    if total == 0 {
+        return vec![0; frequencies.len()];
+    }
+    assert!(frequencies.iter().filter(|&&count| count > 0).count() <= target as usize);
+
+    let mut normalized: Vec<u32> = frequencies
+        .iter()
+        .map(|&count| match count {
+            0 => 0,
+            _ => ((count as u128 * target as u128 / total as u128) as u32).max(1),
+        })
+        .collect();
+
+    // Rounding leaves the sum a little off. Settle the difference on the most
+    // frequent symbols, where it costs the least.
+    let mut sum: u32 = normalized.iter().sum();
+    while sum != target {
+        let largest = (0..normalized.len()).max_by_key(|&i| (normalized[i], usize::MAX - i)).unwrap();
+        if sum < target {
+            normalized[largest] += target - sum;
+            sum = target;
+        } else {
+            let cut = (sum - target).min(normalized[largest] - 1);
+            normalized[largest] -= cut;
+            sum -= cut;
+        }
+    }
+    normalized
+}
+
+// Where each symbol's slice of the scaled total starts
+pub fn cumulative_frequencies(frequencies: &[u32]) -> Vec<u32> {
+    let mut cumulative = Vec::with_capacity(frequencies.len());
+    let mut start = 0;
+    for &frequency in frequencies {
+        cumulative.push(start);
+        start += frequency;
+    }
+    cumulative
+}
+
+// Encode a frequency table compactly: a used symbol is its frequency as a
+// little-endian base-128 varint (never starting with a zero byte), and a zero
+// byte is followed by the number of unused symbols in a row minus one
+pub fn encode_frequencies(frequencies: &[u32]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < frequencies.len() {
+        let mut frequency = frequencies[i];
+        if frequency != 0 {
+            while frequency >= 0x80 {
+                encoded.push(frequency as u8 | 0x80);
+                frequency >>= 7;
+            }
+            encoded.push(frequency as u8);
+            i += 1;
+            continue;
+        }
+
+        let run = frequencies[i..].iter().take(256).take_while(|&&frequency| frequency == 0).count();
+        encoded.push(0);
+        encoded.push((run - 1) as u8);
+        i += run;
+    }
+    encoded
+}
+
+// Read a frequency table written by encode_frequencies and check that it
+// adds up to FREQUENCY_TOTAL
+pub fn decode_frequencies<R: Read>(reader: &mut R, alphabet_size: usize) -> Result<Vec<u32>> {
+    let mut frequencies = Vec::with_capacity(alphabet_size);
+    let mut sum = 0u32;
+    while frequencies.len() < alphabet_size {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            reader.read_exact(&mut byte)?;
+            let run = byte[0] as usize + 1;
+            if frequencies.len() + run > alphabet_size {
+                return Err(Error::InvalidHeader("frequency table overruns the alphabet".to_string()));
+            }
+            frequencies.resize(frequencies.len() + run, 0);
+            continue;
+        }
+
+        let mut frequency = (byte[0] & 0x7F) as u32;
+        let mut shift = 7;
+        while byte[0] & 0x80 != 0 {
+            reader.read_exact(&mut byte)?;
+            if shift > FREQUENCY_BITS {
+                return Err(Error::InvalidHeader("frequency too large".to_string()));
+            }
+            frequency |= ((byte[0] & 0x7F) as u32) << shift;
+            shift += 7;
+        }
+        sum += frequency;
+        if frequency == 0 || sum > FREQUENCY_TOTAL {
+            return Err(Error::InvalidHeader("frequencies don't add up".to_string()));
+        }
+        frequencies.push(frequency);
+    }
+    if sum != FREQUENCY_TOTAL {
+        return Err(Error::InvalidHeader("frequencies don't add up".to_string()));
+    }
+    Ok(frequencies)
+}
+
+pub struct RangeEncoder {
+    low: u64,
+    range: u32,
+    // The last byte written out, held back in case a carry still reaches it
+    cache: u8,
+    // The cached byte plus the 0xFF bytes after it that a carry would ripple through
+    cache_size: u64,
+    output: Vec<u8>,
+}
+
+impl RangeEncoder {
+    pub fn new() -> Self {
+        RangeEncoder { low: 0, range: u32::MAX, cache: 0, cache_size: 1, output: Vec::new() }
+    }
+
+    // Narrow the range to the slice of a symbol with the given scaled frequency
+    pub fn encode(&mut self, cumulative: u32, frequency: u32) {
+        let r = self.range >> FREQUENCY_BITS;
+        self.low += r as u64 * cumulative as u64;
+        self.range = r * frequency;
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    // Flush the rest of the state and return the coded bytes
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..FLUSH_BYTES {
+            self.shift_low();
+        }
+        self.output
+    }
+
+    // Move the top byte of `low` out, resolving any pending carry first
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.cache_size > 0 {
+                self.output.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+}
+
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct RangeDecoder<'a> {
+    input: &'a [u8],
+    code: u32,
+    range: u32,
+    // The range divided by FREQUENCY_TOTAL, from the last call to frequency
+    scale: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    pub fn new(input: &'a [u8]) -> Result<Self> {
+        if input.len() < FLUSH_BYTES {
+            return Err(Error::CorruptData("range coded payload is too short".to_string()));
+        }
+        // The first byte only ever holds a carry the encoder never makes
+        if input[0] != 0 {
+            return Err(Error::CorruptData("range coded payload doesn't start with a zero byte".to_string()));
+        }
+        let code = u32::from_be_bytes([input[1], input[2], input[3], input[4]]);
+        Ok(RangeDecoder { input: &input[FLUSH_BYTES..], code, range: u32::MAX, scale: 0 })
+    }
+
+    // The scaled frequency the next symbol's slice contains. Look up the symbol
+    // it belongs to and pass its slice to `consume`.
+    pub fn frequency(&mut self) -> u32 {
+        self.scale = self.range >> FREQUENCY_BITS;
+        (self.code / self.scale).min(FREQUENCY_TOTAL - 1)
+    }
+
+    pub fn consume(&mut self, cumulative: u32, frequency: u32) -> Result<()> {
+        self.code -= self.scale * cumulative;
+        self.range = self.scale * frequency;
+        while self.range < TOP {
+            let (&byte, rest) = self
+                .input
+                .split_first()
+                .ok_or_else(|| Error::CorruptData("range coded payload ends early".to_string()))?;
+            self.input = rest;
+            self.code = self.code << 8 | byte as u32;
+            self.range <<= 8;
+        }
+        Ok(())
+    }
+
+    // Whether every byte of the payload was used
+    pub fn is_finished(&self) -> bool {
+        self.input.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(symbols: &[u8], frequencies: &[u64]) -> Vec<u8> {
+        let normalized = normalize_frequencies(frequencies);
+        let cumulative = cumulative_frequencies(&normalized);
+        let mut encoder = RangeEncoder::new();
+        for &symbol in symbols {
+            encoder.encode(cumulative[symbol as usize], normalized[symbol as usize]);
+        }
+        let encoded = encoder.finish();
+
+        let mut decoder = RangeDecoder::new(&encoded).unwrap();
+        for &symbol in symbols {
+            let value = decoder.frequency();
+            let decoded = cumulative.iter().rposition(|&start| start <= value).unwrap();
+            assert_eq!(decoded, symbol as usize);
+            decoder.consume(cumulative[decoded], normalized[decoded]).unwrap();
+        }
+        assert!(decoder.is_finished());
+        encoded
+    }
+
+    fn counts(symbols: &[u8]) -> Vec<u64> {
+        let mut counts = vec![0u64; 256];
+        for &symbol in symbols {
+            counts[symbol as usize] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_normalize_frequencies() {
+        assert_eq!(normalize_frequencies(&[3, 0, 1]), vec![24576, 0, 8192]);
+        assert_eq!(normalize_frequencies(&[0, 7, 0]), vec![0, FREQUENCY_TOTAL, 0]);
+
+        // Rare symbols keep a frequency of one, and the total still comes out right
+        let mut skewed = vec![1u64; 256];
+        skewed[0] = 1_000_000_000;
+        let normalized = normalize_frequencies(&skewed);
+        assert!(normalized.iter().all(|&frequency| frequency >= 1));
+        assert_eq!(normalized.iter().sum::<u32>(), FREQUENCY_TOTAL);
+    }
+
+    #[test]
+    fn test_frequency_table_round_trip() {
+        let normalized = normalize_frequencies(&counts(b"an example with a few distinct symbols"));
+        let encoded = encode_frequencies(&normalized);
+        assert_eq!(decode_frequencies(&mut &encoded[..], 256).unwrap(), normalized);
+
+        // Tables that don't add up are rejected
+        let mut wrong = normalized.clone();
+        wrong[b'a' as usize] += 1;
+        let encoded = encode_frequencies(&wrong);
+        assert!(matches!(decode_frequencies(&mut &encoded[..], 256), Err(Error::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_range_coder_round_trips() {
+        let text = b"the quick brown fox jumps over the lazy dog".repeat(50);
+        round_trip(&text, &counts(&text));
+
+        let every_byte: Vec<u8> = (0..=255).cycle().take(5000).collect();
+        round_trip(&every_byte, &counts(&every_byte));
+
+        // A single symbol costs next to nothing
+        let single = vec![b'x'; 10_000];
+        assert_eq!(round_trip(&single, &counts(&single)).len(), FLUSH_BYTES);
+    }
+
+    // On a skewed source the output stays within a few bytes of the entropy,
+    // well under the one bit per symbol a Huffman code needs
+    #[test]
+    fn test_range_coder_approaches_entropy() {
+        let mut symbols = Vec::new();
+        for i in 0..100_000u32 {
+            symbols.push(if i % 50 == 0 { 1 } else { 0 });
+        }
+        let counts = counts(&symbols);
+        let entropy_bits: f64 = counts
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / symbols.len() as f64;
+                -(count as f64) * p.log2()
+            })
+            .sum();
+
+        let encoded = round_trip(&symbols, &counts);
+        assert!((encoded.len() as f64) < entropy_bits / 8.0 * 1.01 + 16.0);
+        assert!(encoded.len() * 8 < symbols.len() / 4);
+    }
+
+    #[test]
+    fn test_truncated_payload_is_rejected() {
+        let symbols = b"abracadabra".repeat(100);
+        let normalized = normalize_frequencies(&counts(&symbols));
+        let cumulative = cumulative_frequencies(&normalized);
+        let mut encoder = RangeEncoder::new();
+        for &symbol in &symbols {
+            encoder.encode(cumulative[symbol as usize], normalized[symbol as usize]);
+        }
+        let encoded = encoder.finish();
+
+        assert!(RangeDecoder::new(&encoded[..4]).is_err());
+        let mut decoder = RangeDecoder::new(&encoded[..encoded.len() / 2]).unwrap();
+        let result = symbols.iter().try_for_each(|_| {
+            let value = decoder.frequency();
+            let symbol = cumulative.iter().rposition(|&start| start <= value).unwrap();
+            decoder.consume(cumulative[symbol], normalized[symbol])
+        });
+        assert!(matches!(result, Err(Error::CorruptData(_))));
+    }
+}
@@ -0,0 +1,197 @@
+// Run-length encoding of a block's bytes ahead of its coder. Huffman coding
+// spends at least a bit on every byte, so a long run of zeros in a disk image
+// costs an eighth of its length; here it shrinks to three bytes per 258.
+//
+// The encoded block starts with its escape byte, the rarest byte of the block.
+// After that, the escape followed by a count `n` from 1 to 255 and a byte
+// stands for `n + 3` copies of that byte, the escape followed by 0 stands for
+// the escape byte itself, and every other byte stands for itself.
+
+use std::io::{self, Write};
+use crate::error::{Error, Result};
+
+// Runs shorter than this are left alone, as the escape sequence is 3 bytes
+pub const MIN_RUN: usize = 4;
+pub const MAX_RUN: usize = MIN_RUN + 254;
+
+// The escape byte is never more frequent than one byte in 256, so that is all
+// the escaping can add, besides the escape byte in front
+pub fn max_encoded_length(length: usize) -> usize {
+    1 + length + length / 256
+}
+
+// Longest input whose encoding is sure to fit in `encoded_length` bytes
+pub fn max_input_length(encoded_length: usize) -> usize {
+    (encoded_length - 1) * 256 / 257
+}
+
+pub fn encode(block: &[u8]) -> Vec<u8> {
+    let mut counts = [0usize; 256];
+    for &byte in block {
+        counts[byte as usize] += 1;
+    }
+    let escape = (0..=255u8).min_by_key(|&byte| counts[byte as usize]).expect("256 byte values");
+
+    let mut encoded = Vec::with_capacity(max_encoded_length(block.len()));
+    encoded.push(escape);
+    let mut position = 0;
+    while position < block.len() {
+        let byte = block[position];
+        let run = block[position..].iter().take(MAX_RUN).take_while(|&&other| other == byte).count();
+        if run >= MIN_RUN {
+            encoded.extend_from_slice(&[escape, (run - MIN_RUN + 1) as u8, byte]);
+            position += run;
+            continue;
+        }
+        encoded.push(byte);
+        if byte == escape {
+            encoded.push(0);
+        }
+        position += 1;
+    }
+    encoded
+}
+
+// How much shorter encoding makes a block, without encoding it
+pub fn saving(block: &[u8]) -> usize {
+    let mut saved = 0;
+    for run in block.chunk_by(|a, b| a == b) {
+        let (full, rest) = (run.len() / MAX_RUN, run.len() % MAX_RUN);
+        saved += full * (MAX_RUN - 3);
+        if rest >= MIN_RUN {
+            saved += rest - 3;
+        }
+    }
+    saved
+}
+
+// Where a decoder is within an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Expecting the escape byte that starts the block
+    Start,
+    Literal,
+    // After the escape, expecting a count
+    Count,
+    // After the escape and a count, expecting the byte to repeat
+    Run(u8),
+}
+
+// Undoes the encoding of one block on its way to `inner`, whatever pieces the
+// encoded bytes arrive in
+pub struct Decoder<W: Write> {
+    inner: W,
+    escape: u8,
+    state: State,
+    output: Vec<u8>,
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(inner: W) -> Self {
+        Decoder { inner, escape: 0, state: State::Start, output: Vec::new() }
+    }
+
+    // Check that the block didn't end partway through an escape sequence
+    pub fn finish(mut self) -> Result<W> {
+        if matches!(self.state, State::Count | State::Run(_)) {
+            return Err(Error::CorruptData("block ends inside a run".to_string()));
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.clear();
+        for &byte in buf {
+            self.state = match self.state {
+                State::Start => {
+                    self.escape = byte;
+                    State::Literal
+                },
+                State::Literal if byte == self.escape => State::Count,
+                State::Literal => {
+                    self.output.push(byte);
+                    State::Literal
+                },
+                State::Count if byte == 0 => {
+                    self.output.push(self.escape);
+                    State::Literal
+                },
+                State::Count => State::Run(byte),
+                State::Run(count) => {
+                    let length = self.output.len() + count as usize + MIN_RUN - 1;
+                    self.output.resize(length, byte);
+                    State::Literal
+                },
+            };
+        }
+        self.inner.write_all(&self.output)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(encoded: &[u8], piece: usize) -> Result<Vec<u8>> {
+        let mut decoder = Decoder::new(Vec::new());
+        for chunk in encoded.chunks(piece) {
+            decoder.write_all(chunk)?;
+        }
+        decoder.finish()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut sparse = vec![0u8; 100_000];
+        sparse[500..520].copy_from_slice(b"a header in the gaps");
+        let every_byte: Vec<u8> = (0..=255u8).cycle().take(3000).collect();
+        let inputs: [&[u8]; 6] = [b"", b"x", b"aaabbbbccccc", &[9; 259], &sparse, &every_byte];
+        for input in inputs {
+            let encoded = encode(input);
+            assert!(encoded.len() <= max_encoded_length(input.len()));
+            assert_eq!(input.len() - saving(input), encoded.len() - 1 - input.iter().filter(|&&b| b == encoded[0]).count());
+            for piece in [1, 2, 7, 4096] {
+                assert_eq!(decode(&encoded, piece).unwrap(), input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_runs_and_escapes() {
+        // Byte 0 doesn't occur, so it is the escape
+        assert_eq!(encode(b"abbbbbc"), [0, b'a', 0, 2, b'b', b'c']);
+        assert_eq!(encode(&[7; 300]), [0, 0, 255, 7, 0, 39, 7]);
+        // With every byte in use, the rarest one is escaped where it occurs alone
+        let mut block: Vec<u8> = (0..=255u8).chain(1..=255u8).collect();
+        block.push(0);
+        let encoded = encode(&block);
+        assert_eq!(encoded[..3], [0, 0, 0]);
+        assert_eq!(encoded.len(), 1 + block.len() + 2);
+        // 100,000 zeros shrink to three bytes per 258
+        assert_eq!(encode(&[0; 100_000]).len(), 1 + 3 * 100_000usize.div_ceil(MAX_RUN));
+    }
+
+    #[test]
+    fn test_unfinished_sequences() {
+        assert!(matches!(decode(&[0, b'a', 0], 1), Err(Error::CorruptData(_))));
+        assert!(matches!(decode(&[0, b'a', 0, 5], 1), Err(Error::CorruptData(_))));
+        assert_eq!(decode(&[], 1).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_max_input_length() {
+        for encoded_length in [1, 2, 257, 258, 900_000] {
+            let length = max_input_length(encoded_length);
+            assert!(max_encoded_length(length) <= encoded_length);
+            assert!(max_encoded_length(length + 2) > encoded_length);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// The zlib stream format (RFC 1950): a two-byte header, an optional preset
+// dictionary id, a DEFLATE stream and the Adler-32 of the original data, all
+// big-endian. Unlike gzip, zlib streams are meant to be embedded in other
+// protocols, so besides Format::Zlib for the tools this module has an
+// encoder/decoder pair that works on in-memory payloads.
+
+use std::io::{Read, Write};
+use crate::checksum::Adler32;
+use crate::compression::CompressionTool;
+use crate::decompression::DecompressionTool;
+use crate::error::{Error, Result};
+use crate::format::Format;
+
+// Compression method 8 (DEFLATE) in the low nibble of CMF
+const METHOD_DEFLATE: u8 = 8;
+
+// Largest window zlib allows, as log2(window size) - 8 in the high nibble of CMF
+const MAX_WINDOW_INFO: u8 = 7;
+
+// A preset dictionary id follows the header
+const FLAG_DICTIONARY: u8 = 0x20;
+
+// Compression level hint in the top bits of FLG. We always claim the default.
+const LEVEL_DEFAULT: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZlibHeader {
+    // log2 of the LZ77 window size, between 8 and 15
+    pub window_bits: u8,
+    // How hard the encoder tried, from 0 (fastest) to 3 (smallest)
+    pub level: u8,
+    // Adler-32 of the preset dictionary the stream was compressed with
+    pub dictionary_id: Option<u32>,
+}
+
+impl ZlibHeader {
+    pub fn new(dictionary_id: Option<u32>) -> Self {
+        ZlibHeader { window_bits: MAX_WINDOW_INFO + 8, level: LEVEL_DEFAULT, dictionary_id }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let cmf = (self.window_bits - 8) << 4 | METHOD_DEFLATE;
+        let mut flg = self.level << 6;
+        if self.dictionary_id.is_some() {
+            flg |= FLAG_DICTIONARY;
+        }
+        // The check bits make CMF * 256 + FLG a multiple of 31
+        flg |= ((31 - ((cmf as u16) << 8 | flg as u16) % 31) % 31) as u8;
+
+        writer.write_all(&[cmf, flg])?;
+        if let Some(dictionary_id) = self.dictionary_id {
+            writer.write_all(&dictionary_id.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut fixed = [0u8; 2];
+        reader.read_exact(&mut fixed).map_err(|_| Error::InvalidHeader("not a zlib stream (too short)".to_string()))?;
+        let [cmf, flg] = fixed;
+        if !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
+            return Err(Error::InvalidHeader("not a zlib stream (bad header check bits)".to_string()));
+        }
+        if cmf & 0x0F != METHOD_DEFLATE {
+            return Err(Error::InvalidHeader(format!("unknown zlib compression method {}", cmf & 0x0F)));
+        }
+        if cmf >> 4 > MAX_WINDOW_INFO {
+            return Err(Error::InvalidHeader(format!("zlib window of 2^{} bytes is too large", (cmf >> 4) + 8)));
+        }
+
+        let mut header = ZlibHeader { window_bits: (cmf >> 4) + 8, level: flg >> 6, dictionary_id: None };
+        if flg & FLAG_DICTIONARY != 0 {
+            let mut dictionary_id = [0u8; 4];
+            reader.read_exact(&mut dictionary_id)?;
+            header.dictionary_id = Some(u32::from_be_bytes(dictionary_id));
+        }
+        Ok(header)
+    }
+}
+
+// The id a zlib header uses to name a preset dictionary
+pub fn dictionary_id(dictionary: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(dictionary);
+    adler.finalize()
+}
+
+// Read the Adler-32 trailer and check it against the decoded data
+pub fn check_trailer<R: Read>(reader: &mut R, adler: u32) -> Result<()> {
+    let mut trailer = [0u8; 4];
+    reader.read_exact(&mut trailer)?;
+    let expected = u32::from_be_bytes(trailer);
+    if expected != adler {
+        return Err(Error::ChecksumMismatch { expected: expected as u64, actual: adler as u64 });
+    }
+    Ok(())
+}
+
+// Compresses payloads into zlib streams
+#[derive(Default)]
+pub struct ZlibEncoder {
+    dictionary: Vec<u8>,
+}
+
+impl ZlibEncoder {
+    pub fn new() -> Self {
+        ZlibEncoder { dictionary: Vec::new() }
+    }
+
+    // Let matches reach into a dictionary both sides agreed on beforehand.
+    // Helps most with short payloads that share a lot with the dictionary.
+    pub fn dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.dictionary = dictionary.to_vec();
+        self
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        CompressionTool::new()
+            .format(Format::Zlib)
+            .dictionary(&self.dictionary)
+            .compress(&mut &payload[..], &mut compressed)?;
+        Ok(compressed)
+    }
+}
+
+// Decompresses zlib streams back into payloads
+#[derive(Default)]
+pub struct ZlibDecoder {
+    dictionary: Vec<u8>,
+}
+
+impl ZlibDecoder {
+    pub fn new() -> Self {
+        ZlibDecoder { dictionary: Vec::new() }
+    }
+
+    // The preset dictionary for streams that name one. Its id has to match
+    // the one in the header.
+    pub fn dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.dictionary = dictionary.to_vec();
+        self
+    }
+
+    pub fn decode(&self, compressed: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        DecompressionTool::new()
+            .format(Format::Zlib)
+            .dictionary(&self.dictionary)
+            .decompress(&mut &compressed[..], &mut payload)?;
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_round_trip() {
+        // The same bytes zlib writes at its default level
+        for (header, expected) in [
+            (ZlibHeader::new(None), vec![0x78, 0x9C]),
+            (ZlibHeader::new(Some(0x1234_5678)), vec![0x78, 0xBB, 0x12, 0x34, 0x56, 0x78]),
+        ] {
+            let mut bytes = Vec::new();
+            header.write_to(&mut bytes).unwrap();
+            assert_eq!(bytes, expected);
+            assert_eq!(ZlibHeader::read_from(&mut Cursor::new(bytes)).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn test_check_bits_for_every_level() {
+        for level in 0..4 {
+            for dictionary_id in [None, Some(1)] {
+                let header = ZlibHeader { window_bits: 15, level, dictionary_id };
+                let mut bytes = Vec::new();
+                header.write_to(&mut bytes).unwrap();
+                assert_eq!(ZlibHeader::read_from(&mut Cursor::new(bytes)).unwrap(), header);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        let invalid = |bytes: &[u8]| matches!(ZlibHeader::read_from(&mut Cursor::new(bytes.to_vec())), Err(Error::InvalidHeader(_)));
+        assert!(invalid(&[0x78]));
+        // Check bits don't add up
+        assert!(invalid(&[0x78, 0x9D]));
+        // Compression method 15, with valid check bits
+        assert!(invalid(&[0x7F, 0x83]));
+        // A 64 KiB window, with valid check bits
+        assert!(invalid(&[0x88, 0x98]));
+    }
+
+    #[test]
+    fn test_dictionary_id() {
+        assert_eq!(dictionary_id(b""), 1);
+        assert_eq!(dictionary_id(b"123456789"), 0x091E_01DE);
+    }
+}
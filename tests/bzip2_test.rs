@@ -0,0 +1,95 @@
+mod common;
+
+use common::{assert_bit_flips_rejected, assert_concatenation_decodes, assert_prefixes_rejected, compress, decoder, decompress, sample};
+use compression_tool::compression::CompressionTool;
+use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::Format;
+use compression_tool::Error;
+use std::fs;
+
+// The files in tests/bzip2 were written by bzip2 1.0.8 from
+// tests/deflate/sample.txt: sample.bz2 at level 9, multi.bz2 in two streams
+// (split after 3000 bytes) and blocks.bz2 from six copies of the sample at
+// level 1, which takes two blocks. randomised.bz2 holds the first 5000 bytes
+// in a randomised block, which only bzip2 versions before 0.9.5 wrote.
+fn bzip2(compression_tool: CompressionTool, input: &[u8]) -> Vec<u8> {
+    compress(compression_tool.format(Format::Bzip2), input)
+}
+
+#[test]
+fn test_bunzip2_bzip2_files() {
+    let decompression_tool = || DecompressionTool::new().format(Format::Bzip2);
+    for name in ["sample.bz2", "multi.bz2"] {
+        let compressed = fs::read(format!("tests/bzip2/{}", name)).unwrap();
+        assert_eq!(decompress(decompression_tool(), &compressed).unwrap(), sample(), "{}", name);
+    }
+
+    let compressed = fs::read("tests/bzip2/blocks.bz2").unwrap();
+    for threads in [1, 2] {
+        assert_eq!(decompress(decompression_tool().threads(threads), &compressed).unwrap(), sample().repeat(6));
+    }
+
+    let compressed = fs::read("tests/bzip2/randomised.bz2").unwrap();
+    assert_eq!(compressed[14] & 0x80, 0x80, "the block should be randomised");
+    assert_eq!(decompress(decompression_tool(), &compressed).unwrap(), &sample()[..5000]);
+}
+
+// Without a format, bzip2 files are told apart from our own by their magic bytes
+#[test]
+fn test_bzip2_is_detected() {
+    let compressed = fs::read("tests/bzip2/multi.bz2").unwrap();
+    assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), sample());
+    assert!(matches!(decompress(DecompressionTool::new(), b"BZ"), Err(Error::InvalidHeader(_))));
+}
+
+#[test]
+fn test_bzip2_round_trips() {
+    let text = &fs::read("tests/test.txt").unwrap()[..300_000];
+    let runs: Vec<u8> = (0..200_000u32).map(|i| (i / 700) as u8).collect();
+    for input in [&b""[..], b"a", &[7; 1000], &sample(), &runs, text] {
+        let compressed = bzip2(CompressionTool::new(), input);
+        assert_eq!(&compressed[..4], b"BZh9");
+        assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input);
+    }
+
+    // Smaller blocks choose a lower level, which cuts the input into more
+    // blocks; the output doesn't depend on the number of threads
+    let compressed = bzip2(CompressionTool::new().block_size(100_000), text);
+    assert_eq!(&compressed[..4], b"BZh1");
+    assert_eq!(decompress(DecompressionTool::new().threads(3), &compressed).unwrap(), text);
+    assert_eq!(bzip2(CompressionTool::new().block_size(100_000).threads(3), text), compressed);
+
+    // Our output is close to bzip2's own
+    let reference = fs::read("tests/bzip2/sample.bz2").unwrap();
+    let ours = bzip2(CompressionTool::new(), &sample());
+    assert!(ours.len() * 100 < reference.len() * 102, "{} vs {}", ours.len(), reference.len());
+
+    // Concatenating our own files gives a valid multi-stream file
+    assert_concatenation_decodes(|input| bzip2(CompressionTool::new(), input), decoder(Format::Bzip2));
+}
+
+#[test]
+fn test_damaged_bzip2_files_are_rejected() {
+    let bunzip2 = decoder(Format::Bzip2);
+    let compressed = fs::read("tests/bzip2/sample.bz2").unwrap();
+
+    // A flipped bit in the block CRC, which follows the stream header and the
+    // block magic
+    let mut damaged = compressed.clone();
+    damaged[10] ^= 1;
+    assert!(matches!(bunzip2(&damaged), Err(Error::ChecksumMismatch { .. })));
+
+    // Anywhere else, only the padding in the last byte may change harmlessly
+    assert_bit_flips_rejected(&compressed, (4..compressed.len() - 1).step_by(7), &bunzip2);
+
+    // Cut short, or followed by something that isn't another stream
+    assert_prefixes_rejected(&compressed, 101, &bunzip2);
+    let mut trailing = compressed.clone();
+    trailing.extend_from_slice(b"junk");
+    assert!(matches!(bunzip2(&trailing), Err(Error::InvalidHeader(_))));
+
+    // Levels run from 1 to 9
+    let mut damaged = compressed;
+    damaged[3] = b'0';
+    assert!(matches!(bunzip2(&damaged), Err(Error::InvalidHeader(_))));
+}
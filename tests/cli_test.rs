@@ -0,0 +1,262 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+const CCZIP: &str = env!("CARGO_BIN_EXE_cczip");
+const CCUNZIP: &str = env!("CARGO_BIN_EXE_ccunzip");
+
+// Fresh scratch directory for one test
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Run a binary with `input` on stdin and capture everything it prints
+fn run_with_stdin(binary: &str, args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(binary)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Feed stdin from another thread so a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let feeder = thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    feeder.join().unwrap().unwrap();
+    output
+}
+
+// `producer | cczip -c | ccunzip -c` gives back the original bytes and keeps
+// stdout free of status messages
+#[test]
+fn test_pipeline_round_trip() {
+    let input = fs::read("tests/test.txt").unwrap();
+
+    let compressed = run_with_stdin(CCZIP, &["-c"], &input);
+    assert!(compressed.status.success());
+    assert_eq!(&compressed.stdout[..4], &[0xCC, b'Z', b'I', b'P']);
+
+    let decompressed = run_with_stdin(CCUNZIP, &["-c", "-"], &compressed.stdout);
+    assert!(decompressed.status.success());
+    assert_eq!(decompressed.stdout, input);
+}
+
+// Without arguments both tools filter stdin to stdout
+#[test]
+fn test_no_arguments_filters_stdin() {
+    let compressed = run_with_stdin(CCZIP, &[], b"filter me");
+    assert!(compressed.status.success());
+
+    let decompressed = run_with_stdin(CCUNZIP, &[], &compressed.stdout);
+    assert_eq!(decompressed.stdout, b"filter me");
+}
+
+// Like gzip, the output file replaces the input unless -k is given
+#[test]
+fn test_keep_and_replace_input() {
+    let dir = scratch_dir("keep_and_replace_input");
+    let original = dir.join("data.txt");
+    let compressed = dir.join("data.txt.compressed");
+    fs::write(&original, b"some data worth keeping").unwrap();
+
+    let status = Command::new(CCZIP).arg("-k").arg(&original).status().unwrap();
+    assert!(status.success());
+    assert!(original.exists());
+    assert!(compressed.exists());
+
+    fs::remove_file(&original).unwrap();
+    let status = Command::new(CCUNZIP).arg(&compressed).status().unwrap();
+    assert!(status.success());
+    assert!(!compressed.exists());
+    assert_eq!(fs::read(&original).unwrap(), b"some data worth keeping");
+}
+
+// Existing outputs are only overwritten with -f, and a refused run leaves
+// both files alone
+#[test]
+fn test_force_overwrite() {
+    let dir = scratch_dir("force_overwrite");
+    let original = dir.join("data.txt");
+    let compressed = dir.join("data.txt.compressed");
+    fs::write(&original, b"new contents").unwrap();
+    fs::write(&compressed, b"already here").unwrap();
+
+    let output = Command::new(CCZIP).arg(&original).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+    assert_eq!(fs::read(&compressed).unwrap(), b"already here");
+    assert!(original.exists());
+
+    let status = Command::new(CCZIP).arg("-f").arg(&original).status().unwrap();
+    assert!(status.success());
+    assert!(!original.exists());
+
+    let output = Command::new(CCUNZIP).args(["-c"]).arg(&compressed).output().unwrap();
+    assert_eq!(output.stdout, b"new contents");
+    assert!(compressed.exists());
+}
+
+// --format deflate writes raw DEFLATE under a .deflate name
+#[test]
+fn test_deflate_format() {
+    let dir = scratch_dir("deflate_format");
+    let original = dir.join("data.txt");
+    let compressed = dir.join("data.txt.deflate");
+    fs::copy("tests/deflate/sample.txt", &original).unwrap();
+
+    let status = Command::new(CCZIP).args(["--format", "deflate"]).arg(&original).status().unwrap();
+    assert!(status.success());
+    assert!(!original.exists());
+    assert_ne!(&fs::read(&compressed).unwrap()[..4], b"\xCCZIP");
+
+    let status = Command::new(CCUNZIP).arg("--format=deflate").arg(&compressed).status().unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(&original).unwrap(), fs::read("tests/deflate/sample.txt").unwrap());
+
+    let output = Command::new(CCZIP).args(["--format", "zip"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+}
+
+// gzip files get a .gz name and ccunzip recognises them without --format
+#[test]
+fn test_gzip_format() {
+    let dir = scratch_dir("gzip_format");
+    let original = dir.join("data.txt");
+    let compressed = dir.join("data.txt.gz");
+    fs::copy("tests/deflate/sample.txt", &original).unwrap();
+
+    let status = Command::new(CCZIP).args(["--format", "gzip"]).arg(&original).status().unwrap();
+    assert!(status.success());
+    assert_eq!(&fs::read(&compressed).unwrap()[..2], &[0x1F, 0x8B]);
+
+    let status = Command::new(CCUNZIP).arg(&compressed).status().unwrap();
+    assert!(status.success());
+    assert!(!compressed.exists());
+    assert_eq!(fs::read(&original).unwrap(), fs::read("tests/deflate/sample.txt").unwrap());
+}
+
+// bzip2 files get a .bz2 name and ccunzip recognises them without --format
+#[test]
+fn test_bzip2_format() {
+    let dir = scratch_dir("bzip2_format");
+    let compressed = dir.join("data.txt.bz2");
+    fs::copy("tests/bzip2/sample.bz2", &compressed).unwrap();
+
+    let status = Command::new(CCUNZIP).arg(&compressed).status().unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(dir.join("data.txt")).unwrap(), fs::read("tests/deflate/sample.txt").unwrap());
+
+    let status = Command::new(CCZIP).args(["--format", "bzip2"]).arg(dir.join("data.txt")).status().unwrap();
+    assert!(status.success());
+    assert_eq!(&fs::read(&compressed).unwrap()[..3], b"BZh");
+}
+
+#[test]
+fn test_compress_format() {
+    let dir = scratch_dir("compress_format");
+    let compressed = dir.join("data.txt.Z");
+    fs::copy("tests/compress/sample.b9.Z", &compressed).unwrap();
+
+    let status = Command::new(CCUNZIP).arg(&compressed).status().unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(dir.join("data.txt")).unwrap(), fs::read("tests/deflate/sample.txt").unwrap());
+
+    let status = Command::new(CCZIP).args(["--format", "compress", "--lzw-bits", "9"]).arg(dir.join("data.txt")).status().unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(&compressed).unwrap(), fs::read("tests/compress/sample.b9.Z").unwrap());
+}
+
+// --adaptive output is an ordinary .compressed file that ccunzip decodes as is
+#[test]
+fn test_adaptive_option() {
+    let input = fs::read("tests/deflate/sample.txt").unwrap();
+    let compressed = run_with_stdin(CCZIP, &["-c", "--adaptive"], &input);
+    assert!(compressed.status.success());
+    assert_eq!(&compressed.stdout[..4], b"\xCCZIP");
+    assert_ne!(compressed.stdout[5] & 0x04, 0);
+
+    let decompressed = run_with_stdin(CCUNZIP, &["-c"], &compressed.stdout);
+    assert!(decompressed.status.success());
+    assert_eq!(decompressed.stdout, input);
+}
+
+#[test]
+fn test_bwt_option() {
+    let input = fs::read("tests/deflate/sample.txt").unwrap();
+    let compressed = run_with_stdin(CCZIP, &["-c", "--bwt"], &input);
+    assert!(compressed.status.success());
+    let plain = run_with_stdin(CCZIP, &["-c"], &input);
+    assert_ne!(compressed.stdout, plain.stdout);
+
+    let decompressed = run_with_stdin(CCUNZIP, &["-c"], &compressed.stdout);
+    assert!(decompressed.status.success());
+    assert_eq!(decompressed.stdout, input);
+}
+
+#[test]
+fn test_lzw_option() {
+    let input = fs::read("tests/deflate/sample.txt").unwrap();
+    let compressed = run_with_stdin(CCZIP, &["-c", "--lzw", "--lzw-bits", "12"], &input);
+    assert!(compressed.status.success());
+    let plain = run_with_stdin(CCZIP, &["-c"], &input);
+    assert_ne!(compressed.stdout, plain.stdout);
+
+    let decompressed = run_with_stdin(CCUNZIP, &["-c"], &compressed.stdout);
+    assert!(decompressed.status.success());
+    assert_eq!(decompressed.stdout, input);
+
+    let output = run_with_stdin(CCZIP, &["-c", "--lzw-bits", "17"], &input);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+// A file compressed from disk records its length, so --progress can count up to 100%
+#[test]
+fn test_progress_option() {
+    let dir = scratch_dir("progress");
+    let original = dir.join("data.txt");
+    fs::copy("tests/deflate/sample.txt", &original).unwrap();
+    let status = Command::new(CCZIP).arg("-k").arg(&original).status().unwrap();
+    assert!(status.success());
+
+    let output = Command::new(CCUNZIP).args(["-c", "--progress"]).arg(dir.join("data.txt.compressed")).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, fs::read(&original).unwrap());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.ends_with("100%\n"), "{:?}", stderr);
+
+    // From a pipe the length isn't known up front, so the count is in MiB
+    let compressed = run_with_stdin(CCZIP, &[], &fs::read(&original).unwrap());
+    let output = run_with_stdin(CCUNZIP, &["--progress"], &compressed.stdout);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().ends_with("0 MiB\n"));
+}
+
+// A failed decompression doesn't leave a partial output file behind
+#[test]
+fn test_failed_run_removes_output() {
+    let dir = scratch_dir("failed_run_removes_output");
+    let bogus = dir.join("bogus.compressed");
+    fs::write(&bogus, b"not compressed at all").unwrap();
+
+    let output = Command::new(CCUNZIP).arg(&bogus).output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(!dir.join("bogus").exists());
+    assert!(bogus.exists());
+}
+
+#[test]
+fn test_usage_errors() {
+    let output = Command::new(CCZIP).arg("--bogus").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    let output = Command::new(CCZIP).args(["a", "b", "c"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+}
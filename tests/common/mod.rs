@@ -0,0 +1,75 @@
+// Helpers shared by the tests of the other formats we read and write. Each
+// test file uses its own subset of them.
+#![allow(dead_code)]
+
+use compression_tool::compression::CompressionTool;
+use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::Format;
+use compression_tool::Result;
+use std::fs;
+
+// The text every format's fixtures were written from
+pub fn sample() -> Vec<u8> {
+    fs::read("tests/deflate/sample.txt").unwrap()
+}
+
+pub fn compress(mut compression_tool: CompressionTool, input: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    compression_tool.compress(&mut &input[..], &mut compressed).unwrap();
+    compressed
+}
+
+pub fn decompress(decompression_tool: DecompressionTool, compressed: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    decompression_tool.decompress(&mut &compressed[..], &mut decompressed)?;
+    Ok(decompressed)
+}
+
+// A decoder for `format` with the default settings, to hand to the checks below
+pub fn decoder(format: Format) -> impl Fn(&[u8]) -> Result<Vec<u8>> {
+    move |compressed| decompress(DecompressionTool::new().format(format), compressed)
+}
+
+// Every `step`th prefix of a stream that ends with an end marker or a
+// trailer fails to decode
+pub fn assert_prefixes_rejected(compressed: &[u8], step: usize, decode: impl Fn(&[u8]) -> Result<Vec<u8>>) {
+    for length in (0..compressed.len()).step_by(step) {
+        assert!(decode(&compressed[..length]).is_err(), "prefix of {} bytes", length);
+    }
+}
+
+// A flipped bit at any of `positions` makes decoding fail, as the checksum
+// catches whatever the decoder itself doesn't
+pub fn assert_bit_flips_rejected(
+    compressed: &[u8],
+    positions: impl Iterator<Item = usize>,
+    decode: impl Fn(&[u8]) -> Result<Vec<u8>>,
+) {
+    for position in positions {
+        let mut damaged = compressed.to_vec();
+        damaged[position] ^= 0x10;
+        assert!(decode(&damaged).is_err(), "bit flipped in byte {}", position);
+    }
+}
+
+// Concatenating two of our own files gives a valid file with both members in it
+pub fn assert_concatenation_decodes(encode: impl Fn(&[u8]) -> Vec<u8>, decode: impl Fn(&[u8]) -> Result<Vec<u8>>) {
+    let mut both = encode(b"first, ");
+    both.extend(encode(b"second"));
+    assert_eq!(decode(&both).unwrap(), b"first, second");
+}
+
+// Every `step`th prefix of a stream without an end marker either fails to
+// decode or decodes to a prefix of `original`
+pub fn assert_prefixes_decode_to_prefixes(
+    compressed: &[u8],
+    original: &[u8],
+    step: usize,
+    decode: impl Fn(&[u8]) -> Result<Vec<u8>>,
+) {
+    for length in (0..compressed.len()).step_by(step) {
+        if let Ok(decompressed) = decode(&compressed[..length]) {
+            assert!(original.starts_with(&decompressed), "prefix of {} bytes", length);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{assert_prefixes_decode_to_prefixes, compress, decoder, decompress, sample};
+use compression_tool::compression::CompressionTool;
+use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::Format;
+use compression_tool::Error;
+use std::fs;
+
+// The files in tests/compress hold tests/deflate/sample.txt as written by a
+// port of compress 4.2's encoder, and were checked with gzip -d: sample.Z
+// with the usual 16-bit codes and sample.b9.Z with -b9, where the dictionary
+// fills up and gets cleared along the way.
+
+#[test]
+fn test_uncompress_z_files() {
+    for name in ["sample.Z", "sample.b9.Z"] {
+        let compressed = fs::read(format!("tests/compress/{}", name)).unwrap();
+        let decompression_tool = DecompressionTool::new().format(Format::Compress);
+        assert_eq!(decompress(decompression_tool, &compressed).unwrap(), sample(), "{}", name);
+        // Without a format, .Z files are recognised by their magic bytes
+        assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), sample(), "{}", name);
+    }
+}
+
+#[test]
+fn test_z_round_trips() {
+    let text = &fs::read("tests/test.txt").unwrap()[..300_000];
+    let noise: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+    for bits in [9, 12, 16] {
+        for input in [&b""[..], b"a", &[7; 1000], &sample(), &noise, text] {
+            let compressed = compress(CompressionTool::new().format(Format::Compress).lzw_max_bits(bits), input);
+            assert_eq!(compressed[..3], [0x1F, 0x9D, 0x80 | bits]);
+            assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input, "{} bits", bits);
+        }
+    }
+
+    // Our output is the same as compress's own
+    let compressed = compress(CompressionTool::new().format(Format::Compress), &sample());
+    assert_eq!(compressed, fs::read("tests/compress/sample.Z").unwrap());
+    let compressed = compress(CompressionTool::new().format(Format::Compress).lzw_max_bits(9), &sample());
+    assert_eq!(compressed, fs::read("tests/compress/sample.b9.Z").unwrap());
+}
+
+// LZW blocks in our own container are coded independently, so they decode in
+// parallel and don't depend on the number of threads
+#[test]
+fn test_lzw_blocks() {
+    let text = &fs::read("tests/test.txt").unwrap()[..300_000];
+    let compression_tool = || CompressionTool::new().lzw(true).block_size(64 * 1024);
+    let compressed = compress(compression_tool(), text);
+    assert_eq!(compress(compression_tool().threads(3), text), compressed);
+    assert!(compressed.len() < text.len() / 2);
+    for threads in [1, 3] {
+        assert_eq!(decompress(DecompressionTool::new().threads(threads), &compressed).unwrap(), text);
+    }
+
+    for bits in [9, 13] {
+        let compressed = compress(compression_tool().lzw_max_bits(bits), text);
+        assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), text);
+    }
+
+    // The container's checksum catches damage that still decodes
+    let mut damaged = compress(CompressionTool::new().lzw(true), &sample());
+    let position = damaged.len() / 2;
+    damaged[position] ^= 0x01;
+    assert!(decompress(DecompressionTool::new(), &damaged).is_err());
+}
+
+#[test]
+fn test_damaged_z_files_are_rejected() {
+    let decompression_tool = || DecompressionTool::new().format(Format::Compress);
+    let compressed = fs::read("tests/compress/sample.Z").unwrap();
+
+    // .Z files have no end marker, so a file cut short decodes to a prefix of
+    // the original, or fails if it ends where the codes no longer make sense
+    assert_prefixes_decode_to_prefixes(&compressed, &sample(), 101, decoder(Format::Compress));
+    for length in 0..3 {
+        assert!(matches!(decompress(decompression_tool(), &compressed[..length]), Err(Error::InvalidHeader(_))));
+    }
+
+    // Widths run from 9 to 16 bits, and the reserved flags must be clear
+    let mut damaged = compressed.clone();
+    damaged[2] = 0x80 | 17;
+    assert!(matches!(decompress(decompression_tool(), &damaged), Err(Error::InvalidHeader(_))));
+    damaged[2] = 0x80 | 0x20 | 16;
+    assert!(matches!(decompress(decompression_tool(), &damaged), Err(Error::UnsupportedFlags(_))));
+
+    // Codes that aren't in the dictionary yet
+    assert!(matches!(decompress(decompression_tool(), &[0x1F, 0x9D, 0x90, 0xFF, 0xFF]), Err(Error::CorruptData(_))));
+}
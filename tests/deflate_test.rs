@@ -0,0 +1,108 @@
+mod common;
+
+use common::{assert_prefixes_rejected, compress, decoder, sample};
+use compression_tool::compression::CompressionTool;
+use compression_tool::format::Format;
+use compression_tool::Error;
+use std::fs;
+
+// The streams in tests/deflate were written by zlib (raw DEFLATE, window
+// bits -15) from sample.txt, one per block type and compression strategy
+const ZLIB_STREAMS: &[&str] = &["stored", "fixed", "dynamic", "huffman_only", "flushes"];
+
+fn inflate(compressed: &[u8]) -> compression_tool::Result<Vec<u8>> {
+    decoder(Format::Deflate)(compressed)
+}
+
+fn deflate(compression_tool: CompressionTool, input: &[u8]) -> Vec<u8> {
+    compress(compression_tool.format(Format::Deflate), input)
+}
+
+// Input with long runs of the same byte, as used for the RLE stream
+fn runs() -> Vec<u8> {
+    (0..500).flat_map(|i| vec![b'A' + (i % 7) as u8; i % 50 + 1]).collect()
+}
+
+#[test]
+fn test_inflate_zlib_streams() {
+    let sample = sample();
+    for name in ZLIB_STREAMS {
+        let compressed = fs::read(format!("tests/deflate/sample.{}.deflate", name)).unwrap();
+        assert_eq!(inflate(&compressed).unwrap(), sample, "stream {}", name);
+    }
+
+    let compressed = fs::read("tests/deflate/runs.rle.deflate").unwrap();
+    assert_eq!(inflate(&compressed).unwrap(), runs());
+}
+
+// Small streams put together by hand, bit by bit
+#[test]
+fn test_inflate_hand_crafted_streams() {
+    // An empty fixed block
+    assert_eq!(inflate(&[0x03, 0x00]).unwrap(), b"");
+    // An empty stored block
+    assert_eq!(inflate(&[0x01, 0x00, 0x00, 0xFF, 0xFF]).unwrap(), b"");
+    // A stored block holding "hi"
+    assert_eq!(inflate(&[0x01, 0x02, 0x00, 0xFD, 0xFF, b'h', b'i']).unwrap(), b"hi");
+    // "abc" as fixed Huffman literals
+    assert_eq!(inflate(&[0x4B, 0x4C, 0x4A, 0x06, 0x00]).unwrap(), b"abc");
+    // A non-final empty stored block followed by a final fixed one
+    assert_eq!(inflate(&[0x00, 0x00, 0x00, 0xFF, 0xFF, 0x4B, 0x4C, 0x4A, 0x06, 0x00]).unwrap(), b"abc");
+    // "a" followed by a match of length 3 at distance 1, overlapping itself
+    assert_eq!(inflate(&[0x4B, 0x04, 0x02, 0x00]).unwrap(), b"aaaa");
+}
+
+#[test]
+fn test_inflate_rejects_invalid_streams() {
+    let corrupt = |compressed: &[u8]| matches!(inflate(compressed), Err(Error::CorruptData(_)));
+
+    // Block type 11 is reserved
+    assert!(corrupt(&[0x07, 0x00]));
+    // LEN and NLEN of a stored block don't match
+    assert!(corrupt(&[0x01, 0x02, 0x00, 0xFF, 0xFF, b'h', b'i']));
+    // A match at distance 1 before any output
+    assert!(corrupt(&[0x03, 0x02, 0x00]));
+    // Data after the final block
+    assert!(corrupt(&[0x03, 0x00, 0x00]));
+
+    // No final block, or a stream cut short anywhere
+    assert!(matches!(inflate(&[0x00, 0x00, 0x00, 0xFF, 0xFF]), Err(Error::Truncated)));
+    let compressed = fs::read("tests/deflate/sample.dynamic.deflate").unwrap();
+    assert_prefixes_rejected(&compressed, 97, inflate);
+}
+
+#[test]
+fn test_deflate_round_trips() {
+    let sample = sample();
+    let text = &fs::read("tests/test.txt").unwrap()[..200_000];
+    let noise: Vec<u8> = (0..70_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+
+    for input in [&b""[..], b"a", &sample, text, &noise, &runs()] {
+        let compressed = deflate(CompressionTool::new(), input);
+        assert_eq!(inflate(&compressed).unwrap(), input);
+    }
+
+    // The stream is much smaller than the text, in the same league as zlib
+    let compressed = deflate(CompressionTool::new(), &sample);
+    let zlib = fs::read("tests/deflate/sample.dynamic.deflate").unwrap();
+    assert!(compressed.len() < zlib.len() * 11 / 10, "{} vs {}", compressed.len(), zlib.len());
+}
+
+// Blocks are compressed in parallel chunks, which must not change the stream
+#[test]
+fn test_deflate_output_is_independent_of_threads() {
+    let text = &fs::read("tests/test.txt").unwrap()[..100_000];
+    for block_size in [1024, 40_000, 1 << 20] {
+        let single = deflate(CompressionTool::new().block_size(block_size), text);
+        assert_eq!(inflate(&single).unwrap(), text);
+        for threads in [2, 3] {
+            let parallel = deflate(CompressionTool::new().block_size(block_size).threads(threads), text);
+            assert_eq!(parallel, single, "block size {}, {} threads", block_size, threads);
+        }
+    }
+
+    // Input that ends exactly on a batch boundary
+    let exact = &text[..4 * 1024];
+    let compressed = deflate(CompressionTool::new().block_size(1024).threads(2), exact);
+    assert_eq!(inflate(&compressed).unwrap(), exact);
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::{assert_bit_flips_rejected, assert_concatenation_decodes, assert_prefixes_rejected, compress, decoder, decompress, sample};
+use compression_tool::compression::CompressionTool;
+use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::Format;
+use compression_tool::gzip::GzipHeader;
+use compression_tool::Error;
+use std::fs;
+
+// The files in tests/gzip were written by gzip from tests/deflate/sample.txt:
+// sample.gz in one member, multi.gz in two (split after 3000 bytes).
+// all_fields.gz holds the first 1000 bytes behind a header that sets every
+// optional field, including the header CRC.
+fn gzip(compression_tool: CompressionTool, input: &[u8]) -> Vec<u8> {
+    compress(compression_tool.format(Format::Gzip), input)
+}
+
+#[test]
+fn test_gunzip_gzip_files() {
+    for name in ["sample.gz", "multi.gz"] {
+        let compressed = fs::read(format!("tests/gzip/{}", name)).unwrap();
+        assert_eq!(decompress(DecompressionTool::new().format(Format::Gzip), &compressed).unwrap(), sample(), "{}", name);
+    }
+
+    let compressed = fs::read("tests/gzip/all_fields.gz").unwrap();
+    assert_eq!(decompress(DecompressionTool::new().format(Format::Gzip), &compressed).unwrap(), &sample()[..1000]);
+
+    let header = GzipHeader::read_from(&mut &compressed[..]).unwrap().unwrap();
+    assert_eq!(header.mtime, 1_234_567_890);
+    assert_eq!(header.name.as_deref(), Some(&b"extra.txt"[..]));
+    assert_eq!(header.comment.as_deref(), Some(&b"a comment"[..]));
+    assert_eq!(header.extra.as_deref(), Some(&b"AP\x04\x00wxyz"[..]));
+}
+
+// Without a format, gzip files are told apart from our own by their magic bytes
+#[test]
+fn test_gzip_is_detected() {
+    let compressed = fs::read("tests/gzip/multi.gz").unwrap();
+    assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), sample());
+
+    let mut ours = Vec::new();
+    CompressionTool::new().compress(&mut &sample()[..], &mut ours).unwrap();
+    assert_eq!(decompress(DecompressionTool::new(), &ours).unwrap(), sample());
+
+    // Too short to hold either magic
+    assert!(matches!(decompress(DecompressionTool::new(), &[0x1F]), Err(Error::InvalidHeader(_))));
+}
+
+#[test]
+fn test_gzip_round_trips() {
+    let text = &fs::read("tests/test.txt").unwrap()[..200_000];
+    for input in [&b""[..], b"a", &sample(), text] {
+        for threads in [1, 3] {
+            let compressed = gzip(CompressionTool::new().block_size(1024 * 64).threads(threads), input);
+            assert_eq!(&compressed[..2], &[0x1F, 0x8B]);
+            assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input);
+        }
+    }
+
+    let compressed = gzip(CompressionTool::new().file_name("notes.txt").modification_time(1_600_000_000), b"notes");
+    let header = GzipHeader::read_from(&mut &compressed[..]).unwrap().unwrap();
+    assert_eq!(header.name.as_deref(), Some(&b"notes.txt"[..]));
+    assert_eq!(header.mtime, 1_600_000_000);
+
+    // Concatenating our own files gives a valid multi-member file
+    assert_concatenation_decodes(|input| gzip(CompressionTool::new(), input), decoder(Format::Gzip));
+}
+
+#[test]
+fn test_damaged_gzip_files_are_rejected() {
+    let gunzip = decoder(Format::Gzip);
+    let compressed = fs::read("tests/gzip/sample.gz").unwrap();
+
+    // A flipped bit in the CRC-32 or ISIZE of the trailer
+    let mut damaged = compressed.clone();
+    let crc = damaged.len() - 8;
+    damaged[crc] ^= 1;
+    assert!(matches!(gunzip(&damaged), Err(Error::ChecksumMismatch { .. })));
+    let mut damaged = compressed.clone();
+    let size = damaged.len() - 4;
+    damaged[size] ^= 1;
+    assert!(matches!(gunzip(&damaged), Err(Error::CorruptData(_))));
+
+    // Damage past the header, whose modification time and file name aren't
+    // checked, is caught
+    let data = 10 + b"sample.txt\0".len();
+    assert_bit_flips_rejected(&compressed, (data..compressed.len()).step_by(7), &gunzip);
+
+    // Cut short, or followed by something that isn't another member
+    assert_prefixes_rejected(&compressed, 101, &gunzip);
+    let mut trailing = compressed.clone();
+    trailing.extend_from_slice(b"junk");
+    assert!(matches!(gunzip(&trailing), Err(Error::InvalidHeader(_))));
+
+    // Only DEFLATE is defined as a compression method
+    let mut damaged = compressed;
+    damaged[2] = 7;
+    assert!(matches!(gunzip(&damaged), Err(Error::InvalidHeader(_))));
+}
@@ -1,7 +1,14 @@
+use compression_tool::checksum::ChecksumKind;
 use compression_tool::compression::CompressionTool;
 use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::{EntropyCoder, Header, RunLength, FLAG_ADAPTIVE_HUFFMAN, FLAG_ORIGINAL_LENGTH, FLAG_RUN_LENGTH};
+use compression_tool::Error;
 use std::fs::{remove_file, File};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+
+mod common;
+use common::{compress, decompress};
 
 // Test compression and decompression of a file
 #[test]
@@ -17,7 +24,7 @@ fn test_compress_decompress() {
     let mut compression_tool = CompressionTool::new();
     let compressed_file = File::create(compressed_file_path).unwrap();
     let mut compressed_writer = BufWriter::new(compressed_file);
-    compression_tool.compress(&mut reader, &mut compressed_writer);
+    compression_tool.compress(&mut reader, &mut compressed_writer).unwrap();
 
     // **Ensure the compression writer is flushed to disk**
     compressed_writer.flush().unwrap();
@@ -31,7 +38,7 @@ fn test_compress_decompress() {
     let decompression_tool = DecompressionTool::new();
 
     let mut decompressed_writer = BufWriter::new(decompressed_file);
-    decompression_tool.decompress(&mut BufReader::new(compressed_file_for_decompression), &mut decompressed_writer);
+    decompression_tool.decompress(&mut BufReader::new(compressed_file_for_decompression), &mut decompressed_writer).unwrap();
 
     // **Ensure the decompressed writer is flushed to disk**
     decompressed_writer.flush().unwrap();
@@ -52,3 +59,657 @@ fn test_compress_decompress() {
     remove_file(compressed_file_path).expect("Failed to delete compressed file");
     remove_file(decompressed_file_path).expect("Failed to delete decompressed file");
 }
+
+// Files produced before the container header existed still decode through the legacy path
+#[test]
+fn test_decompress_legacy_file() {
+    let compressed_file = File::open("tests/test.txt.compressed").unwrap();
+    let mut decompressed_content = Vec::new();
+
+    let decompression_tool = DecompressionTool::new();
+    decompression_tool.decompress_legacy(&mut BufReader::new(compressed_file), &mut decompressed_content).unwrap();
+
+    let mut original_content = Vec::new();
+    File::open("tests/test.txt").unwrap().read_to_end(&mut original_content).unwrap();
+
+    assert_eq!(original_content, decompressed_content);
+}
+
+// The old encoder gave a lone symbol the empty code and no payload, so the
+// symbol's count is what says how many there were
+#[test]
+fn test_decompress_legacy_single_symbol_file() {
+    let mut legacy = 1u32.to_le_bytes().to_vec();
+    legacy.push(b'z');
+    legacy.extend_from_slice(&5i32.to_le_bytes());
+    legacy.extend_from_slice(&[0, 0]);
+    let mut output = Vec::new();
+    DecompressionTool::new().decompress_legacy(&mut &legacy[..], &mut output).unwrap();
+    assert_eq!(output, b"zzzzz");
+}
+
+// A lone symbol with the largest count is written out a little at a time,
+// not built up in memory first
+#[test]
+fn test_decompress_legacy_large_single_symbol_count() {
+    struct FirstWrite(Option<usize>);
+
+    impl Write for FirstWrite {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 = Some(buf.len());
+            Err(std::io::Error::other("stop after the first write"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut legacy = 1u32.to_le_bytes().to_vec();
+    legacy.push(b'z');
+    legacy.extend_from_slice(&i32::MAX.to_le_bytes());
+    legacy.extend_from_slice(&[0, 0]);
+    let mut writer = FirstWrite(None);
+    let result = DecompressionTool::new().decompress_legacy(&mut &legacy[..], &mut writer);
+    assert!(matches!(result, Err(Error::Io(_))));
+    assert!(writer.0.unwrap() <= 64 * 1024);
+}
+
+// Frequencies that add up to more than the old encoder could have counted
+#[test]
+fn test_decompress_legacy_rejects_overflowing_frequencies() {
+    let mut legacy = 2u32.to_le_bytes().to_vec();
+    for symbol in [b'a', b'b'] {
+        legacy.push(symbol);
+        legacy.extend_from_slice(&i32::MAX.to_le_bytes());
+    }
+    legacy.extend_from_slice(&[0, 0, 0x80]);
+    let mut output = Vec::new();
+    let result = DecompressionTool::new().decompress_legacy(&mut &legacy[..], &mut output);
+    assert!(matches!(result, Err(Error::InvalidHeader(_))));
+    assert!(output.is_empty());
+}
+
+// Input without the magic bytes is rejected instead of being decoded into garbage
+#[test]
+fn test_decompress_rejects_non_compressed_input() {
+    let decompression_tool = DecompressionTool::new();
+
+    let mut output = Vec::new();
+    let result = decompression_tool.decompress(&mut Cursor::new(b"just some plain text".to_vec()), &mut output);
+    assert!(matches!(result, Err(Error::InvalidHeader(_))));
+    assert!(output.is_empty());
+
+    // Legacy files must be requested explicitly
+    let legacy_file = File::open("tests/test.txt.compressed").unwrap();
+    let result = decompression_tool.decompress(&mut BufReader::new(legacy_file), &mut output);
+    assert!(result.is_err());
+    assert!(output.is_empty());
+}
+
+// Both checksum kinds round-trip
+#[test]
+fn test_checksum_kinds_round_trip() {
+    let input = b"abracadabra, abracadabra, abracadabra!";
+
+    for kind in [ChecksumKind::Crc32, ChecksumKind::Crc64] {
+        let compression_tool = CompressionTool::new().checksum(kind);
+        let compressed = compress(compression_tool, input);
+
+        let mut output = Vec::new();
+        DecompressionTool::new().decompress(&mut Cursor::new(compressed), &mut output).unwrap();
+        assert_eq!(output, input);
+    }
+}
+
+// A flipped bit in the payload is reported as a checksum mismatch
+#[test]
+fn test_corrupted_payload_is_detected() {
+    let input = b"the quick brown fox jumps over the lazy dog".repeat(10);
+    let mut compressed = compress(CompressionTool::new(), &input);
+
+    // Flip a bit in the middle of the payload, well clear of the header and trailer
+    let middle = compressed.len() - 20;
+    compressed[middle] ^= 0x10;
+
+    let mut output = Vec::new();
+    let err = DecompressionTool::new().decompress(&mut Cursor::new(compressed), &mut output).unwrap_err();
+    assert!(matches!(err, Error::ChecksumMismatch { .. }));
+}
+
+// A file cut short is never reported as a successful decode
+#[test]
+fn test_truncated_file_is_detected() {
+    let input = b"the quick brown fox jumps over the lazy dog".repeat(10);
+    let compressed = compress(CompressionTool::new(), &input);
+    let truncated = compressed[..compressed.len() - 3].to_vec();
+
+    let mut output = Vec::new();
+    assert!(DecompressionTool::new().decompress(&mut Cursor::new(truncated), &mut output).is_err());
+}
+
+// Empty input makes a file with no blocks, which decodes to nothing; an empty
+// file isn't a compressed file at all
+#[test]
+fn test_empty_input_round_trips() {
+    // The header, the end-of-stream block and the checksum
+    let compressed = compress(CompressionTool::new().checksum(ChecksumKind::Crc64), b"");
+    assert_eq!(compressed.len(), 13 + 1 + 8);
+    assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), b"");
+    for tool in [CompressionTool::new().adaptive_huffman(true), CompressionTool::new().bwt(true).threads(2)] {
+        assert_eq!(decompress(DecompressionTool::new(), &compress(tool, b"")).unwrap(), b"");
+    }
+
+    let mut output = Vec::new();
+    let result = DecompressionTool::new().decompress(&mut Cursor::new(Vec::new()), &mut output);
+    assert!(matches!(result, Err(Error::InvalidHeader(_))));
+
+    let result = DecompressionTool::new().decompress_legacy(&mut Cursor::new(Vec::new()), &mut output);
+    assert!(matches!(result, Err(Error::Truncated)));
+}
+
+// Every prefix of a valid file fails cleanly instead of panicking
+#[test]
+fn test_every_truncation_errors() {
+    let input = b"mississippi river banks";
+    let compressed = compress(CompressionTool::new(), input);
+
+    for length in 0..compressed.len() {
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut Cursor::new(compressed[..length].to_vec()), &mut output);
+        assert!(result.is_err(), "prefix of length {} decoded successfully", length);
+    }
+}
+
+// Skewed (Fibonacci-distributed) input whose unconstrained Huffman tree is
+// much deeper than the code length limit
+#[test]
+fn test_fibonacci_distributed_input_round_trips() {
+    let mut input = Vec::new();
+    let (mut a, mut b) = (1usize, 1usize);
+    for symbol in 0..24u8 {
+        input.extend(std::iter::repeat_n(symbol, a));
+        (a, b) = (b, a + b);
+    }
+
+    for max_code_length in [8, 12, 15] {
+        let compression_tool = CompressionTool::new().max_code_length(max_code_length);
+        let compressed = compress(compression_tool, &input);
+
+        let mut output = Vec::new();
+        DecompressionTool::new().decompress(&mut Cursor::new(compressed), &mut output).unwrap();
+        assert_eq!(output, input);
+    }
+}
+
+// Files written with the single-table version 1 layout still decode
+#[test]
+fn test_decompress_version_1_file() {
+    let compressed_file = File::open("tests/test_v1.compressed").unwrap();
+    let mut decompressed_content = Vec::new();
+    DecompressionTool::new().decompress(&mut BufReader::new(compressed_file), &mut decompressed_content).unwrap();
+
+    let mut original_content = Vec::new();
+    File::open("tests/test.txt").unwrap().read_to_end(&mut original_content).unwrap();
+    assert_eq!(decompressed_content, &original_content[..4096]);
+}
+
+// Input is split into blocks of any size, and the last block may be short
+#[test]
+fn test_block_sizes_round_trip() {
+    let input: Vec<u8> = (0..20_000u32).map(|i| (i * i % 251) as u8).collect();
+
+    for block_size in [1024, 1500, 4096, 20_000, 1024 * 1024] {
+        let compression_tool = CompressionTool::new().block_size(block_size);
+        let compressed = compress(compression_tool, &input);
+        assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input);
+    }
+}
+
+// Compression and decompression work on plain Read/Write, such as a pipe
+#[test]
+fn test_compress_from_unseekable_reader() {
+    struct Pipe<'a>(&'a [u8]);
+
+    impl Read for Pipe<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            // Hand out short reads like a pipe would
+            let length = buf.len().min(self.0.len()).min(100);
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    let input = b"streaming data through a pipe ".repeat(200);
+    let mut compressed = Vec::new();
+    CompressionTool::new().block_size(1024).compress(&mut Pipe(&input), &mut compressed).unwrap();
+
+    let mut output = Vec::new();
+    DecompressionTool::new().decompress(&mut Pipe(&compressed), &mut output).unwrap();
+    assert_eq!(output, input);
+}
+
+// Per-block tables adapt when the statistics of the input change. The data
+// is periodic, so LZ77 is turned off to look at the Huffman tables alone.
+#[test]
+fn test_per_block_tables_adapt() {
+    let mut input = Vec::new();
+    for i in 0..16_384u32 {
+        input.push(b"abcd"[(i * 7 % 4) as usize]);
+    }
+    for i in 0..16_384u32 {
+        input.push(b"wxyz"[(i * 5 % 4) as usize]);
+    }
+
+    let one_block = compress(CompressionTool::new().lz77(false), &input);
+    let two_blocks = compress(CompressionTool::new().lz77(false).block_size(16_384), &input);
+    assert!(two_blocks.len() < one_block.len());
+    assert_eq!(decompress(DecompressionTool::new(), &two_blocks).unwrap(), input);
+}
+
+// The output doesn't depend on the number of threads, and any thread count
+// decodes it
+#[test]
+fn test_thread_count_does_not_change_output() {
+    let input = std::fs::read("tests/test.txt").unwrap();
+    let serial = compress(CompressionTool::new().block_size(16 * 1024), &input);
+
+    for threads in [2, 3, 8] {
+        let parallel = compress(CompressionTool::new().block_size(16 * 1024).threads(threads), &input);
+        assert_eq!(parallel, serial, "{} threads", threads);
+
+        let mut output = Vec::new();
+        DecompressionTool::new().threads(threads).decompress(&mut &serial[..], &mut output).unwrap();
+        assert_eq!(output, input, "{} threads", threads);
+    }
+}
+
+// Errors in a batch are reported in block order, whatever the thread count
+#[test]
+fn test_parallel_decode_reports_first_error() {
+    let input = b"first block, second block, third block ".repeat(100);
+    let mut compressed = compress(CompressionTool::new().block_size(1024), &input);
+    compressed.truncate(compressed.len() - 20);
+
+    for threads in [1, 2, 4] {
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().threads(threads).decompress(&mut &compressed[..], &mut output);
+        assert!(matches!(result, Err(Error::Truncated)), "{} threads", threads);
+        assert_eq!(output, &input[..output.len()]);
+        assert_eq!(output.len(), 3 * 1024);
+    }
+}
+
+// LZ77 pays off on repetitive data, and every window size and matching mode
+// round-trips
+#[test]
+fn test_lz77_round_trips() {
+    let mut input = Vec::new();
+    for i in 0..2000 {
+        input.extend_from_slice(format!("{{\"id\":{},\"level\":\"INFO\",\"msg\":\"request handled\"}}\n", i % 97).as_bytes());
+    }
+
+    let huffman_only = compress(CompressionTool::new().lz77(false), &input);
+    let lz77 = compress(CompressionTool::new(), &input);
+    assert!(lz77.len() * 4 < huffman_only.len());
+    assert_eq!(decompress(DecompressionTool::new(), &lz77).unwrap(), input);
+
+    for (window_size, lazy) in [(256, false), (256, true), (4096, true), (1 << 24, true)] {
+        let compressed = compress(
+            CompressionTool::new().window_size(window_size).lazy_matching(lazy).block_size(32 * 1024),
+            &input,
+        );
+        assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input, "window {} lazy {}", window_size, lazy);
+    }
+}
+
+// The adaptive Huffman mode is recorded in the header, needs no stored
+// tables and round-trips under every checksum and thread count
+#[test]
+fn test_adaptive_huffman_round_trips() {
+    let text = &std::fs::read("tests/test.txt").unwrap()[..100_000];
+    let adaptive = compress(CompressionTool::new().adaptive_huffman(true), text);
+    let header = Header::read_from(&mut &adaptive[..]).unwrap();
+    assert_ne!(header.flags & FLAG_ADAPTIVE_HUFFMAN, 0);
+    assert_eq!(decompress(DecompressionTool::new(), &adaptive).unwrap(), text);
+
+    // One pass over the data costs about as much as two-pass static coding
+    let static_huffman = compress(CompressionTool::new().lz77(false), text);
+    assert!(adaptive.len() < static_huffman.len() + static_huffman.len() / 50);
+
+    for input in [&b"a"[..], b"mississippi", &[0xFF; 3000]] {
+        for checksum in [ChecksumKind::Crc32, ChecksumKind::Crc64] {
+            let compressed = compress(CompressionTool::new().adaptive_huffman(true).checksum(checksum), input);
+            assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input);
+        }
+    }
+    let compressed = compress(CompressionTool::new().adaptive_huffman(true).threads(4), text);
+    assert_eq!(compressed, adaptive);
+    let mut output = Vec::new();
+    DecompressionTool::new().threads(4).decompress(&mut &compressed[..], &mut output).unwrap();
+    assert_eq!(output, text);
+}
+
+// Damaged adaptive streams fail cleanly
+#[test]
+fn test_damaged_adaptive_streams_are_rejected() {
+    let input = b"the quick brown fox jumps over the lazy dog".repeat(10);
+    let compressed = compress(CompressionTool::new().adaptive_huffman(true), &input);
+
+    for length in 0..compressed.len() {
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &compressed[..length], &mut output);
+        assert!(result.is_err(), "prefix of length {} decoded successfully", length);
+    }
+
+    let mut damaged = compressed;
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 0x10;
+    let mut output = Vec::new();
+    assert!(DecompressionTool::new().decompress(&mut &damaged[..], &mut output).is_err());
+}
+
+// The range coder beats Huffman coding on tests/test.txt, by a wide margin on
+// skewed data, and either kind of block decodes without being told which
+#[test]
+fn test_range_coder_against_huffman() {
+    let input = std::fs::read("tests/test.txt").unwrap();
+    let huffman = compress(CompressionTool::new().lz77(false), &input);
+    let range = compress(CompressionTool::new().lz77(false).entropy_coder(EntropyCoder::Range), &input);
+    assert!(range.len() < huffman.len(), "range {} vs Huffman {}", range.len(), huffman.len());
+    assert_eq!(decompress(DecompressionTool::new(), &range).unwrap(), input);
+
+    // Nearly all zeros: Huffman can't spend less than a bit on each byte
+    let mut skewed = vec![0u8; 200_000];
+    for i in (0..skewed.len()).step_by(97) {
+        skewed[i] = (i % 7) as u8 + 1;
+    }
+    // without the run-length encoding that would take most of the zeros away
+    let tool = || CompressionTool::new().lz77(false).run_length(RunLength::Off);
+    let huffman = compress(tool(), &skewed);
+    let range = compress(tool().entropy_coder(EntropyCoder::Range), &skewed);
+    assert!(range.len() * 2 < huffman.len(), "range {} vs Huffman {}", range.len(), huffman.len());
+    assert_eq!(decompress(DecompressionTool::new(), &range).unwrap(), skewed);
+
+    // LZ77 still steps in where it pays off, and any thread count decodes the mix
+    let compressed =
+        compress(CompressionTool::new().entropy_coder(EntropyCoder::Range).block_size(64 * 1024).threads(3), &input);
+    for threads in [1, 4] {
+        let mut output = Vec::new();
+        DecompressionTool::new().threads(threads).decompress(&mut &compressed[..], &mut output).unwrap();
+        assert_eq!(output, input);
+    }
+}
+
+// Damaged range coded blocks fail cleanly. The last flushed bytes carry more
+// precision than decoding needs, so damage there may still decode correctly.
+#[test]
+fn test_damaged_range_blocks_are_rejected() {
+    let input = b"mississippi river banks".repeat(20);
+    let compressed = compress(CompressionTool::new().lz77(false).entropy_coder(EntropyCoder::Range), &input);
+    for length in 0..compressed.len() {
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &compressed[..length], &mut output);
+        assert!(result.is_err(), "prefix of length {} decoded successfully", length);
+    }
+    for position in 14..compressed.len() {
+        let mut damaged = compressed.clone();
+        damaged[position] ^= 0x40;
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &damaged[..], &mut output);
+        assert!(result.is_err() || output == input, "byte {}", position);
+    }
+}
+
+// FSE lands close to the range coder and well ahead of Huffman on skewed
+// data. Auto picks a coder per block, so it never loses to any single one.
+#[test]
+fn test_fse_and_per_block_coders() {
+    let text = std::fs::read("tests/test.txt").unwrap();
+    let mut skewed = vec![0u8; 200_000];
+    for i in (0..skewed.len()).step_by(97) {
+        skewed[i] = (i % 7) as u8 + 1;
+    }
+
+    for input in [&text[..], &skewed] {
+        let size = |coder| compress(CompressionTool::new().lz77(false).entropy_coder(coder), input).len();
+        let (huffman, range, fse) = (size(EntropyCoder::Huffman), size(EntropyCoder::Range), size(EntropyCoder::Fse));
+        assert!(fse < huffman, "FSE {} vs Huffman {}", fse, huffman);
+        assert!(fse < range + range / 50, "FSE {} vs range {}", fse, range);
+
+        let compressed = compress(CompressionTool::new().lz77(false).entropy_coder(EntropyCoder::Fse), input);
+        assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input);
+    }
+
+    // Text followed by skewed data, in blocks that favour different coders
+    let mut mixed = text[..256 * 1024].to_vec();
+    mixed.extend_from_slice(&skewed);
+    let tool = || CompressionTool::new().block_size(64 * 1024).threads(2);
+    let auto = compress(tool().entropy_coder(EntropyCoder::Auto), &mixed);
+    for coder in [EntropyCoder::Huffman, EntropyCoder::Range, EntropyCoder::Fse] {
+        let single = compress(tool().entropy_coder(coder), &mixed);
+        assert!(auto.len() <= single.len(), "{:?}", coder);
+    }
+    assert_eq!(decompress(DecompressionTool::new(), &auto).unwrap(), mixed);
+}
+
+// Damaged FSE blocks fail cleanly or, if the damage misses every bit that
+// matters, still decode to the original
+#[test]
+fn test_damaged_fse_blocks_are_rejected() {
+    let input = b"mississippi river banks".repeat(20);
+    let compressed = compress(CompressionTool::new().lz77(false).entropy_coder(EntropyCoder::Fse), &input);
+    for length in 0..compressed.len() {
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &compressed[..length], &mut output);
+        assert!(result.is_err(), "prefix of length {} decoded successfully", length);
+    }
+    for position in 14..compressed.len() {
+        let mut damaged = compressed.clone();
+        damaged[position] ^= 0x40;
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &damaged[..], &mut output);
+        assert!(result.is_err() || output == input, "byte {}", position);
+    }
+}
+
+// Order-1 tables pick up what each byte says about the next, which order-0
+// coders can't, and rare contexts fall back to the shared table
+#[test]
+fn test_order1_context_tables() {
+    let text = &std::fs::read("tests/test.txt").unwrap()[..512 * 1024];
+    let tool = || CompressionTool::new().lz77(false).block_size(128 * 1024);
+    let huffman = compress(tool(), text);
+    let order1 = compress(tool().entropy_coder(EntropyCoder::Order1), text);
+    assert!(order1.len() * 10 < huffman.len() * 9, "order-1 {} vs Huffman {}", order1.len(), huffman.len());
+    assert_eq!(decompress(DecompressionTool::new(), &order1).unwrap(), text);
+
+    // Too short for any context to earn a table of its own
+    let short = b"a short block where every context is rare";
+    let compressed = compress(tool().entropy_coder(EntropyCoder::Order1), short);
+    assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), short);
+
+    // Every context has its own table and the shared one goes unused
+    let alternating: Vec<u8> = (0..10_000).map(|i| if i % 2 == 0 { b'a' } else { b'b' }).collect();
+    let compressed = compress(tool().entropy_coder(EntropyCoder::Order1), &alternating);
+    assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), alternating);
+
+    for threads in [1, 3] {
+        let compressed = compress(tool().entropy_coder(EntropyCoder::Order1).threads(threads), text);
+        assert_eq!(compressed, order1);
+    }
+}
+
+// Damaged order-1 blocks fail cleanly or still decode to the original
+#[test]
+fn test_damaged_order1_blocks_are_rejected() {
+    let input = b"the quick brown fox jumps over the lazy dog. ".repeat(30);
+    let compressed = compress(CompressionTool::new().lz77(false).entropy_coder(EntropyCoder::Order1), &input);
+    for length in 0..compressed.len() {
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &compressed[..length], &mut output);
+        assert!(result.is_err(), "prefix of length {} decoded successfully", length);
+    }
+    for position in 14..compressed.len() {
+        let mut damaged = compressed.clone();
+        damaged[position] ^= 0x40;
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &damaged[..], &mut output);
+        assert!(result.is_err() || output == input, "byte {}", position);
+    }
+}
+
+// The BWT pipeline beats the default on text, splits oversized blocks and
+// round-trips inputs the transform finds awkward
+#[test]
+fn test_bwt_blocks() {
+    let text = std::fs::read("tests/test.txt").unwrap();
+    let default = compress(CompressionTool::new(), &text);
+    let bwt = compress(CompressionTool::new().bwt(true), &text);
+    assert!(bwt.len() < default.len(), "BWT {} vs default {}", bwt.len(), default.len());
+    assert_eq!(decompress(DecompressionTool::new(), &bwt).unwrap(), text);
+
+    // A 2 MiB block goes out as three transform blocks of at most 900,000 bytes
+    let tool = || CompressionTool::new().bwt(true).block_size(2 * 1024 * 1024);
+    let large = compress(tool(), &text[..2 * 1024 * 1024]);
+    assert_eq!(decompress(DecompressionTool::new(), &large).unwrap(), &text[..2 * 1024 * 1024]);
+    for threads in [1, 3] {
+        assert_eq!(compress(tool().threads(threads), &text[..2 * 1024 * 1024]), large);
+    }
+
+    let runs: Vec<u8> = (0..50_000u32).map(|i| (i / 1000) as u8).collect();
+    let all_bytes: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+    for input in [&b"x"[..], &[0; 100_000], &runs, &all_bytes] {
+        let compressed = compress(CompressionTool::new().bwt(true), input);
+        assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input);
+    }
+}
+
+// Damaged BWT blocks fail cleanly or still decode to the original
+#[test]
+fn test_damaged_bwt_blocks_are_rejected() {
+    let input = b"she sells sea shells by the sea shore. ".repeat(30);
+    let compressed = compress(CompressionTool::new().bwt(true), &input);
+    for length in 0..compressed.len() {
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &compressed[..length], &mut output);
+        assert!(result.is_err(), "prefix of length {} decoded successfully", length);
+    }
+    for position in 14..compressed.len() {
+        let mut damaged = compressed.clone();
+        damaged[position] ^= 0x40;
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &damaged[..], &mut output);
+        assert!(result.is_err() || output == input, "byte {}", position);
+    }
+}
+
+// Inputs made mostly of long runs get run-length encoded, which the header
+// records; other inputs are left alone unless asked
+#[test]
+fn test_run_length_blocks() {
+    let flags = |compressed: &[u8]| Header::read_from(&mut &compressed[..]).unwrap().flags;
+
+    // A sparse disk image: 4 MiB of zeros with a few short records in it
+    let mut sparse = vec![0u8; 4 * 1024 * 1024];
+    for (index, record) in sparse.chunks_mut(64 * 1024).enumerate() {
+        record[..100].copy_from_slice(&format!("record {:>93}", index).into_bytes());
+    }
+    let plain = compress(CompressionTool::new().lz77(false).run_length(RunLength::Off), &sparse);
+    let compressed = compress(CompressionTool::new().lz77(false), &sparse);
+    assert_eq!(flags(&plain) & FLAG_RUN_LENGTH, 0);
+    assert_ne!(flags(&compressed) & FLAG_RUN_LENGTH, 0);
+    assert!(compressed.len() * 20 < plain.len(), "{} vs {}", compressed.len(), plain.len());
+    assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), sparse);
+    assert!(compress(CompressionTool::new(), &sparse).len() < plain.len());
+
+    // Text has too few runs to be worth it
+    let text = std::fs::read("tests/test.txt").unwrap();
+    assert_eq!(flags(&compress(CompressionTool::new(), &text)) & FLAG_RUN_LENGTH, 0);
+
+    // Forced on, it works with every block coder and thread count, including
+    // blocks whose escape byte occurs on its own
+    let all_bytes: Vec<u8> = (0..=255).cycle().take(300_000).collect();
+    let tools: [fn() -> CompressionTool; 5] = [
+        CompressionTool::new,
+        || CompressionTool::new().entropy_coder(EntropyCoder::Auto),
+        || CompressionTool::new().bwt(true).block_size(2 * 1024 * 1024),
+        || CompressionTool::new().lzw(true),
+        || CompressionTool::new().block_size(64 * 1024).threads(3),
+    ];
+    for tool in tools {
+        for input in [&text[..], &sparse[..2 * 1024 * 1024], &all_bytes, b"x"] {
+            let compressed = compress(tool().run_length(RunLength::On), input);
+            assert_ne!(flags(&compressed) & FLAG_RUN_LENGTH, 0);
+            assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), input);
+        }
+    }
+
+    // Damage is caught as usual
+    let compressed = compress(CompressionTool::new().run_length(RunLength::On), &sparse[..100_000]);
+    for length in (0..compressed.len()).step_by(7) {
+        let mut output = Vec::new();
+        assert!(DecompressionTool::new().decompress(&mut &compressed[..length], &mut output).is_err(), "length {}", length);
+    }
+    for position in 14..compressed.len() {
+        let mut damaged = compressed.clone();
+        damaged[position] ^= 0x08;
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &damaged[..], &mut output);
+        assert!(result.is_err() || output == sparse[..100_000], "byte {}", position);
+    }
+}
+
+// A length given up front goes in the header; decoding then reports progress
+// against it and fails if the data comes out any other length
+#[test]
+fn test_original_length() {
+    let input = &std::fs::read("tests/test.txt").unwrap()[..100_000];
+    let tool = || CompressionTool::new().block_size(16 * 1024);
+    let compressed = compress(tool().original_length(100_000), input);
+    let header = Header::read_from(&mut &compressed[..]).unwrap();
+    assert_eq!(header.original_length, Some(100_000));
+    assert_ne!(header.flags & FLAG_ORIGINAL_LENGTH, 0);
+    assert_eq!(compressed.len(), compress(tool(), input).len() + 3);
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Arc::clone(&reports);
+    let decompression_tool = DecompressionTool::new().threads(2).progress(move |done, total| {
+        recorder.lock().unwrap().push((done, total));
+    });
+    let mut output = Vec::new();
+    decompression_tool.decompress(&mut &compressed[..], &mut output).unwrap();
+    assert_eq!(output, input);
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() >= 7);
+    assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(reports.last(), Some(&(100_000, Some(100_000))));
+
+    // The compressor holds the input to the length it was given, and stops
+    // before writing the batch where it finds out
+    let full = compress(tool().block_size(10_000).original_length(100_000), input);
+    for (length, actual, blocks) in [(99_999, 100_000, 9), (100_001, 100_000, 10), (30_000, 30_001, 3)] {
+        let mut partial = Vec::new();
+        let result = tool().block_size(10_000).original_length(length).compress(&mut &input[..], &mut partial);
+        assert!(matches!(result, Err(Error::LengthMismatch { expected, actual: a }) if expected == length && a == actual));
+        // The header and at most the blocks before it, never the end of the stream
+        assert!(partial.len() <= full.len() * blocks / 10 && partial.len() < full.len(), "length {}", length);
+    }
+
+    // A header that claims a byte more or less, which is a change to the first
+    // byte of the varint. Decoding never writes past the claimed length.
+    assert_eq!(compressed[13..16], [0xA0, 0x8D, 0x06]);
+    for (claimed, adaptive) in [(99_999, false), (100_001, false), (99_999, true), (100_001, true)] {
+        let mut damaged = compress(tool().adaptive_huffman(adaptive).original_length(100_000), input);
+        damaged[13] = 0x80 | (claimed & 0x7F) as u8;
+        let mut output = Vec::new();
+        let result = DecompressionTool::new().decompress(&mut &damaged[..], &mut output);
+        assert!(matches!(result, Err(Error::CorruptData(_))), "{} {}", claimed, adaptive);
+        assert!(output.len() <= claimed);
+    }
+
+    // Empty input records a length of zero
+    let compressed = compress(CompressionTool::new().original_length(0), b"");
+    assert_eq!(decompress(DecompressionTool::new(), &compressed).unwrap(), b"");
+}
@@ -0,0 +1,140 @@
+use compression_tool::checksum::ChecksumKind;
+use compression_tool::compression::CompressionTool;
+use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::{EntropyCoder, Format, RunLength};
+
+// Round-trip properties checked over many generated inputs. Generation is
+// seeded, so a failure names a case that can be replayed exactly.
+const CASES: u64 = 150;
+
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+// An input of one of the shapes that tend to find edge cases: nothing at all,
+// one byte value, two very unequal ones, long runs, a small alphabet, any bytes
+fn generate(rng: &mut Lcg) -> Vec<u8> {
+    // Mostly short, sometimes a few blocks' worth
+    let length = match rng.below(4) {
+        0 => rng.below(4),
+        1 | 2 => rng.below(300),
+        _ => rng.below(5000),
+    } as usize;
+    let a = rng.next() as u8;
+    let b = rng.next() as u8;
+    match rng.below(6) {
+        0 => Vec::new(),
+        1 => vec![a; length],
+        2 => (0..length).map(|_| if rng.below(50) == 0 { b } else { a }).collect(),
+        3 => {
+            let mut input = Vec::with_capacity(length);
+            while input.len() < length {
+                let run = 1 + rng.below(600) as usize;
+                input.resize((input.len() + run).min(length), rng.below(3) as u8);
+            }
+            input
+        },
+        4 => (0..length).map(|_| a.wrapping_add(rng.below(5) as u8)).collect(),
+        _ => (0..length).map(|_| rng.next() as u8).collect(),
+    }
+}
+
+fn compress(mut compression_tool: CompressionTool, input: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    compression_tool.compress(&mut &input[..], &mut compressed).unwrap();
+    compressed
+}
+
+fn decompress(decompression_tool: DecompressionTool, compressed: &[u8]) -> compression_tool::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    decompression_tool.decompress(&mut &compressed[..], &mut output)?;
+    Ok(output)
+}
+
+// Container settings, with blocks small enough that longer inputs span several
+fn container_tools() -> Vec<(&'static str, CompressionTool)> {
+    let tool = || CompressionTool::new().block_size(1024);
+    vec![
+        ("default", tool()),
+        ("no LZ77", tool().lz77(false)),
+        ("range", tool().lz77(false).entropy_coder(EntropyCoder::Range)),
+        ("FSE", tool().lz77(false).entropy_coder(EntropyCoder::Fse)),
+        ("order-1", tool().lz77(false).entropy_coder(EntropyCoder::Order1)),
+        ("auto coder", tool().entropy_coder(EntropyCoder::Auto)),
+        ("adaptive", tool().adaptive_huffman(true)),
+        ("BWT", tool().bwt(true)),
+        ("LZW", tool().lzw(true).lzw_max_bits(9)),
+        ("run-length", tool().run_length(RunLength::On).checksum(ChecksumKind::Crc64)),
+        ("no run-length", tool().run_length(RunLength::Off).max_code_length(8)),
+    ]
+}
+
+#[test]
+fn test_container_round_trips() {
+    let mut rng = Lcg(0x5EED);
+    for case in 0..CASES {
+        let input = generate(&mut rng);
+        for (name, compression_tool) in container_tools() {
+            let compressed = compress(compression_tool, &input);
+            let output = decompress(DecompressionTool::new().threads(1 + case as usize % 3), &compressed);
+            assert_eq!(output.unwrap(), input, "case {} ({} bytes) with {}", case, input.len(), name);
+        }
+    }
+}
+
+// Blocks are coded on their own, so threads only change the speed
+#[test]
+fn test_thread_count_never_changes_output() {
+    let mut rng = Lcg(0x7EAD);
+    for case in 0..CASES / 3 {
+        let input = generate(&mut rng);
+        for ((name, one), (_, three)) in container_tools().into_iter().zip(container_tools()) {
+            assert_eq!(compress(one, &input), compress(three.threads(3), &input), "case {} with {}", case, name);
+        }
+    }
+}
+
+#[test]
+fn test_other_format_round_trips() {
+    let mut rng = Lcg(0xF0F0);
+    for case in 0..CASES {
+        let input = generate(&mut rng);
+        for format in [Format::Deflate, Format::Gzip, Format::Zlib, Format::Bzip2, Format::Compress] {
+            let compressed = compress(CompressionTool::new().format(format).block_size(1024), &input);
+            let output = decompress(DecompressionTool::new().format(format), &compressed);
+            assert_eq!(output.unwrap(), input, "case {} ({} bytes) as {:?}", case, input.len(), format);
+        }
+    }
+}
+
+// Any prefix of a file is either refused or, for formats without a trailer,
+// decodes to a prefix of the input; none of them panic
+#[test]
+fn test_truncated_files_never_panic() {
+    let mut rng = Lcg(0xC0DE);
+    for case in 0..CASES / 5 {
+        let input = generate(&mut rng);
+        for (name, compression_tool) in container_tools() {
+            let compressed = compress(compression_tool, &input);
+            for length in 0..compressed.len() {
+                let result = decompress(DecompressionTool::new(), &compressed[..length]);
+                assert!(result.is_err(), "case {} with {}: prefix of {} bytes decoded", case, name, length);
+            }
+        }
+        let compressed = compress(CompressionTool::new().format(Format::Compress), &input);
+        for length in 0..compressed.len() {
+            if let Ok(output) = decompress(DecompressionTool::new().format(Format::Compress), &compressed[..length]) {
+                assert!(input.starts_with(&output), "case {}: .Z prefix of {} bytes", case, length);
+            }
+        }
+    }
+}
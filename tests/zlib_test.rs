@@ -0,0 +1,107 @@
+mod common;
+
+use common::{assert_bit_flips_rejected, assert_prefixes_rejected, compress, decompress, sample};
+use compression_tool::compression::CompressionTool;
+use compression_tool::decompression::DecompressionTool;
+use compression_tool::format::Format;
+use compression_tool::zlib::{dictionary_id, ZlibDecoder, ZlibEncoder, ZlibHeader};
+use compression_tool::Error;
+use std::fs;
+
+// The streams in tests/zlib were written by zlib from tests/deflate/sample.txt
+// at levels 9 and 1, and with a 1 KiB window. payload.zz holds 400 bytes from
+// the middle of the sample, compressed with dictionary.txt as the preset
+// dictionary.
+fn payload() -> Vec<u8> {
+    sample()[10_000..10_400].to_vec()
+}
+
+fn dictionary() -> Vec<u8> {
+    fs::read("tests/zlib/dictionary.txt").unwrap()
+}
+
+#[test]
+fn test_decode_zlib_streams() {
+    for name in ["sample.zz", "sample.level1.zz", "sample.window1k.zz"] {
+        let compressed = fs::read(format!("tests/zlib/{}", name)).unwrap();
+        assert_eq!(ZlibDecoder::new().decode(&compressed).unwrap(), sample(), "{}", name);
+    }
+
+    let compressed = fs::read("tests/zlib/sample.window1k.zz").unwrap();
+    assert_eq!(ZlibHeader::read_from(&mut &compressed[..]).unwrap().window_bits, 10);
+}
+
+#[test]
+fn test_preset_dictionary() {
+    let compressed = fs::read("tests/zlib/payload.zz").unwrap();
+    let header = ZlibHeader::read_from(&mut &compressed[..]).unwrap();
+    assert_eq!(header.dictionary_id, Some(dictionary_id(&dictionary())));
+    assert_eq!(ZlibDecoder::new().dictionary(&dictionary()).decode(&compressed).unwrap(), payload());
+
+    // Without the dictionary, or with the wrong one, the stream can't be decoded
+    assert!(matches!(ZlibDecoder::new().decode(&compressed), Err(Error::InvalidHeader(_))));
+    assert!(matches!(ZlibDecoder::new().dictionary(b"other").decode(&compressed), Err(Error::InvalidHeader(_))));
+
+    // Our own streams can reach into the dictionary too
+    let with_dictionary = ZlibEncoder::new().dictionary(&dictionary()).encode(&payload()).unwrap();
+    let without = ZlibEncoder::new().encode(&payload()).unwrap();
+    assert!(with_dictionary.len() < without.len(), "{} vs {}", with_dictionary.len(), without.len());
+    assert_eq!(ZlibDecoder::new().dictionary(&dictionary()).decode(&with_dictionary).unwrap(), payload());
+
+    // A payload taken from the dictionary comes down to a single match
+    let repeated = &dictionary()[1000..1258];
+    let compressed = ZlibEncoder::new().dictionary(&dictionary()).encode(repeated).unwrap();
+    assert!(compressed.len() < 20, "{} bytes", compressed.len());
+    assert_eq!(ZlibDecoder::new().dictionary(&dictionary()).decode(&compressed).unwrap(), repeated);
+}
+
+#[test]
+fn test_zlib_round_trips() {
+    let text = &fs::read("tests/test.txt").unwrap()[..200_000];
+    for input in [&b""[..], b"a", &sample(), text] {
+        let compressed = ZlibEncoder::new().encode(input).unwrap();
+        assert_eq!(&compressed[..2], &[0x78, 0x9C]);
+        assert_eq!(ZlibDecoder::new().decode(&compressed).unwrap(), input);
+    }
+
+    // The tools write the same stream for any number of threads
+    let compression_tool = || CompressionTool::new().format(Format::Zlib).block_size(1 << 16);
+    let expected = compress(compression_tool(), text);
+    for threads in [1, 3] {
+        let compressed = compress(compression_tool().threads(threads), text);
+        assert_eq!(compressed, expected);
+        assert_eq!(decompress(DecompressionTool::new().format(Format::Zlib).threads(threads), &compressed).unwrap(), text);
+    }
+}
+
+#[test]
+fn test_damaged_zlib_streams_are_rejected() {
+    let compressed = fs::read("tests/zlib/sample.zz").unwrap();
+    let decode = |compressed: &[u8]| ZlibDecoder::new().decode(compressed);
+
+    let mut damaged = compressed.clone();
+    let last = damaged.len() - 1;
+    damaged[last] ^= 1;
+    assert!(matches!(ZlibDecoder::new().decode(&damaged), Err(Error::ChecksumMismatch { .. })));
+
+    let mut damaged = compressed.clone();
+    damaged[1] ^= 1;
+    assert!(matches!(ZlibDecoder::new().decode(&damaged), Err(Error::InvalidHeader(_))));
+
+    // The header check and the Adler-32 leave no byte unguarded
+    assert_bit_flips_rejected(&compressed, (0..compressed.len()).step_by(7), decode);
+    assert_prefixes_rejected(&compressed, 89, decode);
+    let mut trailing = compressed;
+    trailing.push(0);
+    assert!(matches!(ZlibDecoder::new().decode(&trailing), Err(Error::CorruptData(_))));
+
+    // A header that declares a smaller window than the matches reach across.
+    // zlib's own 1 KiB stream stays within its window.
+    let mut narrowed = Vec::new();
+    ZlibHeader { window_bits: 10, level: 2, dictionary_id: None }.write_to(&mut narrowed).unwrap();
+    let ours = ZlibEncoder::new().encode(&sample()).unwrap();
+    narrowed.extend_from_slice(&ours[2..]);
+    assert!(matches!(ZlibDecoder::new().decode(&narrowed), Err(Error::CorruptData(_))));
+    let window1k = fs::read("tests/zlib/sample.window1k.zz").unwrap();
+    assert_eq!(ZlibDecoder::new().decode(&window1k).unwrap(), sample());
+}